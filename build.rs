@@ -0,0 +1,32 @@
+use std::process::Command;
+
+fn main() {
+    let git_sha = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = command_output(&rustc, &["-V"]).unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=RATTLER_SERVER_GIT_SHA={git_sha}");
+    println!("cargo:rustc-env=RATTLER_SERVER_RUSTC_VERSION={rustc_version}");
+
+    // Refresh the git SHA whenever a new commit is checked out (refs are moved to `packed-refs`
+    // when git packs them)
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+
+    // The gRPC service is compiled with the bundled protoc, unless another one is configured
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no bundled protoc");
+        std::env::set_var("PROTOC", protoc);
+    }
+    tonic_build::compile_protos("proto/rattler_server.proto").expect("compiling the protos");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
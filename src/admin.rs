@@ -0,0 +1,273 @@
+//! Contains the administrative endpoints, which are only available when an admin token is
+//! configured
+
+use crate::auth::{bearer_token, token_matches};
+use crate::available_packages_cache::{CacheInfo, FlushResult};
+use crate::config;
+use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
+use crate::subdir::Subdir;
+use crate::AppState;
+use axum::extract::{Query, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use rattler_conda_types::RepoDataRecord;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
+
+/// Returns the admin routes, protected by the admin token
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/log-level", get(get_log_level).put(set_log_level))
+        .route("/admin/cache", get(get_cache_info).delete(invalidate_cache))
+        .route("/admin/cache/repodata", put(insert_repodata))
+        .route("/admin/cache/flush", post(flush_cache))
+        .route("/admin/reload", post(reload_config))
+        .route_layer(middleware::from_fn_with_state(state, require_admin_token))
+}
+
+/// Rejects requests that don't carry the admin token as a bearer token
+async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let provided = bearer_token(request.headers());
+    match (&state.admin_token, provided) {
+        (Some(expected), Some(provided)) if token_matches(expected, provided) => {
+            next.run(request).await
+        }
+        _ => response_from_error(ApiError::Unauthorized),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevel {
+    pub level: String,
+}
+
+async fn get_log_level(State(state): State<Arc<AppState>>) -> Response {
+    match &state.log_level {
+        Some(handle) => Json(LogLevel {
+            level: handle.current().to_string().to_lowercase(),
+        })
+        .into_response(),
+        None => response_from_error(ApiError::Internal(anyhow::anyhow!(
+            "log level is not reloadable"
+        ))),
+    }
+}
+
+async fn set_log_level(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<LogLevel>,
+) -> Response {
+    let level = match LevelFilter::from_str(&payload.level) {
+        Ok(level) => level,
+        Err(e) => {
+            return response_from_error(ApiError::Validation(ValidationError::LogLevel(
+                ParseError {
+                    input: payload.level,
+                    error: e.to_string(),
+                },
+            )))
+        }
+    };
+
+    let Some(handle) = &state.log_level else {
+        return response_from_error(ApiError::Internal(anyhow::anyhow!(
+            "log level is not reloadable"
+        )));
+    };
+
+    match handle.set(level) {
+        Ok(()) => Json(LogLevel {
+            level: level.to_string().to_lowercase(),
+        })
+        .into_response(),
+        Err(e) => response_from_error(ApiError::Internal(e)),
+    }
+}
+
+/// Reloads the config file, so its settings are used from now on
+async fn reload_config(State(state): State<Arc<AppState>>) -> Response {
+    let Some(config) = &state.config else {
+        return response_from_error(ApiError::Internal(anyhow::anyhow!(
+            "no config file to reload"
+        )));
+    };
+
+    match config::reload(&state, config) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => response_from_error(ApiError::Validation(ValidationError::ConfigFile(
+            ParseError {
+                input: config.path().display().to_string(),
+                error: format!("{e:#}"),
+            },
+        ))),
+    }
+}
+
+async fn get_cache_info(State(state): State<Arc<AppState>>) -> Json<CacheInfo> {
+    Json(state.available_packages.info())
+}
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize)]
+pub struct FlushCache {
+    /// Whether to remove the repodata files cached on disk as well
+    #[serde(default)]
+    pub disk: bool,
+    /// Whether to remove the cached solve results as well
+    #[serde(default)]
+    pub solve_cache: bool,
+}
+
+/// Removes all cached repodata, so it is downloaded again when needed
+async fn flush_cache(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<FlushCache>,
+) -> Response {
+    match flush(&state, payload).await {
+        Ok(result) => Json::<FlushResult>(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+/// Removes all cached repodata, and the cached solve results if requested
+pub async fn flush(state: &AppState, params: FlushCache) -> Result<FlushResult, ApiError> {
+    let mut result = state.available_packages.flush(params.disk).await?;
+    result.solve_results_removed = flush_solve_results(state, params.solve_cache);
+    Ok(result)
+}
+
+/// Removes the cached solve results if requested, returning how many were removed
+fn flush_solve_results(state: &AppState, solve_cache: bool) -> usize {
+    match &state.solve_results {
+        Some(solve_results) if solve_cache => solve_results.clear(),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvalidateCache {
+    pub channel: Option<String>,
+    pub platform: Option<String>,
+    /// Whether to remove the repodata files cached on disk as well, when flushing everything
+    #[serde(default)]
+    pub disk: bool,
+    /// Whether to remove the cached solve results as well. They are not keyed by channel, so all
+    /// of them are removed.
+    #[serde(default)]
+    pub solve_cache: bool,
+}
+
+/// Removes the cached repodata of a channel (and platform, if provided), or all cached repodata if
+/// no channel is provided
+async fn invalidate_cache(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<InvalidateCache>,
+) -> Response {
+    match invalidate(&state, params).await {
+        Ok(result) => Json::<FlushResult>(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+/// Removes the cached repodata of a channel (and platform, if provided), or all cached repodata if
+/// no channel is provided
+pub async fn invalidate(
+    state: &AppState,
+    params: InvalidateCache,
+) -> Result<FlushResult, ApiError> {
+    let Some(channel) = &params.channel else {
+        if let Some(platform) = params.platform {
+            return Err(ApiError::Validation(ValidationError::Subdir(ParseError {
+                input: platform,
+                error: "a platform can only be invalidated together with its channel".to_string(),
+            })));
+        }
+        let params = FlushCache {
+            disk: params.disk,
+            solve_cache: params.solve_cache,
+        };
+        return flush(state, params).await;
+    };
+
+    let channel = state.settings().parse_channel(channel).map_err(|e| {
+        ValidationError::Channels(ParseErrors(vec![ParseError {
+            input: channel.clone(),
+            error: e.to_string(),
+        }]))
+    })?;
+    let subdir = params
+        .platform
+        .as_deref()
+        .map(|platform| {
+            Subdir::parse(platform).map_err(|error| {
+                ValidationError::Subdir(ParseError {
+                    input: platform.to_string(),
+                    error,
+                })
+            })
+        })
+        .transpose()?;
+    let mut result = state
+        .available_packages
+        .invalidate(&channel, subdir.as_ref());
+    result.solve_results_removed = flush_solve_results(state, params.solve_cache);
+    Ok(result)
+}
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize)]
+pub struct InsertRepoData {
+    pub channel: String,
+    pub subdir: String,
+    #[serde(with = "crate::v1::records")]
+    pub records: Vec<RepoDataRecord>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize)]
+pub struct InsertRepoDataOk {
+    pub hash: String,
+}
+
+/// Overrides the repodata of a channel and subdir with the provided records, until it expires
+async fn insert_repodata(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<InsertRepoData>,
+) -> Response {
+    let result = async {
+        let channel = state
+            .settings()
+            .parse_channel(&payload.channel)
+            .map_err(|e| {
+                ValidationError::Channels(ParseErrors(vec![ParseError {
+                    input: payload.channel.clone(),
+                    error: e.to_string(),
+                }]))
+            })?;
+        let subdir = Subdir::parse(&payload.subdir).map_err(|error| {
+            ValidationError::Subdir(ParseError {
+                input: payload.subdir.clone(),
+                error,
+            })
+        })?;
+
+        state
+            .available_packages
+            .insert(&channel, &subdir, payload.records)
+            .await
+    };
+
+    match result.await {
+        Ok(hash) => Json(InsertRepoDataOk { hash }).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
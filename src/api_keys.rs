@@ -0,0 +1,171 @@
+//! Restricts the server to callers with an API key, each of which may be rate limited and is
+//! identified by its label in the logs and metrics
+
+use crate::auth::{bearer_token, token_digest, TokenDigest};
+use crate::error::{response_from_error, ApiError};
+use crate::rate_limit::{RateLimitStatus, RateLimiter};
+use crate::AppState;
+use anyhow::Context;
+use axum::extract::{Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::Instrument;
+
+/// The contents of the API keys file
+#[derive(Debug, Deserialize)]
+pub struct ApiKeysConfig {
+    /// The API keys, keyed by their label
+    pub keys: HashMap<String, ApiKeyConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyConfig {
+    /// The bearer token of the key
+    pub token: String,
+    /// How many requests the key may make per minute, on average. Unlimited if absent.
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// How many requests the key may make at once. Defaults to a minute worth of requests.
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// The API keys accepted by the server
+pub struct ApiKeys {
+    by_token: HashMap<TokenDigest, ApiKey>,
+}
+
+struct ApiKey {
+    label: String,
+    limiter: Option<RateLimiter>,
+}
+
+impl ApiKeys {
+    /// Loads the API keys from a JSON file
+    pub fn from_path(path: &Path) -> anyhow::Result<ApiKeys> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("opening API keys file {}", path.display()))?;
+        let config: ApiKeysConfig = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("parsing API keys file {}", path.display()))?;
+        ApiKeys::from_config(config)
+    }
+
+    pub fn from_config(config: ApiKeysConfig) -> anyhow::Result<ApiKeys> {
+        let mut by_token = HashMap::new();
+        for (label, key) in config.keys {
+            let limiter = key.requests_per_minute.map(|requests_per_minute| {
+                RateLimiter::new(
+                    requests_per_minute,
+                    key.burst.unwrap_or(requests_per_minute),
+                )
+            });
+            let previous = by_token.insert(token_digest(&key.token), ApiKey { label, limiter });
+            if let Some(previous) = previous {
+                anyhow::bail!(
+                    "API key {} shares its token with another key",
+                    previous.label
+                );
+            }
+        }
+        Ok(ApiKeys { by_token })
+    }
+
+    /// Whether the request carries one of the keys
+    pub fn is_known(&self, headers: &HeaderMap) -> bool {
+        bearer_token(headers).is_some_and(|token| self.by_token.contains_key(&token_digest(token)))
+    }
+
+    /// The label of the key that the request carries, if any
+    pub fn label(&self, headers: &HeaderMap) -> Option<&str> {
+        let key = self.by_token.get(&token_digest(bearer_token(headers)?))?;
+        Some(&key.label)
+    }
+}
+
+/// A request made with an API key
+pub struct Authorized {
+    pub label: String,
+    /// Absent if the key is not rate limited
+    pub rate_limit: Option<RateLimitStatus>,
+}
+
+/// Rejects requests without a known API key, and those of keys that exceed their rate limit.
+/// Tenants are identified by the same header, so their tokens are accepted as well.
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = match authorize(&state, request.headers()) {
+        Ok(authorized) => authorized,
+        Err(e) => return response_from_error(e),
+    };
+
+    match authorized {
+        Some(Authorized { label, rate_limit }) => {
+            let span = tracing::info_span!("api_key", label = %label);
+            let mut response = next.run(request).instrument(span).await;
+            if let Some(rate_limit) = rate_limit {
+                rate_limit.insert_headers(response.headers_mut());
+            }
+            response
+        }
+        None => next.run(request).await,
+    }
+}
+
+/// Checks the API key of a request, if the server requires one. Requests of tenants carry no API
+/// key.
+pub fn authorize(state: &AppState, headers: &HeaderMap) -> Result<Option<Authorized>, ApiError> {
+    let Some(api_keys) = &state.api_keys else {
+        return Ok(None);
+    };
+
+    let token = bearer_token(headers);
+    let Some(key) = token.and_then(|token| api_keys.by_token.get(&token_digest(token))) else {
+        let is_tenant = state
+            .tenants
+            .as_ref()
+            .zip(token)
+            .is_some_and(|(tenants, token)| tenants.authenticate(Some(token)).is_ok());
+        if is_tenant {
+            return Ok(None);
+        }
+        return Err(ApiError::Unauthorized);
+    };
+
+    let rate_limit = key.limiter.as_ref().map(RateLimiter::acquire);
+    if let Some(status) = rate_limit.as_ref().filter(|s| s.retry_after.is_some()) {
+        state.metrics.record_api_key_request(&key.label, false);
+        tracing::info!("rate limited API key {}", key.label);
+        return Err(ApiError::RateLimited(status.clone()));
+    }
+
+    state.metrics.record_api_key_request(&key.label, true);
+    Ok(Some(Authorized {
+        label: key.label.clone(),
+        rate_limit,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokens_must_be_unique() {
+        let config: ApiKeysConfig = serde_json::from_value(serde_json::json!({
+            "keys": {
+                "ci": { "token": "secret" },
+                "dashboard": { "token": "secret", "requests_per_minute": 10 }
+            }
+        }))
+        .unwrap();
+        assert!(ApiKeys::from_config(config).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Writes a line of JSON per solve to the audit log, recording who solved what and when. Unlike
+//! tracing spans, the lines are kept wherever the log is written to.
+//!
+//! The address of the client is recorded by a middleware for the task that handles the request, so
+//! the code that solves doesn't need to know about the connection.
+
+use crate::dto::SolveEnvironment;
+use crate::error::ApiError;
+use crate::rate_limit::client_ip;
+use crate::{AppState, SolveOutcome};
+use anyhow::Context;
+use axum::extract::{Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(test)]
+use serde::Deserialize;
+
+tokio::task_local! {
+    static CLIENT_IP: Option<IpAddr>;
+}
+
+/// The destination of the audit records
+pub struct AuditLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// Whether the client address is taken from the `X-Forwarded-For` header, set by a proxy
+    forwarded_for: bool,
+}
+
+/// What is recorded about a solve
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    /// The label of the API key of the request, if any
+    pub api_key: Option<String>,
+    /// Absent if the client did not connect over TCP (e.g. through a Unix domain socket)
+    pub client_ip: Option<IpAddr>,
+    pub name: Option<String>,
+    pub platform: String,
+    pub channels: Vec<String>,
+    pub specs: Vec<String>,
+    /// The amount of subdirs whose repodata was already in memory, out of `repodata_fetched`
+    pub repodata_cache_hits: usize,
+    pub repodata_fetched: usize,
+    /// Whether the solution was taken from the cache of recent solve results
+    pub solve_cache_hit: Option<bool>,
+    pub solve_ms: Option<f64>,
+    pub total_ms: f64,
+    /// The hex-encoded blake2b hash of the URLs of the solved packages, in order
+    pub result_hash: Option<String>,
+    /// The `error_kind` of the error response, if the solve failed
+    pub error_kind: Option<String>,
+}
+
+impl AuditLog {
+    /// Appends the records to the file, or writes them to stdout if the path is `-`
+    pub fn open(path: &Path, forwarded_for: bool) -> anyhow::Result<AuditLog> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening audit log {}", path.display()))?;
+            Box::new(file)
+        };
+        Ok(AuditLog {
+            writer: Mutex::new(writer),
+            forwarded_for,
+        })
+    }
+
+    /// Records a solve request, which took `elapsed` to complete. Failures to write the record are
+    /// logged, since the request was already handled.
+    pub fn record(
+        &self,
+        state: &AppState,
+        headers: &HeaderMap,
+        payload: &SolveEnvironment,
+        result: &Result<SolveOutcome, ApiError>,
+        elapsed: Duration,
+    ) {
+        let mut record = AuditRecord {
+            timestamp: Utc::now(),
+            api_key: state
+                .api_keys
+                .as_ref()
+                .and_then(|keys| keys.label(headers))
+                .map(str::to_string),
+            client_ip: current_client_ip(),
+            name: payload.name.clone(),
+            platform: payload.platform.clone(),
+            channels: payload.channels.clone(),
+            specs: payload.specs.clone(),
+            repodata_cache_hits: 0,
+            repodata_fetched: 0,
+            solve_cache_hit: None,
+            solve_ms: None,
+            total_ms: elapsed.as_secs_f64() * 1000.0,
+            result_hash: None,
+            error_kind: None,
+        };
+        match result {
+            Ok(SolveOutcome::Solved { solution, .. }) => {
+                if let Some(timings) = &solution.timings {
+                    record.repodata_fetched = timings.repodata.len();
+                    record.repodata_cache_hits =
+                        timings.repodata.values().filter(|t| t.cache_hit).count();
+                    record.solve_cache_hit = Some(timings.solve_cache_hit);
+                    record.solve_ms = Some(timings.solve_ms);
+                }
+                let urls: Vec<_> = solution.packages.iter().map(|p| p.url.as_str()).collect();
+                let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Blake2b256>(
+                    urls.join("\n"),
+                );
+                record.result_hash = Some(format!("{hash:x}"));
+            }
+            // The client already has the solution, so it was neither fetched nor solved again
+            Ok(SolveOutcome::NotModified { .. }) => {}
+            Err(e) => record.error_kind = Some(e.kind().to_string()),
+        }
+        self.write(&record);
+    }
+
+    fn write(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::warn!("cannot serialize audit record: {err}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(err) = writer.write_all(&line).and_then(|()| writer.flush()) {
+            tracing::warn!("cannot write audit record: {err}");
+        }
+    }
+}
+
+/// Runs the future as if it handled a request of the client (e.g. a job that outlives its request)
+pub async fn with_client_ip<F: Future>(client_ip: Option<IpAddr>, future: F) -> F::Output {
+    CLIENT_IP.scope(client_ip, future).await
+}
+
+/// The address of the client of the request being handled, if known
+pub fn current_client_ip() -> Option<IpAddr> {
+    CLIENT_IP.try_with(|ip| *ip).ok().flatten()
+}
+
+/// Records the address of the client for the audit records of the request
+pub async fn identify_client(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(audit_log) = &state.audit_log else {
+        return next.run(request).await;
+    };
+    let client_ip = client_ip(&request, audit_log.forwarded_for);
+    with_client_ip(client_ip, next.run(request)).await
+}
@@ -0,0 +1,47 @@
+//! Helpers to authenticate the callers of the server
+
+use crate::error::ApiError;
+use crate::tenants::Tenant;
+use crate::AppState;
+use axum::http::{header, HeaderMap};
+use rattler_digest::{compute_bytes_digest, Sha256, Sha256Hash};
+
+/// Returns the bearer token from the `Authorization` header, if any
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// The digest of a secret token. Tokens are compared, and looked up, by their digests, so the time
+/// a comparison takes reveals nothing about the secret.
+pub type TokenDigest = Sha256Hash;
+
+pub fn token_digest(token: &str) -> TokenDigest {
+    compute_bytes_digest::<Sha256>(token)
+}
+
+/// Whether the provided token is the expected secret
+pub fn token_matches(expected: &str, provided: &str) -> bool {
+    token_digest(expected) == token_digest(provided)
+}
+
+/// Identifies the tenant of the request, if the server has tenants. Callers with an API key share
+/// the header of tenants, so they are anonymous callers rather than unknown tenants.
+pub fn authenticate_tenant<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+) -> Result<Option<&'a Tenant>, ApiError> {
+    let Some(tenants) = &state.tenants else {
+        return Ok(None);
+    };
+    if state
+        .api_keys
+        .as_ref()
+        .is_some_and(|api_keys| api_keys.is_known(headers))
+    {
+        return Ok(None);
+    }
+    tenants.authenticate(bearer_token(headers))
+}
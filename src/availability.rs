@@ -0,0 +1,211 @@
+//! Contains the `/check` endpoint, which tells front-ends whether packages match the specs of an
+//! environment before it is solved (e.g. to report a typo in a package name right away)
+
+use crate::channels::check_access;
+use crate::error::{
+    response_from_error, ApiError, LimitExceeded, ParseError, ParseErrors, ValidationError,
+};
+use crate::subdir::Subdir;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{StreamExt, TryStreamExt};
+use rattler_conda_types::{MatchSpec, Platform, RepoDataRecord};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CheckSpecs {
+    pub platform: String,
+    pub channels: Vec<String>,
+    pub specs: Vec<String>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct SpecsAvailability {
+    /// Whether packages match every spec
+    pub available: bool,
+    /// The availability of each spec, in the order of the request
+    pub specs: Vec<SpecAvailability>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct SpecAvailability {
+    pub spec: String,
+    pub available: bool,
+    /// The amount of packages that match the spec, in all channels
+    pub candidates: usize,
+    /// The highest version that matches the spec, from the first channel that has a match
+    pub best: Option<Candidate>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct Candidate {
+    pub version: String,
+    pub build: String,
+    /// The canonical URL of the channel of the package
+    pub channel: String,
+}
+
+/// Looks up the packages that match each spec in the repodata of the channels, without solving
+/// the environment
+#[utoipa::path(
+    post,
+    path = "/v1/check",
+    request_body = CheckSpecs,
+    responses(
+        (status = 200, description = "Whether packages match each spec", body = SpecsAvailability),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 401, description = "The tenant token is missing or invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn check_specs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CheckSpecs>,
+) -> Response {
+    match check_specs_inner(&state, &payload, &headers).await {
+        Ok(availability) => Json(availability).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn check_specs_inner(
+    state: &AppState,
+    payload: &CheckSpecs,
+    headers: &HeaderMap,
+) -> Result<SpecsAvailability, ApiError> {
+    let settings = state.settings();
+    if payload.channels.len() > settings.max_channels_per_request {
+        return Err(ApiError::Validation(ValidationError::TooManyChannels(
+            LimitExceeded {
+                count: payload.channels.len(),
+                limit: settings.max_channels_per_request,
+            },
+        )));
+    }
+    if payload.specs.len() > settings.max_specs_per_request {
+        return Err(ApiError::Validation(ValidationError::TooManySpecs(
+            LimitExceeded {
+                count: payload.specs.len(),
+                limit: settings.max_specs_per_request,
+            },
+        )));
+    }
+    let platform = Platform::from_str(&payload.platform).map_err(|e| {
+        ValidationError::Platform(ParseError {
+            input: payload.platform.clone(),
+            error: e.to_string(),
+        })
+    })?;
+
+    let mut channels = Vec::with_capacity(payload.channels.len());
+    let mut invalid_channels = Vec::new();
+    for channel in &payload.channels {
+        match settings.parse_channel(channel) {
+            Ok(channel) => channels.push(channel),
+            Err(e) => invalid_channels.push(ParseError {
+                input: channel.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !invalid_channels.is_empty() {
+        return Err(ValidationError::Channels(ParseErrors(invalid_channels)).into());
+    }
+
+    let mut specs = Vec::with_capacity(payload.specs.len());
+    let mut invalid_specs = Vec::new();
+    for spec in &payload.specs {
+        match MatchSpec::from_str(spec) {
+            Ok(matchspec) => specs.push((spec, matchspec)),
+            Err(e) => invalid_specs.push(ParseError {
+                input: spec.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !invalid_specs.is_empty() {
+        return Err(ValidationError::MatchSpecs(ParseErrors(invalid_specs)).into());
+    }
+
+    let clients = channels
+        .iter()
+        .map(|channel| check_access(state, channel, headers))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (channels, clients) = (&channels, &clients);
+    let subdirs = (0..channels.len()).flat_map(|index| {
+        [
+            (index, Subdir::Platform(platform)),
+            (index, Subdir::Platform(Platform::NoArch)),
+        ]
+    });
+    let mut repodata: Vec<(usize, Arc<[RepoDataRecord]>)> = futures::stream::iter(subdirs)
+        .map(|(index, subdir)| async move {
+            let snapshot = state
+                .available_packages
+                .get(&channels[index], &subdir, clients[index], None)
+                .await?;
+            Ok::<_, ApiError>((index, snapshot.records.into_loaded()?))
+        })
+        .buffer_unordered(settings.concurrent_repodata_downloads_per_request)
+        .try_collect()
+        .await?;
+    // Channels are looked up in the order of the request, which is their priority
+    repodata.sort_by_key(|(index, _)| *index);
+
+    let specs: Vec<_> = specs
+        .into_iter()
+        .map(|(spec, matchspec)| {
+            let mut candidates = 0;
+            let mut best: Option<(usize, &RepoDataRecord)> = None;
+            for (index, records) in &repodata {
+                for record in records
+                    .iter()
+                    .filter(|r| matchspec.matches(&r.package_record))
+                {
+                    candidates += 1;
+                    let better = match best {
+                        None => true,
+                        Some((best_index, _)) if best_index != *index => false,
+                        Some((_, best)) => {
+                            let (a, b) = (&record.package_record, &best.package_record);
+                            (a.version.cmp(&b.version))
+                                .then(a.build_number.cmp(&b.build_number))
+                                .then(a.timestamp.cmp(&b.timestamp))
+                                .is_gt()
+                        }
+                    };
+                    if better {
+                        best = Some((*index, record));
+                    }
+                }
+            }
+            SpecAvailability {
+                spec: spec.clone(),
+                available: candidates > 0,
+                candidates,
+                best: best.map(|(_, record)| Candidate {
+                    version: record.package_record.version.to_string(),
+                    build: record.package_record.build.clone(),
+                    channel: record.channel.clone(),
+                }),
+            }
+        })
+        .collect();
+
+    Ok(SpecsAvailability {
+        available: specs.iter().all(|s| s.available),
+        specs,
+    })
+}
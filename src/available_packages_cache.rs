@@ -1,72 +1,3991 @@
-use crate::error::ApiError;
+use crate::channel_settings::{ChannelSettings, RepodataVariant};
+use crate::cli::RepodataCacheMode;
+use crate::coalesce::{Coalescer, Waiter};
+use crate::credentials::{DownloadClient, EnvCredentials};
+use crate::error::{ApiError, TransferFailure};
 use anyhow::Context;
-use rattler_conda_types::{Channel, Platform, RepoData, RepoDataRecord};
+use rattler_conda_types::{
+    Channel, PackageName, PatchInstructions, Platform, RepoData, RepoDataRecord,
+};
 use rattler_networking::AuthenticatedClient;
 use rattler_repodata_gateway::fetch;
 use reqwest::Url;
-use std::sync::Arc;
-use std::time::Duration;
-use std::{default::Default, path::PathBuf};
-use tracing::{span, Instrument, Level};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+use std::{
+    default::Default,
+    path::{Path, PathBuf},
+};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::{span, Instrument, Level, Span};
+use utoipa::ToSchema;
 
-use crate::generic_cache::{GenericCache, GetCachedResult};
+use crate::download::{self, DownloadedObject, Fetched};
+use crate::generic_cache::{EntryInfo, GenericCache, GetCachedResult};
+use crate::interned::InternedRecords;
+use crate::metrics::Metrics;
+use crate::oci::OciTransport;
+use crate::progress::{self, Progress};
+use crate::redact::{redact_path, redact_url};
+use crate::repodata_store::RepodataStore;
+use crate::retry::RetryPolicy;
+use crate::s3::S3Transport;
+use crate::shards::{self, ShardFetcher, ShardedRecords};
+use crate::sparse::SparseRecords;
+use crate::subdir::Subdir;
+
+const REPODATA_FILE_NAME: &str = "repodata.json";
+
+/// The directory within the cache directory that holds the repo data downloaded with credentials
+/// supplied by callers, in a subdirectory per scope
+const SCOPED_CACHE_DIR: &str = "scoped";
+
+/// The directory within the cache directory in which the files of [`RepodataCacheMode::Sparse`]
+/// are written (they are removed as soon as they are memory-mapped)
+const SPARSE_CACHE_DIR: &str = "sparse";
+
+/// The zstd compression level used in [`RepodataCacheMode::Compressed`]
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The amount of bytes downloaded between two progress reports of a download
+const PROGRESS_REPORT_BYTES: u64 = 1024 * 1024;
 
 /// Caches the available packages for (channel, platform) pairs
 pub struct AvailablePackagesCache {
-    cache: GenericCache<Url, Vec<RepoDataRecord>>,
+    cache: Arc<GenericCache<Url, CachedRepoData>>,
+    /// The repo data that was previously downloaded, keyed by the platform URL with the hash of the
+    /// repo data as its fragment (absent if snapshots are not retained)
+    snapshots: Option<Arc<GenericCache<Url, CachedRepoData>>>,
     cache_dir: PathBuf,
     download_client: AuthenticatedClient,
+    /// Downloads the repo data of channels hosted in S3 buckets (`s3://` URLs)
+    s3: S3Transport,
+    /// Downloads the repo data of channels hosted in OCI registries (`oci://` URLs)
+    oci: OciTransport,
+    mode: RepodataCacheMode,
+    /// Limits the amount of repo data that is parsed (or compressed) at the same time, so cold
+    /// requests can't use up the CPU needed by requests that are served from the cache
+    parse_permits: Arc<Semaphore>,
+    channel_settings: ChannelSettings,
+    /// How downloads that fail for transient reasons are retried
+    retry: RetryPolicy,
+    /// Keeps downloaded repo data on disk across restarts, if configured
+    store: Option<Arc<RepodataStore>>,
+    metrics: Arc<Metrics>,
+    /// Whether repo data is only served from the caches, without accessing the network
+    offline: bool,
+    /// The cache fills in flight, keyed by platform URL, which the requests that miss the cache
+    /// at the same time share
+    fills: Coalescer<Url, Result<RepoDataSnapshot, ApiError>>,
+    /// The shard indexes of the subdirs with sharded repo data, keyed like `cache`
+    shard_indexes: Arc<GenericCache<Url, ShardIndex>>,
+    /// The shard indexes that were replaced, keyed like `snapshots` (absent if snapshots are not
+    /// retained)
+    shard_snapshots: Option<Arc<GenericCache<Url, ShardIndex>>>,
+    /// The subdirs that are known to have no shard index, keyed like `cache`
+    unsharded: GenericCache<Url, ()>,
+    /// The shard index downloads in flight, keyed like `cache`
+    shard_fills: Coalescer<Url, Option<RepoDataSnapshot>>,
+    /// The task that periodically removes outdated data, if any
+    gc_task: Option<JoinHandle<()>>,
+}
+
+/// The variants of the repo data that are available for a (channel, platform) pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Availability {
+    pub zst: bool,
+    pub bz2: bool,
+    pub plain: bool,
+}
+
+/// The repo data of a (channel, platform) pair, together with the hash that identifies it
+#[derive(Clone)]
+pub struct RepoDataSnapshot {
+    pub records: SnapshotRecords,
+    /// The hex-encoded blake2b hash of the repodata.json file
+    pub hash: String,
+    repodata_bytes: u64,
+    /// Absent if the repo data was not downloaded
+    validators: Option<Validators>,
+    /// Whether the repo data was served from the in-memory cache
+    pub cache_hit: bool,
+}
+
+impl RepoDataSnapshot {
+    /// The `ETag` with which the repo data was downloaded, if upstream sent one
+    pub fn etag(&self) -> Option<&str> {
+        self.validators.as_ref()?.etag.as_deref()
+    }
+}
+
+/// The records of a [`RepoDataSnapshot`], which are shared with the cache (and with the other
+/// requests for the same subdir) instead of being copied for every request
+#[derive(Clone)]
+pub enum SnapshotRecords {
+    Loaded(Arc<[RepoDataRecord]>),
+    /// Records that are only rebuilt when they are needed, see [`crate::sparse::load_reachable`]
+    Interned(Arc<InternedRecords>),
+    /// Records that are only parsed when they are needed, see [`crate::sparse::load_reachable`]
+    Sparse(Arc<SparseRecords>),
+    /// Records that are only downloaded when they are needed, which is only done by solves (see
+    /// [`AvailablePackagesCache::get_sharded`])
+    Sharded(Arc<ShardedRecords>),
+}
+
+impl SnapshotRecords {
+    pub fn count(&self) -> usize {
+        match self {
+            SnapshotRecords::Loaded(records) => records.len(),
+            SnapshotRecords::Interned(interned) => interned.record_count(),
+            SnapshotRecords::Sparse(sparse) => sparse.record_count(),
+            SnapshotRecords::Sharded(sharded) => sharded.package_count(),
+        }
+    }
+
+    /// Returns all the records, parsing them if needed
+    pub fn into_records(self) -> anyhow::Result<Arc<[RepoDataRecord]>> {
+        match self {
+            SnapshotRecords::Loaded(records) => Ok(records),
+            SnapshotRecords::Interned(interned) => Ok(interned.to_records().into()),
+            SnapshotRecords::Sparse(sparse) => Ok(sparse.load_all()?.into()),
+            SnapshotRecords::Sharded(_) => {
+                anyhow::bail!("sharded repo data is only loaded by solves")
+            }
+        }
+    }
+
+    /// Like [`SnapshotRecords::into_records`], for callers that need the records as a whole
+    pub fn into_loaded(self) -> Result<Arc<[RepoDataRecord]>, ApiError> {
+        self.into_records()
+            .context("parsing sparse repo data")
+            .map_err(ApiError::Internal)
+    }
+
+    /// Returns the records of a single package, without parsing any others
+    pub fn into_package(self, name: &PackageName) -> Result<Vec<RepoDataRecord>, ApiError> {
+        match self {
+            SnapshotRecords::Loaded(records) => Ok(records
+                .iter()
+                .filter(|r| &r.package_record.name == name)
+                .cloned()
+                .collect()),
+            SnapshotRecords::Interned(interned) => Ok(interned.load(name)),
+            SnapshotRecords::Sparse(sparse) => sparse
+                .load(name)
+                .context("parsing sparse repo data")
+                .map_err(ApiError::Internal),
+            SnapshotRecords::Sharded(_) => Err(ApiError::Internal(anyhow::anyhow!(
+                "sharded repo data is only loaded by solves"
+            ))),
+        }
+    }
+}
+
+/// The shard index of a subdir, together with the shards downloaded so far
+struct ShardIndex {
+    records: Arc<ShardedRecords>,
+    /// The hex-encoded blake2b hash of the shard index
+    hash: String,
+    bytes: u64,
+    validators: Validators,
+}
+
+/// A downloaded shard index, as kept in the [`RepodataStore`] (keyed by the URL of the index)
+#[derive(Serialize, Deserialize)]
+struct PersistedShardIndex {
+    validators: Validators,
+    index: Vec<u8>,
+}
+
+impl ShardIndex {
+    /// Parses a shard index, keeping the shards that the `previous` index of the subdir loaded.
+    /// This blocks, so it should be called from a blocking task.
+    fn parse(
+        index: &[u8],
+        channel: &Channel,
+        fetcher: ShardFetcher,
+        previous: Option<&ShardIndex>,
+        validators: Validators,
+    ) -> anyhow::Result<ShardIndex> {
+        let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Blake2b256>(index);
+        let subdir_url = validators
+            .url
+            .join(".")
+            .expect("the subdir is a valid relative URL");
+        let previous = previous.map(|previous| previous.records.as_ref());
+        let records = ShardedRecords::from_index(index, &subdir_url, channel, fetcher, previous)?;
+        Ok(ShardIndex {
+            records: Arc::new(records),
+            hash: format!("{hash:x}"),
+            bytes: index.len() as u64,
+            validators,
+        })
+    }
+
+    /// The approximate memory used by the index and the shards loaded so far
+    fn approximate_bytes(&self) -> u64 {
+        self.records.approximate_bytes()
+    }
+
+    fn to_snapshot(&self, cache_hit: bool) -> RepoDataSnapshot {
+        RepoDataSnapshot {
+            records: SnapshotRecords::Sharded(self.records.clone()),
+            hash: self.hash.clone(),
+            repodata_bytes: self.bytes,
+            validators: Some(self.validators.clone()),
+            cache_hit,
+        }
+    }
+}
+
+/// Identifies the version of downloaded repo data, to detect when it changes upstream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Validators {
+    /// The URL that was downloaded, which depends on the variant of the repo data
+    url: Url,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// The state of the file that was read, for local channels (`file://` URLs), which is
+    /// checked instead of the headers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    local_file: Option<LocalFile>,
+}
+
+/// The modification time and size of a local repodata.json file. The size guards against file
+/// systems with a coarse modification time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct LocalFile {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl LocalFile {
+    /// Reads the state of the file behind a `file://` URL, returning `None` for other URLs or if
+    /// the file does not exist
+    async fn read(url: &Url) -> Option<LocalFile> {
+        if url.scheme() != "file" {
+            return None;
+        }
+        let metadata = tokio::fs::metadata(url.to_file_path().ok()?).await.ok()?;
+        Some(LocalFile {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+impl Validators {
+    /// Returns whether the file of a local channel changed on disk since it was read, or `None`
+    /// if the repo data was downloaded over HTTP
+    async fn local_changed(&self) -> Option<bool> {
+        let local_file = self.local_file?;
+        Some(LocalFile::read(&self.url).await != Some(local_file))
+    }
+
+    /// Returns true if the response headers describe a different version of the repo data
+    fn changed(&self, headers: &reqwest::header::HeaderMap) -> bool {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        match (&self.etag, header(reqwest::header::ETAG)) {
+            (Some(etag), Some(new_etag)) => etag != new_etag,
+            _ => match (&self.last_modified, header(reqwest::header::LAST_MODIFIED)) {
+                (Some(last_modified), Some(new_last_modified)) => {
+                    last_modified != new_last_modified
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Returns true if the response headers confirm that the repo data did not change, which
+    /// requires a matching `ETag` or `Last-Modified` header
+    fn confirmed_by(&self, headers: &reqwest::header::HeaderMap) -> bool {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        match (&self.etag, header(reqwest::header::ETAG)) {
+            (Some(etag), Some(new_etag)) => etag == new_etag,
+            _ => match (&self.last_modified, header(reqwest::header::LAST_MODIFIED)) {
+                (Some(last_modified), Some(new_last_modified)) => {
+                    last_modified == new_last_modified
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Downloaded repo data, as kept in the [`RepodataStore`]
+#[derive(Serialize, Deserialize)]
+struct PersistedRepoData {
+    validators: Validators,
+    hash: String,
+    repodata_bytes: u64,
+    records: Arc<[RepoDataRecord]>,
+}
+
+struct CachedRepoData {
+    records: CachedRecords,
+    hash: String,
+    record_count: usize,
+    /// The size of the repodata.json file
+    repodata_bytes: u64,
+    validators: Option<Validators>,
+}
+
+/// Describes a (channel, platform) pair in the cache, for debugging purposes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntryInfo {
+    pub url: Url,
+    pub hash: String,
+    pub age_seconds: u64,
+    /// Absent if the entry never expires
+    pub expires_in_seconds: Option<u64>,
+    pub record_count: usize,
+    /// The approximate amount of memory used by the entry
+    pub approximate_bytes: u64,
+}
+
+/// The entries of the cache, sorted by URL
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheInfo {
+    pub entries: Vec<CacheEntryInfo>,
+    /// Previously fetched repodata that remains available by hash
+    pub snapshots: Vec<CacheEntryInfo>,
+}
+
+/// What was removed by [`AvailablePackagesCache::flush`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlushResult {
+    pub entries_removed: usize,
+    pub snapshots_removed: usize,
+    pub files_removed: usize,
+    /// The cached solve results that were removed along with the repodata, if requested (see
+    /// [`crate::admin::FlushCache`])
+    #[serde(default)]
+    pub solve_results_removed: usize,
+}
+
+impl CachedRepoData {
+    fn approximate_bytes(&self) -> u64 {
+        match &self.records {
+            CachedRecords::Compressed(compressed) => compressed.len() as u64,
+            CachedRecords::Interned(interned) => interned.approximate_bytes(),
+            CachedRecords::Sparse(sparse) => sparse.approximate_bytes(),
+        }
+    }
+
+    /// Makes the snapshot use the sparse records, if any, so even the request that filled the cache
+    /// only parses the records it needs
+    fn share_sparse(&self, snapshot: &mut RepoDataSnapshot) {
+        if let CachedRecords::Sparse(sparse) = &self.records {
+            snapshot.records = SnapshotRecords::Sparse(sparse.clone());
+        }
+    }
+
+    fn to_snapshot(&self) -> Result<RepoDataSnapshot, ApiError> {
+        let records = match &self.records {
+            CachedRecords::Interned(interned) => SnapshotRecords::Interned(interned.clone()),
+            CachedRecords::Sparse(sparse) => SnapshotRecords::Sparse(sparse.clone()),
+            records => SnapshotRecords::Loaded(
+                records
+                    .to_records()
+                    .context("decompressing cached repo data")
+                    .map_err(ApiError::Internal)?,
+            ),
+        };
+        Ok(RepoDataSnapshot {
+            records,
+            hash: self.hash.clone(),
+            repodata_bytes: self.repodata_bytes,
+            validators: self.validators.clone(),
+            cache_hit: true,
+        })
+    }
+}
+
+/// The records of a (channel, platform) pair, as stored in the cache
+enum CachedRecords {
+    /// The parsed records, with the strings that repeat across them shared
+    Interned(Arc<InternedRecords>),
+    /// The records serialized as JSON and compressed with zstd
+    Compressed(Vec<u8>),
+    /// The records memory-mapped from a repodata.json file in `sparse_dir`
+    Sparse(Arc<SparseRecords>),
+}
+
+impl CachedRecords {
+    fn new(
+        records: Arc<[RepoDataRecord]>,
+        mode: RepodataCacheMode,
+        sparse_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        match mode {
+            RepodataCacheMode::Parsed => Ok(CachedRecords::interned(&records)),
+            RepodataCacheMode::Compressed => {
+                let json = serde_json::to_vec(&*records)?;
+                let compressed = zstd::encode_all(json.as_slice(), COMPRESSION_LEVEL)?;
+                Ok(CachedRecords::Compressed(compressed))
+            }
+            RepodataCacheMode::Sparse => match SparseRecords::new(&records, sparse_dir)? {
+                Some(sparse) => Ok(CachedRecords::Sparse(Arc::new(sparse))),
+                None => Ok(CachedRecords::interned(&records)),
+            },
+        }
+    }
+
+    fn interned(records: &[RepoDataRecord]) -> CachedRecords {
+        CachedRecords::Interned(Arc::new(InternedRecords::new(records)))
+    }
+
+    fn to_records(&self) -> anyhow::Result<Arc<[RepoDataRecord]>> {
+        match self {
+            CachedRecords::Compressed(compressed) => {
+                let json = zstd::decode_all(compressed.as_slice())?;
+                Ok(serde_json::from_slice(&json)?)
+            }
+            CachedRecords::Interned(interned) => Ok(interned.to_records().into()),
+            CachedRecords::Sparse(sparse) => Ok(sparse.load_all()?.into()),
+        }
+    }
 }
 
 impl AvailablePackagesCache {
-    /// Creates an empty `AvailablePackagesCache` with keys that expire after `expiration`
-    pub fn new(expiration: Duration, cache_dir: PathBuf) -> AvailablePackagesCache {
+    /// Creates an empty `AvailablePackagesCache` with keys that expire after `expiration`. Repo
+    /// data that is no longer current remains available by hash during `snapshot_retention`, if
+    /// provided. If a `gc_interval` is provided, outdated data is removed on that interval by a
+    /// background task, which must therefore be created within a tokio runtime. At most
+    /// `parse_concurrency` repo data files are parsed at the same time. If a `memory_budget` (in
+    /// bytes) is provided, the least recently used repo data is evicted once the cached repo data
+    /// exceeds it, and so are the least recently used snapshots. Expired repo data keeps being
+    /// served during `max_staleness` while it is refreshed in the background.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        expiration: Duration,
+        snapshot_retention: Option<Duration>,
+        cache_dir: PathBuf,
+        mode: RepodataCacheMode,
+        gc_interval: Option<Duration>,
+        parse_concurrency: usize,
+        memory_budget: Option<u64>,
+        max_staleness: Duration,
+    ) -> AvailablePackagesCache {
+        let with_budget = |cache: GenericCache<_, _>| match memory_budget {
+            Some(bytes) => cache.with_memory_budget(bytes, CachedRepoData::approximate_bytes),
+            None => cache,
+        };
+        let cache = Arc::new(
+            with_budget(GenericCache::with_expiration(expiration))
+                .with_max_staleness(max_staleness),
+        );
+        let snapshots = snapshot_retention
+            .map(|retention| Arc::new(with_budget(GenericCache::with_expiration(retention))));
+        let with_shards_budget = |cache: GenericCache<_, _>| match memory_budget {
+            Some(bytes) => cache.with_memory_budget(bytes, ShardIndex::approximate_bytes),
+            None => cache,
+        };
+        let shard_indexes = Arc::new(with_shards_budget(GenericCache::with_expiration(
+            expiration,
+        )));
+        let shard_snapshots = snapshot_retention.map(|retention| {
+            Arc::new(with_shards_budget(GenericCache::with_expiration(retention)))
+        });
+        let gc_task = gc_interval.map(|interval| {
+            let caches = std::iter::once(&cache)
+                .chain(snapshots.as_ref())
+                .map(|cache| Arc::downgrade(cache) as Weak<dyn Collectable>)
+                .chain(
+                    std::iter::once(&shard_indexes)
+                        .chain(shard_snapshots.as_ref())
+                        .map(|cache| Arc::downgrade(cache) as Weak<dyn Collectable>),
+                )
+                .collect();
+            tokio::spawn(gc_task(caches, interval))
+        });
         AvailablePackagesCache {
-            cache: GenericCache::with_expiration(expiration),
-            download_client: AuthenticatedClient::default(),
+            cache,
+            snapshots,
+            download_client: EnvCredentials::from_env().into_client(),
+            s3: S3Transport::from_env(),
+            oci: OciTransport::from_env(),
             cache_dir,
+            mode,
+            parse_permits: Arc::new(Semaphore::new(parse_concurrency)),
+            channel_settings: ChannelSettings::default(),
+            retry: RetryPolicy::default(),
+            store: None,
+            metrics: Arc::default(),
+            offline: false,
+            fills: Coalescer::default(),
+            shard_indexes,
+            shard_snapshots,
+            unsharded: GenericCache::with_expiration(expiration),
+            shard_fills: Coalescer::default(),
+            gc_task,
         }
     }
 
-    /// Removes outdated data from the cache
-    pub fn gc(&self) {
-        self.cache.gc();
+    /// Sets the credentials used to download repo data on behalf of callers without a client of
+    /// their own
+    pub fn set_download_credentials(&mut self, credentials: EnvCredentials) {
+        self.download_client = credentials.into_client();
+    }
+
+    /// Replaces the transport of S3-hosted channels, which is configured from the environment by
+    /// default
+    #[cfg(test)]
+    pub fn set_s3_transport(&mut self, s3: S3Transport) {
+        self.s3 = s3;
+    }
+
+    /// Replaces the transport of OCI-hosted channels, which uses the docker configuration of the
+    /// current user by default
+    #[cfg(test)]
+    pub fn set_oci_transport(&mut self, oci: OciTransport) {
+        self.oci = oci;
     }
 
-    /// Gets the repo data for this channel and platform if they exist in the cache, and downloads
-    /// them otherwise
+    /// Sets the per-channel settings used when downloading repo data
+    pub fn set_channel_settings(&mut self, channel_settings: ChannelSettings) {
+        self.channel_settings = channel_settings;
+    }
+
+    /// Retries downloads that fail for transient reasons, which are not retried by default
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Persists downloaded repo data in the store, reusing it after a restart for as long as it
+    /// doesn't change upstream
+    pub fn set_repodata_store(&mut self, store: RepodataStore) {
+        self.store = Some(Arc::new(store));
+    }
+
+    /// Serves all repo data as if requested through [`AvailablePackagesCache::get_offline`], and
+    /// stops checking channels for changes
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// Changes how long repodata is cached, starting with the repodata that is downloaded next
+    pub fn set_expiration(&self, expiration: Duration) {
+        self.cache.set_expiration(expiration);
+        self.shard_indexes.set_expiration(expiration);
+        self.unsharded.set_expiration(expiration);
+    }
+
+    /// The directory to which repodata is downloaded
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// How many repodata downloads (cache fills) are in flight
+    pub fn downloads_in_flight(&self) -> usize {
+        self.fills.in_flight()
+    }
+
+    /// Records cache lookups and downloads in `metrics`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// The time left until the repo data of the snapshot expires from the cache (or from the
+    /// retained snapshots, if it was pinned by hash), or `None` if it never expires. Zero if the
+    /// snapshot is no longer cached.
+    pub fn expires_in(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        snapshot: &RepoDataSnapshot,
+    ) -> Option<Duration> {
+        let key = cache_key(channel, subdir, client);
+        let retained_key = snapshot_key(&key, &snapshot.hash);
+        let expires_in = [
+            self.cache
+                .entry(&key)
+                .filter(|entry| entry.value.hash == snapshot.hash)
+                .map(|entry| entry.expires_in),
+            self.snapshots
+                .as_ref()
+                .and_then(|snapshots| snapshots.entry(&retained_key))
+                .map(|entry| entry.expires_in),
+            self.shard_indexes
+                .entry(&key)
+                .filter(|entry| entry.value.hash == snapshot.hash)
+                .map(|entry| entry.expires_in),
+            self.shard_snapshots
+                .as_ref()
+                .and_then(|snapshots| snapshots.entry(&retained_key))
+                .map(|entry| entry.expires_in),
+        ];
+        expires_in
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap_or(Some(Duration::ZERO))
+    }
+
+    /// Gets the repo data for this channel and subdir if they exist in the cache, and downloads
+    /// them otherwise (using `client` if provided, or the default client otherwise). If a `hash`
+    /// is pinned, only the repo data with that hash is returned, failing if it is unavailable.
+    /// Repo data downloaded with credentials supplied by a caller is cached separately for those
+    /// credentials.
     pub async fn get(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        hash: Option<&str>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        self.get_with(channel, subdir, client, hash, self.offline)
+            .await
+    }
+
+    /// Like [`AvailablePackagesCache::get`], but without accessing the network: the repo data is
+    /// served from memory, the store or the on-disk cache of downloads, even if it is outdated, and
+    /// an error is returned if it is in none of them. Local channels are read as usual.
+    pub async fn get_offline(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        hash: Option<&str>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        self.get_with(channel, subdir, client, hash, true).await
+    }
+
+    /// Like [`AvailablePackagesCache::get`], but if the channel provides sharded repo data (see
+    /// [`crate::shards`]) only its shard index is downloaded, and the shards are downloaded as the
+    /// solve needs them. A pinned `hash` is then the hash of the shard index, which remains
+    /// available by hash like full repo data does. The full repo data is used instead if the
+    /// pinned hash doesn't match, or if the shard index cannot be downloaded.
+    ///
+    /// Sharded repo data is not used for the subdirs of channels with mirrors or local patches, or
+    /// that are configured to use another variant than `repodata.json` (or not to use shards).
+    pub async fn get_sharded(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        hash: Option<&str>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let settings = self.channel_settings.for_channel(&channel.base_url);
+        let shardable = !self.offline
+            && matches!(channel.base_url.scheme(), "http" | "https")
+            && settings.shards
+            && settings.mirrors.is_empty()
+            && settings.patches.is_none()
+            && settings.variant == RepodataVariant::Full;
+        if shardable {
+            if let (Some(hash), Some(snapshots)) = (hash, &self.shard_snapshots) {
+                let key = cache_key(channel, subdir, client);
+                if let Some(index) = snapshots.get_fresh(&snapshot_key(&key, hash)) {
+                    self.metrics.record_cache_lookup(true);
+                    return Ok(index.to_snapshot(true));
+                }
+            }
+            if let Some(snapshot) = self.get_shard_index(channel, subdir, client).await {
+                if hash.map_or(true, |hash| hash == snapshot.hash) {
+                    return Ok(snapshot);
+                }
+            }
+        }
+        self.get(channel, subdir, client, hash).await
+    }
+
+    /// Gets the shard index of the subdir from the cache, downloading it if needed. Returns `None`
+    /// if the subdir has no shard index (which is cached as well) or if it cannot be downloaded.
+    async fn get_shard_index(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Option<RepoDataSnapshot> {
+        let key = cache_key(channel, subdir, client);
+        if self.unsharded.get_fresh(&key).is_some() {
+            return None;
+        }
+        if let Some(index) = self.shard_indexes.get_fresh(&key) {
+            self.metrics.record_cache_lookup(true);
+            return Some(index.to_snapshot(true));
+        }
+
+        let fill = self.shard_fills.run(key, || {
+            let cache = self.clone();
+            let (channel, subdir, client) = (channel.clone(), subdir.clone(), client.cloned());
+            async move {
+                cache
+                    .fill_shard_index(&channel, &subdir, client.as_ref())
+                    .await
+            }
+        });
+        fill.await.as_ref().clone()
+    }
+
+    /// Downloads the shard index of the subdir into the cache. The previous index is revalidated
+    /// with its `ETag`, so the shards it loaded are kept if it did not change, and retained by hash
+    /// if it did.
+    async fn fill_shard_index(
         &self,
         channel: &Channel,
-        platform: Platform,
-    ) -> Result<Vec<RepoDataRecord>, ApiError> {
-        let platform_url = channel.platform_url(platform);
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Option<RepoDataSnapshot> {
+        let key = cache_key(channel, subdir, client);
+        let write_token = match self.shard_indexes.get_cached(&key).await {
+            GetCachedResult::Found(index) => {
+                self.metrics.record_cache_lookup(true);
+                return Some(index.to_snapshot(true));
+            }
+            GetCachedResult::NotFound(write_token) => write_token,
+        };
+
+        let url = subdir
+            .url(channel)
+            .join(shards::INDEX_FILE_NAME)
+            .expect("file name is a valid relative URL");
+        // Like the repo data, the store only holds the indexes downloaded without caller credentials
+        let store = match client.and_then(DownloadClient::cache_scope) {
+            Some(_) => None,
+            None => self.store.clone(),
+        };
+        let http_client = client.map_or(&self.download_client, DownloadClient::client);
+        let fetcher = ShardFetcher {
+            client: http_client.clone(),
+            retry: self.retry.clone(),
+            dir: self.cache_dir.join(shards::SHARDS_DIR),
+            metrics: self.metrics.clone(),
+        };
+        let previous = match self.shard_indexes.entry(&key) {
+            Some(entry) => Some(entry.value),
+            None => self
+                .load_stored_shard_index(store.clone(), &url, channel, &fetcher)
+                .await
+                .map(Arc::new),
+        };
+
+        let etag = previous.as_ref().and_then(|p| p.validators.etag.clone());
+        let request = || http_client.get(url.clone());
+        let index = match download::fetch(request, etag.as_deref(), &self.retry).await {
+            Ok(Fetched::NotModified) => previous.clone()?,
+            Ok(Fetched::Modified {
+                bytes,
+                etag,
+                last_modified,
+            }) => {
+                let len = bytes.len() as u64;
+                self.metrics.record_downloaded_bytes(len);
+                progress::report(|| Progress::Downloading {
+                    channel: redact_url(&channel.base_url),
+                    platform: subdir.to_string(),
+                    bytes: len,
+                    total: Some(len),
+                });
+                let validators = Validators {
+                    url: url.clone(),
+                    etag,
+                    last_modified,
+                    local_file: None,
+                };
+                let (channel, previous) = (channel.clone(), previous.clone());
+                let parsed = self
+                    .run_throttled(move || {
+                        let index = ShardIndex::parse(
+                            &bytes,
+                            &channel,
+                            fetcher,
+                            previous.as_deref(),
+                            validators.clone(),
+                        )?;
+                        if let Some(store) = store {
+                            let persisted = PersistedShardIndex {
+                                validators,
+                                index: bytes,
+                            };
+                            if let Err(err) = store.save(&persisted.validators.url, &persisted) {
+                                tracing::warn!(
+                                    "cannot persist {}: {err:#}",
+                                    redact_url(&persisted.validators.url)
+                                );
+                            }
+                        }
+                        anyhow::Ok(index)
+                    })
+                    .await;
+                match parsed {
+                    Ok(Ok(index)) => Arc::new(index),
+                    Ok(Err(err)) => {
+                        tracing::debug!("cannot parse {}: {err:#}", redact_url(&url));
+                        return None;
+                    }
+                    Err(err) => {
+                        tracing::debug!("cannot parse {}: {err}", redact_url(&url));
+                        return None;
+                    }
+                }
+            }
+            // The full repo data is used instead
+            Err(fetch::FetchRepoDataError::NotFound(_)) => {
+                self.unsharded.insert(key, Arc::new(()));
+                return None;
+            }
+            // The full repo data is downloaded instead, and reports the error if it fails as well
+            Err(err) => {
+                tracing::debug!("cannot fetch {}: {err:#}", redact_url(&url));
+                return None;
+            }
+        };
+        self.metrics.record_cache_lookup(false);
+
+        if let Some(previous) = previous.filter(|previous| previous.hash != index.hash) {
+            if let Some(snapshots) = &self.shard_snapshots {
+                snapshots.insert(snapshot_key(&key, &previous.hash), previous);
+            }
+        }
+        // The index grows as shards are loaded, so its weight is estimated again
+        let shard_indexes = Arc::downgrade(&self.shard_indexes);
+        let weighed_key = key.clone();
+        index.records.on_loaded(move || {
+            if let Some(shard_indexes) = shard_indexes.upgrade() {
+                shard_indexes.reweigh(&weighed_key);
+            }
+        });
+        let snapshot = index.to_snapshot(false);
+        self.shard_indexes.set(write_token, index);
+        Some(snapshot)
+    }
+
+    /// Returns the shard index at `url` from the store, if any. Failures to read it are logged.
+    async fn load_stored_shard_index(
+        &self,
+        store: Option<Arc<RepodataStore>>,
+        url: &Url,
+        channel: &Channel,
+        fetcher: &ShardFetcher,
+    ) -> Option<ShardIndex> {
+        let store = store?;
+        let (index_url, channel, fetcher) = (url.clone(), channel.clone(), fetcher.clone());
+        let loaded = self
+            .run_throttled(move || {
+                let Some(persisted) = store.load::<PersistedShardIndex>(&index_url)? else {
+                    return Ok(None);
+                };
+                let index = ShardIndex::parse(
+                    &persisted.index,
+                    &channel,
+                    fetcher,
+                    None,
+                    persisted.validators,
+                )?;
+                anyhow::Ok(Some(index))
+            })
+            .await;
+        match loaded {
+            Ok(Ok(index)) => index,
+            Ok(Err(err)) => {
+                tracing::warn!("cannot load persisted {}: {err:#}", redact_url(url));
+                None
+            }
+            Err(err) => {
+                tracing::warn!("cannot load persisted {}: {err}", redact_url(url));
+                None
+            }
+        }
+    }
+
+    async fn get_with(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        hash: Option<&str>,
+        offline: bool,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        async {
+            if let Some(hash) = hash {
+                if let Some(snapshot) = self.get_snapshot(channel, subdir, client, hash).await {
+                    return snapshot;
+                }
+            }
+
+            let current = match offline && channel.base_url.scheme() != "file" {
+                true => self.get_cached_offline(channel, subdir, client).await?,
+                false => self.get_current(channel, subdir, client).await?,
+            };
+            match hash {
+                Some(hash) if hash != current.hash => {
+                    // The pinned repo data may have been replaced while getting the current one
+                    match self.get_retained(&cache_key(channel, subdir, client), hash) {
+                        Some(cached) => cached.to_snapshot(),
+                        None => Err(ApiError::SnapshotUnavailable(
+                            subdir.url(channel),
+                            hash.to_string(),
+                        )),
+                    }
+                }
+                _ => Ok(current),
+            }
+        }
+        .instrument(subdir_span(channel, subdir))
+        .await
+    }
+
+    /// Gets the repo data with the given hash if it is no longer current but still retained, or
+    /// if it is the repo data in the store but not in memory (e.g. after a restart)
+    async fn get_snapshot(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+        hash: &str,
+    ) -> Option<Result<RepoDataSnapshot, ApiError>> {
+        let key = cache_key(channel, subdir, client);
+        if let Some(cached) = self.get_retained(&key, hash) {
+            self.metrics.record_cache_lookup(true);
+            return Some(cached.to_snapshot());
+        }
+
+        // The store only holds repo data downloaded without credentials supplied by a caller
+        let in_memory = self.cache.entry(&key);
+        if in_memory.is_some_and(|entry| entry.value.hash == hash)
+            || client.and_then(DownloadClient::cache_scope).is_some()
+        {
+            return None;
+        }
+        let persisted = self
+            .load_stored(&key)
+            .await
+            .filter(|persisted| persisted.hash == hash)?;
+        self.metrics.record_cache_lookup(false);
+        let snapshot = RepoDataSnapshot {
+            records: SnapshotRecords::Loaded(persisted.records),
+            hash: persisted.hash,
+            repodata_bytes: persisted.repodata_bytes,
+            validators: Some(persisted.validators),
+            cache_hit: false,
+        };
+        Some(self.to_cached(&snapshot).await.map(|cached| {
+            let mut snapshot = snapshot;
+            cached.share_sparse(&mut snapshot);
+            if let Some(snapshots) = &self.snapshots {
+                snapshots.insert(snapshot_key(&key, hash), cached);
+            }
+            snapshot
+        }))
+    }
+
+    /// Gets the retained repo data with the given hash, if any
+    fn get_retained(&self, platform_url: &Url, hash: &str) -> Option<Arc<CachedRepoData>> {
+        let snapshots = self.snapshots.as_ref()?;
+        snapshots.get_fresh(&snapshot_key(platform_url, hash))
+    }
+
+    /// Gets the current repo data from the cache, filling it if needed. A fill is shared by the
+    /// requests that miss the cache while it is in flight, and is only cancelled once none of them
+    /// awaits it anymore (e.g. because their clients disconnected).
+    async fn get_current(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let key = cache_key(channel, subdir, client);
+        if let Some((cached, fresh)) = self.cache.get_servable(&key) {
+            // The repo data of local channels is read again as soon as its file changes
+            let changed_on_disk = match &cached.validators {
+                Some(validators) => validators.local_changed().await == Some(true),
+                None => false,
+            };
+            if changed_on_disk {
+                self.retain_snapshot(&key, self.cache.entry(&key), None);
+                self.cache.remove(&key);
+            } else {
+                self.metrics.record_cache_lookup(true);
+
+                // Stale repo data is refreshed without making the request wait for it. Requests that
+                // arrive in the meantime join the same fill.
+                if !fresh {
+                    tokio::spawn(self.start_fill(channel, subdir, client));
+                }
+                return cached.to_snapshot();
+            }
+        }
+
+        // The result only needs to be copied if other requests awaited it too
+        let fill = self.start_fill(channel, subdir, client);
+        Arc::try_unwrap(fill.await).unwrap_or_else(|shared| copy_fill_result(&shared))
+    }
+
+    /// Gets the current repo data from the caches, without accessing the network. Outdated repo data
+    /// is served too, since it cannot be refreshed.
+    async fn get_cached_offline(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let key = cache_key(channel, subdir, client);
+        if let Some(entry) = self.cache.entry(&key) {
+            self.metrics.record_cache_lookup(true);
+            return entry.value.to_snapshot();
+        }
+        self.metrics.record_cache_lookup(false);
+
+        // Persisted repo data is used without checking whether it changed upstream, and so is the
+        // repo data that rattler keeps in the cache directory
+        let scope = client.and_then(DownloadClient::cache_scope);
+        let persisted = match scope {
+            Some(_) => None,
+            None => self.load_stored(&key).await,
+        };
+        let mut snapshot = match persisted {
+            Some(persisted) => RepoDataSnapshot {
+                records: SnapshotRecords::Loaded(persisted.records),
+                hash: persisted.hash,
+                repodata_bytes: persisted.repodata_bytes,
+                validators: Some(persisted.validators),
+                cache_hit: false,
+            },
+            None => {
+                let client = client.map_or(&self.download_client, DownloadClient::client);
+                self.download(
+                    channel,
+                    subdir,
+                    client,
+                    scope,
+                    fetch::CacheAction::ForceCacheOnly,
+                )
+                .await
+                .map_err(|err| match err {
+                    ApiError::FetchRepoDataJson(_, fetch::FetchRepoDataError::NoCacheAvailable) => {
+                        ApiError::NotCachedOffline(subdir.url(channel))
+                    }
+                    err => err,
+                })?
+            }
+        };
+
+        let cached = self.to_cached(&snapshot).await?;
+        cached.share_sparse(&mut snapshot);
+        self.retain_snapshot(&key, self.cache.entry(&key), Some(&cached.hash));
+        self.cache.insert(key, cached);
+        Ok(snapshot)
+    }
+
+    /// Joins the fill in flight for the subdir, starting one if there is none
+    fn start_fill(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Waiter<Url, Result<RepoDataSnapshot, ApiError>> {
+        self.fills.run(cache_key(channel, subdir, client), || {
+            let cache = self.clone();
+            let (channel, subdir, client) = (channel.clone(), subdir.clone(), client.cloned());
+            async move { cache.fill(&channel, &subdir, client.as_ref()).await }
+        })
+    }
+
+    async fn fill(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: Option<&DownloadClient>,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let platform_url = cache_key(channel, subdir, client);
         let write_token = match self.cache.get_cached(&platform_url).await {
-            GetCachedResult::Found(repodata) => return Ok(repodata.to_vec()),
+            GetCachedResult::Found(cached) => {
+                self.metrics.record_cache_lookup(true);
+                return cached.to_snapshot();
+            }
             GetCachedResult::NotFound(write_guard) => write_guard,
         };
+        self.metrics.record_cache_lookup(false);
+
+        // Repo data persisted before a restart is reused if it didn't change upstream, and
+        // downloaded otherwise. Repo data downloaded with credentials supplied by a caller is not
+        // persisted, since the store is keyed by URL.
+        let scope = client.and_then(DownloadClient::cache_scope);
+        let client = client.map_or(&self.download_client, DownloadClient::client);
+        let persisted = match scope {
+            Some(_) => None,
+            None => self.load_persisted(&platform_url, client).await,
+        };
+        let mut snapshot = match persisted {
+            Some(snapshot) => snapshot,
+            None => {
+                let snapshot = self
+                    .download(
+                        channel,
+                        subdir,
+                        client,
+                        scope,
+                        fetch::CacheAction::default(),
+                    )
+                    .await?;
+                if scope.is_none() {
+                    self.persist(&platform_url, &snapshot).await;
+                }
+                snapshot
+            }
+        };
+
+        // Update the cache
+        let cached = self.to_cached(&snapshot).await?;
+        cached.share_sparse(&mut snapshot);
+        let replaced = self.cache.entry(&platform_url);
+        let hash = cached.hash.clone();
+        if self.cache.set(write_token, cached) {
+            self.retain_snapshot(&platform_url, replaced, Some(&hash));
+        }
+
+        Result::Ok(snapshot)
+    }
+
+    /// Populates the cache with the given records for this channel and subdir, without downloading
+    /// anything (e.g. to test against fixed repo data, or to override a channel with locally built
+    /// packages). The records replace the cached repo data, if any, and expire like downloaded
+    /// repo data. Their hash, which is returned, is computed from their JSON representation.
+    pub async fn insert(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        records: Vec<RepoDataRecord>,
+    ) -> Result<String, ApiError> {
+        async {
+            let json = serde_json::to_vec(&records)
+                .context("serializing repo data")
+                .map_err(ApiError::Internal)?;
+            let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Blake2b256>(&json);
+            let snapshot = RepoDataSnapshot {
+                records: SnapshotRecords::Loaded(records.into()),
+                hash: format!("{hash:x}"),
+                repodata_bytes: json.len() as u64,
+                validators: None,
+                cache_hit: false,
+            };
+
+            let platform_url = subdir.url(channel);
+            let cached = self.to_cached(&snapshot).await?;
+            self.retain_snapshot(
+                &platform_url,
+                self.cache.entry(&platform_url),
+                Some(&cached.hash),
+            );
+            self.cache.insert(platform_url, cached);
+            Ok(snapshot.hash)
+        }
+        .instrument(subdir_span(channel, subdir))
+        .await
+    }
 
-        // Download
-        let result = fetch::fetch_repo_data(
-            channel.platform_url(platform),
-            self.download_client.clone(),
-            self.cache_dir.clone(),
-            fetch::FetchRepoDataOptions {
-                ..Default::default()
+    /// Returns the repo data of the subdir from the store, if the upstream validators confirm that it
+    /// is still current
+    async fn load_persisted(
+        &self,
+        platform_url: &Url,
+        client: &AuthenticatedClient,
+    ) -> Option<RepoDataSnapshot> {
+        let persisted = self.load_stored(platform_url).await?;
+        let validators = persisted.validators;
+        let is_current = match validators.local_changed().await {
+            Some(changed) => !changed,
+            None => match self.head(client, &validators.url).await {
+                Ok(response) => {
+                    response.status().is_success() && validators.confirmed_by(response.headers())
+                }
+                Err(_) => false,
             },
-            None,
-        )
-        .instrument(span!(Level::DEBUG, "fetch_repo_data"))
+        };
+        if !is_current {
+            tracing::debug!("persisted {} is outdated", redact_url(platform_url));
+            return None;
+        }
+
+        tracing::debug!("reusing persisted {}", redact_url(platform_url));
+        Some(RepoDataSnapshot {
+            records: SnapshotRecords::Loaded(persisted.records),
+            hash: persisted.hash,
+            repodata_bytes: persisted.repodata_bytes,
+            validators: Some(validators),
+            cache_hit: false,
+        })
+    }
+
+    /// Returns the repo data of the subdir from the store, if any. Failures to read it are logged.
+    async fn load_stored(&self, platform_url: &Url) -> Option<PersistedRepoData> {
+        let store = self.store.clone()?;
+        let url = platform_url.clone();
+        match self
+            .run_throttled(move || store.load::<PersistedRepoData>(&url))
+            .await
+        {
+            Ok(Ok(persisted)) => persisted,
+            Ok(Err(err)) => {
+                tracing::warn!(
+                    "cannot load persisted {}: {err:#}",
+                    redact_url(platform_url)
+                );
+                None
+            }
+            Err(err) => {
+                tracing::warn!("cannot load persisted {}: {err}", redact_url(platform_url));
+                None
+            }
+        }
+    }
+
+    /// Writes downloaded repo data to the store, if any. Failures are logged, since the repo data
+    /// can still be served from memory.
+    async fn persist(&self, platform_url: &Url, snapshot: &RepoDataSnapshot) {
+        let (Some(store), Some(validators)) = (self.store.clone(), snapshot.validators.clone())
+        else {
+            return;
+        };
+
+        let url = platform_url.clone();
+        let hash = snapshot.hash.clone();
+        let repodata_bytes = snapshot.repodata_bytes;
+        let records = snapshot.records.clone();
+        match self
+            .run_throttled(move || {
+                let persisted = PersistedRepoData {
+                    validators,
+                    hash,
+                    repodata_bytes,
+                    records: records.into_records()?,
+                };
+                store.save(&url, &persisted)
+            })
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                tracing::warn!("cannot persist {}: {err:#}", redact_url(platform_url))
+            }
+            Err(err) => tracing::warn!("cannot persist {}: {err}", redact_url(platform_url)),
+        }
+    }
+
+    /// Converts the snapshot into its cached representation
+    async fn to_cached(
+        &self,
+        snapshot: &RepoDataSnapshot,
+    ) -> Result<Arc<CachedRepoData>, ApiError> {
+        let records = match &snapshot.records {
+            SnapshotRecords::Loaded(records) => {
+                let records = records.clone();
+                let mode = self.mode;
+                let sparse_dir = self.cache_dir.join(SPARSE_CACHE_DIR);
+                self.run_throttled(move || CachedRecords::new(records, mode, &sparse_dir))
+                    .await?
+                    .context("compressing repo data")
+                    .map_err(ApiError::Internal)?
+            }
+            SnapshotRecords::Interned(interned) => CachedRecords::Interned(interned.clone()),
+            SnapshotRecords::Sparse(sparse) => CachedRecords::Sparse(sparse.clone()),
+            SnapshotRecords::Sharded(_) => {
+                return Err(ApiError::Internal(anyhow::anyhow!(
+                    "sharded repo data is not cached as a whole"
+                )))
+            }
+        };
+        Ok(Arc::new(CachedRepoData {
+            records,
+            hash: snapshot.hash.clone(),
+            record_count: snapshot.records.count(),
+            repodata_bytes: snapshot.repodata_bytes,
+            validators: snapshot.validators.clone(),
+        }))
+    }
+
+    /// Keeps the data that was replaced by data with the given hash (or removed, if there is no
+    /// hash) available by hash. It is retained from now on, rather than from when it was cached.
+    fn retain_snapshot(
+        &self,
+        platform_url: &Url,
+        replaced: Option<EntryInfo<Url, CachedRepoData>>,
+        hash: Option<&str>,
+    ) {
+        let (Some(snapshots), Some(replaced)) = (&self.snapshots, replaced) else {
+            return;
+        };
+        if hash != Some(replaced.value.hash.as_str()) {
+            let key = snapshot_key(platform_url, &replaced.value.hash);
+            snapshots.insert(key, replaced.value);
+        }
+    }
+
+    /// Downloads the repo data for this channel and platform, bypassing both the in-memory cache
+    /// and the on-disk cache
+    pub async fn get_uncached(
+        &self,
+        channel: &Channel,
+        platform: Platform,
+    ) -> Result<Vec<RepoDataRecord>, ApiError> {
+        async {
+            let subdir = Subdir::Platform(platform);
+            if self.offline && channel.base_url.scheme() != "file" {
+                return Err(ApiError::NotCachedOffline(subdir.url(channel)));
+            }
+            let snapshot = self
+                .download(
+                    channel,
+                    &subdir,
+                    &self.download_client,
+                    None,
+                    fetch::CacheAction::NoCache,
+                )
+                .await?;
+            Ok(snapshot.records.into_loaded()?.to_vec())
+        }
+        .instrument(subdir_span(channel, &platform))
         .await
-        .map_err(|err| ApiError::FetchRepoDataJson(channel.platform_url(platform), err))?;
+    }
+
+    /// Checks whether the cached repo data of the channel changed upstream, using `HEAD` requests
+    /// (or the modification time of the file, for local channels), and downloads it again if it
+    /// did. Returns the URLs of the refreshed subdirs.
+    ///
+    /// Repo data that was not downloaded (see [`AvailablePackagesCache::insert`]), or whose
+    /// upstream does not report an `ETag` or `Last-Modified` header, is left alone.
+    pub async fn refresh_changed(&self, channel: &Channel) -> Vec<Url> {
+        let mut refreshed = Vec::new();
+        if self.offline {
+            return refreshed;
+        }
+        for entry in self.cache.entries() {
+            let Some(validators) = &entry.value.validators else {
+                continue;
+            };
+            let Some(subdir) = entry
+                .key
+                .as_str()
+                .strip_prefix(channel.base_url.as_str())
+                .and_then(|rest| rest.strip_suffix('/'))
+                .and_then(|name| Subdir::parse(name).ok())
+            else {
+                continue;
+            };
 
-        let repodata = RepoData::from_path(result.repo_data_json_path)
-            .context("loading repo data")
-            .map_err(ApiError::Internal)?
-            .into_repo_data_records(channel);
+            let changed = match validators.local_changed().await {
+                Some(changed) => changed,
+                None => match self.head(&self.download_client, &validators.url).await {
+                    Ok(response) if response.status().is_success() => {
+                        validators.changed(response.headers())
+                    }
+                    Ok(response) => {
+                        tracing::debug!("cannot check {}: {}", validators.url, response.status());
+                        false
+                    }
+                    Err(err) => {
+                        tracing::debug!("cannot check {}: {err}", validators.url);
+                        false
+                    }
+                },
+            };
+            if !changed {
+                continue;
+            }
 
-        // Update the cache
-        self.cache.set(write_token, Arc::new(repodata.clone()));
-        Result::Ok(repodata)
+            tracing::info!("repodata changed upstream: {}", redact_url(&entry.key));
+            let result = self
+                .refresh(channel, &subdir, &entry.key, fetch::CacheAction::NoCache)
+                .await;
+            if let Err(err) = result {
+                // The next request will try to download it again
+                tracing::warn!("cannot refresh {}: {err}", redact_url(&entry.key));
+                self.cache.remove(&entry.key);
+            }
+            refreshed.push(entry.key);
+        }
+
+        refreshed
+    }
+
+    /// Makes sure the repo data of the subdir is cached and doesn't expire within `horizon`,
+    /// downloading it otherwise. Returns whether it was downloaded.
+    pub async fn warm(
+        self: &Arc<Self>,
+        channel: &Channel,
+        subdir: &Subdir,
+        horizon: Duration,
+    ) -> Result<bool, ApiError> {
+        let platform_url = subdir.url(channel);
+        match self.cache.entry(&platform_url) {
+            // Requests for the subdir in the meantime wait for the same download
+            None => {
+                self.get_with(channel, subdir, None, None, self.offline)
+                    .await?;
+                Ok(true)
+            }
+            // Requests keep using the cached repo data until it is replaced
+            Some(entry)
+                if !self.offline
+                    && entry
+                        .expires_in
+                        .is_some_and(|expires_in| expires_in <= horizon) =>
+            {
+                self.refresh(
+                    channel,
+                    subdir,
+                    &platform_url,
+                    fetch::CacheAction::default(),
+                )
+                .await?;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+        }
+    }
+
+    /// Downloads the repo data of the subdir, replacing the cached one
+    async fn refresh(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        platform_url: &Url,
+        cache_action: fetch::CacheAction,
+    ) -> Result<(), ApiError> {
+        let snapshot = self
+            .download(channel, subdir, &self.download_client, None, cache_action)
+            .await?;
+        self.persist(platform_url, &snapshot).await;
+        let cached = self.to_cached(&snapshot).await?;
+        self.retain_snapshot(
+            platform_url,
+            self.cache.entry(platform_url),
+            Some(&cached.hash),
+        );
+        self.cache.insert(platform_url.clone(), cached);
+        Ok(())
+    }
+
+    /// Describes the contents of the cache
+    pub fn info(&self) -> CacheInfo {
+        CacheInfo {
+            entries: entry_infos(&self.cache),
+            snapshots: self
+                .snapshots
+                .as_ref()
+                .map(|snapshots| entry_infos(snapshots))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The approximate amount of memory used by the current repo data (excluding snapshots that
+    /// are no longer current)
+    pub fn approximate_bytes(&self) -> u64 {
+        let shard_indexes = self.shard_indexes.entries();
+        let shard_bytes: u64 = shard_indexes
+            .iter()
+            .map(|entry| entry.value.approximate_bytes())
+            .sum();
+        let entries = self.cache.entries();
+        shard_bytes
+            + entries
+                .iter()
+                .map(|entry| entry.value.approximate_bytes())
+                .sum::<u64>()
+    }
+
+    /// Removes all repo data from memory (including retained snapshots), and from the on-disk
+    /// cache if `disk` is set. Downloads that are in progress while flushing still complete, but
+    /// their result is not cached.
+    pub async fn flush(&self, disk: bool) -> Result<FlushResult, ApiError> {
+        let entries_removed = self.cache.clear() + self.shard_indexes.clear();
+        self.unsharded.clear();
+        let snapshots_removed = self.snapshots.as_ref().map_or(0, |s| s.clear())
+            + self.shard_snapshots.as_ref().map_or(0, |s| s.clear());
+        let mut files_removed = 0;
+        if disk {
+            files_removed += remove_shard_files(&self.cache_dir.join(shards::SHARDS_DIR))
+                .await
+                .context("removing downloaded shards")
+                .map_err(ApiError::Internal)?;
+            files_removed += remove_cached_files(&self.cache_dir)
+                .await
+                .context("removing cached repo data files")
+                .map_err(ApiError::Internal)?;
+            files_removed += remove_scoped_files(&self.cache_dir.join(SCOPED_CACHE_DIR))
+                .await
+                .context("removing cached repo data files")
+                .map_err(ApiError::Internal)?;
+            if let Some(store) = self.store.clone() {
+                files_removed += self
+                    .run_throttled(move || store.clear())
+                    .await?
+                    .map_err(ApiError::Internal)?;
+            }
+        }
+
+        Ok(FlushResult {
+            entries_removed,
+            snapshots_removed,
+            files_removed,
+            solve_results_removed: 0,
+        })
+    }
+
+    /// Removes the repo data of the channel from memory (including retained snapshots), only for
+    /// the subdir if provided, so it is downloaded again when needed
+    pub fn invalidate(&self, channel: &Channel, subdir: Option<&Subdir>) -> FlushResult {
+        let subdir_url = subdir.map(|subdir| subdir.url(channel));
+        let matches = |key: &Url| {
+            let mut url = key.clone();
+            url.set_fragment(None);
+            url.set_query(None);
+            match &subdir_url {
+                Some(subdir_url) => url == *subdir_url,
+                None => url.as_str().starts_with(channel.base_url.as_str()),
+            }
+        };
+
+        self.unsharded.remove_matching(matches);
+        FlushResult {
+            entries_removed: self.cache.remove_matching(matches)
+                + self.shard_indexes.remove_matching(matches),
+            snapshots_removed: self
+                .snapshots
+                .as_ref()
+                .map_or(0, |s| s.remove_matching(matches))
+                + self
+                    .shard_snapshots
+                    .as_ref()
+                    .map_or(0, |s| s.remove_matching(matches)),
+            files_removed: 0,
+            solve_results_removed: 0,
+        }
+    }
+
+    /// Downloads a file of a channel other than its repodata (e.g. `run_exports.json`), or returns
+    /// `None` if the channel does not have it
+    pub async fn download_file(
+        &self,
+        url: &Url,
+        client: Option<&DownloadClient>,
+    ) -> Result<Option<Vec<u8>>, ApiError> {
+        if url.scheme() == "file" {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::anyhow!("invalid file URL {url}"))?;
+            return match tokio::fs::read(&path).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(ApiError::Internal(
+                    anyhow::Error::new(e).context(format!("reading {}", path.display())),
+                )),
+            };
+        }
+        if self.offline {
+            return Err(ApiError::NotCachedOffline(url.clone()));
+        }
+
+        let client = client.map_or(&self.download_client, DownloadClient::client);
+        let download = async {
+            let response = match url.scheme() {
+                "s3" => self.s3.send(reqwest::Method::GET, url).await?,
+                "oci" => self.oci.send(reqwest::Method::GET, url).await?,
+                _ => client.get(url.clone()).send().await?,
+            };
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let bytes = response.error_for_status()?.bytes().await?;
+            Ok(Some(bytes.to_vec()))
+        };
+        download
+            .await
+            .map_err(|e| ApiError::FetchRepoDataJson(url.clone(), e))
+    }
+
+    /// Checks which variants of the repo data for this channel and platform are available, using
+    /// `HEAD` requests instead of downloading them
+    pub async fn check_availability(
+        &self,
+        channel: &Channel,
+        platform: Platform,
+        client: Option<&DownloadClient>,
+    ) -> Availability {
+        async {
+            let client = client.map_or(&self.download_client, DownloadClient::client);
+            let subdir_url = channel.platform_url(platform);
+            let plain_url = subdir_url
+                .join(REPODATA_FILE_NAME)
+                .expect("file name is a valid relative URL");
+
+            // Only the plain repodata.json of local, S3-hosted and OCI-hosted channels is read
+            match subdir_url.scheme() {
+                "file" => {
+                    return Availability {
+                        zst: false,
+                        bz2: false,
+                        plain: LocalFile::read(&plain_url).await.is_some(),
+                    }
+                }
+                // No other channel is reachable in offline mode
+                _ if self.offline => {
+                    return Availability {
+                        zst: false,
+                        bz2: false,
+                        plain: false,
+                    }
+                }
+                "s3" | "oci" => {
+                    let plain = match self.head(client, &plain_url).await {
+                        Ok(response) => response.status().is_success(),
+                        Err(err) => {
+                            tracing::debug!("cannot reach {subdir_url}: {err}");
+                            false
+                        }
+                    };
+                    return Availability {
+                        zst: false,
+                        bz2: false,
+                        plain,
+                    };
+                }
+                _ => {}
+            }
+
+            let (variants, plain) = futures::join!(
+                fetch::check_variant_availability(client, &subdir_url, None, REPODATA_FILE_NAME),
+                async {
+                    match client.head(plain_url).send().await {
+                        Ok(response) => response.status().is_success(),
+                        Err(err) => {
+                            tracing::debug!("cannot reach {subdir_url}: {err}");
+                            false
+                        }
+                    }
+                }
+            );
+
+            Availability {
+                zst: variants.has_zst(),
+                bz2: variants.has_bz2(),
+                plain,
+            }
+        }
+        .instrument(subdir_span(channel, &platform))
+        .await
+    }
+
+    /// Downloads and parses the full repo data of the subdir
+    async fn download(
+        &self,
+        channel: &Channel,
+        subdir: &Subdir,
+        client: &AuthenticatedClient,
+        cache_scope: Option<&str>,
+        cache_action: fetch::CacheAction,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        async {
+            // The mirrors of the channel are tried in order, as long as they cannot be reached
+            let settings = self.channel_settings.for_channel(&channel.base_url);
+            let mut sources: Vec<_> = settings
+                .mirrors
+                .iter()
+                .map(|mirror| Channel {
+                    base_url: mirror.clone(),
+                    ..channel.clone()
+                })
+                .collect();
+            if !settings.mirrors.contains(&channel.base_url) {
+                sources.push(channel.clone());
+            }
+
+            for (i, source) in sources.iter().enumerate() {
+                let result = self
+                    .download_with_retries(
+                        channel,
+                        source,
+                        subdir,
+                        client,
+                        cache_scope,
+                        cache_action,
+                    )
+                    .await;
+                match result {
+                    Err(err) if i + 1 < sources.len() && is_unreachable(&err) => {
+                        tracing::warn!(
+                            "cannot fetch {} from {}, trying the next mirror: {err}",
+                            subdir,
+                            redact_url(&source.base_url)
+                        );
+                    }
+                    result => return result,
+                }
+            }
+            unreachable!("the channel itself is always a source")
+        }
+        .instrument(subdir_span(channel, subdir))
+        .await
+    }
+
+    /// Downloads the repo data of the subdir from `source`, attempting it again while it fails for
+    /// transient reasons (as far as the retry policy allows)
+    async fn download_with_retries(
+        &self,
+        channel: &Channel,
+        source: &Channel,
+        subdir: &Subdir,
+        client: &AuthenticatedClient,
+        cache_scope: Option<&str>,
+        cache_action: fetch::CacheAction,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .download_from(channel, source, subdir, client, cache_scope, cache_action)
+                .await;
+            match result {
+                Err(err) if self.retry.allows_retry(attempt) && is_transient(&self.retry, &err) => {
+                    let delay = self.retry.delay(attempt);
+                    tracing::warn!(
+                        "cannot fetch {} from {} (attempt {attempt}), retrying in {delay:?}: {err}",
+                        subdir,
+                        redact_url(&source.base_url)
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Downloads the repo data of the subdir from `source`, which is either the channel itself or
+    /// one of its mirrors. The URLs of the packages point to the source.
+    async fn download_from(
+        &self,
+        channel: &Channel,
+        source: &Channel,
+        subdir: &Subdir,
+        client: &AuthenticatedClient,
+        cache_scope: Option<&str>,
+        cache_action: fetch::CacheAction,
+    ) -> Result<RepoDataSnapshot, ApiError> {
+        let mut options = fetch::FetchRepoDataOptions {
+            cache_action,
+            ..Default::default()
+        };
+        let settings = self.channel_settings.for_channel(&channel.base_url);
+        settings.apply(&mut options);
+        let patch_file = settings.patch_file(&subdir.to_string());
+
+        // Keep track of the bytes received, to report how far a failed download got
+        let progress = Arc::new(std::sync::Mutex::new(fetch::DownloadProgress {
+            bytes: 0,
+            total: None,
+        }));
+        let record_progress = progress.clone();
+        let sink = progress::current_sink();
+        let (channel_name, platform) = (redact_url(&channel.base_url), subdir.to_string());
+        // The files cached on disk are keyed by URL as well
+        let cache_dir = match cache_scope {
+            Some(scope) => self.cache_dir.join(SCOPED_CACHE_DIR).join(scope),
+            None => self.cache_dir.clone(),
+        };
+        let file_url = subdir
+            .url(source)
+            .join(options.variant.file_name())
+            .expect("file name is a valid relative URL");
+        // The file of a local channel is checked before it is read, so a change in the meantime
+        // causes it to be read again
+        let local_file = LocalFile::read(&file_url).await;
+        let report_progress = move |p: fetch::DownloadProgress| {
+            let mut progress = record_progress.lock().unwrap();
+            let reached_step =
+                p.bytes / PROGRESS_REPORT_BYTES > progress.bytes / PROGRESS_REPORT_BYTES;
+            if let Some(sink) = sink
+                .as_ref()
+                .filter(|_| reached_step || p.total == Some(p.bytes))
+            {
+                sink.send(Progress::Downloading {
+                    channel: channel_name.clone(),
+                    platform: platform.clone(),
+                    bytes: p.bytes,
+                    total: p.total,
+                });
+            }
+            *progress = p;
+        };
+        let span = subdir_span(channel, subdir);
+        // Buckets and registries are not reachable over plain HTTP, so their repo data is fetched
+        // by our own transports instead of by rattler
+        let result = match file_url.scheme() {
+            // Only rattler keeps downloads in the cache directory
+            "s3" | "oci" if cache_action == fetch::CacheAction::ForceCacheOnly => {
+                Err(fetch::FetchRepoDataError::NoCacheAvailable)
+            }
+            "s3" => self
+                .s3
+                .download(&file_url, &cache_dir, &self.retry, report_progress)
+                .instrument(span)
+                .await
+                .map(FetchedRepoData::from),
+            "oci" => self
+                .oci
+                .download(&file_url, &cache_dir, &self.retry, report_progress)
+                .instrument(span)
+                .await
+                .map(FetchedRepoData::from),
+            _ => fetch::fetch_repo_data(
+                subdir.url(source),
+                client.clone(),
+                cache_dir,
+                options,
+                Some(Box::new(report_progress)),
+            )
+            .instrument(span)
+            .await
+            .map(FetchedRepoData::from),
+        };
+        self.metrics
+            .record_downloaded_bytes(progress.lock().unwrap().bytes);
+        let result = result.map_err(|err| {
+            let progress = progress.lock().unwrap();
+            classify_fetch_error(subdir.url(source), err, &progress)
+        })?;
+
+        let validators = Validators {
+            url: result.url.clone(),
+            etag: result.etag.clone(),
+            last_modified: result.last_modified.clone(),
+            local_file,
+        };
+        let blake2_hash = result.blake2_hash;
+        let path = result.path.clone();
+        let failure = {
+            let progress = progress.lock().unwrap();
+            TransferFailure {
+                url: redact_url(&subdir.url(source)),
+                received_bytes: progress.bytes,
+                expected_bytes: progress.total,
+            }
+        };
+        progress::report(|| Progress::Parsing {
+            channel: redact_url(&channel.base_url),
+            platform: subdir.to_string(),
+        });
+        let (channel_name, source) = (channel.canonical_name(), source.clone());
+        let (hash, records) = self
+            .run_throttled(move || {
+                let hash = match blake2_hash {
+                    Some(hash) => hash,
+                    None => {
+                        rattler_digest::compute_file_digest::<rattler_digest::Blake2b256>(&path)
+                            .context("hashing repo data")?
+                    }
+                };
+                let mut repo_data = match RepoData::from_path(&path) {
+                    Ok(repo_data) => repo_data,
+                    // The decoders don't notice archives that end mid-frame, so truncation only
+                    // surfaces when parsing. The file is removed to download it again next time.
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        let _ = std::fs::remove_file(&path);
+                        return Err(ApiError::RepodataTruncated(failure));
+                    }
+                    Err(e) => {
+                        return Err(anyhow::Error::new(e).context("loading repo data").into())
+                    }
+                };
+                // Local patches change the records, so they are part of the hash as well
+                let hash = match patch_file {
+                    Some(patch_file) => {
+                        let patches = std::fs::read(&patch_file).with_context(|| {
+                            format!("reading patch instructions {}", patch_file.display())
+                        })?;
+                        let instructions: PatchInstructions = serde_json::from_slice(&patches)
+                            .with_context(|| {
+                                format!("parsing patch instructions {}", patch_file.display())
+                            })?;
+                        repo_data.apply_patches(&instructions);
+                        rattler_digest::compute_bytes_digest::<rattler_digest::Blake2b256>(
+                            [hash.as_slice(), &patches].concat(),
+                        )
+                    }
+                    None => hash,
+                };
+                // The records belong to the channel, wherever they were downloaded from
+                let mut records = repo_data.into_repo_data_records(&source);
+                for record in &mut records {
+                    record.channel = channel_name.clone();
+                }
+                Ok((hash, records))
+            })
+            .await??;
+
+        Ok(RepoDataSnapshot {
+            records: SnapshotRecords::Loaded(records.into()),
+            hash: format!("{hash:x}"),
+            repodata_bytes: result.bytes,
+            validators: Some(validators),
+            cache_hit: false,
+        })
+    }
+
+    /// Sends a `HEAD` request for the URL, signed if it points to an S3 bucket. For repo data in
+    /// an OCI registry, the request is for the manifest of the artifact.
+    async fn head(
+        &self,
+        client: &AuthenticatedClient,
+        url: &Url,
+    ) -> Result<reqwest::Response, fetch::FetchRepoDataError> {
+        match url.scheme() {
+            "s3" => self.s3.send(reqwest::Method::HEAD, url).await,
+            "oci" => self.oci.send(reqwest::Method::HEAD, url).await,
+            _ => Ok(client.head(url.clone()).send().await?),
+        }
+    }
+
+    /// Runs CPU-intensive work on the blocking thread pool, once a parse permit is available
+    async fn run_throttled<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, ApiError> {
+        // The permit is only released once the work is done, even if the request is cancelled
+        let permit = self
+            .parse_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .context("acquiring a parse permit")
+            .map_err(ApiError::Internal)?;
+        tokio::task::spawn_blocking(move || {
+            let result = f();
+            drop(permit);
+            result
+        })
+        .await
+        .context("running blocking task")
+        .map_err(ApiError::Internal)
+    }
+}
+
+/// Whether the download failed for a reason that may go away by itself, e.g. a connection that
+/// was reset or an overloaded server
+fn is_transient(retry: &RetryPolicy, err: &ApiError) -> bool {
+    match err {
+        ApiError::FetchRepoDataJson(_, err) => retry.is_transient(err),
+        ApiError::RepodataTruncated(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the repo data could not be fetched from where it was requested (as opposed to not
+/// existing there), so it may be fetched from a mirror instead
+fn is_unreachable(err: &ApiError) -> bool {
+    match err {
+        ApiError::FetchRepoDataJson(_, fetch::FetchRepoDataError::NotFound(_)) => false,
+        ApiError::FetchRepoDataJson(..)
+        | ApiError::RepodataTruncated(_)
+        | ApiError::RepodataCorrupt(_) => true,
+        _ => false,
+    }
+}
+
+/// Repo data that was fetched to a file, by rattler or by one of our own transports
+struct FetchedRepoData {
+    path: PathBuf,
+    /// The URL that was fetched, which depends on the variant of the repo data
+    url: Url,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    blake2_hash: Option<rattler_digest::Blake2b256Hash>,
+    bytes: u64,
+    /// Keeps rattler from replacing the file while it is read
+    _lock: Option<fetch::CachedRepoData>,
+}
+
+impl From<fetch::CachedRepoData> for FetchedRepoData {
+    fn from(cached: fetch::CachedRepoData) -> Self {
+        let state = &cached.cache_state;
+        FetchedRepoData {
+            path: cached.repo_data_json_path.clone(),
+            url: state.url.clone(),
+            etag: state.cache_headers.etag.clone(),
+            last_modified: state.cache_headers.last_modified.clone(),
+            blake2_hash: state.blake2_hash,
+            bytes: state.cache_size,
+            _lock: Some(cached),
+        }
+    }
+}
+
+impl From<DownloadedObject> for FetchedRepoData {
+    fn from(object: DownloadedObject) -> Self {
+        FetchedRepoData {
+            path: object.path,
+            url: object.url,
+            etag: object.etag,
+            last_modified: object.last_modified,
+            blake2_hash: None,
+            bytes: object.bytes,
+            _lock: None,
+        }
+    }
+}
+
+/// Tells downloads that were cut short or that failed to decode apart from other fetch errors
+fn classify_fetch_error(
+    url: Url,
+    err: fetch::FetchRepoDataError,
+    progress: &fetch::DownloadProgress,
+) -> ApiError {
+    let fetch::FetchRepoDataError::FailedToDownload(_, io_err) = &err else {
+        return ApiError::FetchRepoDataJson(url, err);
+    };
+
+    let failure = TransferFailure {
+        url: redact_url(&url),
+        received_bytes: progress.bytes,
+        expected_bytes: progress.total,
+    };
+    let incomplete = progress.total.is_some_and(|total| progress.bytes < total);
+    if io_err.kind() == std::io::ErrorKind::UnexpectedEof || incomplete {
+        // The decoders report a stream that ends mid-frame as an unexpected EOF
+        ApiError::RepodataTruncated(failure)
+    } else if io_err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<reqwest::Error>())
+    {
+        // Network errors are wrapped as-is, anything else comes from the decoders
+        ApiError::FetchRepoDataJson(url, err)
+    } else {
+        ApiError::RepodataCorrupt(failure)
+    }
+}
+
+/// Copies the result of a cache fill for one of the requests that awaited it. Errors can't be
+/// cloned, so they are copied as far as the error responses (and the callers that skip missing
+/// subdirs) tell them apart.
+fn copy_fill_result(
+    result: &Result<RepoDataSnapshot, ApiError>,
+) -> Result<RepoDataSnapshot, ApiError> {
+    match result {
+        Ok(snapshot) => Ok(snapshot.clone()),
+        Err(err) => Err(copy_error(err)),
+    }
+}
+
+/// Copies an error that is shared by the requests that awaited the same work (errors are not
+/// `Clone`), keeping the variants that determine the response
+pub fn copy_error(err: &ApiError) -> ApiError {
+    match err {
+        ApiError::FetchRepoDataJson(url, err) => {
+            let copy = match err {
+                fetch::FetchRepoDataError::NotFound(_) => fetch::FetchRepoDataError::NotFound(
+                    fetch::RepoDataNotFoundError::FileSystemError(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        err.to_string(),
+                    )),
+                ),
+                _ => fetch::FetchRepoDataError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    err.to_string(),
+                )),
+            };
+            ApiError::FetchRepoDataJson(url.clone(), copy)
+        }
+        ApiError::RepodataTruncated(failure) => ApiError::RepodataTruncated(failure.clone()),
+        ApiError::RepodataCorrupt(failure) => ApiError::RepodataCorrupt(failure.clone()),
+        e => ApiError::Internal(anyhow::anyhow!("{e:#}")),
+    }
+}
+
+/// Removes the downloaded shards, returning how many were removed
+async fn remove_shard_files(dir: &std::path::Path) -> std::io::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".msgpack.zst")
+        {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes the repo data files from the on-disk cache. The directory is shared with other tools, so
+/// only the files named like the fetcher's cache files (`<hash>.json` and `<hash>.info.json`) are
+/// removed. Lock files are kept, because downloads may be holding them.
+async fn remove_cached_files(cache_dir: &std::path::Path) -> std::io::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(cache_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let cache_key = file_name
+            .strip_suffix(".info.json")
+            .or_else(|| file_name.strip_suffix(".json"));
+        let is_cache_file = cache_key.map_or(false, |key| {
+            key.len() == 8 && key.bytes().all(|b| b.is_ascii_hexdigit())
+        });
+        if is_cache_file && entry.file_type().await?.is_file() {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes the repo data files cached on disk for every scope of caller-supplied credentials,
+/// returning the amount of removed files
+async fn remove_scoped_files(scoped_dir: &std::path::Path) -> std::io::Result<usize> {
+    let mut scopes = match tokio::fs::read_dir(scoped_dir).await {
+        Ok(scopes) => scopes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    while let Some(scope) = scopes.next_entry().await? {
+        if scope.file_type().await?.is_dir() {
+            removed += remove_cached_files(&scope.path()).await?;
+        }
+    }
+    Ok(removed)
+}
+
+impl Drop for AvailablePackagesCache {
+    fn drop(&mut self) {
+        if let Some(gc_task) = &self.gc_task {
+            gc_task.abort();
+        }
+    }
+}
+
+fn entry_infos(cache: &GenericCache<Url, CachedRepoData>) -> Vec<CacheEntryInfo> {
+    let mut infos: Vec<_> = cache
+        .entries()
+        .into_iter()
+        .map(|entry| {
+            let mut url = entry.key;
+            url.set_fragment(None);
+            CacheEntryInfo {
+                url,
+                hash: entry.value.hash.clone(),
+                age_seconds: entry.age.as_secs(),
+                expires_in_seconds: entry.expires_in.map(|d| d.as_secs()),
+                record_count: entry.value.record_count,
+                approximate_bytes: entry.value.approximate_bytes(),
+            }
+        })
+        .collect();
+    infos.sort_by(|a, b| a.url.cmp(&b.url).then_with(|| a.hash.cmp(&b.hash)));
+    infos
+}
+
+/// The name of the channel, with its credentials masked
+fn redacted_name(channel: &Channel) -> String {
+    redact_path(channel.name.as_deref().unwrap_or_default())
+}
+
+/// The key of the repo data of the subdir in the caches, which is its URL unless it is downloaded
+/// with credentials supplied by the caller
+fn cache_key(channel: &Channel, subdir: &Subdir, client: Option<&DownloadClient>) -> Url {
+    let mut key = subdir.url(channel);
+    if let Some(scope) = client.and_then(DownloadClient::cache_scope) {
+        key.query_pairs_mut().append_pair("scope", scope);
+    }
+    key
+}
+
+/// The span of an operation on the repo data of a channel's subdir
+fn subdir_span(channel: &Channel, subdir: &impl std::fmt::Display) -> Span {
+    span!(
+        Level::DEBUG,
+        "subdir",
+        channel.name = %redacted_name(channel),
+        channel.base_url = %redact_url(&channel.base_url),
+        platform = %subdir,
+    )
+}
+
+fn snapshot_key(platform_url: &Url, hash: &str) -> Url {
+    let mut key = platform_url.clone();
+    key.set_fragment(Some(hash));
+    key
+}
+
+/// A cache whose outdated data is removed by the [`gc_task`]
+trait Collectable: Send + Sync {
+    fn gc(&self);
+}
+
+impl<V: Send + Sync> Collectable for GenericCache<Url, V> {
+    fn gc(&self) {
+        GenericCache::gc(self)
+    }
+}
+
+/// Removes outdated data from the caches on every tick of the interval, until they are dropped
+async fn gc_task(caches: Vec<Weak<dyn Collectable>>, interval: Duration) {
+    let mut interval_timer = tokio::time::interval(interval);
+
+    // The first tick completes immediately, when there is nothing to collect yet
+    interval_timer.tick().await;
+    loop {
+        interval_timer.tick().await;
+        for cache in &caches {
+            match cache.upgrade() {
+                Some(cache) => cache.gc(),
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel_settings::ChannelSettingsConfig;
+    use mock_instant::MockClock;
+    use rattler_conda_types::{ChannelConfig, PackageName, PackageRecord};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    impl RepoDataSnapshot {
+        fn records(&self) -> Vec<RepoDataRecord> {
+            self.records.clone().into_records().unwrap().to_vec()
+        }
+    }
+
+    fn fixture_records(count: usize) -> Vec<RepoDataRecord> {
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        (0..count)
+            .map(|i| {
+                let mut package_record = PackageRecord::new(
+                    PackageName::new_unchecked(format!("package-{}", i % 50)),
+                    rattler_conda_types::Version::from_str(&format!("1.{i}")).unwrap(),
+                    format!("py311h{i:07x}_0"),
+                );
+                package_record.subdir = "linux-64".to_string();
+                package_record.depends = vec!["python >=3.11,<3.12.0a0".to_string()];
+                package_record.license = Some("MIT".to_string());
+                package_record.size = Some(1000 + i as u64);
+
+                let file_name = format!(
+                    "{}-{}-{}.conda",
+                    package_record.name.as_normalized(),
+                    package_record.version,
+                    package_record.build
+                );
+                RepoDataRecord {
+                    url: channel
+                        .platform_url(Platform::Linux64)
+                        .join(&file_name)
+                        .unwrap(),
+                    channel: channel.base_url().to_string(),
+                    package_record,
+                    file_name,
+                }
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gc_runs_in_background() {
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = AvailablePackagesCache::new(
+            Duration::from_secs(10),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            Some(Duration::from_secs(60)),
+            1,
+            None,
+            Duration::ZERO,
+        );
+
+        let url = Url::parse("https://example.com/linux-64/").unwrap();
+        let GetCachedResult::NotFound(write_token) = cache.cache.get_cached(&url).await else {
+            panic!("the cache should be empty");
+        };
+        let cached = CachedRepoData {
+            records: CachedRecords::new(Arc::new([]), RepodataCacheMode::Parsed, Path::new(""))
+                .unwrap(),
+            hash: String::new(),
+            record_count: 0,
+            repodata_bytes: 0,
+            validators: None,
+        };
+        cache.cache.set(write_token, Arc::new(cached));
+
+        // Expire the entry and cross a gc tick, without calling `gc` ourselves
+        mock_instant::MockClock::advance(Duration::from_secs(30));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert!(cache.cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_are_not_throttled_by_cold_fetches() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/channel/noarch/repodata.json")
+            .with_body(r#"{"info": {"subdir": "noarch"}, "packages": {}}"#)
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+
+        // Warm up the linux-64 repodata
+        let url = channel.platform_url(Platform::Linux64);
+        let GetCachedResult::NotFound(write_token) = cache.cache.get_cached(&url).await else {
+            panic!("the cache should be empty");
+        };
+        let cached = CachedRepoData {
+            records: CachedRecords::new(
+                fixture_records(10).into(),
+                RepodataCacheMode::Parsed,
+                Path::new(""),
+            )
+            .unwrap(),
+            hash: String::new(),
+            record_count: 10,
+            repodata_bytes: 0,
+            validators: None,
+        };
+        cache.cache.set(write_token, Arc::new(cached));
+
+        // Simulate a long-running parse, which takes the only permit
+        let permit = cache.parse_permits.clone().acquire_owned().await.unwrap();
+
+        // Cold requests have to wait for it, warm requests don't
+        let cold = tokio::time::timeout(
+            Duration::from_millis(200),
+            cache.get(&channel, &Subdir::Platform(Platform::NoArch), None, None),
+        );
+        assert!(cold.await.is_err(), "the cold request should be throttled");
+        let warm = tokio::time::timeout(
+            Duration::from_millis(200),
+            cache.get(&channel, &Subdir::Platform(Platform::Linux64), None, None),
+        );
+        assert_eq!(warm.await.unwrap().unwrap().records().len(), 10);
+
+        // Cold requests proceed once the parse is done
+        drop(permit);
+        let cold = cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await;
+        assert!(cold.unwrap().records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fills_survive_while_someone_awaits_them() {
+        let mut server = mockito::Server::new_async().await;
+        let get = server
+            .mock("GET", "/channel/noarch/repodata.json")
+            .with_body(
+                serde_json::json!({
+                    "info": { "subdir": "noarch" },
+                    "packages": {
+                        "foo-1.0-0.tar.bz2": {
+                            "build": "0",
+                            "build_number": 0,
+                            "depends": [],
+                            "name": "foo",
+                            "subdir": "noarch",
+                            "version": "1.0"
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+
+        // Hold the only parse permit, so the fill can't complete yet
+        let permit = cache.parse_permits.clone().acquire_owned().await.unwrap();
+        let mut first = Box::pin(cache.get(&channel, &subdir, None, None));
+        let started = tokio::time::timeout(Duration::from_millis(200), &mut first);
+        assert!(
+            started.await.is_err(),
+            "the fill should be waiting to parse"
+        );
+        let mut second = Box::pin(cache.get(&channel, &subdir, None, None));
+        assert!(futures::poll!(&mut second).is_pending());
+
+        // The request that started the fill goes away, but the other one still gets its result
+        drop(first);
+        drop(permit);
+        let snapshot = second.await.unwrap();
+        assert_eq!(
+            snapshot.records()[0].package_record.name.as_normalized(),
+            "foo"
+        );
+        get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_inserted_records_are_returned_without_download() {
+        // Nothing listens at this address, so any download would fail
+        let channel =
+            Channel::from_str("http://127.0.0.1:9/channel", &ChannelConfig::default()).unwrap();
+        let subdir = Subdir::Platform(Platform::Linux64);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            Some(Duration::from_secs(60)),
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Compressed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let records = fixture_records(10);
+        cache
+            .insert(&channel, &subdir, records.clone())
+            .await
+            .unwrap();
+
+        let snapshot = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(snapshot.records(), records);
+
+        // The records are also available by their hash
+        let pinned = cache
+            .get(&channel, &subdir, None, Some(&snapshot.hash))
+            .await
+            .unwrap();
+        assert_eq!(pinned.records(), records);
+
+        // Inserting again replaces the cached records
+        cache.insert(&channel, &subdir, Vec::new()).await.unwrap();
+        let snapshot = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert!(snapshot.records().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parsed_records_are_shared_between_requests() {
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        cache
+            .insert(&channel, &subdir, fixture_records(10))
+            .await
+            .unwrap();
+
+        let (first, second) = futures::join!(
+            cache.get(&channel, &subdir, None, None),
+            cache.get(&channel, &subdir, None, None)
+        );
+        let (SnapshotRecords::Interned(first), SnapshotRecords::Interned(second)) =
+            (first.unwrap().records, second.unwrap().records)
+        else {
+            panic!("the records should be interned");
+        };
+        assert_eq!(first.record_count(), 10);
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[tokio::test]
+    async fn test_sparse_records_are_parsed_on_demand() {
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        let subdir = Subdir::Platform(Platform::Linux64);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Sparse,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let records = fixture_records(100);
+        cache
+            .insert(&channel, &subdir, records.clone())
+            .await
+            .unwrap();
+
+        let snapshot = cache.get(&channel, &subdir, None, None).await.unwrap();
+        let SnapshotRecords::Sparse(sparse) = &snapshot.records else {
+            panic!("expected sparse records");
+        };
+        assert_eq!(sparse.record_count(), 100);
+        assert!(cache.approximate_bytes() < snapshot.repodata_bytes);
+        let mut package = snapshot
+            .records
+            .clone()
+            .into_package(&PackageName::new_unchecked("package-7"))
+            .unwrap();
+        package.sort_by_key(|r| r.package_record.size);
+        assert_eq!(package, vec![records[7].clone(), records[57].clone()]);
+
+        let mut all = snapshot.records();
+        all.sort_by_key(|r| r.package_record.size);
+        assert_eq!(all, records);
+
+        // The file is removed as soon as it is mapped
+        let sparse_dir = temp_dir.join(SPARSE_CACHE_DIR);
+        assert_eq!(std::fs::read_dir(sparse_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_changed_repodata() {
+        let repodata = |name: &str| {
+            serde_json::json!({
+                "info": { "subdir": "noarch" },
+                "packages": {
+                    format!("{name}-1.0-0.tar.bz2"): {
+                        "build": "0",
+                        "build_number": 0,
+                        "depends": [],
+                        "name": name,
+                        "subdir": "noarch",
+                        "version": "1.0"
+                    }
+                }
+            })
+            .to_string()
+        };
+        let path = "/channel/noarch/repodata.json";
+        let mut server = mockito::Server::new_async().await;
+        let get_v1 = server
+            .mock("GET", path)
+            .with_header("ETag", "\"v1\"")
+            .with_body(repodata("foo"))
+            .create_async()
+            .await;
+        let head_v1 = server
+            .mock("HEAD", path)
+            .with_header("ETag", "\"v1\"")
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(3600),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let first = cache.get(&channel, &subdir, None, None).await.unwrap();
+
+        // Nothing changed upstream
+        assert!(cache.refresh_changed(&channel).await.is_empty());
+        head_v1.assert_async().await;
+
+        // The repodata changes upstream
+        get_v1.remove_async().await;
+        head_v1.remove_async().await;
+        let _get_v2 = server
+            .mock("GET", path)
+            .with_header("ETag", "\"v2\"")
+            .with_body(repodata("bar"))
+            .create_async()
+            .await;
+        let _head_v2 = server
+            .mock("HEAD", path)
+            .with_header("ETag", "\"v2\"")
+            .create_async()
+            .await;
+
+        assert_eq!(
+            cache.refresh_changed(&channel).await,
+            vec![subdir.url(&channel)]
+        );
+        let second = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_ne!(second.hash, first.hash);
+        assert_eq!(
+            second.records()[0].package_record.name.as_normalized(),
+            "bar"
+        );
+
+        // The refreshed repodata is tracked as well
+        assert!(cache.refresh_changed(&channel).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_channel_is_read_again_when_changed() {
+        let repodata = |name: &str| {
+            serde_json::json!({
+                "info": { "subdir": "noarch" },
+                "packages": {
+                    format!("{name}-1.0-0.tar.bz2"): {
+                        "build": "0",
+                        "build_number": 0,
+                        "depends": [],
+                        "name": name,
+                        "subdir": "noarch",
+                        "version": "1.0"
+                    }
+                }
+            })
+            .to_string()
+        };
+        let channel_dir = mktemp::Temp::new_dir().unwrap();
+        let repodata_path = channel_dir.join("noarch").join(REPODATA_FILE_NAME);
+        std::fs::create_dir(channel_dir.join("noarch")).unwrap();
+        std::fs::write(&repodata_path, repodata("foo")).unwrap();
+        let channel =
+            Channel::from_str(channel_dir.to_str().unwrap(), &ChannelConfig::default()).unwrap();
+        assert_eq!(channel.base_url.scheme(), "file");
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(3600),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let availability = cache
+            .check_availability(&channel, Platform::NoArch, None)
+            .await;
+        assert!(availability.plain);
+        let first = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(
+            first.records()[0].package_record.name.as_normalized(),
+            "foo"
+        );
+        assert_eq!(first.records()[0].url.scheme(), "file");
+
+        // Unchanged files are served from the cache
+        assert!(cache.refresh_changed(&channel).await.is_empty());
+        let cached = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(cached.hash, first.hash);
+
+        // Changed files are read again, long before the cache expires
+        std::fs::write(&repodata_path, repodata("foobar")).unwrap();
+        let second = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_ne!(second.hash, first.hash);
+        assert_eq!(
+            second.records()[0].package_record.name.as_normalized(),
+            "foobar"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oci_channel() {
+        let repodata = serde_json::json!({
+            "info": { "subdir": "noarch" },
+            "packages": {
+                "foo-1.0-0.tar.bz2": {
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "foo",
+                    "subdir": "noarch",
+                    "version": "1.0"
+                }
+            }
+        })
+        .to_string();
+        let digest = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(&repodata);
+        let digest = format!("sha256:{digest:x}");
+        let manifest_path = "/v2/channel/noarch/repodata.json/manifests/latest";
+
+        let mut server = mockito::Server::new_async().await;
+        let challenge = server
+            .mock("GET", manifest_path)
+            .match_header("authorization", mockito::Matcher::Missing)
+            .with_status(401)
+            .with_header(
+                "WWW-Authenticate",
+                &format!(
+                    r#"Bearer realm="{}/token",service="registry""#,
+                    server.url()
+                ),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let token = server
+            .mock("GET", "/token")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("service".into(), "registry".into()),
+                mockito::Matcher::UrlEncoded(
+                    "scope".into(),
+                    "repository:channel/noarch/repodata.json:pull".into(),
+                ),
+            ]))
+            .with_body(r#"{"token": "abc", "expires_in": 300}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let manifest = server
+            .mock("GET", manifest_path)
+            .match_header("authorization", "Bearer abc")
+            .with_header("ETag", &format!("\"{digest}\""))
+            .with_body(
+                serde_json::json!({
+                    "schemaVersion": 2,
+                    "layers": [
+                        { "mediaType": "text/plain", "digest": "sha256:0", "size": 1 },
+                        {
+                            "mediaType": "application/vnd.conda.repodata.v1+json",
+                            "digest": digest,
+                            "size": repodata.len()
+                        }
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let blob = server
+            .mock(
+                "GET",
+                format!("/v2/channel/noarch/repodata.json/blobs/{digest}").as_str(),
+            )
+            .match_header("authorization", "Bearer abc")
+            .with_body(&repodata)
+            .create_async()
+            .await;
+        let head = server
+            .mock("HEAD", manifest_path)
+            .match_header("authorization", "Bearer abc")
+            .with_header("ETag", &format!("\"{digest}\""))
+            .create_async()
+            .await;
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(3600),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_oci_transport(OciTransport::new(Default::default()));
+        let cache = Arc::new(cache);
+        // The mock registry runs on the local machine, so it is accessed over plain HTTP
+        let registry = server.host_with_port();
+        let channel = Channel::from_str(
+            format!("oci://{registry}/channel"),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+
+        let snapshot = cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            snapshot.records()[0].package_record.name.as_normalized(),
+            "foo"
+        );
+        challenge.assert_async().await;
+        token.assert_async().await;
+        manifest.assert_async().await;
+        blob.assert_async().await;
+
+        // The token is reused to detect changes through the manifest
+        assert!(cache.refresh_changed(&channel).await.is_empty());
+        head.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_s3_channel() {
+        let mut server = mockito::Server::new_async().await;
+        let signed = mockito::Matcher::Regex(
+            "^AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/[0-9]{8}/eu-west-1/s3/aws4_request, "
+                .to_string(),
+        );
+        let get = server
+            .mock("GET", "/bucket/channel/noarch/repodata.json")
+            .match_header("authorization", signed.clone())
+            .with_header("ETag", "\"v1\"")
+            .with_body(
+                serde_json::json!({
+                    "info": { "subdir": "noarch" },
+                    "packages": {
+                        "foo-1.0-0.tar.bz2": {
+                            "build": "0",
+                            "build_number": 0,
+                            "depends": [],
+                            "name": "foo",
+                            "subdir": "noarch",
+                            "version": "1.0"
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let head = server
+            .mock("HEAD", "/bucket/channel/noarch/repodata.json")
+            .match_header("authorization", signed)
+            .with_header("ETag", "\"v1\"")
+            .create_async()
+            .await;
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(3600),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_s3_transport(S3Transport::new(crate::s3::S3Config {
+            region: "eu-west-1".to_string(),
+            endpoint: Some(Url::parse(&server.url()).unwrap()),
+            credentials: Some(crate::s3::AwsCredentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+            }),
+            metadata_endpoint: Url::parse(&server.url()).unwrap(),
+        }));
+        let cache = Arc::new(cache);
+        let channel = Channel::from_str("s3://bucket/channel", &ChannelConfig::default()).unwrap();
+
+        let availability = cache
+            .check_availability(&channel, Platform::NoArch, None)
+            .await;
+        assert!(availability.plain);
+        let snapshot = cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            snapshot.records()[0].package_record.name.as_normalized(),
+            "foo"
+        );
+        assert_eq!(
+            snapshot.records()[0].url.as_str(),
+            "s3://bucket/channel/noarch/foo-1.0-0.tar.bz2"
+        );
+        get.assert_async().await;
+
+        // Changes are detected through signed `HEAD` requests as well
+        assert!(cache.refresh_changed(&channel).await.is_empty());
+        head.expect(2).assert_async().await;
+
+        // Missing subdirs are reported like those of other channels
+        let _missing = server
+            .mock("GET", "/bucket/channel/linux-64/repodata.json")
+            .with_status(404)
+            .create_async()
+            .await;
+        let missing = cache
+            .get(&channel, &Subdir::Platform(Platform::Linux64), None, None)
+            .await;
+        assert!(matches!(
+            missing,
+            Err(ApiError::FetchRepoDataJson(
+                _,
+                fetch::FetchRepoDataError::NotFound(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stale_repodata_is_refreshed_in_background() {
+        let repodata = |name: &str| {
+            serde_json::json!({
+                "info": { "subdir": "noarch" },
+                "packages": {
+                    format!("{name}-1.0-0.tar.bz2"): {
+                        "build": "0",
+                        "build_number": 0,
+                        "depends": [],
+                        "name": name,
+                        "subdir": "noarch",
+                        "version": "1.0"
+                    }
+                }
+            })
+            .to_string()
+        };
+        let served_name = |snapshot: RepoDataSnapshot| {
+            snapshot.records()[0]
+                .package_record
+                .name
+                .as_normalized()
+                .to_string()
+        };
+        let path = "/channel/noarch/repodata.json";
+        let mut server = mockito::Server::new_async().await;
+        let mut get = server
+            .mock("GET", path)
+            .with_body(repodata("foo"))
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(10),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::from_secs(24 * 3600),
+        ));
+        let first = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(served_name(first), "foo");
+
+        // The expired repodata is served while the new one is downloaded
+        MockClock::advance(Duration::from_secs(20));
+        get.remove_async().await;
+        get = server
+            .mock("GET", path)
+            .with_body(repodata("bar"))
+            .create_async()
+            .await;
+        let stale = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(served_name(stale), "foo");
+
+        let refreshed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let snapshot = cache.get(&channel, &subdir, None, None).await.unwrap();
+                if served_name(snapshot) == "bar" {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        refreshed.await.unwrap();
+
+        // Past the maximum staleness, requests wait for the download
+        MockClock::advance(Duration::from_secs(2 * 24 * 3600));
+        get.remove_async().await;
+        let _get = server
+            .mock("GET", path)
+            .with_body(repodata("baz"))
+            .create_async()
+            .await;
+        let blocking = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(served_name(blocking), "baz");
+    }
+
+    #[tokio::test]
+    async fn test_warm_downloads_repodata_before_it_expires() {
+        let mut server = mockito::Server::new_async().await;
+        let get = server
+            .mock("GET", "/channel/noarch/repodata.json")
+            .with_body(r#"{"info": {"subdir": "noarch"}, "packages": {}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(3600),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let horizon = Duration::from_secs(60);
+        assert!(cache.warm(&channel, &subdir, horizon).await.unwrap());
+        assert!(!cache.warm(&channel, &subdir, horizon).await.unwrap());
+
+        // Downloaded again before it expires, without making requests wait for it
+        MockClock::advance(Duration::from_secs(3590));
+        assert!(cache.warm(&channel, &subdir, horizon).await.unwrap());
+        assert!(cache.cache.get_fresh(&subdir.url(&channel)).is_some());
+        get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_settings_choose_encoding() {
+        let mut server = mockito::Server::new_async().await;
+        let empty = r#"{"info": {"subdir": "noarch"}, "packages": {}}"#;
+        let mut variant_heads = Vec::new();
+        for channel in ["plain-channel", "zst-channel"] {
+            for variant in ["repodata.json.zst", "repodata.json.bz2"] {
+                let mock = server
+                    .mock("HEAD", format!("/{channel}/noarch/{variant}").as_str())
+                    .create_async()
+                    .await;
+                variant_heads.push(mock);
+            }
+        }
+
+        // The zst variant is available for both channels, but only used where configured
+        let plain_get = server
+            .mock("GET", "/plain-channel/noarch/repodata.json")
+            .with_body(empty)
+            .expect(1)
+            .create_async()
+            .await;
+        let zst_get = server
+            .mock("GET", "/zst-channel/noarch/repodata.json.zst")
+            .with_body(zstd::encode_all(empty.as_bytes(), 0).unwrap())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let channel = |name: &str| {
+            Channel::from_str(
+                format!("{}/{name}", server.url()),
+                &ChannelConfig::default(),
+            )
+            .unwrap()
+        };
+        let config: ChannelSettingsConfig = serde_json::from_value(serde_json::json!({
+            "channels": {
+                channel("plain-channel").base_url.to_string(): { "encoding": "plain" },
+                channel("zst-channel").base_url.to_string(): { "encoding": "zst" },
+            }
+        }))
+        .unwrap();
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_channel_settings(ChannelSettings::from_config(config));
+        let cache = Arc::new(cache);
+        let subdir = Subdir::Platform(Platform::NoArch);
+        for name in ["plain-channel", "zst-channel"] {
+            cache
+                .get(&channel(name), &subdir, None, None)
+                .await
+                .unwrap();
+        }
+
+        plain_get.assert_async().await;
+        zst_get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_mirror_fallback() {
+        let mut broken = mockito::Server::new_async().await;
+        let mut working = mockito::Server::new_async().await;
+        let broken_get = broken
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_status(500)
+            .create_async()
+            .await;
+        let working_get = working
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_body(
+                serde_json::json!({
+                    "info": { "subdir": "noarch" },
+                    "packages": {
+                        "foo-1.0-0.tar.bz2": {
+                            "build": "0",
+                            "build_number": 0,
+                            "depends": [],
+                            "name": "foo",
+                            "subdir": "noarch",
+                            "version": "1.0"
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        // The channel itself is not used, because it is listed after the working mirror
+        let channel =
+            Channel::from_str("https://example.com/conda-forge", &ChannelConfig::default())
+                .unwrap();
+        let config: ChannelSettingsConfig = serde_json::from_value(serde_json::json!({
+            "channels": {
+                channel.base_url.to_string(): {
+                    "encoding": "plain",
+                    "jlap": false,
+                    "mirrors": [
+                        format!("{}/conda-forge", broken.url()),
+                        format!("{}/conda-forge", working.url()),
+                        channel.base_url.to_string(),
+                    ]
+                },
+            }
+        }))
+        .unwrap();
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_channel_settings(ChannelSettings::from_config(config));
+        let cache = Arc::new(cache);
+        let snapshot = cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await
+            .unwrap();
+
+        // The packages are downloaded from the mirror that succeeded, but belong to the channel
+        let record = &snapshot.records()[0];
+        assert_eq!(
+            record.url.as_str(),
+            format!("{}/conda-forge/noarch/foo-1.0-0.tar.bz2", working.url())
+        );
+        assert_eq!(record.channel, channel.canonical_name());
+        broken_get.assert_async().await;
+        working_get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/channel/noarch/repodata.json";
+        let unavailable = server
+            .mock("GET", path)
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let available = server
+            .mock("GET", path)
+            .with_body(r#"{"info": {"subdir": "noarch"}, "packages": {}}"#)
+            .create_async()
+            .await;
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            statuses: vec![reqwest::StatusCode::SERVICE_UNAVAILABLE],
+        });
+        let cache = Arc::new(cache);
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+
+        // The third attempt succeeds
+        cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await
+            .unwrap();
+        unavailable.assert_async().await;
+        available.assert_async().await;
+
+        // Statuses that are not retried fail right away
+        let forbidden = server
+            .mock("GET", "/channel/linux-64/repodata.json")
+            .with_status(403)
+            .expect(1)
+            .create_async()
+            .await;
+        let result = cache
+            .get(&channel, &Subdir::Platform(Platform::Linux64), None, None)
+            .await;
+        assert!(matches!(result, Err(ApiError::FetchRepoDataJson(..))));
+        forbidden.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_truncated_zst_repodata() {
+        let mut server = mockito::Server::new_async().await;
+        let _zst_head = server
+            .mock("HEAD", "/channel/noarch/repodata.json.zst")
+            .create_async()
+            .await;
+        let _bz2_head = server
+            .mock("HEAD", "/channel/noarch/repodata.json.bz2")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        // Only the first half of the archive is served
+        let repodata = r#"{"info": {"subdir": "noarch"}, "packages": {}}"#;
+        let mut archive = zstd::encode_all(repodata.as_bytes(), 0).unwrap();
+        archive.truncate(archive.len() / 2);
+        let zst_get = server
+            .mock("GET", "/channel/noarch/repodata.json.zst")
+            .with_body(&archive)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+
+        // The truncated file isn't kept around, so retrying downloads it again
+        for _ in 0..2 {
+            let result = cache
+                .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+                .await;
+            let Err(ApiError::RepodataTruncated(failure)) = result else {
+                panic!("expected a truncated download");
+            };
+            assert_eq!(failure.received_bytes, archive.len() as u64);
+            assert_eq!(failure.expected_bytes, Some(archive.len() as u64));
+        }
+        zst_get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_settings_disable_jlap() {
+        let mut server = mockito::Server::new_async().await;
+        let empty = r#"{"info": {"subdir": "noarch"}, "packages": {}}"#;
+        let mut mocks = Vec::new();
+        for (channel, jlap_fetches) in [("jlap-channel", 1), ("plain-channel", 0)] {
+            let head = server
+                .mock("HEAD", format!("/{channel}/noarch/repodata.jlap").as_str())
+                .create_async()
+                .await;
+            // The patches are invalid, so the repodata is downloaded in full again anyway
+            let jlap = server
+                .mock("GET", format!("/{channel}/noarch/repodata.jlap").as_str())
+                .with_body("invalid")
+                .expect(jlap_fetches)
+                .create_async()
+                .await;
+            let repodata = server
+                .mock("GET", format!("/{channel}/noarch/repodata.json").as_str())
+                .with_body(empty)
+                .expect(2)
+                .create_async()
+                .await;
+            mocks.extend([head, jlap, repodata]);
+        }
+
+        let channel = |name: &str| {
+            Channel::from_str(
+                format!("{}/{name}", server.url()),
+                &ChannelConfig::default(),
+            )
+            .unwrap()
+        };
+        let config: ChannelSettingsConfig = serde_json::from_value(serde_json::json!({
+            "channels": {
+                channel("plain-channel").base_url.to_string(): { "jlap": false },
+            }
+        }))
+        .unwrap();
+
+        // The in-memory cache expires immediately, so the repodata is fetched on every request
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::ZERO,
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_channel_settings(ChannelSettings::from_config(config));
+        let cache = Arc::new(cache);
+        let subdir = Subdir::Platform(Platform::NoArch);
+        for name in ["jlap-channel", "plain-channel"] {
+            for _ in 0..2 {
+                cache
+                    .get(&channel(name), &subdir, None, None)
+                    .await
+                    .unwrap();
+                mock_instant::MockClock::advance(Duration::from_secs(1));
+            }
+        }
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_settings_apply_local_patches() {
+        let mut server = mockito::Server::new_async().await;
+        let record = |name: &str| {
+            serde_json::json!({
+                "build": "0",
+                "build_number": 0,
+                "depends": ["python"],
+                "name": name,
+                "subdir": "noarch",
+                "version": "1.0"
+            })
+        };
+        let repodata = serde_json::json!({
+            "info": { "subdir": "noarch" },
+            "packages": {
+                "foo-1.0-0.tar.bz2": record("foo"),
+                "broken-1.0-0.tar.bz2": record("broken")
+            }
+        });
+        let mut mocks = Vec::new();
+        for channel in ["patched-channel", "plain-channel"] {
+            let mock = server
+                .mock("GET", format!("/{channel}/noarch/repodata.json").as_str())
+                .with_body(repodata.to_string())
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+
+        let patches = mktemp::Temp::new_dir().unwrap();
+        std::fs::create_dir(patches.join("noarch")).unwrap();
+        std::fs::write(
+            patches.join("noarch").join("patch_instructions.json"),
+            serde_json::json!({
+                "packages": { "foo-1.0-0.tar.bz2": { "depends": ["python >=3.8"] } },
+                "remove": ["broken-1.0-0.tar.bz2"]
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let channel = |name: &str| {
+            Channel::from_str(
+                format!("{}/{name}", server.url()),
+                &ChannelConfig::default(),
+            )
+            .unwrap()
+        };
+        let config: ChannelSettingsConfig = serde_json::from_value(serde_json::json!({
+            "channels": {
+                channel("patched-channel").base_url.to_string(): { "patches": patches.to_path_buf() },
+            }
+        }))
+        .unwrap();
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let mut cache = AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        );
+        cache.set_channel_settings(ChannelSettings::from_config(config));
+        let cache = Arc::new(cache);
+        let subdir = Subdir::Platform(Platform::NoArch);
+        let patched = cache
+            .get(&channel("patched-channel"), &subdir, None, None)
+            .await
+            .unwrap();
+        let plain = cache
+            .get(&channel("plain-channel"), &subdir, None, None)
+            .await
+            .unwrap();
+
+        let depends = |snapshot: &RepoDataSnapshot| -> Vec<(String, Vec<String>)> {
+            snapshot
+                .records()
+                .iter()
+                .map(|r| {
+                    let record = &r.package_record;
+                    (
+                        record.name.as_normalized().to_string(),
+                        record.depends.clone(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            depends(&patched),
+            [("foo".to_string(), vec!["python >=3.8".to_string()])]
+        );
+        assert_eq!(depends(&plain).len(), 2);
+        assert_ne!(patched.hash, plain.hash);
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persisted_repodata_survives_restart() {
+        let repodata = serde_json::json!({
+            "info": { "subdir": "noarch" },
+            "packages": {
+                "foo-1.0-0.tar.bz2": {
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "foo",
+                    "subdir": "noarch",
+                    "version": "1.0"
+                }
+            }
+        })
+        .to_string();
+        let path = "/channel/noarch/repodata.json";
+        let mut server = mockito::Server::new_async().await;
+        let get = server
+            .mock("GET", path)
+            .with_header("ETag", "\"v1\"")
+            .with_body(&repodata)
+            .expect(2)
+            .create_async()
+            .await;
+        let head_v1 = server
+            .mock("HEAD", path)
+            .with_header("ETag", "\"v1\"")
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        // Every cache starts with empty memory and an empty gateway cache, like after a restart
+        let store_dir = mktemp::Temp::new_dir().unwrap();
+        let restart = || {
+            let temp_dir = mktemp::Temp::new_dir().unwrap();
+            let mut cache = AvailablePackagesCache::new(
+                Duration::from_secs(60),
+                None,
+                temp_dir.to_path_buf(),
+                RepodataCacheMode::Parsed,
+                None,
+                1,
+                None,
+                Duration::ZERO,
+            );
+            cache.set_repodata_store(RepodataStore::new(store_dir.to_path_buf()));
+            (Arc::new(cache), temp_dir)
+        };
+
+        let (cache, _temp_dir) = restart();
+        let downloaded = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(downloaded.records().len(), 1);
+
+        // The repo data didn't change upstream, so the persisted copy is used
+        let (cache, _temp_dir) = restart();
+        let persisted = cache.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(persisted.records(), downloaded.records());
+        assert_eq!(persisted.hash, downloaded.hash);
+
+        // Once it changes, it is downloaded again
+        head_v1.remove_async().await;
+        let _head_v2 = server
+            .mock("HEAD", path)
+            .with_header("ETag", "\"v2\"")
+            .create_async()
+            .await;
+        let (cache, _temp_dir) = restart();
+
+        // The persisted copy remains available by hash until it is replaced
+        let pinned = cache
+            .get(&channel, &subdir, None, Some(&downloaded.hash))
+            .await
+            .unwrap();
+        assert_eq!(pinned.records(), downloaded.records());
+
+        cache.get(&channel, &subdir, None, None).await.unwrap();
+
+        get.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_shard_indexes_are_revalidated_and_retained() {
+        let shard = crate::shards::encode(&serde_json::json!({
+            "packages": {
+                "foo-1.0-0.tar.bz2": {
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "foo",
+                    "subdir": "noarch",
+                    "version": "1.0"
+                }
+            }
+        }));
+        let shard_hash = format!(
+            "{:x}",
+            rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(&shard)
+        );
+        let index = |packages: &[&str]| {
+            let shards: serde_json::Map<_, _> = packages
+                .iter()
+                .map(|name| (name.to_string(), shard_hash.clone().into()))
+                .collect();
+            crate::shards::encode(&serde_json::json!({
+                "version": 1,
+                "info": { "base_url": "./", "shards_base_url": "./", "subdir": "noarch" },
+                "shards": shards,
+            }))
+        };
+        let path = "/channel/noarch/repodata_shards.msgpack.zst";
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("GET", path)
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let _full = server
+            .mock("GET", "/channel/noarch/repodata.json")
+            .with_body(r#"{"info": {"subdir": "noarch"}, "packages": {}}"#)
+            .create_async()
+            .await;
+        let shard_mock = server
+            .mock(
+                "GET",
+                format!("/channel/noarch/{shard_hash}.msgpack.zst").as_str(),
+            )
+            .with_body(&shard)
+            .expect(1)
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let (temp_dir, store_dir) = (
+            mktemp::Temp::new_dir().unwrap(),
+            mktemp::Temp::new_dir().unwrap(),
+        );
+        let restart = || {
+            let mut cache = AvailablePackagesCache::new(
+                Duration::from_secs(60),
+                Some(Duration::from_secs(3600)),
+                temp_dir.to_path_buf(),
+                RepodataCacheMode::Parsed,
+                None,
+                1,
+                None,
+                Duration::ZERO,
+            );
+            cache.set_repodata_store(RepodataStore::new(store_dir.to_path_buf()));
+            Arc::new(cache)
+        };
+        let cache = restart();
+        let get = |cache: Arc<AvailablePackagesCache>, hash: Option<String>| {
+            let (channel, subdir) = (channel.clone(), subdir.clone());
+            async move {
+                let snapshot = cache
+                    .get_sharded(&channel, &subdir, None, hash.as_deref())
+                    .await
+                    .unwrap();
+                let SnapshotRecords::Sharded(sharded) = &snapshot.records else {
+                    return (snapshot, None);
+                };
+                let name = PackageName::new_unchecked("foo");
+                let records = sharded.records(&[name]).await.unwrap();
+                (snapshot.clone(), Some(records["foo"].len()))
+            }
+        };
+
+        // A failure to download the index is not cached, the full repo data is used meanwhile
+        let (snapshot, records) = get(cache.clone(), None).await;
+        assert!(records.is_none());
+        unavailable.assert_async().await;
+        let v1 = server
+            .mock("GET", path)
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("ETag", "\"v1\"")
+            .with_body(index(&["foo"]))
+            .create_async()
+            .await;
+        let (first, records) = get(cache.clone(), None).await;
+        assert_ne!(first.hash, snapshot.hash);
+        assert_eq!(records, Some(1));
+        assert!(cache.approximate_bytes() > 0);
+
+        // Once it expires, the index is revalidated and keeps its shards
+        let not_modified = server
+            .mock("GET", path)
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .expect(2)
+            .create_async()
+            .await;
+        MockClock::advance(Duration::from_secs(120));
+        let (revalidated, records) = get(cache.clone(), None).await;
+        assert_eq!(revalidated.hash, first.hash);
+        assert_eq!(records, Some(1));
+
+        // After a restart, the persisted index is revalidated and the shards are read from disk
+        let restarted = restart();
+        let (persisted, records) = get(restarted, None).await;
+        assert_eq!(persisted.hash, first.hash);
+        assert_eq!(records, Some(1));
+        not_modified.assert_async().await;
+        v1.assert_async().await;
+        shard_mock.assert_async().await;
+
+        // A changed index replaces the current one, and the previous one stays pinnable
+        not_modified.remove_async().await;
+        let _v2 = server
+            .mock("GET", path)
+            .with_header("ETag", "\"v2\"")
+            .with_body(index(&["foo", "bar"]))
+            .create_async()
+            .await;
+        MockClock::advance(Duration::from_secs(120));
+        let (second, records) = get(cache.clone(), None).await;
+        assert_ne!(second.hash, first.hash);
+        assert_eq!(records, Some(1));
+        let (pinned, records) = get(cache.clone(), Some(first.hash.clone())).await;
+        assert_eq!(pinned.hash, first.hash);
+        assert_eq!(records, Some(1));
+        shard_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_offline_mode_only_uses_cached_repodata() {
+        let path = "/channel/noarch/repodata.json";
+        let mut server = mockito::Server::new_async().await;
+        let get = server
+            .mock("GET", path)
+            .with_header("ETag", "\"v1\"")
+            .with_body(
+                serde_json::json!({
+                    "info": { "subdir": "noarch" },
+                    "packages": {
+                        "foo-1.0-0.tar.bz2": {
+                            "build": "0",
+                            "build_number": 0,
+                            "depends": [],
+                            "name": "foo",
+                            "subdir": "noarch",
+                            "version": "1.0"
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let head = server.mock("HEAD", path).expect(0).create_async().await;
+        let channel = Channel::from_str(
+            format!("{}/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+        let subdir = Subdir::Platform(Platform::NoArch);
+
+        let cache_dir = mktemp::Temp::new_dir().unwrap();
+        let store_dir = mktemp::Temp::new_dir().unwrap();
+        let new_cache = |cache_dir: &std::path::Path, offline: bool| {
+            let mut cache = AvailablePackagesCache::new(
+                Duration::from_secs(60),
+                None,
+                cache_dir.to_path_buf(),
+                RepodataCacheMode::Parsed,
+                None,
+                1,
+                None,
+                Duration::ZERO,
+            );
+            cache.set_repodata_store(RepodataStore::new(store_dir.to_path_buf()));
+            cache.set_offline(offline);
+            Arc::new(cache)
+        };
+        let downloaded = new_cache(&cache_dir, false)
+            .get(&channel, &subdir, None, None)
+            .await
+            .unwrap();
+
+        // The repo data is read from the cache directory, or from the store if that is empty,
+        // without checking whether it changed upstream
+        let offline = new_cache(&cache_dir, true);
+        let cached = offline.get(&channel, &subdir, None, None).await.unwrap();
+        assert_eq!(cached.records(), downloaded.records());
+        let empty_dir = mktemp::Temp::new_dir().unwrap();
+        let persisted = new_cache(&empty_dir, false)
+            .get_offline(&channel, &subdir, None, None)
+            .await
+            .unwrap();
+        assert_eq!(persisted.hash, downloaded.hash);
+
+        // Subdirs that were never downloaded are missing
+        let missing = offline
+            .get(&channel, &Subdir::Platform(Platform::Linux64), None, None)
+            .await;
+        assert!(matches!(missing, Err(ApiError::NotCachedOffline(_))));
+        assert!(offline.refresh_changed(&channel).await.is_empty());
+
+        get.assert_async().await;
+        head.assert_async().await;
+    }
+
+    /// The name of a span, together with its fields
+    type RecordedSpan = (&'static str, HashMap<String, String>);
+
+    /// Records the fields of every span that is created
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            struct Visitor(HashMap<String, String>);
+            impl Visit for Visitor {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    self.0
+                        .insert(field.name().to_string(), format!("{value:?}"));
+                }
+            }
+
+            let mut visitor = Visitor(HashMap::new());
+            attrs.record(&mut visitor);
+            self.0
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name(), visitor.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spans_have_channel_fields() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing_subscriber::registry()
+            .with(recorder.clone())
+            .set_default();
+
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/t/secret-token/channel/noarch/repodata.json")
+            .with_body(r#"{"info": {"subdir": "noarch"}, "packages": {}}"#)
+            .create_async()
+            .await;
+        let channel = Channel::from_str(
+            format!("{}/t/secret-token/channel", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            temp_dir.to_path_buf(),
+            RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        cache
+            .get(&channel, &Subdir::Platform(Platform::NoArch), None, None)
+            .await
+            .unwrap();
+
+        // Getting, downloading and fetching the repo data are each spanned
+        let spans = recorder.0.lock().unwrap();
+        let subdir_spans: Vec<_> = spans.iter().filter(|(name, _)| *name == "subdir").collect();
+        assert_eq!(subdir_spans.len(), 3);
+        for (_, fields) in subdir_spans {
+            assert_eq!(fields["channel.name"], "t/********/channel");
+            assert_eq!(
+                fields["channel.base_url"],
+                format!("{}/t/********/channel/", server.url())
+            );
+            assert_eq!(fields["platform"], "noarch");
+        }
+    }
+
+    #[test]
+    fn test_compressed_records_roundtrip() {
+        let records = fixture_records(1000);
+        let cached = CachedRecords::new(
+            records.clone().into(),
+            RepodataCacheMode::Compressed,
+            Path::new(""),
+        )
+        .unwrap();
+
+        assert_eq!(*cached.to_records().unwrap(), *records);
+
+        // The JSON representation is a lower bound for the memory used by the parsed records
+        let CachedRecords::Compressed(compressed) = &cached else {
+            panic!("expected compressed records");
+        };
+        let json_len = serde_json::to_vec(&records).unwrap().len();
+        assert!(
+            compressed.len() * 4 < json_len,
+            "compressed size {} is not much smaller than {json_len}",
+            compressed.len()
+        );
+    }
+
+    #[test]
+    fn test_interned_records_roundtrip() {
+        let mut records = fixture_records(1000);
+        for record in &mut records {
+            let package = &mut record.package_record;
+            package.depends.extend(
+                [
+                    "python_abi 3.11.* *_cp311",
+                    "libgcc-ng >=12",
+                    "libstdcxx-ng >=12",
+                    "numpy >=1.23.5,<2.0a0",
+                ]
+                .map(str::to_string),
+            );
+            package.constrains = vec!["pyarrow >=10.0.1,<11.0a0".to_string()];
+        }
+        // Fields that are usually empty are kept too
+        records[0].package_record.track_features = vec!["blas_openblas".to_string()];
+        records[1].package_record.legacy_bz2_size = Some(1024);
+        let cached = CachedRecords::new(
+            records.clone().into(),
+            RepodataCacheMode::Parsed,
+            Path::new(""),
+        )
+        .unwrap();
+
+        assert_eq!(*cached.to_records().unwrap(), *records);
+
+        let CachedRecords::Interned(interned) = &cached else {
+            panic!("expected interned records");
+        };
+        let name = PackageName::new_unchecked("package-7");
+        let expected: Vec<_> = records
+            .iter()
+            .filter(|r| r.package_record.name == name)
+            .cloned()
+            .collect();
+        assert_eq!(expected.len(), 20);
+        assert_eq!(interned.load(&name), expected);
+        let parsed_bytes: u64 = records.iter().map(crate::interned::record_bytes).sum();
+        assert!(
+            interned.approximate_bytes() * 10 < parsed_bytes * 7,
+            "interned size {} is not much smaller than {parsed_bytes}",
+            interned.approximate_bytes()
+        );
     }
 }
@@ -0,0 +1,140 @@
+//! Contains the `/solve/batch` endpoint, which solves several independent environments in a single
+//! request (e.g. the environments of the projects in a monorepo)
+
+use crate::dto::{SolveEnvironment, SolveEnvironmentOk};
+use crate::error::{response_from_error, ApiError, LimitExceeded, ValidationError};
+use crate::output::{deserialize_flag, OutputFormat, OutputParams};
+use crate::{graph, solve_unconditionally, AppState};
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchSolve {
+    /// The solve requests, which have the same fields as a `/solve` request
+    pub requests: Vec<SolveEnvironment>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct BatchSolveOk {
+    /// The result of each request, in the order of the requests
+    pub results: Vec<BatchResult>,
+}
+
+/// The result of one of the requests of a batch, which failed if it has no `solution`
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct BatchResult {
+    /// The status of the equivalent `/solve` response
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solution: Option<SolveEnvironmentOk>,
+    /// The body of the equivalent `/solve` error response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub error: Option<serde_json::Value>,
+}
+
+/// Query parameters that apply to every request of the batch
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BatchOutputParams {
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub include_graph: bool,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub debug: bool,
+}
+
+/// Solves several independent environments, reporting the solution or the error of each
+#[utoipa::path(
+    post,
+    path = "/v1/solve/batch",
+    params(BatchOutputParams),
+    request_body = BatchSolve,
+    responses(
+        (status = 200, description = "The result of each request", body = BatchSolveOk),
+        (status = 400, description = "The batch has too many requests", body = ErrorResponse),
+        (status = 401, description = "The API key is missing or invalid", body = ErrorResponse),
+        (status = 429, description = "The rate limit was exceeded", body = ErrorResponse),
+    )
+)]
+pub async fn solve_batch(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BatchOutputParams>,
+    headers: HeaderMap,
+    Json(payload): Json<BatchSolve>,
+) -> Response {
+    state.metrics.record_solve_request();
+
+    if payload.requests.len() > state.max_solves_per_batch {
+        return response_from_error(ApiError::Validation(ValidationError::TooManySolves(
+            LimitExceeded {
+                count: payload.requests.len(),
+                limit: state.max_solves_per_batch,
+            },
+        )));
+    }
+
+    let output = OutputParams {
+        format: OutputFormat::Json,
+        include_graph: params.include_graph,
+        debug: params.debug,
+    };
+
+    // The requests share the repodata through the cache, and identical requests share their solve.
+    // At most one solve per solver thread is in flight, so a batch can't fill up the solver queue.
+    let results = futures::stream::iter(payload.requests)
+        .map(|environment| {
+            let state = state.clone();
+            let (headers, output) = (&headers, &output);
+            async move { solve_one(state, headers, &environment, output).await }
+        })
+        .buffered(state.solver_pool.threads())
+        .collect()
+        .await;
+    Json(BatchSolveOk { results }).into_response()
+}
+
+async fn solve_one(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    environment: &SolveEnvironment,
+    output: &OutputParams,
+) -> BatchResult {
+    // The batch response as a whole is never cached by ETag
+    let error = match solve_unconditionally(state, headers, environment, output).await {
+        Ok(mut solution) => {
+            if output.include_graph {
+                solution.graph = Some(graph::dependency_graph(&solution.packages));
+            }
+            if !output.debug {
+                solution.solver_stats = None;
+                solution.timings = None;
+            }
+            return BatchResult {
+                status: 200,
+                solution: Some(solution),
+                error: None,
+            };
+        }
+        Err(e) => e,
+    };
+
+    let response = response_from_error(error);
+    let status = response.status().as_u16();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+    BatchResult {
+        status,
+        solution: None,
+        error: serde_json::from_slice(&body).ok(),
+    }
+}
@@ -0,0 +1,104 @@
+//! Downloads the repodata of the configured subdirs on startup and keeps it from expiring, so the
+//! first requests after a deploy don't wait for a cold fetch of large channels
+
+use crate::custom_channels::CustomChannels;
+use crate::redact::redact_url;
+use crate::subdir::Subdir;
+use crate::AppState;
+use rattler_conda_types::{Channel, ChannelConfig};
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+
+/// Parses a subdir to warm, given as `<channel>/<subdir>` (e.g. `conda-forge/linux-64`)
+pub fn parse_warm_subdir(
+    value: &str,
+    channel_config: &ChannelConfig,
+    custom_channels: &CustomChannels,
+) -> Result<(Channel, Subdir), String> {
+    let Some((channel, subdir)) = value.trim_end_matches('/').rsplit_once('/') else {
+        return Err(format!("'{value}' is not of the form <channel>/<subdir>"));
+    };
+    let subdir = Subdir::parse(subdir)?;
+    let channel = custom_channels
+        .parse_channel(channel, channel_config)
+        .map_err(|e| e.to_string())?;
+    Ok((channel, subdir))
+}
+
+/// Downloads the repodata of the `subdirs` right away, and checks on the given interval whether it
+/// expires before the next check, downloading it again in that case. All subdirs are warmed
+/// concurrently. Returns once the state is dropped.
+pub async fn warm_subdirs(
+    state: Weak<AppState>,
+    subdirs: Vec<(Channel, Subdir)>,
+    interval: Duration,
+) {
+    let mut interval_timer = tokio::time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+
+        let warmed = futures::future::join_all(subdirs.iter().map(|(channel, subdir)| {
+            let state = &state;
+            async move {
+                let name = redact_url(&subdir.url(channel));
+                let started = Instant::now();
+                match state
+                    .available_packages
+                    .warm(channel, subdir, interval)
+                    .await
+                {
+                    Ok(true) => {
+                        tracing::info!("warmed {name} in {:?}", started.elapsed());
+                        true
+                    }
+                    Ok(false) => true,
+                    Err(err) => {
+                        tracing::warn!("cannot warm {name}: {err}");
+                        state.metrics.record_warm_failure();
+                        false
+                    }
+                }
+            }
+        }))
+        .await;
+
+        let warm = warmed.iter().filter(|&&warm| warm).count();
+        state.metrics.record_warm_subdirs(warm as u64);
+        state.warm_up.record_round(warm);
+        tracing::debug!("{warm} of {} subdirs are warm", subdirs.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::Platform;
+
+    #[test]
+    fn test_parse_warm_subdir() {
+        let config = ChannelConfig::default();
+        let custom = CustomChannels::default();
+        let (channel, subdir) =
+            parse_warm_subdir("conda-forge/linux-64", &config, &custom).unwrap();
+        assert_eq!(
+            channel.canonical_name(),
+            "https://conda.anaconda.org/conda-forge/"
+        );
+        assert_eq!(subdir, Subdir::Platform(Platform::Linux64));
+
+        let (channel, subdir) = parse_warm_subdir(
+            "https://example.com/channel/linux-64-cuda/",
+            &config,
+            &custom,
+        )
+        .unwrap();
+        assert_eq!(channel.base_url.as_str(), "https://example.com/channel/");
+        assert_eq!(subdir, Subdir::Custom("linux-64-cuda".to_string()));
+
+        assert!(parse_warm_subdir("conda-forge", &config, &custom).is_err());
+        assert!(parse_warm_subdir("conda-forge/linux 64", &config, &custom).is_err());
+    }
+}
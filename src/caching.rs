@@ -0,0 +1,141 @@
+//! HTTP caching of solve responses through `ETag` and `Cache-Control` headers
+
+use crate::dto::SolveEnvironment;
+use crate::output::OutputParams;
+use axum::http::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, HeaderValue};
+use rattler_digest::{compute_bytes_digest, Sha256};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The largest `max-age` that clients are required to understand (see RFC 9111, section 1.2.2)
+const MAX_AGE_LIMIT: u64 = 2_147_483_648;
+
+/// Everything that determines the response to a solve request
+#[derive(Serialize)]
+struct SolveKey<'a> {
+    request: &'a SolveEnvironment,
+    /// Absent when only the solution matters, since the output parameters only affect rendering
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a OutputParams>,
+    repodata_hashes: &'a BTreeMap<String, String>,
+}
+
+/// Returns false if the client asked for the response not to be cached
+pub fn is_cacheable(request_headers: &HeaderMap) -> bool {
+    !request_headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| matches!(directive.trim(), "no-cache" | "no-store"))
+}
+
+/// Computes the (quoted) `ETag` of the response to a solve request. Since solving is deterministic,
+/// identical requests against identical repodata share their `ETag`. Responses served as
+/// MessagePack are a different representation of the solution, so their `ETag` differs.
+pub fn etag(
+    request: &SolveEnvironment,
+    output: &OutputParams,
+    repodata_hashes: &BTreeMap<String, String>,
+    msgpack: bool,
+) -> String {
+    let key = SolveKey {
+        request,
+        output: Some(output),
+        repodata_hashes,
+    };
+    match msgpack {
+        true => format!("\"{}-msgpack\"", key.digest()),
+        false => format!("\"{}\"", key.digest()),
+    }
+}
+
+/// Computes a key identifying the solution of a solve request, which is shared by requests that
+/// only differ in their output parameters
+pub fn solve_key(request: &SolveEnvironment, repodata_hashes: &BTreeMap<String, String>) -> String {
+    let key = SolveKey {
+        request,
+        output: None,
+        repodata_hashes,
+    };
+    key.digest()
+}
+
+impl SolveKey<'_> {
+    fn digest(&self) -> String {
+        let mut key = serde_json::to_value(self).expect("solve key serialization is infallible");
+
+        // How long the server may work on the request doesn't affect its solution, and neither do
+        // the credentials it was downloaded with (which must not end up in keys) once the
+        // repodata hashes are known
+        if let Some(request) = key["request"].as_object_mut() {
+            request.remove("timeout_ms");
+            request.remove("channel_credentials");
+        }
+
+        let key = serde_json::to_vec(&key).expect("solve key serialization is infallible");
+        format!("{:x}", compute_bytes_digest::<Sha256>(key))
+    }
+}
+
+/// Returns true if the request's `If-None-Match` header matches `etag`
+pub fn if_none_match(request_headers: &HeaderMap, etag: &str) -> bool {
+    request_headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Adds the headers advertising that the response with the given `etag` may be cached for as long
+/// as the repodata it was computed from
+pub fn insert_headers(headers: &mut HeaderMap, etag: &str, max_age: Duration) {
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(etag).expect("hex-encoded etags are valid header values"),
+    );
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&format!("max-age={}", max_age.as_secs().min(MAX_AGE_LIMIT)))
+            .expect("cache-control is a valid header value"),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(name: axum::http::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = "\"abc\"";
+        assert!(if_none_match(&headers(IF_NONE_MATCH, "\"abc\""), etag));
+        assert!(if_none_match(
+            &headers(IF_NONE_MATCH, "\"x\", W/\"abc\""),
+            etag
+        ));
+        assert!(if_none_match(&headers(IF_NONE_MATCH, "*"), etag));
+        assert!(!if_none_match(&headers(IF_NONE_MATCH, "\"x\""), etag));
+        assert!(!if_none_match(&HeaderMap::new(), etag));
+    }
+
+    #[test]
+    fn test_is_cacheable() {
+        assert!(is_cacheable(&HeaderMap::new()));
+        assert!(is_cacheable(&headers(CACHE_CONTROL, "max-age=0")));
+        assert!(!is_cacheable(&headers(
+            CACHE_CONTROL,
+            "max-age=0, no-cache"
+        )));
+        assert!(!is_cacheable(&headers(CACHE_CONTROL, "no-store")));
+    }
+}
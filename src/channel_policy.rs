@@ -0,0 +1,93 @@
+//! Restricts the channels that clients may request (e.g. to `conda-forge` and an internal mirror)
+
+use crate::custom_channels::CustomChannels;
+use crate::error::ApiError;
+use crate::tenants::is_within;
+use anyhow::Context;
+use rattler_conda_types::ChannelConfig;
+use reqwest::Url;
+
+/// The channels that may be requested, by base URL. A channel is also covered by the URLs of its
+/// parents (e.g. `https://conda.example.com/` covers every channel on that host).
+#[derive(Debug, Default, Clone)]
+pub struct ChannelPolicy {
+    /// Absent if any channel that is not denied may be requested
+    allowed: Option<Vec<Url>>,
+    denied: Vec<Url>,
+}
+
+impl ChannelPolicy {
+    pub fn new(
+        allowed: &[String],
+        denied: &[String],
+        channel_config: &ChannelConfig,
+        custom_channels: &CustomChannels,
+    ) -> anyhow::Result<ChannelPolicy> {
+        let parse = |channels: &[String]| {
+            channels
+                .iter()
+                .map(|channel| {
+                    custom_channels
+                        .parse_channel(channel, channel_config)
+                        .map(|c| c.base_url)
+                        .with_context(|| format!("invalid channel `{channel}`"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(ChannelPolicy {
+            allowed: (!allowed.is_empty()).then(|| parse(allowed)).transpose()?,
+            denied: parse(denied)?,
+        })
+    }
+
+    /// Fails if the channel is denied or, when there is an allowlist, not on it. Denied channels
+    /// are rejected even if they are within an allowed one.
+    pub fn check(&self, channel_url: &Url) -> Result<(), ApiError> {
+        let is_denied = self.denied.iter().any(|d| is_within(channel_url, d));
+        let is_allowed = self.allowed.as_ref().map_or(true, |allowed| {
+            allowed.iter().any(|a| is_within(channel_url, a))
+        });
+
+        match is_allowed && !is_denied {
+            true => Ok(()),
+            false => Err(ApiError::ChannelNotAllowed(channel_url.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::Channel;
+
+    fn check(policy: &ChannelPolicy, channel: &str) -> bool {
+        let channel = Channel::from_str(channel, &ChannelConfig::default()).unwrap();
+        policy.check(&channel.base_url).is_ok()
+    }
+
+    #[test]
+    fn test_channel_policy() {
+        let config = ChannelConfig::default();
+        let custom = CustomChannels::default();
+        let unrestricted = ChannelPolicy::new(&[], &[], &config, &custom).unwrap();
+        assert!(check(&unrestricted, "bioconda"));
+
+        let allowed = [
+            "conda-forge".to_string(),
+            "https://conda.example.com/".to_string(),
+        ];
+        let denied = ["https://conda.example.com/staging".to_string()];
+        let policy = ChannelPolicy::new(&allowed, &denied, &config, &custom).unwrap();
+        assert!(check(&policy, "conda-forge"));
+        assert!(check(&policy, "https://conda.anaconda.org/conda-forge/"));
+        assert!(check(&policy, "https://conda.example.com/mirror"));
+        assert!(!check(&policy, "bioconda"));
+        assert!(!check(
+            &policy,
+            "https://conda.anaconda.org/conda-forge-extra"
+        ));
+        assert!(!check(&policy, "https://conda.example.com/staging"));
+        assert!(!check(&policy, "https://conda.example.com/staging/nightly"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Applies the channel priority of a request to the available packages
+//!
+//! The solvers disagree on channel priority: resolvo only takes a package from the first channel
+//! that provides it, while libsolv picks the best build across all channels. To apply the same
+//! priority with either solver, the available records are filtered (for strict priority) or
+//! attributed to a single channel (for disabled priority) before solving. In the latter case, the
+//! channels of the solved records are restored afterwards.
+
+use crate::dto::ChannelPriority;
+use rattler_conda_types::{MatchSpec, RepoDataRecord};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+
+/// The original channels of the records that were attributed to a single channel before solving
+#[derive(Default)]
+pub struct OriginalChannels(HashMap<Url, String>);
+
+impl OriginalChannels {
+    /// Restores the channels of the solved records
+    pub fn restore(mut self, records: &mut [RepoDataRecord]) {
+        for record in records {
+            if let Some(original) = self.0.remove(&record.url) {
+                record.channel = original;
+            }
+        }
+    }
+}
+
+/// Applies the priority to the available packages, which are ordered by channel priority. Packages
+/// that a spec requests from a specific channel are left untouched, since the solvers look them up
+/// in that channel regardless of the priority.
+///
+/// * [`ChannelPriority::Strict`]: the records of a package are only kept for the first channel
+///   that provides it.
+/// * [`ChannelPriority::Disabled`]: all records are attributed to the same channel, so the solver
+///   picks the best build in any of them.
+pub fn apply_channel_priority(
+    priority: ChannelPriority,
+    specs: &[MatchSpec],
+    mut available_packages: Vec<Vec<RepoDataRecord>>,
+) -> (Vec<Vec<RepoDataRecord>>, OriginalChannels) {
+    let mut original = OriginalChannels::default();
+    let channel_specific: HashSet<_> = specs
+        .iter()
+        .filter(|s| s.channel.is_some())
+        .filter_map(|s| s.name.as_ref())
+        .map(|name| name.as_normalized().to_string())
+        .collect();
+    let applies =
+        |r: &RepoDataRecord| !channel_specific.contains(r.package_record.name.as_normalized());
+
+    match priority {
+        ChannelPriority::Strict => {
+            let mut first_channel = HashMap::new();
+            for records in &mut available_packages {
+                records.retain(|r| {
+                    if !applies(r) {
+                        return true;
+                    }
+                    let first = first_channel
+                        .entry(r.package_record.name.as_normalized().to_string())
+                        .or_insert_with(|| r.channel.clone());
+                    *first == r.channel
+                });
+            }
+        }
+        ChannelPriority::Disabled => {
+            let Some(shared) = available_packages.iter().flatten().next().map(|r| r.channel.clone())
+            else {
+                return (available_packages, original);
+            };
+            for record in available_packages.iter_mut().flatten() {
+                if applies(record) && record.channel != shared {
+                    let channel = std::mem::replace(&mut record.channel, shared.clone());
+                    original.0.insert(record.url.clone(), channel);
+                }
+            }
+        }
+    }
+
+    (available_packages, original)
+}
@@ -0,0 +1,166 @@
+//! Per-channel settings that determine which variant of the repodata is fetched, and from where
+
+use anyhow::Context;
+use rattler_repodata_gateway::fetch::{FetchRepoDataOptions, Variant};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The contents of the channel settings file
+#[derive(Debug, Deserialize)]
+pub struct ChannelSettingsConfig {
+    /// The settings of each channel, keyed by base URL
+    pub channels: HashMap<Url, FetchSettings>,
+}
+
+/// How the repodata of a channel is fetched
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchSettings {
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub variant: RepodataVariant,
+    /// Whether previously fetched repodata is updated with the incremental patches of
+    /// `repodata.jlap` (if the channel provides them), instead of being downloaded again
+    #[serde(default = "default_jlap")]
+    pub jlap: bool,
+    /// Whether solves only download the shards of the packages they need (if the channel provides
+    /// sharded repodata), instead of the full `repodata.json`
+    #[serde(default = "default_shards")]
+    pub shards: bool,
+    /// The base URLs of mirrors of the channel, which are tried in order (followed by the channel
+    /// itself, unless it is one of them) until one of them can be reached
+    #[serde(default)]
+    pub mirrors: Vec<Url>,
+    /// A directory with local repodata patches, laid out like the channel
+    /// (`<subdir>/patch_instructions.json`), which are applied on top of the fetched repodata
+    #[serde(default)]
+    pub patches: Option<PathBuf>,
+}
+
+impl Default for FetchSettings {
+    fn default() -> Self {
+        FetchSettings {
+            encoding: Encoding::default(),
+            variant: RepodataVariant::default(),
+            jlap: default_jlap(),
+            shards: default_shards(),
+            mirrors: Vec::new(),
+            patches: None,
+        }
+    }
+}
+
+fn default_jlap() -> bool {
+    true
+}
+
+fn default_shards() -> bool {
+    true
+}
+
+/// The preferred encoding of the downloaded repodata. Plain JSON is used if the preferred encoding
+/// is not available.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Prefer zst, then bz2, then plain JSON
+    #[default]
+    Auto,
+    Zst,
+    Bz2,
+    Plain,
+}
+
+/// The repodata file that is fetched
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepodataVariant {
+    /// `repodata.json`, which has repodata patches applied
+    #[default]
+    Full,
+    /// `current_repodata.json`, which only contains the latest version of each package
+    Current,
+    /// `repodata_from_packages.json`, which contains every package ever uploaded, unpatched
+    FromPackages,
+}
+
+impl FetchSettings {
+    /// Applies the settings to the options of a repodata fetch
+    pub fn apply(&self, options: &mut FetchRepoDataOptions) {
+        (options.zstd_enabled, options.bz2_enabled) = match self.encoding {
+            Encoding::Auto => (true, true),
+            Encoding::Zst => (true, false),
+            Encoding::Bz2 => (false, true),
+            Encoding::Plain => (false, false),
+        };
+        options.jlap_enabled = self.jlap;
+        options.variant = match self.variant {
+            RepodataVariant::Full => Variant::AfterPatches,
+            RepodataVariant::Current => Variant::Current,
+            RepodataVariant::FromPackages => Variant::FromPackages,
+        };
+    }
+
+    /// The file with the local patches for the subdir, if the channel has any
+    pub fn patch_file(&self, subdir: &str) -> Option<PathBuf> {
+        let file = self
+            .patches
+            .as_ref()?
+            .join(subdir)
+            .join("patch_instructions.json");
+        file.exists().then_some(file)
+    }
+}
+
+/// The fetch settings of every configured channel
+#[derive(Default)]
+pub struct ChannelSettings {
+    by_channel: HashMap<Url, FetchSettings>,
+}
+
+impl ChannelSettings {
+    /// Loads the channel settings from a JSON file
+    pub fn from_path(path: &Path) -> anyhow::Result<ChannelSettings> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("opening channel settings file {}", path.display()))?;
+        let config: ChannelSettingsConfig = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("parsing channel settings file {}", path.display()))?;
+        Ok(ChannelSettings::from_config(config))
+    }
+
+    pub fn from_config(config: ChannelSettingsConfig) -> ChannelSettings {
+        ChannelSettings {
+            by_channel: config
+                .channels
+                .into_iter()
+                .map(|(url, mut settings)| {
+                    settings.mirrors = settings
+                        .mirrors
+                        .into_iter()
+                        .map(with_trailing_slash)
+                        .collect();
+                    (with_trailing_slash(url), settings)
+                })
+                .collect(),
+        }
+    }
+
+    /// The settings of the channel with the given base URL, or the defaults if it was not
+    /// configured
+    pub fn for_channel(&self, base_url: &Url) -> FetchSettings {
+        self.by_channel
+            .get(&with_trailing_slash(base_url.clone()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
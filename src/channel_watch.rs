@@ -0,0 +1,28 @@
+//! Periodically checks whether the repodata of the watched channels changed upstream, so solves
+//! don't use stale repodata until it expires
+
+use crate::AppState;
+use rattler_conda_types::Channel;
+use std::sync::Weak;
+use std::time::Duration;
+
+/// Refreshes the cached repodata of the `channels` when it changes upstream, checking on the given
+/// interval. Returns once the state is dropped.
+pub async fn watch_channels(state: Weak<AppState>, channels: Vec<Channel>, interval: Duration) {
+    let mut interval_timer = tokio::time::interval(interval);
+
+    // The first tick completes immediately, when nothing has been cached yet
+    interval_timer.tick().await;
+    loop {
+        interval_timer.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        for channel in &channels {
+            let refreshed = state.available_packages.refresh_changed(channel).await;
+            if !refreshed.is_empty() {
+                tracing::debug!("refreshed {} subdirs", refreshed.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,691 @@
+//! Contains the `/channels` endpoints, which inspect (and search) channels without solving
+
+use crate::available_packages_cache::Availability;
+use crate::credentials::DownloadClient;
+use crate::dto::PackageFormat;
+use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
+use crate::license_filter::glob_matches;
+use crate::package_format::filter_package_format;
+use crate::subdir::Subdir;
+use crate::v1::PackageRecord;
+use crate::{auth, AppState};
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{StreamExt, TryStreamExt};
+use rattler_conda_types::{
+    Channel, MatchSpec, PackageName, Platform, RepoDataRecord, Version, VersionSpec,
+    VersionWithSource,
+};
+use rattler_repodata_gateway::fetch::FetchRepoDataError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// The platforms that are looked up when the client doesn't specify any
+const DEFAULT_PLATFORMS: [Platform; 7] = [
+    Platform::NoArch,
+    Platform::Linux64,
+    Platform::LinuxAarch64,
+    Platform::LinuxPpc64le,
+    Platform::Osx64,
+    Platform::OsxArm64,
+    Platform::Win64,
+];
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ValidateChannelParams {
+    pub channel: String,
+    pub platform: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ValidateChannelResult {
+    /// Whether any variant of the repodata is available
+    pub reachable: bool,
+    pub variants: Availability,
+}
+
+/// Checks whether the repodata of a channel and platform can be downloaded, without downloading it
+#[utoipa::path(
+    get,
+    path = "/v1/channels/validate",
+    params(ValidateChannelParams),
+    responses(
+        (status = 200, body = ValidateChannelResult),
+        (status = 400, description = "The channel or platform is invalid", body = ErrorResponse),
+        (status = 403, description = "The channel may not be used", body = ErrorResponse),
+    )
+)]
+pub async fn validate_channel(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ValidateChannelParams>,
+    headers: HeaderMap,
+) -> Response {
+    match validate_channel_inner(&state, &params, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn validate_channel_inner(
+    state: &AppState,
+    params: &ValidateChannelParams,
+    headers: &HeaderMap,
+) -> Result<ValidateChannelResult, ApiError> {
+    let channel = state
+        .settings()
+        .parse_channel(&params.channel)
+        .map_err(|e| {
+            ValidationError::Channels(ParseErrors(vec![ParseError {
+                input: params.channel.clone(),
+                error: e.to_string(),
+            }]))
+        })?;
+    let platform = Platform::from_str(&params.platform).map_err(|e| {
+        ValidationError::Platform(ParseError {
+            input: params.platform.clone(),
+            error: e.to_string(),
+        })
+    })?;
+
+    let client = check_access(state, &channel, headers)?;
+
+    let variants = state
+        .available_packages
+        .check_availability(&channel, platform, client)
+        .await;
+    Ok(ValidateChannelResult {
+        reachable: variants.zst || variants.bz2 || variants.plain,
+        variants,
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PackageParams {
+    /// Comma-separated list of platforms
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PackageMetadata {
+    pub name: String,
+    /// The versions of the package, newest first
+    pub versions: Vec<PackageVersion>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PackageVersion {
+    pub version: String,
+    pub builds: Vec<PackageBuild>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PackageBuild {
+    pub build: String,
+    pub build_number: u64,
+    pub subdir: String,
+    pub depends: Vec<String>,
+    pub constrains: Vec<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub file_name: String,
+    pub url: String,
+}
+
+/// Returns every build of a package across the platforms of a channel
+#[utoipa::path(
+    get,
+    path = "/v1/channels/{channel}/packages/{name}",
+    params(
+        ("channel" = String, Path, description = "The name or URL of the channel"),
+        ("name" = String, Path, description = "The name of the package"),
+        PackageParams,
+    ),
+    responses(
+        (status = 200, body = PackageMetadata),
+        (status = 400, description = "The channel, package name or platform is invalid", body = ErrorResponse),
+        (status = 403, description = "The channel may not be used", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn package_metadata(
+    State(state): State<Arc<AppState>>,
+    Path((channel, name)): Path<(String, String)>,
+    Query(params): Query<PackageParams>,
+    headers: HeaderMap,
+) -> Response {
+    match package_metadata_inner(&state, &channel, &name, &params, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn package_metadata_inner(
+    state: &AppState,
+    channel: &str,
+    name: &str,
+    params: &PackageParams,
+    headers: &HeaderMap,
+) -> Result<PackageMetadata, ApiError> {
+    let channel = state.settings().parse_channel(channel).map_err(|e| {
+        ValidationError::Channels(ParseErrors(vec![ParseError {
+            input: channel.to_string(),
+            error: e.to_string(),
+        }]))
+    })?;
+    let name = PackageName::from_str(name).map_err(|e| {
+        ValidationError::PackageName(ParseError {
+            input: name.to_string(),
+            error: e.to_string(),
+        })
+    })?;
+
+    // Platforms requested by the client must exist, but the defaults are skipped when missing
+    let (platforms, skip_missing) = match &params.platform {
+        Some(platform) => {
+            let platforms = platform
+                .split(',')
+                .map(|p| {
+                    Platform::from_str(p.trim()).map_err(|e| {
+                        ValidationError::Platform(ParseError {
+                            input: p.to_string(),
+                            error: e.to_string(),
+                        })
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            (platforms, false)
+        }
+        None => (DEFAULT_PLATFORMS.to_vec(), true),
+    };
+
+    let client = check_access(state, &channel, headers)?;
+
+    let records: Vec<Vec<RepoDataRecord>> = futures::stream::iter(platforms)
+        .map(|platform| {
+            let channel = &channel;
+            let name = &name;
+            async move {
+                match package_records(state, channel, platform, client, name).await {
+                    Err(ApiError::FetchRepoDataJson(_, FetchRepoDataError::NotFound(_)))
+                        if skip_missing =>
+                    {
+                        Ok(Vec::new())
+                    }
+                    result => result,
+                }
+            }
+        })
+        .buffer_unordered(state.settings().concurrent_repodata_downloads_per_request)
+        .try_collect()
+        .await?;
+
+    let versions = package_versions(records.into_iter().flatten());
+    if versions.is_empty() {
+        return Err(ApiError::UnknownPackages(vec![name
+            .as_normalized()
+            .to_string()]));
+    }
+
+    Ok(PackageMetadata {
+        name: name.as_normalized().to_string(),
+        versions,
+    })
+}
+
+/// Groups the records of a package by version, newest first
+fn package_versions(records: impl IntoIterator<Item = RepoDataRecord>) -> Vec<PackageVersion> {
+    let mut versions: BTreeMap<VersionWithSource, Vec<PackageBuild>> = BTreeMap::new();
+    for record in records {
+        let package = record.package_record;
+        versions
+            .entry(package.version)
+            .or_default()
+            .push(PackageBuild {
+                build: package.build,
+                build_number: package.build_number,
+                subdir: package.subdir,
+                depends: package.depends,
+                constrains: package.constrains,
+                size: package.size,
+                sha256: package.sha256.map(|hash| format!("{hash:x}")),
+                file_name: record.file_name,
+                url: record.url.to_string(),
+            });
+    }
+
+    versions
+        .into_iter()
+        .rev()
+        .map(|(version, mut builds)| {
+            builds.sort_by(|a, b| {
+                b.build_number
+                    .cmp(&a.build_number)
+                    .then_with(|| a.subdir.cmp(&b.subdir))
+                    .then_with(|| a.build.cmp(&b.build))
+            });
+            PackageVersion {
+                version: version.to_string(),
+                builds,
+            }
+        })
+        .collect()
+}
+
+/// Checks whether the caller may use the channel, returning the client to download its repodata
+/// with (if not the default one)
+pub fn check_access<'a>(
+    state: &'a AppState,
+    channel: &Channel,
+    headers: &HeaderMap,
+) -> Result<Option<&'a DownloadClient>, ApiError> {
+    state.settings().channel_policy.check(&channel.base_url)?;
+    let Some(tenants) = &state.tenants else {
+        return Ok(None);
+    };
+    let tenant = auth::authenticate_tenant(state, headers)?;
+    tenants.check_access(tenant, &channel.base_url)?;
+    Ok(tenant.map(|t| t.client()))
+}
+
+/// Returns the records of the package in the repodata of a single platform
+async fn package_records(
+    state: &AppState,
+    channel: &Channel,
+    platform: Platform,
+    client: Option<&DownloadClient>,
+    name: &PackageName,
+) -> Result<Vec<RepoDataRecord>, ApiError> {
+    let snapshot = state
+        .available_packages
+        .get(channel, &Subdir::Platform(platform), client, None)
+        .await?;
+    snapshot.records.into_package(name)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// The names of the packages, where `*` matches any sequence of characters and `?` any single
+    /// character (e.g. `py*`)
+    pub q: String,
+    /// Only return the versions that match this spec (e.g. `>=1.2,<2`)
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SearchResult {
+    /// The matching packages, sorted by name
+    pub packages: Vec<PackageMetadata>,
+}
+
+/// Searches the repodata of a channel and platform for packages, like `conda search`
+#[utoipa::path(
+    get,
+    path = "/v1/channels/{channel}/{platform}/search",
+    params(
+        ("channel" = String, Path, description = "The name or URL of the channel"),
+        ("platform" = String, Path, description = "The platform whose repodata is searched"),
+        SearchParams,
+    ),
+    responses(
+        (status = 200, body = SearchResult),
+        (status = 400, description = "The channel, platform or version spec is invalid", body = ErrorResponse),
+        (status = 403, description = "The channel may not be used", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn search_packages(
+    State(state): State<Arc<AppState>>,
+    Path((channel, platform)): Path<(String, String)>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+) -> Response {
+    match search_packages_inner(&state, &channel, &platform, &params, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn search_packages_inner(
+    state: &AppState,
+    channel: &str,
+    platform: &str,
+    params: &SearchParams,
+    headers: &HeaderMap,
+) -> Result<SearchResult, ApiError> {
+    let channel = state.settings().parse_channel(channel).map_err(|e| {
+        ValidationError::Channels(ParseErrors(vec![ParseError {
+            input: channel.to_string(),
+            error: e.to_string(),
+        }]))
+    })?;
+    let platform = Platform::from_str(platform).map_err(|e| {
+        ValidationError::Platform(ParseError {
+            input: platform.to_string(),
+            error: e.to_string(),
+        })
+    })?;
+    let version = params
+        .version
+        .as_deref()
+        .map(|version| {
+            VersionSpec::from_str(version).map_err(|e| {
+                ValidationError::VersionSpec(ParseError {
+                    input: version.to_string(),
+                    error: e.to_string(),
+                })
+            })
+        })
+        .transpose()?;
+    // Package names are always lowercase
+    let pattern = params.q.to_lowercase();
+
+    let client = check_access(state, &channel, headers)?;
+
+    let snapshot = state
+        .available_packages
+        .get(&channel, &Subdir::Platform(platform), client, None)
+        .await?;
+    let mut packages: BTreeMap<String, Vec<RepoDataRecord>> = BTreeMap::new();
+    let records = snapshot.records.into_loaded()?;
+    for record in records.iter() {
+        let package = &record.package_record;
+        let name_matches = glob_matches(&pattern, package.name.as_normalized());
+        let version_matches = version
+            .as_ref()
+            .map_or(true, |spec| spec.matches(package.version.version()));
+        if name_matches && version_matches {
+            packages
+                .entry(package.name.as_normalized().to_string())
+                .or_default()
+                .push(record.clone());
+        }
+    }
+
+    Ok(SearchResult {
+        packages: packages
+            .into_iter()
+            .map(|(name, records)| PackageMetadata {
+                name,
+                versions: package_versions(records),
+            })
+            .collect(),
+    })
+}
+
+/// The path of a single build of a package
+#[derive(Debug, Deserialize)]
+pub struct PackageBuildPath {
+    channel: String,
+    platform: String,
+    name: String,
+    version: String,
+    build: String,
+}
+
+/// Returns the full record of a single build of a package
+#[utoipa::path(
+    get,
+    path = "/v1/channels/{channel}/{platform}/packages/{name}/{version}/{build}",
+    params(
+        ("channel" = String, Path, description = "The name or URL of the channel"),
+        ("platform" = String, Path, description = "The platform whose repodata contains the build"),
+        ("name" = String, Path, description = "The name of the package"),
+        ("version" = String, Path, description = "The version of the package"),
+        ("build" = String, Path, description = "The build string of the package"),
+    ),
+    responses(
+        (status = 200, description = "The record of the build, preferring its `.conda` artifact", body = PackageRecord),
+        (status = 400, description = "The channel, platform, package name or version is invalid", body = ErrorResponse),
+        (status = 403, description = "The channel may not be used", body = ErrorResponse),
+        (status = 404, description = "The build is not in the repodata", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn package_build(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<PackageBuildPath>,
+    headers: HeaderMap,
+) -> Response {
+    match package_build_inner(&state, &path, &headers).await {
+        Ok(record) => Json(PackageRecord::from(&record)).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn package_build_inner(
+    state: &AppState,
+    path: &PackageBuildPath,
+    headers: &HeaderMap,
+) -> Result<RepoDataRecord, ApiError> {
+    let channel = state.settings().parse_channel(&path.channel).map_err(|e| {
+        ValidationError::Channels(ParseErrors(vec![ParseError {
+            input: path.channel.clone(),
+            error: e.to_string(),
+        }]))
+    })?;
+    let platform = Platform::from_str(&path.platform).map_err(|e| {
+        ValidationError::Platform(ParseError {
+            input: path.platform.clone(),
+            error: e.to_string(),
+        })
+    })?;
+    let name = PackageName::from_str(&path.name).map_err(|e| {
+        ValidationError::PackageName(ParseError {
+            input: path.name.clone(),
+            error: e.to_string(),
+        })
+    })?;
+    let version = Version::from_str(&path.version).map_err(|e| {
+        ValidationError::VersionSpec(ParseError {
+            input: path.version.clone(),
+            error: e.to_string(),
+        })
+    })?;
+
+    let client = check_access(state, &channel, headers)?;
+    let records = package_records(state, &channel, platform, client, &name).await?;
+    let builds: Vec<_> = records
+        .into_iter()
+        .filter(|r| {
+            r.package_record.version.version() == &version && r.package_record.build == path.build
+        })
+        .collect();
+
+    // Builds that are available in both formats are the same package
+    filter_package_format(PackageFormat::Any, vec![builds])
+        .into_iter()
+        .flatten()
+        .next()
+        .ok_or_else(|| {
+            ApiError::UnknownPackages(vec![format!(
+                "{}={}={}",
+                name.as_normalized(),
+                path.version,
+                path.build
+            )])
+        })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DependentsParams {
+    /// The name of the package whose dependents are returned
+    pub name: String,
+    /// Only return the dependents that accept any version of the package that matches this spec
+    /// (e.g. `1.1.*`)
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DependentsResult {
+    /// The builds that depend on (or constrain) the package, sorted by name and newest version
+    /// first
+    pub dependents: Vec<Dependent>,
+}
+
+/// A build that references the package through its `depends` or `constrains`
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Dependent {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub subdir: String,
+    /// The entries of the build's `depends` that reference the package
+    pub depends: Vec<String>,
+    /// The entries of the build's `constrains` that reference the package
+    pub constrains: Vec<String>,
+}
+
+/// Returns the packages in the repodata of a channel and platform that depend on (or constrain)
+/// a package, like `conda search --reverse-dependency`
+#[utoipa::path(
+    get,
+    path = "/v1/channels/{channel}/{platform}/whoneeds",
+    params(
+        ("channel" = String, Path, description = "The name or URL of the channel"),
+        ("platform" = String, Path, description = "The platform whose repodata is searched"),
+        DependentsParams,
+    ),
+    responses(
+        (status = 200, body = DependentsResult),
+        (status = 400, description = "The channel, platform, package name or version spec is invalid", body = ErrorResponse),
+        (status = 403, description = "The channel may not be used", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn package_dependents(
+    State(state): State<Arc<AppState>>,
+    Path((channel, platform)): Path<(String, String)>,
+    Query(params): Query<DependentsParams>,
+    headers: HeaderMap,
+) -> Response {
+    match package_dependents_inner(&state, &channel, &platform, &params, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn package_dependents_inner(
+    state: &AppState,
+    channel: &str,
+    platform: &str,
+    params: &DependentsParams,
+    headers: &HeaderMap,
+) -> Result<DependentsResult, ApiError> {
+    let channel = state.settings().parse_channel(channel).map_err(|e| {
+        ValidationError::Channels(ParseErrors(vec![ParseError {
+            input: channel.to_string(),
+            error: e.to_string(),
+        }]))
+    })?;
+    let platform = Platform::from_str(platform).map_err(|e| {
+        ValidationError::Platform(ParseError {
+            input: platform.to_string(),
+            error: e.to_string(),
+        })
+    })?;
+    let name = PackageName::from_str(&params.name).map_err(|e| {
+        ValidationError::PackageName(ParseError {
+            input: params.name.clone(),
+            error: e.to_string(),
+        })
+    })?;
+    let version = params
+        .version
+        .as_deref()
+        .map(|version| {
+            VersionSpec::from_str(version).map_err(|e| {
+                ValidationError::VersionSpec(ParseError {
+                    input: version.to_string(),
+                    error: e.to_string(),
+                })
+            })
+        })
+        .transpose()?;
+
+    let client = check_access(state, &channel, headers)?;
+    let snapshot = state
+        .available_packages
+        .get(&channel, &Subdir::Platform(platform), client, None)
+        .await?;
+    // Builds that are available in both formats are the same package
+    let records = filter_package_format(
+        PackageFormat::Any,
+        vec![snapshot.records.into_loaded()?.to_vec()],
+    )
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    // With a version spec, a reference only counts if it accepts any of the matching versions in
+    // the repodata
+    let versions: Option<Vec<&Version>> = version.as_ref().map(|spec| {
+        records
+            .iter()
+            .map(|r| &r.package_record)
+            .filter(|p| p.name == name && spec.matches(p.version.version()))
+            .map(|p| p.version.version())
+            .collect()
+    });
+    let references = |specs: &[String]| -> Vec<String> {
+        specs
+            .iter()
+            .filter(|spec| {
+                let Ok(spec) = MatchSpec::from_str(spec) else {
+                    return false;
+                };
+                if spec.name.as_ref() != Some(&name) {
+                    return false;
+                }
+                match (&versions, &spec.version) {
+                    (Some(versions), Some(spec)) => versions.iter().any(|v| spec.matches(v)),
+                    (Some(versions), None) => !versions.is_empty(),
+                    (None, _) => true,
+                }
+            })
+            .cloned()
+            .collect()
+    };
+
+    let mut dependents: Vec<_> = records
+        .iter()
+        .filter_map(|record| {
+            let package = &record.package_record;
+            let depends = references(&package.depends);
+            let constrains = references(&package.constrains);
+            if depends.is_empty() && constrains.is_empty() {
+                return None;
+            }
+            Some((package, depends, constrains))
+        })
+        .collect();
+    dependents.sort_by(|(a, ..), (b, ..)| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| b.version.cmp(&a.version))
+            .then_with(|| b.build_number.cmp(&a.build_number))
+            .then_with(|| a.build.cmp(&b.build))
+    });
+
+    Ok(DependentsResult {
+        dependents: dependents
+            .into_iter()
+            .map(|(package, depends, constrains)| Dependent {
+                name: package.name.as_normalized().to_string(),
+                version: package.version.to_string(),
+                build: package.build.clone(),
+                subdir: package.subdir.clone(),
+                depends,
+                constrains,
+            })
+            .collect(),
+    })
+}
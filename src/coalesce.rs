@@ -0,0 +1,172 @@
+//! Deduplicates concurrent executions of the same work, so identical requests that arrive while
+//! it is in flight share its result instead of repeating it
+
+use futures::future::{BoxFuture, Shared, WeakShared};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+type Work<T> = BoxFuture<'static, Arc<T>>;
+type InFlight<K, T> = Arc<Mutex<HashMap<K, WeakShared<Work<T>>>>>;
+
+/// The work in flight, by key. Only the callers awaiting the work keep it alive, so it is dropped
+/// (and therefore cancelled) once all of them are.
+pub struct Coalescer<K, T> {
+    in_flight: InFlight<K, T>,
+}
+
+impl<K, T> Default for Coalescer<K, T> {
+    fn default() -> Self {
+        Coalescer {
+            in_flight: Arc::default(),
+        }
+    }
+}
+
+impl<K, T> Coalescer<K, T>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + Sync + 'static,
+{
+    /// Awaits the work in flight for `key`, starting it through `work` if there is none
+    pub fn run<F>(&self, key: K, work: impl FnOnce() -> F) -> Waiter<K, T>
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let shared = match in_flight.get(&key).and_then(WeakShared::upgrade) {
+            Some(shared) => shared,
+            None => {
+                let shared = work().map(Arc::new).boxed().shared();
+                if let Some(weak) = shared.downgrade() {
+                    in_flight.insert(key.clone(), weak);
+                }
+                shared
+            }
+        };
+
+        Waiter {
+            in_flight: self.in_flight.clone(),
+            key,
+            shared: Some(shared),
+        }
+    }
+
+    /// How many computations are in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+/// Resolves to the output of the shared work, and forgets the work once it is no longer useful
+pub struct Waiter<K: Hash + Eq, T> {
+    in_flight: InFlight<K, T>,
+    key: K,
+    shared: Option<Shared<Work<T>>>,
+}
+
+// None of the fields are pinned, since the shared work is polled through its own handle
+impl<K: Hash + Eq, T> Unpin for Waiter<K, T> {}
+
+impl<K: Hash + Eq, T> Future for Waiter<K, T> {
+    type Output = Arc<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let shared = self
+            .shared
+            .as_mut()
+            .expect("waiters are not polled after completion");
+        let output = futures::ready!(shared.poll_unpin(cx));
+        self.shared = None;
+        Poll::Ready(output)
+    }
+}
+
+impl<K: Hash + Eq, T> Drop for Waiter<K, T> {
+    fn drop(&mut self) {
+        // Release this waiter's handle first, so the work is gone if nobody else awaits it
+        drop(self.shared.take());
+
+        // Finished work is forgotten right away, so later callers start it anew
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let forget = match in_flight.get(&self.key).map(WeakShared::upgrade) {
+            Some(Some(shared)) => shared.peek().is_some(),
+            Some(None) => true,
+            None => false,
+        };
+        if forget {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_concurrent_runs_share_the_work() {
+        let coalescer = Coalescer::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut rx = Some(rx);
+
+        let mut waiters = Vec::new();
+        for _ in 0..3 {
+            let runs = runs.clone();
+            let rx = rx.take();
+            waiters.push(coalescer.run("key", move || async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                rx.unwrap().await.unwrap();
+                42
+            }));
+        }
+        let other = coalescer.run("other", || async { 0 });
+        assert_eq!(coalescer.in_flight(), 2);
+
+        tx.send(()).unwrap();
+        for waiter in waiters {
+            assert_eq!(*waiter.await, 42);
+        }
+        assert_eq!(*other.await, 0);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(coalescer.in_flight(), 0);
+
+        // Finished work is not reused
+        assert_eq!(*coalescer.run("key", || async { 7 }).await, 7);
+    }
+
+    #[tokio::test]
+    async fn test_work_survives_while_someone_awaits_it() {
+        let coalescer = Coalescer::default();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let first = coalescer.run("key", || async move {
+            rx.await.unwrap();
+            1
+        });
+        let second = coalescer.run("key", || async { 2 });
+
+        // Dropping one waiter keeps the work going for the other
+        drop(first);
+        assert_eq!(coalescer.in_flight(), 1);
+        tx.send(()).unwrap();
+        assert_eq!(*second.await, 1);
+
+        // Once nobody awaits the work, it is dropped and started anew by the next caller
+        let (_tx, rx) = oneshot::channel::<()>();
+        let abandoned = coalescer.run("key", || async move {
+            rx.await.unwrap();
+            3
+        });
+        drop(abandoned);
+        assert_eq!(coalescer.in_flight(), 0);
+        assert_eq!(*coalescer.run("key", || async { 4 }).await, 4);
+    }
+}
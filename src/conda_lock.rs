@@ -0,0 +1,156 @@
+//! Renders solve results as a `conda-lock.yml` lockfile, in the format written by conda-lock (and
+//! read by `conda-lock install` and pixi)
+
+use crate::dto::SolveEnvironmentOk;
+use rattler_conda_types::RepoDataRecord;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The version of the lockfile format. conda-lock's newer lockfile model is converted to this
+/// version before being written, so it is the only version that tools read.
+const LOCKFILE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct CondaLock {
+    pub version: u32,
+    pub metadata: Metadata,
+    pub package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Metadata {
+    /// Identifies the inputs of each platform's solve, keyed by platform
+    pub content_hash: BTreeMap<String, String>,
+    pub channels: Vec<LockedChannel>,
+    pub platforms: Vec<String>,
+    /// The files the lockfile was generated from, of which there are none
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockedChannel {
+    pub url: String,
+    pub used_env_vars: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub manager: String,
+    pub platform: String,
+    /// The version constraint of each dependency, keyed by name (`*` if unconstrained)
+    pub dependencies: BTreeMap<String, String>,
+    pub url: String,
+    pub hash: PackageHash,
+    pub category: String,
+    pub optional: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageHash {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The solution for one of the platforms in the lockfile
+pub struct PlatformSolution<'a> {
+    pub platform: &'a str,
+    /// Identifies the inputs of the solve (e.g. [`crate::caching::solve_key`])
+    pub content_hash: String,
+    pub solution: &'a SolveEnvironmentOk,
+}
+
+/// Creates the lockfile of the given solutions, which were solved against `channels`
+pub fn lockfile(channels: &[String], solutions: Vec<PlatformSolution>) -> CondaLock {
+    let mut metadata = Metadata {
+        content_hash: BTreeMap::new(),
+        channels: channels
+            .iter()
+            .map(|url| LockedChannel {
+                url: url.clone(),
+                used_env_vars: Vec::new(),
+            })
+            .collect(),
+        platforms: Vec::with_capacity(solutions.len()),
+        sources: Vec::new(),
+    };
+
+    let mut package = Vec::new();
+    for PlatformSolution {
+        platform,
+        content_hash,
+        solution,
+    } in solutions
+    {
+        metadata
+            .content_hash
+            .insert(platform.to_string(), content_hash);
+        metadata.platforms.push(platform.to_string());
+        package.extend(
+            solution
+                .packages
+                .iter()
+                .map(|record| locked_package(platform, record)),
+        );
+    }
+
+    CondaLock {
+        version: LOCKFILE_VERSION,
+        metadata,
+        package,
+    }
+}
+
+fn locked_package(platform: &str, record: &RepoDataRecord) -> LockedPackage {
+    let package = &record.package_record;
+    LockedPackage {
+        name: package.name.as_normalized().to_string(),
+        version: package.version.to_string(),
+        manager: "conda".to_string(),
+        platform: platform.to_string(),
+        dependencies: package
+            .depends
+            .iter()
+            .map(|d| split_dependency(d))
+            .collect(),
+        url: record.url.to_string(),
+        hash: PackageHash {
+            md5: package.md5.map(|md5| format!("{md5:x}")),
+            sha256: package.sha256.map(|sha256| format!("{sha256:x}")),
+        },
+        category: "main".to_string(),
+        optional: false,
+    }
+}
+
+/// Splits a dependency like `python >=3.8,<3.9` into its name and constraint
+fn split_dependency(dependency: &str) -> (String, String) {
+    match dependency.trim().split_once(char::is_whitespace) {
+        Some((name, constraint)) => (name.to_string(), constraint.trim().to_string()),
+        None => (dependency.trim().to_string(), "*".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_dependency() {
+        assert_eq!(
+            split_dependency("python >=3.8,<3.9.0a0"),
+            ("python".to_string(), ">=3.8,<3.9.0a0".to_string())
+        );
+        assert_eq!(
+            split_dependency("libzlib 1.2.13 hd590300_5"),
+            ("libzlib".to_string(), "1.2.13 hd590300_5".to_string())
+        );
+        assert_eq!(
+            split_dependency("tzdata"),
+            ("tzdata".to_string(), "*".to_string())
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Loads the server settings from a config file, which can be reloaded at runtime (on SIGHUP or
+//! through `/admin/reload`), so settings can be tuned without dropping the cached repodata
+
+use crate::channel_policy::ChannelPolicy;
+use crate::cli::{Args, Solver};
+use crate::custom_channels::CustomChannels;
+use crate::{parse_virtual_package, AppState};
+use anyhow::Context;
+use rattler_conda_types::{Channel, ChannelConfig, ParseChannelError};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// The settings in a config file, which take precedence over the command line (and the
+/// environment). Settings that are absent keep the value given on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Only applied on startup
+    pub port: Option<u16>,
+    /// Only applied on startup
+    pub cache_dir: Option<PathBuf>,
+    /// Only applied on startup
+    pub http_proxy: Option<Url>,
+    /// Only applied on startup
+    pub no_proxy: Option<Vec<String>>,
+    /// Only applied on startup
+    pub ca_certificates: Option<Vec<PathBuf>>,
+    pub repodata_cache_expiration_seconds: Option<u64>,
+    pub channel_alias: Option<Url>,
+    /// The channel alias of each custom channel, by name
+    pub custom_channels: Option<BTreeMap<String, Url>>,
+    pub concurrent_repodata_downloads_per_request: Option<usize>,
+    pub max_channels_per_request: Option<usize>,
+    pub max_specs_per_request: Option<usize>,
+    pub solver: Option<Solver>,
+    /// The virtual packages of each profile, as the version of each virtual package by name
+    pub profiles: Option<BTreeMap<String, BTreeMap<String, String>>>,
+}
+
+impl ConfigFile {
+    /// Loads the config file, in YAML (or JSON)
+    pub fn from_path(path: &Path) -> anyhow::Result<ConfigFile> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("opening config file {}", path.display()))?;
+        serde_yaml::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// Overrides the arguments with the settings in the file
+    pub fn apply(self, args: &mut Args) {
+        let ConfigFile {
+            port,
+            cache_dir,
+            http_proxy,
+            no_proxy,
+            ca_certificates,
+            repodata_cache_expiration_seconds,
+            channel_alias,
+            custom_channels,
+            concurrent_repodata_downloads_per_request,
+            max_channels_per_request,
+            max_specs_per_request,
+            solver,
+            profiles,
+        } = self;
+        args.port = port.unwrap_or(args.port);
+        args.cache_dir = cache_dir.unwrap_or(args.cache_dir.clone());
+        args.http_proxy = http_proxy.or(args.http_proxy.take());
+        args.no_proxy = no_proxy.unwrap_or(args.no_proxy.clone());
+        args.ca_certificates = ca_certificates.unwrap_or(args.ca_certificates.clone());
+        args.repodata_cache_expiration_seconds =
+            repodata_cache_expiration_seconds.unwrap_or(args.repodata_cache_expiration_seconds);
+        args.channel_alias = channel_alias.unwrap_or(args.channel_alias.clone());
+        if let Some(custom_channels) = custom_channels {
+            args.custom_channels = custom_channels
+                .into_iter()
+                .map(|(name, url)| format!("{name}={url}"))
+                .collect();
+        }
+        args.concurrent_repodata_downloads_per_request = concurrent_repodata_downloads_per_request
+            .unwrap_or(args.concurrent_repodata_downloads_per_request);
+        args.max_channels_per_request =
+            max_channels_per_request.unwrap_or(args.max_channels_per_request);
+        args.max_specs_per_request = max_specs_per_request.unwrap_or(args.max_specs_per_request);
+        args.solver = solver.unwrap_or(args.solver);
+        if let Some(profiles) = profiles {
+            args.virtual_package_profiles = profiles
+                .into_iter()
+                .map(|(profile, packages)| {
+                    let packages = packages
+                        .into_iter()
+                        .map(|(name, version)| format!("{name}={version}"))
+                        .collect();
+                    (profile, packages)
+                })
+                .collect();
+        }
+    }
+}
+
+/// The settings that change when the config file is reloaded
+#[derive(Clone)]
+pub struct Settings {
+    pub repodata_cache_expiration: Duration,
+    pub concurrent_repodata_downloads_per_request: usize,
+    pub max_channels_per_request: usize,
+    pub max_specs_per_request: usize,
+    /// The solver of the requests that don't ask for one
+    pub solver: Solver,
+    /// The virtual packages of each profile, by name
+    pub virtual_package_profiles: BTreeMap<String, Vec<String>>,
+    pub channel_config: ChannelConfig,
+    pub custom_channels: CustomChannels,
+    pub channel_policy: ChannelPolicy,
+}
+
+impl Settings {
+    pub fn from_args(args: &Args) -> anyhow::Result<Settings> {
+        let channel_config = ChannelConfig {
+            channel_alias: args.channel_alias.clone(),
+        };
+        let custom_channels =
+            CustomChannels::new(&args.custom_channels).context("parsing the custom channels")?;
+        for (profile, packages) in &args.virtual_package_profiles {
+            for spec in packages {
+                parse_virtual_package(spec).map_err(|e| {
+                    anyhow::anyhow!(
+                        "invalid virtual package `{spec}` of profile `{profile}`: {}",
+                        e.error
+                    )
+                })?;
+            }
+        }
+        Ok(Settings {
+            repodata_cache_expiration: Duration::from_secs(args.repodata_cache_expiration_seconds),
+            concurrent_repodata_downloads_per_request: args
+                .concurrent_repodata_downloads_per_request,
+            max_channels_per_request: args.max_channels_per_request,
+            max_specs_per_request: args.max_specs_per_request,
+            solver: args.solver,
+            virtual_package_profiles: args.virtual_package_profiles.clone(),
+            channel_policy: ChannelPolicy::new(
+                &args.allowed_channels,
+                &args.denied_channels,
+                &channel_config,
+                &custom_channels,
+            )
+            .context("parsing the channel policy")?,
+            channel_config,
+            custom_channels,
+        })
+    }
+
+    /// Parses a channel given in a request or on the command line
+    pub fn parse_channel(&self, channel: &str) -> Result<Channel, ParseChannelError> {
+        self.custom_channels
+            .parse_channel(channel, &self.channel_config)
+    }
+}
+
+/// Reloads the config file on top of the arguments the server was started with
+pub struct ConfigReloader {
+    path: PathBuf,
+    /// The arguments given on the command line (and in the environment)
+    args: Args,
+}
+
+impl ConfigReloader {
+    pub fn new(path: PathBuf, args: Args) -> ConfigReloader {
+        ConfigReloader { path, args }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The arguments as overridden by the current contents of the config file
+    pub fn load(&self) -> anyhow::Result<Args> {
+        let mut args = self.args.clone();
+        ConfigFile::from_path(&self.path)?.apply(&mut args);
+        Ok(args)
+    }
+}
+
+/// Reloads the config file of the server, if any, replacing the settings it uses from now on.
+/// Repodata and solve results that are already cached keep their expiration.
+pub fn reload(state: &AppState, config: &ConfigReloader) -> anyhow::Result<()> {
+    let args = config.load()?;
+    let settings = Settings::from_args(&args)?;
+
+    state
+        .available_packages
+        .set_expiration(settings.repodata_cache_expiration);
+    if let Some(solve_results) = &state.solve_results {
+        solve_results.set_expiration(crate::solve_results_expiration(&args));
+    }
+    *state.settings.write().unwrap() = Arc::new(settings);
+    tracing::info!("reloaded config file {}", config.path().display());
+    Ok(())
+}
+
+/// Reloads the config file whenever the process receives SIGHUP. Returns once the state is dropped.
+#[cfg(unix)]
+pub async fn reload_on_hangup(state: Weak<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::warn!("cannot listen for SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        if let Some(config) = &state.config {
+            if let Err(err) = reload(&state, config) {
+                tracing::warn!(
+                    "cannot reload the config file, keeping the current settings: {err:#}"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_config_file_overrides_args() {
+        let config: ConfigFile = serde_yaml::from_str(
+            r#"
+            repodata_cache_expiration_seconds: 60
+            channel_alias: https://conda.example.com/
+            custom_channels:
+              internal: https://internal.example.com/
+            max_specs_per_request: 5
+            solver: libsolv_c
+            http_proxy: http://proxy.example.com:3128/
+            no_proxy: [localhost, internal.example.com]
+            profiles:
+              cuda12:
+                __cuda: "12.4"
+                __glibc: "2.31"
+            "#,
+        )
+        .unwrap();
+
+        let mut args = Args::parse_from(["rattler-server", "--max-channels-per-request", "3"]);
+        config.apply(&mut args);
+        assert_eq!(args.repodata_cache_expiration_seconds, 60);
+        assert_eq!(args.channel_alias.as_str(), "https://conda.example.com/");
+        assert_eq!(
+            args.custom_channels,
+            ["internal=https://internal.example.com/"]
+        );
+        assert_eq!(args.max_specs_per_request, 5);
+        assert_eq!(args.max_channels_per_request, 3);
+        assert_eq!(args.solver, Solver::Libsolvc);
+        assert_eq!(
+            args.http_proxy.as_ref().unwrap().as_str(),
+            "http://proxy.example.com:3128/"
+        );
+        assert_eq!(args.no_proxy, ["localhost", "internal.example.com"]);
+        assert_eq!(
+            args.virtual_package_profiles["cuda12"],
+            ["__cuda=12.4", "__glibc=2.31"]
+        );
+
+        assert!(serde_yaml::from_str::<ConfigFile>("max_spec_per_request: 5").is_err());
+    }
+}
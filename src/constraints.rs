@@ -0,0 +1,33 @@
+//! Reports the `constrains` of solved packages that affected the solution
+
+use crate::dto::AppliedConstraint;
+use rattler_conda_types::{MatchSpec, RepoDataRecord};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Returns the `constrains` entries of the `packages` that apply to another package in the
+/// solution.
+///
+/// The solver enforces every `constrains` entry, but the ones about packages that did not end up in
+/// the solution have no effect, so they are left out.
+pub fn applied_constraints(packages: &[RepoDataRecord]) -> Vec<AppliedConstraint> {
+    let solved_names: HashSet<_> = packages.iter().map(|p| &p.package_record.name).collect();
+
+    let mut applied = Vec::new();
+    for package in packages {
+        for constraint in &package.package_record.constrains {
+            let applies = MatchSpec::from_str(constraint)
+                .ok()
+                .and_then(|spec| spec.name)
+                .map_or(false, |name| solved_names.contains(&name));
+            if applies {
+                applied.push(AppliedConstraint {
+                    package: package.package_record.name.as_normalized().to_string(),
+                    constraint: constraint.clone(),
+                });
+            }
+        }
+    }
+
+    applied
+}
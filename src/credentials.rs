@@ -0,0 +1,416 @@
+//! Resolves the credentials used to download repodata on behalf of callers that aren't tied to a
+//! tenant, reading bearer tokens from `RATTLER_SERVER_TOKEN_<HOST>` environment variables and
+//! credentials from the configured credentials file
+//!
+//! Callers may also supply credentials of their own. Repodata downloaded with those is only shared
+//! with callers that supply the same credentials, since the server cannot tell whether anybody
+//! else is allowed to see it.
+
+use crate::error::{ApiError, ParseError, ValidationError};
+use crate::outbound;
+use anyhow::Context;
+use axum::http::HeaderMap;
+use rattler_digest::{compute_bytes_digest, Blake2b256};
+use rattler_networking::authentication_storage::backends::file::FileStorage;
+use rattler_networking::authentication_storage::backends::keyring::KeyringAuthenticationStorage;
+use rattler_networking::authentication_storage::backends::netrc::NetRcStorage;
+use rattler_networking::authentication_storage::StorageBackend;
+use rattler_networking::{AuthenticatedClient, Authentication, AuthenticationStorage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+const TOKEN_VAR_PREFIX: &str = "RATTLER_SERVER_TOKEN_";
+
+/// The header through which a proxy can forward the channel credentials of a caller, as a JSON
+/// object like the `channel_credentials` of a solve request
+pub const CHANNEL_CREDENTIALS_HEADER: &str = "x-channel-credentials";
+
+/// Returns the name of the environment variable holding the bearer token for `host`, which is
+/// the host in upper case with every character other than a letter or digit replaced by `_` (e.g.
+/// `RATTLER_SERVER_TOKEN_REPO_PREFIX_DEV` for `repo.prefix.dev`)
+pub fn token_var(host: &str) -> String {
+    let host: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{TOKEN_VAR_PREFIX}{host}")
+}
+
+/// Looks up credentials in the token environment variables first, and in the default credential
+/// stores (keyring, rattler's auth file and `.netrc`) otherwise
+pub struct EnvCredentials {
+    /// The tokens, keyed by the name of their environment variable
+    tokens: HashMap<String, String>,
+    fallback: Vec<Arc<dyn StorageBackend + Send + Sync>>,
+}
+
+impl EnvCredentials {
+    /// Reads the tokens from the environment of the process
+    pub fn from_env() -> EnvCredentials {
+        let netrc = NetRcStorage::from_env().unwrap_or_else(|(path, err)| {
+            tracing::warn!("error reading netrc file from {}: {}", path.display(), err);
+            NetRcStorage::default()
+        });
+        EnvCredentials::new(
+            std::env::vars(),
+            vec![
+                Arc::new(KeyringAuthenticationStorage::default()),
+                Arc::new(FileStorage::default()),
+                Arc::new(netrc),
+            ],
+        )
+    }
+
+    fn new(
+        vars: impl IntoIterator<Item = (String, String)>,
+        fallback: Vec<Arc<dyn StorageBackend + Send + Sync>>,
+    ) -> EnvCredentials {
+        let tokens = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(TOKEN_VAR_PREFIX))
+            .collect();
+        EnvCredentials { tokens, fallback }
+    }
+
+    /// Looks up credentials in the file (in the format of rattler's credentials file, mapping hosts
+    /// to credentials) after the token environment variables, and before the default credential
+    /// stores. The file is read once, so it may be on a read-only file system.
+    pub fn with_credentials_file(mut self, path: &Path) -> anyhow::Result<EnvCredentials> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading credentials file {}", path.display()))?;
+        let credentials = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing credentials file {}", path.display()))?;
+        self.fallback
+            .insert(0, Arc::new(MemoryStorage(credentials)));
+        Ok(self)
+    }
+
+    /// Creates a client that authenticates its requests with these credentials
+    pub fn into_client(self) -> AuthenticatedClient {
+        self.into_client_with(outbound::client())
+    }
+
+    /// Like [`EnvCredentials::into_client`], but sending the requests through the given client
+    fn into_client_with(self, client: reqwest::Client) -> AuthenticatedClient {
+        let mut storage = AuthenticationStorage::new();
+        storage.add_backend(Arc::new(self));
+        AuthenticatedClient::from_client(client, storage)
+    }
+}
+
+/// Only the hosts with a token are shown, so tokens never end up in the logs
+impl std::fmt::Debug for EnvCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnvCredentials")
+            .field("tokens", &self.tokens.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback)
+            .finish()
+    }
+}
+
+impl StorageBackend for EnvCredentials {
+    fn store(&self, _host: &str, _authentication: &Authentication) -> anyhow::Result<()> {
+        anyhow::bail!("server credentials are read-only")
+    }
+
+    fn get(&self, host: &str) -> anyhow::Result<Option<Authentication>> {
+        if let Some(token) = self.tokens.get(&token_var(host)) {
+            return Ok(Some(Authentication::BearerToken(token.clone())));
+        }
+
+        // Unlike `AuthenticationStorage`, keep looking when a store has no credentials for the host
+        for backend in &self.fallback {
+            match backend.get(host) {
+                Ok(Some(authentication)) => return Ok(Some(authentication)),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("error retrieving credentials for {host}: {e}"),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn delete(&self, _host: &str) -> anyhow::Result<()> {
+        anyhow::bail!("server credentials are read-only")
+    }
+}
+
+/// A client that downloads repodata on behalf of a caller
+#[derive(Clone)]
+pub struct DownloadClient {
+    client: AuthenticatedClient,
+    /// Identifies the credentials of the client, if they were supplied by the caller
+    cache_scope: Option<String>,
+}
+
+impl DownloadClient {
+    /// A client with credentials configured on the server, whose downloads are shared with every
+    /// caller that may use the channel
+    pub fn shared(client: AuthenticatedClient) -> DownloadClient {
+        DownloadClient {
+            client,
+            cache_scope: None,
+        }
+    }
+
+    pub fn client(&self) -> &AuthenticatedClient {
+        &self.client
+    }
+
+    /// Identifies the credentials supplied by the caller, if any. Repodata downloaded with them
+    /// must only be shared with callers that have the same scope.
+    pub fn cache_scope(&self) -> Option<&str> {
+        self.cache_scope.as_deref()
+    }
+}
+
+/// Credentials supplied by a caller for its own downloads, keyed by host
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ChannelCredentials(pub HashMap<String, Authentication>);
+
+/// Only the hosts are shown, so credentials never end up in the logs
+impl std::fmt::Debug for ChannelCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ChannelCredentials")
+            .field(&self.0.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl ChannelCredentials {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the credentials forwarded in the [`CHANNEL_CREDENTIALS_HEADER`], if any. The header
+    /// is not echoed in errors, since it holds credentials.
+    pub fn from_headers(headers: &HeaderMap) -> Result<ChannelCredentials, ApiError> {
+        let Some(value) = headers.get(CHANNEL_CREDENTIALS_HEADER) else {
+            return Ok(ChannelCredentials::default());
+        };
+        let invalid = |error: String| {
+            ApiError::Validation(ValidationError::ChannelCredentials(ParseError {
+                input: CHANNEL_CREDENTIALS_HEADER.to_string(),
+                error,
+            }))
+        };
+        let value = value.to_str().map_err(|e| invalid(e.to_string()))?;
+        serde_json::from_str(value).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Adds the credentials of `other`, which take precedence for the hosts in both
+    pub fn merge(&mut self, other: ChannelCredentials) {
+        self.0.extend(other.0);
+    }
+
+    /// Creates a client for each host, which only sends the credentials of its host
+    pub fn into_clients(self) -> HashMap<String, DownloadClient> {
+        self.0
+            .into_iter()
+            .map(|(host, authentication)| {
+                let cache_scope = cache_scope(&host, &authentication);
+                let mut storage = AuthenticationStorage::new();
+                storage.add_backend(Arc::new(MemoryStorage(HashMap::from([(
+                    host.clone(),
+                    authentication,
+                )]))));
+                let client = DownloadClient {
+                    client: AuthenticatedClient::from_client(outbound::client(), storage),
+                    cache_scope: Some(cache_scope),
+                };
+                (host, client)
+            })
+            .collect()
+    }
+}
+
+/// Derives a scope from the credentials, which cannot be traced back to them without the salt of
+/// the process
+fn cache_scope(host: &str, authentication: &Authentication) -> String {
+    static SALT: OnceLock<Uuid> = OnceLock::new();
+    let salt = SALT.get_or_init(Uuid::new_v4);
+    let input = serde_json::to_vec(&(salt.as_bytes(), host, authentication))
+        .expect("credentials serialization is infallible");
+    let digest = format!("{:x}", compute_bytes_digest::<Blake2b256>(input));
+    digest[..16].to_string()
+}
+
+/// A read-only credential store backed by a map from host to credentials
+pub struct MemoryStorage(pub HashMap<String, Authentication>);
+
+/// Only the hosts are shown, so credentials never end up in the logs
+impl std::fmt::Debug for MemoryStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MemoryStorage")
+            .field(&self.0.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn store(&self, _host: &str, _authentication: &Authentication) -> anyhow::Result<()> {
+        anyhow::bail!("server credentials are read-only")
+    }
+
+    fn get(&self, host: &str) -> anyhow::Result<Option<Authentication>> {
+        Ok(self.0.get(host).cloned())
+    }
+
+    fn delete(&self, _host: &str) -> anyhow::Result<()> {
+        anyhow::bail!("server credentials are read-only")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_var() {
+        assert_eq!(
+            token_var("repo.prefix.dev"),
+            "RATTLER_SERVER_TOKEN_REPO_PREFIX_DEV"
+        );
+        assert_eq!(token_var("127.0.0.1"), "RATTLER_SERVER_TOKEN_127_0_0_1");
+        assert_eq!(token_var("my-host"), "RATTLER_SERVER_TOKEN_MY_HOST");
+    }
+
+    #[tokio::test]
+    async fn test_token_is_only_applied_to_its_host() {
+        let mut server = mockito::Server::new_async().await;
+        let authenticated = server
+            .mock("GET", "/private/noarch/repodata.json")
+            .match_header("authorization", "Bearer secret")
+            .create_async()
+            .await;
+        let anonymous = server
+            .mock("GET", "/public/noarch/repodata.json")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .create_async()
+            .await;
+
+        // Both hosts connect to the mock server on 127.0.0.1, without relying on how `localhost`
+        // resolves (which may be ::1 first)
+        let addr: std::net::SocketAddr = server.host_with_port().parse().unwrap();
+        let port = addr.port();
+        let client =
+            EnvCredentials::new([(token_var("127.0.0.1"), "secret".to_string())], Vec::new())
+                .into_client_with(
+                    reqwest::Client::builder()
+                        .resolve("localhost", addr)
+                        .build()
+                        .unwrap(),
+                );
+
+        let response = client
+            .get(format!(
+                "http://127.0.0.1:{port}/private/noarch/repodata.json"
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let response = client
+            .get(format!(
+                "http://localhost:{port}/public/noarch/repodata.json"
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        authenticated.assert_async().await;
+        anonymous.assert_async().await;
+    }
+
+    #[test]
+    fn test_credentials_file_is_used_after_tokens() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.join("credentials.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "private.example.com": { "BasicHTTP": { "username": "user", "password": "pass" } },
+                "repo.prefix.dev": { "BearerToken": "from-file" }
+            }"#,
+        )
+        .unwrap();
+
+        let credentials = EnvCredentials::new(
+            [(token_var("repo.prefix.dev"), "from-env".to_string())],
+            Vec::new(),
+        )
+        .with_credentials_file(&path)
+        .unwrap();
+        assert_eq!(
+            credentials.get("private.example.com").unwrap(),
+            Some(Authentication::BasicHTTP {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+            })
+        );
+        assert_eq!(
+            credentials.get("repo.prefix.dev").unwrap(),
+            Some(Authentication::BearerToken("from-env".to_string()))
+        );
+        assert_eq!(credentials.get("other.example.com").unwrap(), None);
+
+        let missing = EnvCredentials::new([], Vec::new()).with_credentials_file(&dir.join("nope"));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_channel_credentials_scopes() {
+        let credentials = |token: &str| {
+            ChannelCredentials(HashMap::from([(
+                "repo.example.com".to_string(),
+                Authentication::BearerToken(token.to_string()),
+            )]))
+        };
+        let scope = |credentials: ChannelCredentials| {
+            credentials.into_clients()["repo.example.com"]
+                .cache_scope()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(scope(credentials("a")), scope(credentials("a")));
+        assert_ne!(scope(credentials("a")), scope(credentials("b")));
+        assert!(!format!("{:?}", credentials("secret")).contains("secret"));
+    }
+
+    #[test]
+    fn test_channel_credentials_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(ChannelCredentials::from_headers(&headers)
+            .unwrap()
+            .is_empty());
+
+        headers.insert(
+            CHANNEL_CREDENTIALS_HEADER,
+            r#"{"repo.example.com": {"BearerToken": "secret"}}"#
+                .parse()
+                .unwrap(),
+        );
+        let credentials = ChannelCredentials::from_headers(&headers).unwrap();
+        assert_eq!(
+            credentials.0["repo.example.com"],
+            Authentication::BearerToken("secret".to_string())
+        );
+
+        headers.insert(CHANNEL_CREDENTIALS_HEADER, "secret".parse().unwrap());
+        let Err(ApiError::Validation(error)) = ChannelCredentials::from_headers(&headers) else {
+            panic!("invalid credentials were accepted");
+        };
+        assert!(!serde_json::to_string(&error).unwrap().contains("secret"));
+    }
+}
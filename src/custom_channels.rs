@@ -0,0 +1,104 @@
+//! Resolves channels given by name against a channel alias of their own (e.g. an internal server),
+//! like conda's `custom_channels`. Other names are relative to the default channel alias.
+
+use anyhow::Context;
+use rattler_conda_types::{Channel, ChannelConfig, ParseChannelError};
+use reqwest::Url;
+
+/// The channel alias of each custom channel, by name (most specific names first)
+#[derive(Debug, Default, Clone)]
+pub struct CustomChannels {
+    by_name: Vec<(String, Url)>,
+}
+
+impl CustomChannels {
+    /// Parses custom channels given as `<name>=<url>` (e.g. `internal=https://conda.example.com/`)
+    pub fn new(custom_channels: &[String]) -> anyhow::Result<CustomChannels> {
+        let mut by_name = custom_channels
+            .iter()
+            .map(|custom_channel| {
+                let (name, url) = custom_channel.split_once('=').with_context(|| {
+                    format!("'{custom_channel}' is not of the form <name>=<url>")
+                })?;
+                let mut url = Url::parse(url)
+                    .with_context(|| format!("invalid URL of custom channel `{name}`"))?;
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Ok((name.trim_matches('/').to_string(), url))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        by_name.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        Ok(CustomChannels { by_name })
+    }
+
+    /// Parses a channel, given by name (optionally with a label, e.g.
+    /// `conda-forge/label/rust_dev`), URL or path. Names within a custom channel are relative to
+    /// its alias, and other names to the alias of the channel config.
+    pub fn parse_channel(
+        &self,
+        channel: &str,
+        channel_config: &ChannelConfig,
+    ) -> Result<Channel, ParseChannelError> {
+        let custom_alias = self.by_name.iter().find_map(|(name, url)| {
+            let rest = channel.strip_prefix(name.as_str())?;
+            (rest.is_empty() || rest.starts_with(['/', '['])).then_some(url)
+        });
+        match custom_alias {
+            Some(channel_alias) => Channel::from_str(
+                channel,
+                &ChannelConfig {
+                    channel_alias: channel_alias.clone(),
+                },
+            ),
+            None => Channel::from_str(channel, channel_config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_channel() {
+        let custom_channels = CustomChannels::new(&[
+            "internal=https://conda.example.com".to_string(),
+            "internal/nightly=https://nightly.example.com/conda/".to_string(),
+        ])
+        .unwrap();
+        let config = ChannelConfig::default();
+        let base_url = |channel: &str| {
+            custom_channels
+                .parse_channel(channel, &config)
+                .unwrap()
+                .base_url
+                .to_string()
+        };
+
+        assert_eq!(
+            base_url("conda-forge/label/rust_dev"),
+            "https://conda.anaconda.org/conda-forge/label/rust_dev/"
+        );
+        assert_eq!(base_url("internal"), "https://conda.example.com/internal/");
+        assert_eq!(
+            base_url("internal/label/dev"),
+            "https://conda.example.com/internal/label/dev/"
+        );
+        assert_eq!(
+            base_url("internal/nightly"),
+            "https://nightly.example.com/conda/internal/nightly/"
+        );
+        assert_eq!(
+            base_url("internal-extra"),
+            "https://conda.anaconda.org/internal-extra/"
+        );
+        assert_eq!(
+            base_url("https://conda.example.com/other"),
+            "https://conda.example.com/other/"
+        );
+
+        assert!(CustomChannels::new(&["internal".to_string()]).is_err());
+        assert!(CustomChannels::new(&["internal=not a url".to_string()]).is_err());
+    }
+}
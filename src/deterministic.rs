@@ -0,0 +1,143 @@
+//! Supports `deterministic` solves, which hand the candidates to the solver in a canonical order so
+//! that the solution only depends on the request and the contents of the repodata (not on the order
+//! in which a channel lists its packages)
+//!
+//! The solvers rank the candidates of a package by version and build number, and fall back to the
+//! order of the candidates between those that are equal in both. Those ties are reported, so audits
+//! can tell which packages the canonical order decided.
+
+use crate::dto::TieBreak;
+use rattler_conda_types::RepoDataRecord;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Sorts the records of each channel canonically, keeping the channels in their order of priority
+pub fn sort_candidates(available_packages: &mut [Vec<RepoDataRecord>]) {
+    for records in available_packages {
+        records.sort_by(canonical_order);
+    }
+}
+
+fn canonical_order(a: &RepoDataRecord, b: &RepoDataRecord) -> Ordering {
+    let (ap, bp) = (&a.package_record, &b.package_record);
+    (ap.name.as_normalized().cmp(bp.name.as_normalized()))
+        .then_with(|| ap.version.cmp(&bp.version))
+        .then(ap.build_number.cmp(&bp.build_number))
+        .then_with(|| ap.build.cmp(&bp.build))
+        .then(ap.timestamp.cmp(&bp.timestamp))
+        .then_with(|| a.url.as_str().cmp(b.url.as_str()))
+}
+
+/// The candidates that the solvers cannot rank, as the URLs of the candidates of each name,
+/// version and build number that has several
+pub struct Ties(HashMap<(String, String, u64), Vec<String>>);
+
+impl Ties {
+    pub fn find(available_packages: &[Vec<RepoDataRecord>]) -> Ties {
+        let mut candidates: HashMap<_, Vec<String>> = HashMap::new();
+        for record in available_packages.iter().flatten() {
+            let package = &record.package_record;
+            candidates
+                .entry((
+                    package.name.as_normalized().to_string(),
+                    package.version.to_string(),
+                    package.build_number,
+                ))
+                .or_default()
+                .push(record.url.to_string());
+        }
+        candidates.retain(|_, urls| urls.len() > 1);
+        Ties(candidates)
+    }
+
+    /// The ties of the solved packages, in the order of the solution
+    pub fn tie_breaks(&self, packages: &[RepoDataRecord]) -> Vec<TieBreak> {
+        packages
+            .iter()
+            .filter_map(|record| {
+                let package = &record.package_record;
+                let key = (
+                    package.name.as_normalized().to_string(),
+                    package.version.to_string(),
+                    package.build_number,
+                );
+                let urls = self.0.get(&key)?;
+                let chosen = record.url.to_string();
+                let (name, version, build_number) = key;
+                Some(TieBreak {
+                    name,
+                    version,
+                    build_number,
+                    candidates: urls.iter().filter(|url| **url != chosen).cloned().collect(),
+                    chosen,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::{PackageName, PackageRecord, Version};
+    use reqwest::Url;
+    use std::str::FromStr;
+
+    fn record(name: &str, version: &str, build: &str) -> RepoDataRecord {
+        let file_name = format!("{name}-{version}-{build}.tar.bz2");
+        RepoDataRecord {
+            package_record: PackageRecord::new(
+                PackageName::new_unchecked(name),
+                Version::from_str(version).unwrap(),
+                build.to_string(),
+            ),
+            url: Url::parse(&format!("https://conda.example.com/linux-64/{file_name}")).unwrap(),
+            file_name,
+            channel: "https://conda.example.com/".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sort_candidates() {
+        let records = vec![
+            record("foo", "1.0", "b_0"),
+            record("bar", "2.0", "0"),
+            record("foo", "1.0", "a_0"),
+            record("foo", "0.9", "0"),
+        ];
+        let mut reversed = vec![records.iter().rev().cloned().collect::<Vec<_>>()];
+        let mut available_packages = vec![records];
+        sort_candidates(&mut available_packages);
+        sort_candidates(&mut reversed);
+
+        let file_names = |packages: &[Vec<RepoDataRecord>]| -> Vec<String> {
+            packages[0].iter().map(|r| r.file_name.clone()).collect()
+        };
+        assert_eq!(
+            file_names(&available_packages),
+            [
+                "bar-2.0-0.tar.bz2",
+                "foo-0.9-0.tar.bz2",
+                "foo-1.0-a_0.tar.bz2",
+                "foo-1.0-b_0.tar.bz2"
+            ]
+        );
+        assert_eq!(file_names(&reversed), file_names(&available_packages));
+
+        let ties = Ties::find(&available_packages);
+        let solution = [record("bar", "2.0", "0"), record("foo", "1.0", "b_0")];
+        let tie_breaks = ties.tie_breaks(&solution);
+        assert_eq!(
+            tie_breaks,
+            [TieBreak {
+                name: "foo".to_string(),
+                version: "1.0".to_string(),
+                build_number: 0,
+                chosen: "https://conda.example.com/linux-64/foo-1.0-b_0.tar.bz2".to_string(),
+                candidates: vec![
+                    "https://conda.example.com/linux-64/foo-1.0-a_0.tar.bz2".to_string()
+                ],
+            }]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Contains the `/diff` endpoint, which compares two environments (e.g. to preview the effect of
+//! bumping a spec)
+
+use crate::dto::SolveEnvironment;
+use crate::error::{response_from_error, ApiError};
+use crate::output::OutputParams;
+use crate::{solve_unconditionally, AppState};
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rattler_conda_types::RepoDataRecord;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Two environments to compare, which have the other fields of a `/solve` request in common
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EnvironmentDiffRequest {
+    pub before: DiffSide,
+    pub after: DiffSide,
+    /// The other fields of a `/solve` request (e.g. `platform` and `channels`), which are used to
+    /// solve the sides given as specs
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub environment: serde_json::Map<String, serde_json::Value>,
+}
+
+/// One of the environments to compare
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum DiffSide {
+    /// Specs, which are solved
+    Specs { specs: Vec<String> },
+    /// The packages of a previous solve, as returned in its `packages`
+    Packages {
+        #[serde(with = "crate::v1::records")]
+        #[schema(value_type = Vec<PackageRecord>)]
+        packages: Vec<RepoDataRecord>,
+    },
+}
+
+/// The packages that differ between the environments, each sorted by name
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct EnvironmentDiff {
+    pub added: Vec<PackageDiff>,
+    pub removed: Vec<PackageDiff>,
+    pub upgraded: Vec<PackageDiff>,
+    pub downgraded: Vec<PackageDiff>,
+    /// The packages whose version stayed the same, but whose build changed
+    pub rebuilt: Vec<PackageDiff>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct PackageDiff {
+    pub name: String,
+    /// Absent for added packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<DiffPackage>,
+    /// Absent for removed packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<DiffPackage>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct DiffPackage {
+    pub version: String,
+    pub build: String,
+    pub channel: String,
+}
+
+/// A side of the diff, once its environment is known
+enum Side {
+    Solve(Box<SolveEnvironment>),
+    Packages(Vec<RepoDataRecord>),
+}
+
+/// Compares two environments, given as specs to solve or as the packages of previous solves
+#[utoipa::path(
+    post,
+    path = "/v1/diff",
+    request_body = EnvironmentDiffRequest,
+    responses(
+        (status = 200, description = "The packages that differ between the environments", body = EnvironmentDiff),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 401, description = "The API key or tenant token is missing or invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 409, description = "An environment cannot be solved", body = SolveEnvironmentUnsolvable),
+        (status = 429, description = "The server is too busy, or the rate limit was exceeded", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+        (status = 504, description = "The request or solve timed out", body = ErrorResponse),
+    )
+)]
+pub async fn environment_diff(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<EnvironmentDiffRequest>,
+) -> Response {
+    state.metrics.record_solve_request();
+
+    // The sides given as specs must be valid solve requests once their specs are filled in
+    let mut sides = Vec::with_capacity(2);
+    for side in [payload.before, payload.after] {
+        let side = match side {
+            DiffSide::Packages { packages } => Side::Packages(packages),
+            DiffSide::Specs { specs } => {
+                let mut environment = payload.environment.clone();
+                environment.insert("specs".to_string(), specs.into());
+                match serde_json::from_value(environment.into()) {
+                    Ok(environment) => Side::Solve(Box::new(environment)),
+                    Err(e) => {
+                        return (
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            format!(
+                                "Failed to deserialize the JSON body into the target type: {e}"
+                            ),
+                        )
+                            .into_response()
+                    }
+                }
+            }
+        };
+        sides.push(side);
+    }
+    let [before, after]: [Side; 2] = sides.try_into().ok().expect("there are two sides");
+
+    let packages = futures::future::try_join(
+        side_packages(state.clone(), &headers, before),
+        side_packages(state, &headers, after),
+    )
+    .await;
+    match packages {
+        Ok((before, after)) => Json(diff(&before, &after)).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+/// Returns the packages of a side, solving it if needed
+async fn side_packages(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    side: Side,
+) -> Result<Vec<RepoDataRecord>, ApiError> {
+    let environment = match side {
+        Side::Packages(packages) => return Ok(packages),
+        Side::Solve(environment) => environment,
+    };
+    // Solve responses are never returned, so they are not cached by ETag
+    let solution =
+        solve_unconditionally(state, headers, &environment, &OutputParams::default()).await?;
+    Ok(solution.packages)
+}
+
+/// Compares the packages of two environments by name
+pub fn diff(before: &[RepoDataRecord], after: &[RepoDataRecord]) -> EnvironmentDiff {
+    let by_name = |records: &[RepoDataRecord]| -> BTreeMap<String, RepoDataRecord> {
+        records
+            .iter()
+            .map(|r| (r.package_record.name.as_normalized().to_string(), r.clone()))
+            .collect()
+    };
+    let mut before = by_name(before);
+    let after = by_name(after);
+
+    let mut diff = EnvironmentDiff::default();
+    for (name, new) in after {
+        let Some(old) = before.remove(&name) else {
+            diff.added.push(PackageDiff {
+                name,
+                before: None,
+                after: Some(diff_package(&new)),
+            });
+            continue;
+        };
+
+        let (old_record, new_record) = (&old.package_record, &new.package_record);
+        let changes = match old_record.version.cmp(&new_record.version) {
+            Ordering::Less => &mut diff.upgraded,
+            Ordering::Greater => &mut diff.downgraded,
+            Ordering::Equal if old_record.build != new_record.build => &mut diff.rebuilt,
+            Ordering::Equal => continue,
+        };
+        changes.push(PackageDiff {
+            name,
+            before: Some(diff_package(&old)),
+            after: Some(diff_package(&new)),
+        });
+    }
+    diff.removed = before
+        .into_iter()
+        .map(|(name, old)| PackageDiff {
+            name,
+            before: Some(diff_package(&old)),
+            after: None,
+        })
+        .collect();
+    diff
+}
+
+fn diff_package(record: &RepoDataRecord) -> DiffPackage {
+    DiffPackage {
+        version: record.package_record.version.to_string(),
+        build: record.package_record.build.clone(),
+        channel: record.channel.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::{PackageName, PackageRecord, Version};
+    use std::str::FromStr;
+
+    fn record(name: &str, version: &str, build: &str) -> RepoDataRecord {
+        RepoDataRecord {
+            package_record: PackageRecord::new(
+                PackageName::new_unchecked(name),
+                Version::from_str(version).unwrap(),
+                build.to_string(),
+            ),
+            file_name: format!("{name}-{version}-{build}.tar.bz2"),
+            url: format!("https://example.com/{name}-{version}-{build}.tar.bz2")
+                .parse()
+                .unwrap(),
+            channel: "https://example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_classifies_changes() {
+        let before = [
+            record("python", "3.11.7", "0"),
+            record("openssl", "3.2.0", "1"),
+            record("numpy", "1.26.4", "0"),
+            record("tk", "8.6.13", "0"),
+            record("six", "1.16.0", "0"),
+        ];
+        let after = [
+            record("python", "3.12.1", "0"),
+            record("openssl", "3.1.4", "0"),
+            record("numpy", "1.26.4", "1"),
+            record("six", "1.16.0", "0"),
+            record("libexpat", "2.5.0", "0"),
+        ];
+        let diff = diff(&before, &after);
+
+        let names = |changes: &[PackageDiff]| -> Vec<String> {
+            changes.iter().map(|c| c.name.clone()).collect()
+        };
+        assert_eq!(names(&diff.added), ["libexpat"]);
+        assert_eq!(names(&diff.removed), ["tk"]);
+        assert_eq!(names(&diff.upgraded), ["python"]);
+        assert_eq!(names(&diff.downgraded), ["openssl"]);
+        assert_eq!(names(&diff.rebuilt), ["numpy"]);
+        assert_eq!(diff.upgraded[0].before.as_ref().unwrap().version, "3.11.7");
+        assert_eq!(diff.upgraded[0].after.as_ref().unwrap().version, "3.12.1");
+        assert!(diff.added[0].before.is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Keeps the cache directory, where rattler (and the S3 and OCI transports) keep downloaded
+//! repodata, within a size budget
+//!
+//! Every download consists of a few files that share the same name up to the first dot (e.g. the
+//! `<key>.json` of rattler and its `<key>.info.json`), which are removed together. Lock files and
+//! downloads in progress are left alone.
+
+use crate::AppState;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+use std::time::{Duration, SystemTime};
+
+/// The files of a single download
+#[derive(Default)]
+struct CachedDownload {
+    files: Vec<PathBuf>,
+    bytes: u64,
+    /// When any of the files was last read or written
+    last_used: Option<SystemTime>,
+}
+
+/// Evicts the least recently used downloads from the cache directory once it exceeds the budget
+/// (if any), checking on the given interval, and records the size of the directory in the
+/// metrics. Returns once the state is dropped.
+pub async fn collect_garbage(
+    state: Weak<AppState>,
+    cache_dir: PathBuf,
+    budget: Option<u64>,
+    interval: Duration,
+) {
+    let mut interval_timer = tokio::time::interval(interval);
+    loop {
+        interval_timer.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+
+        let dir = cache_dir.clone();
+        match tokio::task::spawn_blocking(move || enforce_budget(&dir, budget)).await {
+            Ok(Ok(bytes)) => state.metrics.record_disk_cache_bytes(bytes),
+            Ok(Err(err)) => tracing::warn!("cannot clean up {}: {err}", cache_dir.display()),
+            Err(err) => tracing::warn!("cannot clean up {}: {err}", cache_dir.display()),
+        }
+    }
+}
+
+/// Removes the least recently used downloads until the directory fits in the budget (if any),
+/// returning the size of the remaining ones
+pub fn enforce_budget(dir: &Path, budget: Option<u64>) -> std::io::Result<u64> {
+    let mut downloads: Vec<_> = scan(dir)?.into_values().collect();
+    let mut total: u64 = downloads.iter().map(|d| d.bytes).sum();
+    let Some(budget) = budget else {
+        return Ok(total);
+    };
+
+    downloads.sort_by_key(|d| d.last_used);
+    for download in downloads {
+        if total <= budget {
+            break;
+        }
+        for file in &download.files {
+            match std::fs::remove_file(file) {
+                Ok(()) => tracing::debug!("evicted {}", file.display()),
+                // Files may disappear while they are being evicted, e.g. when they are replaced
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        total -= download.bytes;
+    }
+    Ok(total)
+}
+
+/// Groups the files below `dir` by download, keyed by their path up to the first dot of their name
+fn scan(dir: &Path) -> std::io::Result<HashMap<PathBuf, CachedDownload>> {
+    let mut downloads: HashMap<PathBuf, CachedDownload> = HashMap::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // Nothing was downloaded yet
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+
+            // Temporary files (whose names start with a dot or end in `.part`) belong to downloads in
+            // progress, and lock files to the downloads that are being read
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || name.ends_with(".part") || name.ends_with(".lock") {
+                continue;
+            }
+            let key = name.split('.').next().unwrap_or_default();
+            let download = downloads.entry(dir.join(key)).or_default();
+            download.files.push(entry.path());
+            download.bytes += metadata.len();
+            let used = [metadata.accessed().ok(), metadata.modified().ok()]
+                .into_iter()
+                .flatten()
+                .max();
+            download.last_used = download.last_used.max(used);
+        }
+    }
+    Ok(downloads)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_enforce_budget_evicts_least_recently_used() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let write = |path: &str, bytes: usize| {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![b'x'; bytes]).unwrap();
+            // Makes sure that the files are used at different times
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        write("old.json", 100);
+        write("old.info.json", 10);
+        write("old.lock", 0);
+        write("new.json", 100);
+        write("new.info.json", 10);
+        write("s3/object.json", 100);
+        write("s3/object.0123.part", 1000);
+
+        assert_eq!(enforce_budget(&dir, None).unwrap(), 320);
+        assert_eq!(enforce_budget(&dir, Some(250)).unwrap(), 210);
+        assert!(!dir.join("old.json").exists());
+        assert!(!dir.join("old.info.json").exists());
+        assert!(dir.join("old.lock").exists());
+        assert!(dir.join("new.json").exists());
+        assert!(dir.join("s3/object.json").exists());
+        assert!(dir.join("s3/object.0123.part").exists());
+    }
+}
@@ -0,0 +1,348 @@
+//! Writes the repodata fetched by the transports that rattler doesn't provide ([`crate::s3`] and
+//! [`crate::oci`]) to the cache directory
+
+use crate::retry::RetryPolicy;
+use rattler_digest::digest::Digest;
+use rattler_digest::Sha256;
+use rattler_repodata_gateway::fetch::{
+    DownloadProgress, FetchRepoDataError, RepoDataNotFoundError,
+};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_NONE_MATCH, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::{Response, StatusCode, Url};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Repodata that was downloaded to a file
+pub struct DownloadedObject {
+    pub path: PathBuf,
+    /// The URL that was requested, in the scheme of the transport (e.g. `s3://`)
+    pub url: Url,
+    /// Identify the version of the repodata, to detect when it changes
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub bytes: u64,
+}
+
+/// An object that was fetched into memory by [`fetch`]
+pub enum Fetched {
+    Modified {
+        bytes: Vec<u8>,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+    /// The object still has the `ETag` that the request was conditional on
+    NotModified,
+}
+
+/// Fetches a (small) object into memory with the requests built by `request`, conditional on the
+/// `ETag` it is known to have, if any. Requests that fail for transient reasons are attempted
+/// again, as far as the retry policy allows. A missing object fails with
+/// [`FetchRepoDataError::NotFound`].
+pub async fn fetch(
+    request: impl Fn() -> reqwest::RequestBuilder,
+    etag: Option<&str>,
+    retry: &RetryPolicy,
+) -> Result<Fetched, FetchRepoDataError> {
+    let mut attempt = 1;
+    loop {
+        let mut builder = request();
+        if let Some(etag) = etag {
+            builder = builder.header(IF_NONE_MATCH, etag);
+        }
+        let result = async {
+            let response = builder.send().await?;
+            if response.status() == StatusCode::NOT_MODIFIED && etag.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            let response = check_status(response)?;
+            let (etag, last_modified) = (header(&response, ETAG), header(&response, LAST_MODIFIED));
+            let bytes = response.bytes().await?.to_vec();
+            Ok(Fetched::Modified {
+                bytes,
+                etag,
+                last_modified,
+            })
+        };
+        match result.await {
+            Err(err) if retry.allows_retry(attempt) && retry.is_transient(&err) => {
+                tokio::time::sleep(retry.delay(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Fails if the response is not successful, telling missing repodata apart from other errors
+pub fn check_status(response: Response) -> Result<Response, FetchRepoDataError> {
+    match response.error_for_status() {
+        Ok(response) => Ok(response),
+        Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+            Err(RepoDataNotFoundError::HttpError(e).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the value of a header of the response, if it is valid UTF-8
+pub fn header(response: &Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The headers that request the rest of an object from `start` on, as long as its `ETag` (if known)
+/// still matches
+pub fn range_headers(start: u64, etag: Option<&str>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        RANGE,
+        HeaderValue::from_str(&format!("bytes={start}-")).expect("valid header value"),
+    );
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+        headers.insert(IF_RANGE, etag);
+    }
+    headers
+}
+
+/// Writes the body of the response to a file in `dir` named after `url`, reporting the progress
+/// as it goes, and returns its path and size. If a SHA-256 digest is given (hex-encoded), the body
+/// must match it.
+///
+/// If the body is interrupted by a transient error and the server accepts range requests, the rest
+/// of the body is requested through `resume` (given the amount of bytes received so far), as often
+/// as the retry policy allows.
+pub async fn save_response<Fut>(
+    url: &Url,
+    mut response: Response,
+    dir: &Path,
+    mut progress: impl FnMut(DownloadProgress),
+    sha256: Option<&str>,
+    retry: &RetryPolicy,
+    resume: impl Fn(u64) -> Fut,
+) -> Result<(PathBuf, u64), FetchRepoDataError>
+where
+    Fut: Future<Output = Result<Response, FetchRepoDataError>>,
+{
+    let total = response.content_length();
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(FetchRepoDataError::IoError)?;
+    let digest = rattler_digest::compute_bytes_digest::<Sha256>(url.as_str());
+    let path = dir.join(format!("{}.json", &format!("{digest:x}")[..16]));
+    // Written next to its destination first, so readers never see a partial file
+    let partial_path = path.with_extension(format!("{}.part", uuid::Uuid::new_v4()));
+
+    let mut file = tokio::fs::File::create(&partial_path)
+        .await
+        .map_err(FetchRepoDataError::FailedToCreateTemporaryFile)?;
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    let resumable = response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .is_some_and(|value| value == "bytes");
+    let written = async {
+        let mut attempt = 1;
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    if resumable && retry.allows_retry(attempt) && retry.is_transient_http(&e) {
+                        tokio::time::sleep(retry.delay(attempt)).await;
+                        attempt += 1;
+                        tracing::debug!("resuming the download of {url} after {bytes} bytes");
+                        if let Ok(resumed) = resume(bytes).await {
+                            if resumes_at(&resumed, bytes) {
+                                response = resumed;
+                                continue;
+                            }
+                        }
+                    }
+                    return Err(std::io::Error::new(ErrorKind::Other, e));
+                }
+            };
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            bytes += chunk.len() as u64;
+            progress(DownloadProgress { bytes, total });
+        }
+        file.flush().await?;
+
+        if total.is_some_and(|total| bytes < total) {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        match sha256 {
+            Some(expected) if format!("{:x}", hasher.finalize()) != expected => Err(
+                std::io::Error::new(ErrorKind::InvalidData, "the digest does not match"),
+            ),
+            _ => Ok(()),
+        }
+    }
+    .await;
+    drop(file);
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&partial_path).await;
+        return Err(FetchRepoDataError::FailedToDownload(url.clone(), e));
+    }
+    tokio::fs::rename(&partial_path, &path)
+        .await
+        .map_err(FetchRepoDataError::IoError)?;
+
+    Ok((path, bytes))
+}
+
+/// Whether the response holds the rest of the object from `start` on
+fn resumes_at(response: &Response, start: u64) -> bool {
+    response.status() == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(&format!("bytes {start}-")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_retries_and_revalidates() {
+        let mut server = mockito::Server::new_async().await;
+        let unavailable = server
+            .mock("GET", "/object")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let url = Url::parse(&format!("{}/object", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let request = || client.get(url.clone());
+        let retry = RetryPolicy {
+            max_attempts: 2,
+            statuses: vec![StatusCode::SERVICE_UNAVAILABLE],
+            ..RetryPolicy::default()
+        };
+
+        let failed = fetch(request, None, &RetryPolicy::default()).await;
+        assert!(matches!(failed, Err(FetchRepoDataError::HttpError(_))));
+        unavailable.assert_async().await;
+
+        // Mocks that still expect hits are matched first, so the first attempt is unavailable
+        let _unavailable = server
+            .mock("GET", "/object")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let _available = server
+            .mock("GET", "/object")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("etag", "\"v1\"")
+            .with_body("0123456789")
+            .create_async()
+            .await;
+        let Ok(Fetched::Modified { bytes, etag, .. }) = fetch(request, None, &retry).await else {
+            panic!("the object should be fetched on the second attempt");
+        };
+        assert_eq!(bytes, b"0123456789");
+        assert_eq!(etag.as_deref(), Some("\"v1\""));
+
+        let _not_modified = server
+            .mock("GET", "/object")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .create_async()
+            .await;
+        let revalidated = fetch(request, Some("\"v1\""), &retry).await;
+        assert!(matches!(revalidated, Ok(Fetched::NotModified)));
+
+        let _missing = server
+            .mock("GET", "/object")
+            .match_header("if-none-match", "\"v2\"")
+            .with_status(404)
+            .create_async()
+            .await;
+        let missing = fetch(request, Some("\"v2\""), &retry).await;
+        assert!(matches!(missing, Err(FetchRepoDataError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_download_is_resumed() {
+        let mut server = mockito::Server::new_async().await;
+        let interrupted = server
+            .mock("GET", "/object")
+            .match_header("range", mockito::Matcher::Missing)
+            .with_header("accept-ranges", "bytes")
+            .with_chunked_body(|w| {
+                w.write_all(b"01234")?;
+                // The connection is only dropped once the first part has arrived
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                Err(ErrorKind::ConnectionReset.into())
+            })
+            .create_async()
+            .await;
+        let rest = server
+            .mock("GET", "/object")
+            .match_header("range", "bytes=5-")
+            .match_header("if-range", "\"v1\"")
+            .with_status(206)
+            .with_header("content-range", "bytes 5-9/10")
+            .with_body("56789")
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/object", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let download = |retry: RetryPolicy| {
+            let (client, url, dir) = (&client, &url, &dir);
+            async move {
+                let response = client.get(url.clone()).send().await.unwrap();
+                let digest = rattler_digest::compute_bytes_digest::<Sha256>(b"0123456789");
+                save_response(
+                    url,
+                    response,
+                    dir,
+                    |_| {},
+                    Some(&format!("{digest:x}")),
+                    &retry,
+                    |start| {
+                        let request = client
+                            .get(url.clone())
+                            .headers(range_headers(start, Some("\"v1\"")));
+                        async move { Ok(request.send().await?) }
+                    },
+                )
+                .await
+            }
+        };
+
+        let retry = RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::default()
+        };
+        let (path, bytes) = download(retry).await.unwrap();
+        assert_eq!(bytes, 10);
+        assert_eq!(std::fs::read(path).unwrap(), b"0123456789");
+        interrupted.assert_async().await;
+        rest.assert_async().await;
+
+        // Without retries, the interruption fails the download
+        let failed = download(RetryPolicy::default()).await;
+        assert!(matches!(
+            failed,
+            Err(FetchRepoDataError::FailedToDownload(..))
+        ));
+    }
+}
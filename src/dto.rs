@@ -1,27 +1,322 @@
 //! Contains data transfer objects (DTOs) used as input and output of HTTP requests
 
+use crate::cli::Solver;
+use crate::credentials::ChannelCredentials;
+use crate::problem_report::ProblemReport;
+use chrono::{DateTime, Utc};
 use rattler_conda_types::RepoDataRecord;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
 
-#[cfg_attr(test, derive(Serialize))]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct SolveEnvironment {
     pub name: Option<String>,
     pub platform: String,
     pub specs: Vec<String>,
-    pub virtual_packages: Vec<String>,
+    /// The virtual packages of the target machine (e.g. `__glibc=2.28`), or the server's defaults
+    /// for the platform if absent
+    #[serde(default)]
+    pub virtual_packages: Option<Vec<String>>,
+    /// A profile of virtual packages configured on the server (e.g. `cuda12`), which take the
+    /// place of the server's defaults and are overridden by the `virtual_packages` of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
     pub channels: Vec<String>,
+    /// How specs that pin an unavailable version are handled, or left to the solver if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_mode: Option<MatchMode>,
+    #[serde(default)]
+    pub channel_priority: ChannelPriority,
+    #[serde(default)]
+    pub package_format: PackageFormat,
+    /// License patterns (e.g. `GPL*`) of the packages that may not be part of the solution
+    #[serde(default)]
+    pub license_deny: Vec<String>,
+    /// How builds carrying each tracked feature (e.g. `nomkl`) should be treated
+    #[serde(default)]
+    pub track_features_preferences: BTreeMap<String, FeaturePreference>,
+    /// Specs of the packages that may not be part of the solution, whose names may be patterns
+    /// (e.g. `*mkl*`)
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The hashes of the repodata to solve against, keyed by platform URL (e.g.
+    /// `https://conda.anaconda.org/conda-forge/linux-64/`), as reported by previous solves
+    #[serde(default)]
+    pub repodata_hashes: BTreeMap<String, String>,
+    #[serde(default)]
+    pub depth: Depth,
+    /// Non-standard subdirs (e.g. `linux-64-cuda`) to fetch from every channel, in addition to the
+    /// platform ones
+    #[serde(default)]
+    pub extra_subdirs: Vec<String>,
+    /// Solve against the packages that were published by this time (e.g.
+    /// `2023-06-01T00:00:00Z`), ignoring newer ones
+    #[serde(default)]
+    pub snapshot: Option<DateTime<Utc>>,
+    /// The packages that are currently installed (e.g. from a lockfile), which the solver keeps
+    /// unless the specs require otherwise
+    #[serde(default)]
+    pub locked_packages: Vec<PackageReference>,
+    /// The packages that may not be changed by the solve
+    #[serde(default)]
+    pub pinned_packages: Vec<PackageReference>,
+    /// The amount of milliseconds that fetching the repodata and solving may take, instead of the
+    /// server's default
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Credentials to download the repodata of the channels with, keyed by host, in addition to
+    /// the ones configured on the server
+    #[serde(default, skip_serializing_if = "ChannelCredentials::is_empty")]
+    #[schema(value_type = Object)]
+    pub channel_credentials: ChannelCredentials,
+    /// Only solve against cached repodata, without accessing the network (which servers started
+    /// with `--offline` never do anyway)
+    #[serde(default)]
+    pub offline: bool,
+    /// The solver backend to use, instead of the server's default
+    #[serde(default)]
+    pub solver: Option<Solver>,
+    /// Hand the candidates to the solver in a canonical order, independent of the order of the
+    /// repodata, and report the `ETag` of the repodata and the ties between candidates in the
+    /// response
+    #[serde(default)]
+    pub deterministic: bool,
+}
+
+/// A package that is part of an existing environment
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum PackageReference {
+    /// The full record of the package, as returned by a previous solve
+    #[serde(with = "crate::v1::record")]
+    #[schema(value_type = PackageRecord)]
+    Record(Box<RepoDataRecord>),
+    /// A `name=version=build` string, which is looked up in the request's channels
+    Exact(String),
+}
+
+/// Determines what happens when a spec pins a version that is not available in the channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Specs are used as given, and the solve fails if a pinned version is not available
+    Strict,
+    /// Specs whose pinned version is not available are loosened just enough to match an available
+    /// package
+    Flexible,
+}
+
+/// Determines whether packages may be taken from any of the request's channels
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelPriority {
+    /// A package is only taken from the first channel that provides it, like conda's and mamba's
+    /// `strict` channel priority
+    #[default]
+    Strict,
+    /// A package's builds are compared across all channels, regardless of their order
+    Disabled,
+}
+
+/// The package formats that the client is able to install
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageFormat {
+    /// Only `.conda` packages
+    Conda,
+    /// Only `.tar.bz2` packages
+    Tarbz2,
+    /// Any format, preferring `.conda` when a build is available in both
+    #[default]
+    Any,
+}
+
+/// Which of the solved packages are returned
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Depth {
+    /// The full environment, including transitive dependencies
+    #[default]
+    Full,
+    /// Only the packages that satisfy the requested specs
+    Direct,
+}
+
+/// How builds carrying a tracked feature should be treated. By default, solvers deprioritize any
+/// build with tracked features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FeaturePreference {
+    /// Prefer the builds carrying the feature over the ones lacking it
+    Prefer,
+    /// Only use the builds carrying the feature, for packages that have any
+    Require,
+    /// Never use the builds carrying the feature
+    Forbid,
 }
 
 #[cfg_attr(test, derive(Deserialize))]
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct SolveEnvironmentOk {
+    #[serde(with = "crate::v1::records")]
+    #[schema(value_type = Vec<PackageRecord>)]
     pub packages: Vec<RepoDataRecord>,
+    /// The specs that were loosened when solving in [`MatchMode::Flexible`]
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub loosened_specs: Vec<String>,
+    pub summary: SolveSummary,
+    /// The `constrains` of solved packages that apply to other solved packages
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub applied_constraints: Vec<AppliedConstraint>,
+    /// The hashes of the repodata used for the solve, keyed by platform URL
+    pub repodata_hashes: BTreeMap<String, String>,
+    /// The `ETag` of the repodata used for the solve, keyed by platform URL, if the solve was
+    /// `deterministic` (repodata without an `ETag`, such as that of local channels, is absent)
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub repodata_etags: BTreeMap<String, String>,
+    /// The solved packages that had candidates of the same version and build number, between
+    /// which the canonical order decided, if the solve was `deterministic`
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tie_breaks: Vec<TieBreak>,
+    /// The resolved dependencies of each package, if requested through `include_graph`
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graph: Option<BTreeMap<String, Vec<String>>>,
+    /// How hard the solver worked, if requested through `debug`
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solver_stats: Option<SolverStats>,
+    /// How long each phase of the solve took, if requested through `debug`
+    #[cfg_attr(test, serde(default))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<SolveTimings>,
+}
+
+/// Statistics about a solve. The counters that the solver backend does not expose are absent.
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SolverStats {
+    pub duration_ms: f64,
+    /// The backend that solved the environment
+    pub solver: Solver,
+    /// The amount of package records that were available to the solver
+    pub candidates_considered: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflicts: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backtracks: Option<usize>,
+}
+
+/// The time spent in each phase of a solve request, in milliseconds
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SolveTimings {
+    /// Getting the repodata of each subdir (including downloading and parsing it, unless it was
+    /// cached), keyed by platform URL. Subdirs are fetched concurrently.
+    pub repodata: BTreeMap<String, RepodataTiming>,
+    /// Filtering the available packages (e.g. by `exclude` or the channel priority)
+    pub prepare_ms: f64,
+    /// Solving, or awaiting an identical solve that was already in progress
+    pub solve_ms: f64,
+    /// Whether the solution was taken from the cache of recent solve results
+    pub solve_cache_hit: bool,
+    /// The whole request, up to serializing the response
+    pub total_ms: f64,
 }
 
-#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RepodataTiming {
+    pub duration_ms: f64,
+    /// Whether the repodata was in the server's memory already
+    pub cache_hit: bool,
+}
+
+/// A `constrains` entry of a solved package, restricting another package in the solution
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct AppliedConstraint {
+    /// The name of the package that carries the constraint
+    pub package: String,
+    /// The constraint, as a match spec
+    pub constraint: String,
+}
+
+/// A solved package that the solver could not tell apart from other candidates by version and
+/// build number
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, ToSchema)]
+pub struct TieBreak {
+    pub name: String,
+    pub version: String,
+    pub build_number: u64,
+    /// The URL of the solved package
+    pub chosen: String,
+    /// The URLs of the other candidates, in canonical order
+    pub candidates: Vec<String>,
+}
+
+/// Aggregated information about the packages in a solution
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct SolveSummary {
+    pub package_count: usize,
+    /// The sum of the download sizes of all packages that have a known size
+    pub total_download_bytes: u64,
+    /// A lower bound of the disk space that the installed packages take. Repodata doesn't record
+    /// how large packages are once extracted, so each package counts as at least the size of its
+    /// archive.
+    pub total_installed_bytes: u64,
+    /// Whether there are packages without a known size, in which case `total_download_bytes` and
+    /// `total_installed_bytes` leave them out
+    pub sizes_unknown: bool,
+    /// Whether there are packages without a sha256 hash, whose downloads cannot be verified
+    pub hashes_unknown: bool,
+}
+
+impl SolveSummary {
+    pub fn from_records(records: &[RepoDataRecord]) -> SolveSummary {
+        let mut total_download_bytes = 0;
+        let mut sizes_unknown = false;
+        for record in records {
+            match record.package_record.size {
+                Some(size) => total_download_bytes += size,
+                None => sizes_unknown = true,
+            }
+        }
+
+        SolveSummary {
+            package_count: records.len(),
+            total_download_bytes,
+            total_installed_bytes: total_download_bytes,
+            sizes_unknown,
+            hashes_unknown: records.iter().any(|r| r.package_record.sha256.is_none()),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[aliases(ErrorResponse = SolveEnvironmentErr<serde_json::Value>)]
 pub struct SolveEnvironmentErr<T: Serialize> {
     pub error_kind: String,
     pub message: Option<String>,
+    /// Details about the error, whose form depends on the `error_kind`
+    #[schema(value_type = Option<Value>)]
     pub additional_info: Option<T>,
 }
+
+/// The error returned when the request is unsolvable, which explains the problem in a structured
+/// form too if the solver's explanation could be parsed
+#[derive(Serialize, ToSchema)]
+pub struct SolveEnvironmentUnsolvable {
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub error: SolveEnvironmentErr<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem_report: Option<ProblemReport>,
+}
@@ -0,0 +1,203 @@
+//! Support for conda `environment.yml` files as input for solve requests
+
+use crate::cli::{PipDependencies, Solver};
+use crate::dto::{
+    ChannelPriority, Depth, FeaturePreference, MatchMode, PackageFormat, SolveEnvironment,
+};
+use crate::error::{ParseError, ValidationError};
+use crate::output::deserialize_flag;
+use chrono::{DateTime, Utc};
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{event, Level};
+
+/// The subset of a conda `environment.yml` file that is relevant for solving
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EnvironmentYml {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub dependencies: Vec<EnvironmentYmlDependency>,
+}
+
+/// An entry in the `dependencies` section of an `environment.yml` file
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EnvironmentYmlDependency {
+    Spec(String),
+    Pip { pip: Vec<String> },
+}
+
+/// The query parameters accompanying an `environment.yml` body, since the file itself doesn't
+/// specify what to solve for
+#[derive(Debug, Deserialize)]
+pub struct EnvironmentYmlParams {
+    pub platform: Option<String>,
+    /// Comma-separated list of virtual packages
+    pub virtual_packages: Option<String>,
+    pub profile: Option<String>,
+    pub match_mode: Option<MatchMode>,
+    #[serde(default)]
+    pub channel_priority: ChannelPriority,
+    #[serde(default)]
+    pub package_format: PackageFormat,
+    /// Comma-separated list of denied license patterns
+    pub license_deny: Option<String>,
+    /// Comma-separated list of `feature=preference` pairs (e.g. `nomkl=prefer`)
+    pub track_features_preferences: Option<String>,
+    /// Comma-separated list of the specs of excluded packages
+    pub exclude: Option<String>,
+    #[serde(default)]
+    pub depth: Depth,
+    /// Comma-separated list of non-standard subdirs
+    pub extra_subdirs: Option<String>,
+    pub snapshot: Option<DateTime<Utc>>,
+    pub timeout_ms: Option<u64>,
+    pub solver: Option<Solver>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub deterministic: bool,
+}
+
+impl EnvironmentYml {
+    /// Parses the contents of an `environment.yml` file
+    pub fn from_yaml_bytes(bytes: &[u8]) -> Result<EnvironmentYml, ValidationError> {
+        serde_yaml::from_slice(bytes).map_err(|e| {
+            ValidationError::EnvironmentYml(ParseError {
+                input: String::from_utf8_lossy(bytes).into_owned(),
+                error: e.to_string(),
+            })
+        })
+    }
+
+    /// Converts the file into a solve request, handling its pip dependencies (if any) according to
+    /// `pip_dependencies`
+    pub fn into_solve_environment(
+        self,
+        params: EnvironmentYmlParams,
+        pip_dependencies: PipDependencies,
+    ) -> Result<SolveEnvironment, ValidationError> {
+        let Some(platform) = params.platform else {
+            return Err(ValidationError::Platform(ParseError {
+                input: String::new(),
+                error: "the `platform` query parameter is required for environment.yml input"
+                    .to_string(),
+            }));
+        };
+
+        let mut specs = Vec::with_capacity(self.dependencies.len());
+        let mut pip_specs = Vec::new();
+        for dependency in self.dependencies {
+            match dependency {
+                EnvironmentYmlDependency::Spec(spec) => specs.push(spec),
+                EnvironmentYmlDependency::Pip { pip } => pip_specs.extend(pip),
+            }
+        }
+
+        if !pip_specs.is_empty() {
+            match pip_dependencies {
+                PipDependencies::Ignore => {
+                    event!(
+                        Level::DEBUG,
+                        "Ignoring {} pip dependencies from environment.yml",
+                        pip_specs.len()
+                    );
+                }
+                PipDependencies::Reject => {
+                    return Err(ValidationError::EnvironmentYml(ParseError {
+                        input: pip_specs.join(", "),
+                        error: "pip dependencies are not supported".to_string(),
+                    }));
+                }
+            }
+        }
+
+        let track_features_preferences = params
+            .track_features_preferences
+            .as_deref()
+            .map(parse_feature_preferences)
+            .transpose()?
+            .unwrap_or_default();
+
+        let virtual_packages = params
+            .virtual_packages
+            .as_deref()
+            .map(split_comma_separated);
+
+        Ok(SolveEnvironment {
+            name: self.name,
+            platform,
+            specs,
+            virtual_packages,
+            profile: params.profile,
+            channels: self.channels,
+            match_mode: params.match_mode,
+            channel_priority: params.channel_priority,
+            package_format: params.package_format,
+            license_deny: params
+                .license_deny
+                .as_deref()
+                .map(split_comma_separated)
+                .unwrap_or_default(),
+            track_features_preferences,
+            exclude: params
+                .exclude
+                .as_deref()
+                .map(split_comma_separated)
+                .unwrap_or_default(),
+            repodata_hashes: Default::default(),
+            depth: params.depth,
+            extra_subdirs: params
+                .extra_subdirs
+                .as_deref()
+                .map(split_comma_separated)
+                .unwrap_or_default(),
+            snapshot: params.snapshot,
+            locked_packages: Vec::new(),
+            pinned_packages: Vec::new(),
+            timeout_ms: params.timeout_ms,
+            channel_credentials: Default::default(),
+            offline: false,
+            solver: params.solver,
+            deterministic: params.deterministic,
+        })
+    }
+}
+
+/// Parses a comma-separated list of `feature=preference` pairs
+fn parse_feature_preferences(
+    list: &str,
+) -> Result<BTreeMap<String, FeaturePreference>, ValidationError> {
+    split_comma_separated(list)
+        .into_iter()
+        .map(|pair| {
+            let invalid = |error: String| {
+                ValidationError::EnvironmentYml(ParseError {
+                    input: pair.clone(),
+                    error,
+                })
+            };
+            let Some((feature, preference)) = pair.split_once('=') else {
+                return Err(invalid(
+                    "expected a `feature=preference` pair in `track_features_preferences`"
+                        .to_string(),
+                ));
+            };
+            let preference = FeaturePreference::deserialize(StrDeserializer::<ValueError>::new(
+                preference.trim(),
+            ))
+            .map_err(|e| invalid(e.to_string()))?;
+            Ok((feature.trim().to_string(), preference))
+        })
+        .collect()
+}
+
+fn split_comma_separated(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
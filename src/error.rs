@@ -1,16 +1,23 @@
 //! Contains the errors that the API can return when trying to solve an environment
 
-use crate::dto::SolveEnvironmentErr;
-use axum::http::StatusCode;
+use crate::dto::{SolveEnvironmentErr, SolveEnvironmentUnsolvable};
+use crate::problem_report::parse_problem_report;
+use crate::rate_limit::RateLimitStatus;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use rattler_repodata_gateway::fetch::FetchRepoDataError;
 use rattler_solve::SolveError;
 use reqwest::Url;
 use serde::{Serialize, Serializer};
+use std::time::Duration;
 use thiserror::Error;
 use tracing::{event, Level};
 
+/// How long clients are asked to wait before retrying a solve that was rejected because the solver
+/// queue was full
+const SOLVER_QUEUE_RETRY_AFTER_SECONDS: u64 = 5;
+
 #[derive(Debug, Error)]
 pub enum ApiError {
     #[error("internal error")]
@@ -21,6 +28,81 @@ pub enum ApiError {
     FetchRepoDataJson(Url, #[source] FetchRepoDataError),
     #[error("solve error: {0}")]
     Solver(#[from] SolveError),
+    #[error("no package matches the pinned versions of {}", .0.join(", "))]
+    NoMatchingVersion(Vec<String>),
+    #[error("the following specs can only be satisfied by packages with denied licenses: {}", .0.join(", "))]
+    LicenseDenied(Vec<String>),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("access to channel {0} is forbidden")]
+    ChannelForbidden(String),
+    #[error("channel {0} is not allowed on this server")]
+    ChannelNotAllowed(String),
+    #[error("repodata with hash {1} is not available for {0}")]
+    SnapshotUnavailable(Url, String),
+    #[error("repodata of {0} is not cached, and cannot be downloaded in offline mode")]
+    NotCachedOffline(Url),
+    #[error("the following packages were not found in their channel: {}", .0.join(", "))]
+    UnknownPackages(Vec<String>),
+    #[error("the packages have missing dependencies: {}", .0.join(", "))]
+    MissingDependencies(Vec<String>),
+    #[error("job {0} does not exist")]
+    UnknownJob(String),
+    #[error("the request did not complete within {} seconds", .0.as_secs())]
+    RequestTimeout(Duration),
+    #[error("the solver queue is full ({} solves are waiting)", .0.count)]
+    SolverQueueFull(LimitExceeded),
+    #[error("{} did not complete within {} ms", .0.phase, .0.timeout_ms)]
+    SolveTimeout(PhaseTimeout),
+    #[error(
+        "rate limit exceeded, retry in {} seconds",
+        .0.retry_after.unwrap_or_default().as_secs_f64().ceil()
+    )]
+    RateLimited(RateLimitStatus),
+    #[error("repodata from {} ended prematurely", .0.url)]
+    RepodataTruncated(TransferFailure),
+    #[error("repodata from {} could not be decoded", .0.url)]
+    RepodataCorrupt(TransferFailure),
+    #[error("unsupported content encoding: {0}")]
+    UnsupportedContentEncoding(String),
+    #[error("invalid {0} request body")]
+    InvalidRequestBody(String, #[source] std::io::Error),
+    #[error("request body exceeds the limit of {0} bytes")]
+    RequestBodyTooLarge(usize),
+}
+
+impl ApiError {
+    /// The category of the error, reported as the `error_kind` of its response
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Internal(_) | ApiError::Solver(SolveError::UnsupportedOperations(_)) => {
+                "internal"
+            }
+            ApiError::Validation(_) | ApiError::Solver(SolveError::ParseMatchSpecError(_)) => {
+                "validation"
+            }
+            ApiError::FetchRepoDataJson(..) => "http",
+            ApiError::Solver(SolveError::Unsolvable(_)) => "solver",
+            ApiError::NoMatchingVersion(_) => "match",
+            ApiError::LicenseDenied(_) => "license",
+            ApiError::SnapshotUnavailable(..) => "snapshot",
+            ApiError::NotCachedOffline(_) => "not_cached",
+            ApiError::UnknownPackages(_) | ApiError::UnknownJob(_) => "not_found",
+            ApiError::MissingDependencies(_) => "dependencies",
+            ApiError::RepodataTruncated(_) => "truncated",
+            ApiError::RepodataCorrupt(_) => "corrupt",
+            ApiError::UnsupportedContentEncoding(_) | ApiError::InvalidRequestBody(..) => {
+                "encoding"
+            }
+            ApiError::RequestBodyTooLarge(_) => "too_large",
+            ApiError::RequestTimeout(_) | ApiError::SolveTimeout(_) => "timeout",
+            ApiError::SolverQueueFull(_) => "overloaded",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::ChannelForbidden(_) => "forbidden",
+            ApiError::ChannelNotAllowed(_) => "channel_not_allowed",
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -33,6 +115,34 @@ pub enum ValidationError {
     Channels(ParseErrors),
     #[error("invalid platform")]
     Platform(ParseError),
+    #[error("invalid environment.yml")]
+    EnvironmentYml(ParseError),
+    #[error("invalid log level")]
+    LogLevel(ParseError),
+    #[error("invalid config file")]
+    ConfigFile(ParseError),
+    #[error("too many channels (at most {} are allowed)", .0.limit)]
+    TooManyChannels(LimitExceeded),
+    #[error("too many specs (at most {} are allowed)", .0.limit)]
+    TooManySpecs(LimitExceeded),
+    #[error("too many solves in the batch (at most {} are allowed)", .0.limit)]
+    TooManySolves(LimitExceeded),
+    #[error("invalid package urls")]
+    PackageUrls(ParseErrors),
+    #[error("invalid subdir")]
+    Subdir(ParseError),
+    #[error("invalid package name")]
+    PackageName(ParseError),
+    #[error("invalid version spec")]
+    VersionSpec(ParseError),
+    #[error("invalid locked or pinned packages")]
+    InstalledPackages(ParseErrors),
+    #[error("invalid channel credentials")]
+    ChannelCredentials(ParseError),
+    #[error("invalid lockfile")]
+    Lockfile(ParseError),
+    #[error("unknown profile")]
+    Profile(ParseError),
 }
 
 impl Serialize for ValidationError {
@@ -41,12 +151,24 @@ impl Serialize for ValidationError {
         S: Serializer,
     {
         match self {
-            ValidationError::MatchSpecs(errors) | ValidationError::Channels(errors) => {
-                errors.serialize(serializer)
-            }
-            ValidationError::VirtualPackage(error) | ValidationError::Platform(error) => {
-                error.serialize(serializer)
-            }
+            ValidationError::MatchSpecs(errors)
+            | ValidationError::Channels(errors)
+            | ValidationError::PackageUrls(errors)
+            | ValidationError::InstalledPackages(errors) => errors.serialize(serializer),
+            ValidationError::VirtualPackage(error)
+            | ValidationError::Platform(error)
+            | ValidationError::EnvironmentYml(error)
+            | ValidationError::LogLevel(error)
+            | ValidationError::ConfigFile(error)
+            | ValidationError::Subdir(error)
+            | ValidationError::PackageName(error)
+            | ValidationError::VersionSpec(error)
+            | ValidationError::ChannelCredentials(error)
+            | ValidationError::Lockfile(error)
+            | ValidationError::Profile(error) => error.serialize(serializer),
+            ValidationError::TooManyChannels(error)
+            | ValidationError::TooManySpecs(error)
+            | ValidationError::TooManySolves(error) => error.serialize(serializer),
         }
     }
 }
@@ -60,6 +182,47 @@ pub struct ParseError {
 #[derive(Debug, Serialize)]
 pub struct ParseErrors(pub Vec<ParseError>);
 
+/// How much of a repodata download was received before it failed
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferFailure {
+    pub url: String,
+    pub received_bytes: u64,
+    /// The size announced by the server, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_bytes: Option<u64>,
+}
+
+/// The phase of a solve that exceeded its timeout
+#[derive(Debug, Serialize)]
+pub struct PhaseTimeout {
+    pub phase: SolvePhase,
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SolvePhase {
+    /// Fetching the repodata of the request's channels
+    Fetching,
+    /// Preparing the available packages and solving
+    Solving,
+}
+
+impl std::fmt::Display for SolvePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolvePhase::Fetching => write!(f, "fetching the repodata"),
+            SolvePhase::Solving => write!(f, "solving"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LimitExceeded {
+    pub count: usize,
+    pub limit: usize,
+}
+
 fn rewrite_error(api_error: ApiError) -> ApiError {
     match api_error {
         ApiError::Solver(error @ SolveError::UnsupportedOperations(_)) => {
@@ -71,6 +234,7 @@ fn rewrite_error(api_error: ApiError) -> ApiError {
 
 pub fn response_from_error(api_error: ApiError) -> Response {
     let api_error = rewrite_error(api_error);
+    let error_kind = api_error.kind().to_string();
     match api_error {
         ApiError::Internal(e) => {
             event!(
@@ -84,7 +248,7 @@ pub fn response_from_error(api_error: ApiError) -> Response {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(SolveEnvironmentErr::<()> {
-                    error_kind: "internal".to_string(),
+                    error_kind,
                     message: None,
                     additional_info: None,
                 }),
@@ -100,7 +264,7 @@ pub fn response_from_error(api_error: ApiError) -> Response {
             (
                 StatusCode::BAD_REQUEST,
                 Json(SolveEnvironmentErr {
-                    error_kind: "http".to_string(),
+                    error_kind,
                     message: Some("unable to retrieve repodata.json".to_string()),
                     additional_info: Some(format!("url: {url}")),
                 }),
@@ -110,26 +274,228 @@ pub fn response_from_error(api_error: ApiError) -> Response {
         ApiError::Validation(e) => (
             StatusCode::BAD_REQUEST,
             Json(SolveEnvironmentErr {
-                error_kind: "validation".to_string(),
+                error_kind,
                 message: Some(e.to_string()),
                 additional_info: Some(e),
             }),
         )
             .into_response(),
         ApiError::Solver(SolveError::UnsupportedOperations(_)) => unreachable!(),
-        ApiError::Solver(SolveError::Unsolvable(e)) => (
+        ApiError::Solver(SolveError::Unsolvable(e)) => {
+            let problem_report = match e.as_slice() {
+                [explanation] => parse_problem_report(explanation),
+                _ => None,
+            };
+            (
+                StatusCode::CONFLICT,
+                Json(SolveEnvironmentUnsolvable {
+                    error: SolveEnvironmentErr {
+                        error_kind,
+                        message: Some(
+                            "no solution found for the specified dependencies".to_string(),
+                        ),
+                        additional_info: Some(e),
+                    },
+                    problem_report,
+                }),
+            )
+                .into_response()
+        }
+        ApiError::NoMatchingVersion(specs) => (
             StatusCode::CONFLICT,
             Json(SolveEnvironmentErr {
-                error_kind: "solver".to_string(),
-                message: Some("no solution found for the specified dependencies".to_string()),
-                additional_info: Some(e),
+                error_kind,
+                message: Some("no package matches the pinned versions of the specs".to_string()),
+                additional_info: Some(specs),
+            }),
+        )
+            .into_response(),
+        ApiError::LicenseDenied(specs) => (
+            StatusCode::CONFLICT,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some(
+                    "the specs can only be satisfied by packages with denied licenses".to_string(),
+                ),
+                additional_info: Some(specs),
+            }),
+        )
+            .into_response(),
+        ApiError::SnapshotUnavailable(url, hash) => (
+            StatusCode::CONFLICT,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the pinned repodata is no longer available".to_string()),
+                additional_info: Some(format!("url: {url}, hash: {hash}")),
+            }),
+        )
+            .into_response(),
+        ApiError::NotCachedOffline(url) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some(
+                    "the repodata is not cached, and cannot be downloaded in offline mode"
+                        .to_string(),
+                ),
+                additional_info: Some(format!("url: {url}")),
+            }),
+        )
+            .into_response(),
+        ApiError::UnknownPackages(urls) => (
+            StatusCode::NOT_FOUND,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the packages were not found in their channel".to_string()),
+                additional_info: Some(urls),
+            }),
+        )
+            .into_response(),
+        ApiError::MissingDependencies(dependencies) => (
+            StatusCode::CONFLICT,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the packages have missing dependencies".to_string()),
+                additional_info: Some(dependencies),
+            }),
+        )
+            .into_response(),
+        ApiError::RepodataTruncated(failure) => {
+            event!(Level::WARN, "Truncated repodata download: {failure:?}");
+            // Truncation is usually caused by a flaky connection, so the request may be retried
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(SolveEnvironmentErr {
+                    error_kind,
+                    message: Some("the repodata download ended prematurely".to_string()),
+                    additional_info: Some(failure),
+                }),
+            )
+                .into_response()
+        }
+        ApiError::RepodataCorrupt(failure) => {
+            event!(Level::WARN, "Corrupt repodata download: {failure:?}");
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(SolveEnvironmentErr {
+                    error_kind,
+                    message: Some("the downloaded repodata could not be decoded".to_string()),
+                    additional_info: Some(failure),
+                }),
+            )
+                .into_response()
+        }
+        ApiError::UnsupportedContentEncoding(encoding) => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the content encoding of the request is not supported".to_string()),
+                additional_info: Some(encoding),
+            }),
+        )
+            .into_response(),
+        ApiError::InvalidRequestBody(encoding, e) => (
+            StatusCode::BAD_REQUEST,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some(format!("the request body is not valid {encoding}")),
+                additional_info: Some(e.to_string()),
+            }),
+        )
+            .into_response(),
+        ApiError::RequestBodyTooLarge(limit) => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the request body is too large".to_string()),
+                additional_info: Some(format!("limit: {limit} bytes")),
+            }),
+        )
+            .into_response(),
+        ApiError::UnknownJob(id) => (
+            StatusCode::NOT_FOUND,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the job does not exist or has expired".to_string()),
+                additional_info: Some(format!("id: {id}")),
+            }),
+        )
+            .into_response(),
+        ApiError::RequestTimeout(timeout) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the request did not complete in time".to_string()),
+                additional_info: Some(format!("timeout: {} seconds", timeout.as_secs())),
+            }),
+        )
+            .into_response(),
+        ApiError::SolverQueueFull(exceeded) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                SOLVER_QUEUE_RETRY_AFTER_SECONDS.to_string(),
+            )],
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("too many solves are waiting, try again later".to_string()),
+                additional_info: Some(exceeded),
+            }),
+        )
+            .into_response(),
+        ApiError::RateLimited(status) => {
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(SolveEnvironmentErr::<()> {
+                    error_kind,
+                    message: Some("too many requests, the rate limit was exceeded".to_string()),
+                    additional_info: None,
+                }),
+            )
+                .into_response();
+            status.insert_headers(response.headers_mut());
+            response
+        }
+        ApiError::SolveTimeout(timeout) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some(format!("{} did not complete in time", timeout.phase)),
+                additional_info: Some(timeout),
+            }),
+        )
+            .into_response(),
+        ApiError::Unauthorized => (
+            StatusCode::UNAUTHORIZED,
+            Json(SolveEnvironmentErr::<()> {
+                error_kind,
+                message: Some("missing or invalid bearer token".to_string()),
+                additional_info: None,
+            }),
+        )
+            .into_response(),
+        ApiError::ChannelForbidden(channel) => (
+            StatusCode::FORBIDDEN,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("access to the channel is forbidden".to_string()),
+                additional_info: Some(channel),
+            }),
+        )
+            .into_response(),
+        ApiError::ChannelNotAllowed(channel) => (
+            StatusCode::FORBIDDEN,
+            Json(SolveEnvironmentErr {
+                error_kind,
+                message: Some("the channel is not allowed on this server".to_string()),
+                additional_info: Some(channel),
             }),
         )
             .into_response(),
         ApiError::Solver(SolveError::ParseMatchSpecError(e)) => (
             StatusCode::BAD_REQUEST,
             Json(SolveEnvironmentErr {
-                error_kind: "validation".to_string(),
+                error_kind,
                 message: Some("invalid match spec".to_string()),
                 additional_info: Some(e.to_string()),
             }),
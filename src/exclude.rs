@@ -0,0 +1,122 @@
+//! Removes the packages excluded by the request, so the solver has to find alternatives for them
+
+use crate::error::{ApiError, ParseError, ParseErrors, ValidationError};
+use crate::license_filter::glob_matches;
+use rattler_conda_types::{
+    MatchSpec, NamelessMatchSpec, PackageRecord, ParseMatchSpecError, RepoDataRecord,
+};
+use std::str::FromStr;
+
+/// A spec of the packages to exclude. Unlike in a match spec, the name may be a pattern (e.g.
+/// `*mkl*`), in which `*` matches any sequence of characters and `?` any single character.
+#[derive(Debug)]
+pub enum ExcludeSpec {
+    Spec(MatchSpec),
+    NamePattern {
+        pattern: String,
+        /// The rest of the spec (e.g. a version or build)
+        spec: NamelessMatchSpec,
+    },
+}
+
+impl FromStr for ExcludeSpec {
+    type Err = ParseMatchSpecError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+        let name_len = spec
+            .find(|c: char| c.is_whitespace() || "<>=!~[".contains(c))
+            .unwrap_or(spec.len());
+        let (name, rest) = spec.split_at(name_len);
+        if name.contains(['*', '?']) {
+            Ok(ExcludeSpec::NamePattern {
+                pattern: name.to_lowercase(),
+                spec: NamelessMatchSpec::from_str(rest)?,
+            })
+        } else {
+            MatchSpec::from_str(spec).map(ExcludeSpec::Spec)
+        }
+    }
+}
+
+impl ExcludeSpec {
+    pub fn matches(&self, record: &PackageRecord) -> bool {
+        match self {
+            ExcludeSpec::Spec(spec) => spec.matches(record),
+            ExcludeSpec::NamePattern { pattern, spec } => {
+                glob_matches(pattern, record.name.as_normalized()) && spec.matches(record)
+            }
+        }
+    }
+}
+
+/// Parses the `exclude` specs of a request, rejecting it if any of them is invalid
+pub fn parse_exclude_specs(specs: &[String]) -> Result<Vec<ExcludeSpec>, ApiError> {
+    let mut exclude = Vec::with_capacity(specs.len());
+    let mut invalid_specs = Vec::new();
+    for spec in specs {
+        match ExcludeSpec::from_str(spec) {
+            Ok(spec) => exclude.push(spec),
+            Err(e) => invalid_specs.push(ParseError {
+                input: spec.to_string(),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    if !invalid_specs.is_empty() {
+        return Err(ApiError::Validation(ValidationError::MatchSpecs(
+            ParseErrors(invalid_specs),
+        )));
+    }
+    Ok(exclude)
+}
+
+/// Removes the records that match any of the `exclude` specs
+pub fn exclude_packages(
+    exclude: &[ExcludeSpec],
+    mut available_packages: Vec<Vec<RepoDataRecord>>,
+) -> Vec<Vec<RepoDataRecord>> {
+    if exclude.is_empty() {
+        return available_packages;
+    }
+
+    for records in &mut available_packages {
+        records.retain(|r| !exclude.iter().any(|spec| spec.matches(&r.package_record)));
+    }
+    available_packages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::{PackageName, Version};
+
+    #[test]
+    fn test_exclude_spec_matches() {
+        let record = |name: &str, version: &str, build: &str| {
+            PackageRecord::new(
+                PackageName::new_unchecked(name),
+                Version::from_str(version).unwrap(),
+                build.to_string(),
+            )
+        };
+        let matches = |spec: &str, record: &PackageRecord| {
+            ExcludeSpec::from_str(spec).unwrap().matches(record)
+        };
+        let mkl = record("mkl", "2023.2.0", "h84fe81f_50496");
+        let libblas = record("libblas", "3.9.0", "20_linux64_mkl");
+        let openssl = record("openssl", "3.1.4", "hd590300_0");
+
+        assert!(matches("*mkl*", &mkl));
+        assert!(!matches("*mkl*", &libblas));
+        assert!(matches("* * *_mkl", &libblas));
+        assert!(matches("*[build=*mkl]", &libblas));
+        assert!(matches("open*>=3", &openssl));
+        assert!(!matches("open*<3", &openssl));
+        assert!(matches("openssl", &openssl));
+        assert!(!matches("openssl<3", &openssl));
+
+        assert!(ExcludeSpec::from_str("*mkl* >=>=1").is_err());
+    }
+}
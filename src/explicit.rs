@@ -0,0 +1,207 @@
+//! Contains the `/explicit` endpoint, which resolves a list of package URLs to their records
+//! without solving
+
+use crate::dto::SolveSummary;
+use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
+use crate::subdir::Subdir;
+use crate::{auth, AppState};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{StreamExt, TryStreamExt};
+use rattler_conda_types::{Channel, MatchSpec, Platform, RepoDataRecord};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExplicitEnvironment {
+    /// The URLs of the packages, e.g. `https://conda.anaconda.org/conda-forge/noarch/tzdata-2023c-h71feb2d_0.conda`
+    pub urls: Vec<String>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct ExplicitEnvironmentOk {
+    /// The records of the packages, in the order of the request
+    #[serde(with = "crate::v1::records")]
+    #[schema(value_type = Vec<PackageRecord>)]
+    pub packages: Vec<RepoDataRecord>,
+    pub summary: SolveSummary,
+}
+
+/// A package URL, split into the repodata it can be found in and its file name
+pub struct PackageUrl {
+    pub url: String,
+    pub channel: Channel,
+    pub subdir: Subdir,
+    pub file_name: String,
+}
+
+/// Looks up the records of the requested packages and checks that they form a complete
+/// environment
+#[utoipa::path(
+    post,
+    path = "/v1/explicit",
+    request_body = ExplicitEnvironment,
+    responses(
+        (status = 200, description = "The records of the packages", body = ExplicitEnvironmentOk),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 409, description = "The packages are unknown or miss dependencies", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn explicit_environment(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ExplicitEnvironment>,
+) -> Response {
+    match explicit_environment_inner(&state, &payload, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn explicit_environment_inner(
+    state: &AppState,
+    payload: &ExplicitEnvironment,
+    headers: &HeaderMap,
+) -> Result<ExplicitEnvironmentOk, ApiError> {
+    let mut package_urls = Vec::with_capacity(payload.urls.len());
+    let mut invalid_urls = Vec::new();
+    for url in &payload.urls {
+        match parse_package_url(url) {
+            Ok(package_url) => package_urls.push(package_url),
+            Err(error) => invalid_urls.push(ParseError {
+                input: url.clone(),
+                error,
+            }),
+        }
+    }
+    if !invalid_urls.is_empty() {
+        return Err(ApiError::Validation(ValidationError::PackageUrls(
+            ParseErrors(invalid_urls),
+        )));
+    }
+
+    for package_url in &package_urls {
+        state
+            .settings()
+            .channel_policy
+            .check(&package_url.channel.base_url)?;
+    }
+
+    let mut client = None;
+    if let Some(tenants) = &state.tenants {
+        let tenant = auth::authenticate_tenant(state, headers)?;
+        for package_url in &package_urls {
+            tenants.check_access(tenant, &package_url.channel.base_url)?;
+        }
+        client = tenant.map(|t| t.client());
+    }
+
+    // Each repodata.json is fetched only once, even if it contains multiple of the packages
+    let mut repodata: HashMap<Url, (Channel, Subdir)> = HashMap::new();
+    for package_url in &package_urls {
+        repodata
+            .entry(package_url.subdir.url(&package_url.channel))
+            .or_insert_with(|| (package_url.channel.clone(), package_url.subdir.clone()));
+    }
+    let repodata: HashMap<_, _> = futures::stream::iter(repodata)
+        .map(|(platform_url, (channel, subdir))| async move {
+            let snapshot = state
+                .available_packages
+                .get(&channel, &subdir, client, None)
+                .await?;
+            Ok::<_, ApiError>((platform_url, snapshot.records.into_loaded()?))
+        })
+        .buffer_unordered(state.settings().concurrent_repodata_downloads_per_request)
+        .try_collect()
+        .await?;
+
+    let mut packages = Vec::with_capacity(package_urls.len());
+    let mut unknown_urls = Vec::new();
+    for package_url in &package_urls {
+        let records = &repodata[&package_url.subdir.url(&package_url.channel)];
+        match records
+            .iter()
+            .find(|r| r.file_name == package_url.file_name)
+        {
+            Some(record) => packages.push(record.clone()),
+            None => unknown_urls.push(package_url.url.clone()),
+        }
+    }
+    if !unknown_urls.is_empty() {
+        return Err(ApiError::UnknownPackages(unknown_urls));
+    }
+
+    let missing = missing_dependencies(&packages);
+    if !missing.is_empty() {
+        return Err(ApiError::MissingDependencies(missing));
+    }
+
+    Ok(ExplicitEnvironmentOk {
+        summary: SolveSummary::from_records(&packages),
+        packages,
+    })
+}
+
+pub fn parse_package_url(url: &str) -> Result<PackageUrl, String> {
+    let parsed = Url::parse(url).map_err(|e| e.to_string())?;
+    let mut segments = parsed
+        .path_segments()
+        .ok_or_else(|| "the url has no path".to_string())?
+        .rev();
+    let file_name = segments.next().unwrap_or_default();
+    if !file_name.ends_with(".conda") && !file_name.ends_with(".tar.bz2") {
+        return Err("the url does not point to a conda package".to_string());
+    }
+    let subdir = segments
+        .next()
+        .ok_or_else(|| "the url has no subdir".to_string())
+        .and_then(Subdir::parse)?;
+    let channel_url = parsed.join("../").map_err(|e| e.to_string())?;
+
+    Ok(PackageUrl {
+        url: url.to_string(),
+        channel: Channel::from_url(channel_url, None::<Vec<Platform>>, &Default::default()),
+        subdir,
+        file_name: file_name.to_string(),
+    })
+}
+
+/// Returns a description of every dependency of the `packages` that none of them satisfies.
+/// Dependencies on virtual packages are provided by the system, so they are not checked.
+fn missing_dependencies(packages: &[RepoDataRecord]) -> Vec<String> {
+    let mut missing = Vec::new();
+    for package in packages {
+        let record = &package.package_record;
+        for depends in &record.depends {
+            let satisfied = match MatchSpec::from_str(depends) {
+                Ok(spec) => {
+                    let is_virtual = spec
+                        .name
+                        .as_ref()
+                        .map_or(false, |name| name.as_normalized().starts_with("__"));
+                    is_virtual || packages.iter().any(|p| spec.matches(&p.package_record))
+                }
+                Err(_) => false,
+            };
+            if !satisfied {
+                missing.push(format!(
+                    "{}={}={} depends on {depends}",
+                    record.name.as_normalized(),
+                    record.version,
+                    record.build
+                ));
+            }
+        }
+    }
+
+    missing
+}
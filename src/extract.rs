@@ -0,0 +1,132 @@
+//! Contains the extractors used to obtain solve requests from HTTP requests
+
+use crate::dto::SolveEnvironment;
+use crate::environment_yml::{EnvironmentYml, EnvironmentYmlParams};
+use crate::error::{response_from_error, ApiError};
+use crate::AppState;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use axum::async_trait;
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, Query, Request};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Json, RequestExt};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+/// The content types that are interpreted as an `environment.yml` body. `application/yaml` is the
+/// registered type (RFC 9512), the others are the ones that clients commonly send.
+const YAML_CONTENT_TYPES: &[&str] = &["application/yaml", "application/x-yaml", "text/yaml"];
+
+/// The maximum size of a request body after decompression (matching axum's default limit for
+/// uncompressed bodies), which protects the server against decompression bombs
+const MAX_DECOMPRESSED_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// A solve request, obtained either from a JSON body or from an `environment.yml` body (depending
+/// on the request's content type)
+pub struct SolveRequest(pub SolveEnvironment);
+
+#[async_trait]
+impl FromRequest<Arc<AppState>> for SolveRequest {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let mut req = decompress_body(req).await?;
+        if !has_yaml_content_type(&req) {
+            let Json(payload) = Json::<SolveEnvironment>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(SolveRequest(payload));
+        }
+
+        let Query(params) = req
+            .extract_parts::<Query<EnvironmentYmlParams>>()
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        EnvironmentYml::from_yaml_bytes(&body)
+            .and_then(|env| env.into_solve_environment(params, state.pip_dependencies))
+            .map(SolveRequest)
+            .map_err(|e| response_from_error(ApiError::Validation(e)))
+    }
+}
+
+/// Decompresses the body of the request according to its `Content-Encoding` (if any)
+async fn decompress_body(req: Request) -> Result<Request, Response> {
+    let Some(encoding) = req.headers().get(header::CONTENT_ENCODING) else {
+        return Ok(req);
+    };
+    let encoding = encoding
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if encoding == "identity" {
+        return Ok(req);
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let compressed = axum::body::to_bytes(body, MAX_DECOMPRESSED_BODY_BYTES)
+        .await
+        .map_err(|_| payload_too_large())?;
+
+    // Read at most one byte more than allowed, to detect oversized bodies without decompressing
+    // them completely
+    let limit = MAX_DECOMPRESSED_BODY_BYTES as u64 + 1;
+    let mut decompressed = Vec::new();
+    let result = match encoding.as_str() {
+        "gzip" => {
+            GzipDecoder::new(compressed.as_ref())
+                .take(limit)
+                .read_to_end(&mut decompressed)
+                .await
+        }
+        "zstd" => {
+            ZstdDecoder::new(compressed.as_ref())
+                .take(limit)
+                .read_to_end(&mut decompressed)
+                .await
+        }
+        _ => {
+            return Err(response_from_error(ApiError::UnsupportedContentEncoding(
+                encoding,
+            )))
+        }
+    };
+
+    if let Err(e) = result {
+        return Err(response_from_error(ApiError::InvalidRequestBody(
+            encoding, e,
+        )));
+    }
+    if decompressed.len() > MAX_DECOMPRESSED_BODY_BYTES {
+        return Err(payload_too_large());
+    }
+
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(decompressed)))
+}
+
+fn payload_too_large() -> Response {
+    response_from_error(ApiError::RequestBodyTooLarge(MAX_DECOMPRESSED_BODY_BYTES))
+}
+
+fn has_yaml_content_type(req: &Request) -> bool {
+    let Some(content_type) = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    // Ignore parameters such as `charset`
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    YAML_CONTENT_TYPES
+        .iter()
+        .any(|yaml| mime.eq_ignore_ascii_case(yaml))
+}
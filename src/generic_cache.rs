@@ -3,7 +3,8 @@ use std::hash::Hash;
 
 use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
 use tracing::{event, Level};
@@ -15,9 +16,21 @@ use mock_instant::Instant;
 use std::time::Instant;
 
 pub struct GenericCache<TKey, TValue> {
-    cached_data: DashMap<TKey, (Arc<TValue>, Instant)>,
+    cached_data: DashMap<TKey, CachedEntry<TValue>>,
     active_writes: DashMap<TKey, Arc<RwLock<()>>>,
-    expiration: Duration,
+    expiration: Mutex<Duration>,
+    /// Incremented whenever the cache is cleared, so values written by tokens obtained before that
+    /// are discarded
+    generation: AtomicU64,
+    /// The maximum amount of entries, beyond which the least recently used ones are evicted
+    capacity: Option<usize>,
+    /// The maximum amount of memory used by the entries, beyond which the least recently used ones
+    /// are evicted
+    memory_budget: Option<MemoryBudget<TValue>>,
+    /// How long expired entries can still be served by [`GenericCache::get_servable`]
+    max_staleness: Duration,
+    /// Incremented on every access, to order the entries by when they were last used
+    clock: AtomicU64,
 }
 
 impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
@@ -26,17 +39,81 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
         GenericCache {
             cached_data: DashMap::new(),
             active_writes: DashMap::new(),
-            expiration,
+            expiration: Mutex::new(expiration),
+            generation: AtomicU64::new(0),
+            capacity: None,
+            memory_budget: None,
+            max_staleness: Duration::ZERO,
+            clock: AtomicU64::new(0),
         }
     }
 
+    /// Limits the cache to `capacity` entries, evicting the least recently used entries when more
+    /// are added
+    pub fn with_capacity(mut self, capacity: usize) -> GenericCache<TKey, TValue> {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Limits the memory used by the entries to `bytes`, as estimated by `weigh`, evicting the least
+    /// recently used entries when more are added. The entry that was added last is always kept, even
+    /// if it exceeds the budget on its own.
+    pub fn with_memory_budget(
+        mut self,
+        bytes: u64,
+        weigh: fn(&TValue) -> u64,
+    ) -> GenericCache<TKey, TValue> {
+        self.memory_budget = Some(MemoryBudget { bytes, weigh });
+        self
+    }
+
+    /// Keeps expired entries around for `max_staleness`, during which they can still be served by
+    /// [`GenericCache::get_servable`]
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> GenericCache<TKey, TValue> {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    /// How long entries remain valid after they are written
+    pub fn expiration(&self) -> Duration {
+        *self.expiration.lock().unwrap()
+    }
+
+    /// Changes the expiration of the entries written from now on
+    pub fn set_expiration(&self, expiration: Duration) {
+        *self.expiration.lock().unwrap() = expiration;
+    }
+
+    /// Describes every entry in the cache (including outdated ones), in no particular order
+    pub fn entries(&self) -> Vec<EntryInfo<TKey, TValue>> {
+        let now = Instant::now();
+        self.cached_data
+            .iter()
+            .map(|item| entry_info(item.key(), item.value(), now))
+            .collect()
+    }
+
+    /// Describes the entry of the key (even if outdated), if any
+    pub fn entry(&self, key: &TKey) -> Option<EntryInfo<TKey, TValue>> {
+        let item = self.cached_data.get(key)?;
+        Some(entry_info(item.key(), item.value(), Instant::now()))
+    }
+
+    /// Returns true if the cache holds no data (including outdated data)
+    pub fn is_empty(&self) -> bool {
+        self.cached_data.is_empty()
+    }
+
     /// Removes outdated data from the cache
     pub fn gc(&self) {
+        if self.is_empty() {
+            return;
+        }
+
         let mut expired_keys = Vec::new();
         for item in &self.cached_data {
             let key = item.key();
-            let (_value, insert_instant) = item.value();
-            if Instant::now() > *insert_instant + self.expiration {
+            if is_expired(self.stale_until(item.value())) {
                 event!(Level::TRACE, "Key marked for GC: {key}");
 
                 // We remove the keys in a separate step to avoid deadlocks
@@ -55,17 +132,46 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
         );
     }
 
+    /// Removes all data from the cache, returning the amount of removed entries. Writes that are
+    /// in progress while clearing are discarded once they finish, so the cache doesn't get
+    /// repopulated with data that was retrieved before clearing it.
+    pub fn clear(&self) -> usize {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let removed = self.cached_data.len();
+        self.cached_data.clear();
+        removed
+    }
+
+    /// Gets the cached data if available and not outdated, without waiting for active writers
+    pub fn get_fresh(&self, key: &TKey) -> Option<Arc<TValue>> {
+        let cached = self.cached_data.get(key)?;
+        let entry = cached.value();
+        (!is_expired(entry.expires_at)).then(|| self.touch(entry))
+    }
+
+    /// Gets the cached data if available and not outdated for longer than the maximum staleness,
+    /// without waiting for active writers. The data is returned together with whether it is still
+    /// fresh.
+    pub fn get_servable(&self, key: &TKey) -> Option<(Arc<TValue>, bool)> {
+        let cached = self.cached_data.get(key)?;
+        let entry = cached.value();
+        if is_expired(self.stale_until(entry)) {
+            return None;
+        }
+        Some((self.touch(entry), !is_expired(entry.expires_at)))
+    }
+
     /// Gets the cached data if available, waiting for it if there is an active writer (to avoid
     /// double work). If the data is not available and there is no other task busy with writing it,
     /// returns not found.
     pub async fn get_cached(&self, key: &TKey) -> GetCachedResult<TKey, TValue> {
         loop {
             if let Some(repodata) = self.cached_data.get(key) {
-                if Instant::now() > repodata.value().1 + self.expiration {
+                if is_expired(repodata.value().expires_at) {
                     event!(Level::TRACE, "Cache hit, but data was stale: {key}");
                 } else {
                     event!(Level::TRACE, "Cache hit: {key}");
-                    return GetCachedResult::Found(repodata.value().0.clone());
+                    return GetCachedResult::Found(self.touch(repodata.value()));
                 }
             }
 
@@ -78,7 +184,15 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
                         Level::TRACE,
                         "Download already started, waiting for it to finish..."
                     );
-                    let _ = e.get().read().await;
+                    let lock = e.get().clone();
+                    drop(e);
+                    let _ = lock.read().await;
+
+                    // If the writer went away without writing (e.g. because its request was
+                    // cancelled), its write is no longer active, so the next iteration can start
+                    // another one
+                    self.active_writes
+                        .remove_if(key, |_, active| Arc::ptr_eq(active, &lock));
                 }
                 Entry::Vacant(e) => {
                     // No download is going on, register ours so others can see it (there can still
@@ -90,25 +204,183 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
                     return GetCachedResult::NotFound(WriteToken {
                         key: key.clone(),
                         rw_guard: write_guard,
+                        generation: self.generation.load(Ordering::SeqCst),
                     });
                 }
             };
         }
     }
 
-    /// Caches the value at the given key and notifies
-    pub fn set(&self, token: WriteToken<TKey>, value: Arc<TValue>) {
-        self.cached_data
-            .insert(token.key.clone(), (value, Instant::now()));
+    /// Caches the value at the given key and notifies, returning false if the value was discarded
+    /// because the cache was cleared since obtaining the token
+    pub fn set(&self, token: WriteToken<TKey>, value: Arc<TValue>) -> bool {
+        self.set_with_ttl(token, value, self.expiration())
+    }
+
+    /// Like [`GenericCache::set`], but the value expires after `ttl` instead of after the
+    /// cache-wide expiration
+    pub fn set_with_ttl(&self, token: WriteToken<TKey>, value: Arc<TValue>, ttl: Duration) -> bool {
+        let current = token.generation == self.generation.load(Ordering::SeqCst);
+        if current {
+            self.insert_entry(token.key.clone(), value, ttl);
+        }
 
         // This will notify anyone who is waiting for the write to finish
         drop(token.rw_guard);
 
         // Remove the active write, since it is no longer necessary
         self.active_writes.remove(&token.key);
+
+        current
+    }
+
+    /// Caches the value at the given key, replacing the cached value (if any) without waiting for
+    /// active writers
+    pub fn insert(&self, key: TKey, value: Arc<TValue>) {
+        self.insert_entry(key, value, self.expiration());
+    }
+
+    /// Estimates the memory used by the value at the given key again (e.g. because it grew since it
+    /// was cached), evicting the least recently used entries if the cache now exceeds its budget
+    pub fn reweigh(&self, key: &TKey) {
+        let Some(budget) = &self.memory_budget else {
+            return;
+        };
+        match self.cached_data.get_mut(key) {
+            Some(mut entry) => entry.bytes = (budget.weigh)(&entry.value),
+            None => return,
+        }
+        self.evict(key);
+    }
+
+    /// Removes the value at the given key, if any
+    pub fn remove(&self, key: &TKey) {
+        self.cached_data.remove(key);
+    }
+
+    /// Removes the entries whose key matches, returning how many were removed
+    pub fn remove_matching(&self, matches: impl Fn(&TKey) -> bool) -> usize {
+        let mut removed = 0;
+        self.cached_data.retain(|key, _| {
+            let remove = matches(key);
+            removed += usize::from(remove);
+            !remove
+        });
+        removed
+    }
+
+    fn insert_entry(&self, key: TKey, value: Arc<TValue>, ttl: Duration) {
+        let inserted_at = Instant::now();
+        let entry = CachedEntry {
+            bytes: self
+                .memory_budget
+                .as_ref()
+                .map_or(0, |budget| (budget.weigh)(&value)),
+            value,
+            inserted_at,
+            expires_at: inserted_at.checked_add(ttl),
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        };
+        self.cached_data.insert(key.clone(), entry);
+        self.evict(&key);
+    }
+
+    /// Evicts the least recently used entries (other than the one at `keep`) while the cache
+    /// exceeds its capacity or its memory budget
+    fn evict(&self, keep: &TKey) {
+        loop {
+            let over_capacity = self
+                .capacity
+                .is_some_and(|capacity| self.cached_data.len() > capacity);
+            let over_budget = self.memory_budget.as_ref().is_some_and(|budget| {
+                let used: u64 = self.cached_data.iter().map(|item| item.value().bytes).sum();
+                used > budget.bytes
+            });
+            if !over_capacity && !over_budget {
+                return;
+            }
+
+            // The key is cloned to avoid deadlocks, since removing requires a write lock
+            let least_recently_used = self
+                .cached_data
+                .iter()
+                .filter(|item| item.key() != keep)
+                .min_by_key(|item| item.value().last_used.load(Ordering::Relaxed))
+                .map(|item| item.key().clone());
+            match least_recently_used {
+                Some(key) => {
+                    event!(Level::TRACE, "Evicting least recently used key: {key}");
+                    self.cached_data.remove(&key);
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// The time until which the entry can be served, including the maximum staleness
+    fn stale_until(&self, entry: &CachedEntry<TValue>) -> Option<Instant> {
+        entry
+            .expires_at
+            .and_then(|expires_at| expires_at.checked_add(self.max_staleness))
+    }
+
+    /// Marks the entry as used, returning its value
+    fn touch(&self, entry: &CachedEntry<TValue>) -> Arc<TValue> {
+        entry.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        entry.value.clone()
     }
 }
 
+struct MemoryBudget<TValue> {
+    bytes: u64,
+    /// Estimates the memory used by a value
+    weigh: fn(&TValue) -> u64,
+}
+
+struct CachedEntry<TValue> {
+    value: Arc<TValue>,
+    /// The memory used by the value, if the cache has a memory budget (and zero otherwise)
+    bytes: u64,
+    inserted_at: Instant,
+    /// `None` if the TTL is too large to be represented, in which case the entry never expires
+    expires_at: Option<Instant>,
+    /// The value of the cache's clock when the entry was last used
+    last_used: AtomicU64,
+}
+
+/// Describes an entry of the cache, as returned by [`GenericCache::entries`]
+pub struct EntryInfo<TKey, TValue> {
+    pub key: TKey,
+    pub value: Arc<TValue>,
+    /// The time elapsed since the value was cached
+    pub age: Duration,
+    /// The time left until the value expires (zero if it already expired), or `None` if it never
+    /// expires
+    pub expires_in: Option<Duration>,
+}
+
+fn entry_info<TKey: Clone, TValue>(
+    key: &TKey,
+    entry: &CachedEntry<TValue>,
+    now: Instant,
+) -> EntryInfo<TKey, TValue> {
+    EntryInfo {
+        key: key.clone(),
+        value: entry.value.clone(),
+        age: now.saturating_duration_since(entry.inserted_at),
+        expires_in: entry
+            .expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(now)),
+    }
+}
+
+fn is_expired(expires_at: Option<Instant>) -> bool {
+    expires_at.map_or(false, |expires_at| Instant::now() > expires_at)
+}
+
 /// Represents the result of a call to [`GenericCache::get_cached`]
 pub enum GetCachedResult<TKey, TValue> {
     /// The key was found in the cache and its value is included in the enum variant
@@ -123,6 +395,8 @@ pub enum GetCachedResult<TKey, TValue> {
 pub struct WriteToken<T> {
     key: T,
     rw_guard: OwnedRwLockWriteGuard<()>,
+    /// The generation of the cache when the token was obtained
+    generation: u64,
 }
 
 #[cfg(test)]
@@ -159,7 +433,104 @@ mod test {
         assert_eq!(cache.cached_data.len(), 1);
         let (key, value) = cache.cached_data.into_iter().next().unwrap();
         assert_eq!(key, 43);
-        assert_eq!(*value.0.as_ref(), "bar");
+        assert_eq!(*value.value.as_ref(), "bar");
+    }
+
+    #[tokio::test]
+    async fn test_capacity_evicts_least_recently_used() {
+        let cache = default_cache().with_capacity(2);
+        cache.insert(1, Arc::new("foo"));
+        cache.insert(2, Arc::new("bar"));
+
+        // Using the first entry makes the second one the least recently used
+        assert_eq!(cache.get_fresh(&1).as_deref(), Some(&"foo"));
+        cache.insert(3, Arc::new("baz"));
+        assert_eq!(cache.cached_data.len(), 2);
+        assert!(cache.get_fresh(&2).is_none());
+        assert_eq!(cache.get_fresh(&1).as_deref(), Some(&"foo"));
+        assert_eq!(cache.get_fresh(&3).as_deref(), Some(&"baz"));
+
+        // Replacing an entry doesn't evict anything
+        cache.insert(3, Arc::new("qux"));
+        assert_eq!(cache.cached_data.len(), 2);
+        assert_eq!(cache.get_fresh(&1).as_deref(), Some(&"foo"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_evicts_least_recently_used() {
+        let cache = default_cache().with_memory_budget(10, |value| value.len() as u64);
+        cache.insert(1, Arc::new("abcd"));
+        cache.insert(2, Arc::new("efgh"));
+
+        // Using the first entry makes the second one the least recently used
+        assert!(cache.get_fresh(&1).is_some());
+        cache.insert(3, Arc::new("ijkl"));
+        assert!(cache.get_fresh(&2).is_none());
+        assert!(cache.get_fresh(&1).is_some());
+        assert!(cache.get_fresh(&3).is_some());
+
+        // An entry that exceeds the budget on its own evicts everything else, but is kept
+        cache.insert(4, Arc::new("too large for the budget"));
+        assert_eq!(cache.cached_data.len(), 1);
+        assert!(cache.get_fresh(&4).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reweigh_evicts_when_entries_grow() {
+        let cache: GenericCache<u32, Mutex<String>> =
+            GenericCache::with_expiration(Duration::from_secs(60))
+                .with_memory_budget(10, |value| value.lock().unwrap().len() as u64);
+        cache.insert(1, Arc::new(Mutex::new("abcd".to_string())));
+        cache.insert(2, Arc::new(Mutex::new("efgh".to_string())));
+
+        // The second entry grows beyond the budget, so the first one is evicted
+        let grown = cache.get_fresh(&2).unwrap();
+        grown.lock().unwrap().push_str("ijkl");
+        assert!(cache.get_fresh(&1).is_some());
+        cache.reweigh(&2);
+        assert!(cache.get_fresh(&1).is_none());
+        assert!(cache.get_fresh(&2).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stale_entries_are_servable_until_max_staleness() {
+        let cache = default_cache().with_max_staleness(Duration::from_secs(600));
+        cache.insert(42, Arc::new("foo"));
+        assert_eq!(cache.get_servable(&42), Some((Arc::new("foo"), true)));
+
+        // Expired, but within the maximum staleness
+        MockClock::advance(Duration::from_secs(120));
+        assert!(cache.get_fresh(&42).is_none());
+        assert_eq!(cache.get_servable(&42), Some((Arc::new("foo"), false)));
+        cache.gc();
+        assert_eq!(cache.cached_data.len(), 1);
+
+        // Past the maximum staleness
+        MockClock::advance(Duration::from_secs(600));
+        assert_eq!(cache.get_servable(&42), None);
+        cache.gc();
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_with_ttl_overrides_expiration() {
+        let cache = default_cache();
+
+        let write_token = get_cached_not_found(&cache, 42).await;
+        cache.set_with_ttl(write_token, Arc::new("short"), Duration::from_secs(10));
+        let write_token = get_cached_not_found(&cache, 43).await;
+        cache.set_with_ttl(write_token, Arc::new("long"), Duration::from_secs(600));
+
+        // Past the short TTL and the cache-wide expiration, but not past the long TTL
+        MockClock::advance(Duration::from_secs(120));
+        assert!(matches!(
+            cache.get_cached(&42).await,
+            GetCachedResult::NotFound(_)
+        ));
+        match cache.get_cached(&43).await {
+            GetCachedResult::Found(value) => assert_eq!(*value, "long"),
+            GetCachedResult::NotFound(_) => panic!("the long-lived entry should not expire"),
+        }
     }
 
     #[tokio::test]
@@ -186,6 +557,69 @@ mod test {
         assert_eq!(*get_cached_2.await.unwrap(), "foo");
     }
 
+    #[tokio::test]
+    async fn test_dropped_write_token_does_not_block_readers() {
+        let cache = default_cache();
+
+        // The writer goes away without writing
+        drop(get_cached_not_found(&cache, 42).await);
+
+        // The next reader becomes the writer instead of waiting forever
+        add_item(&cache, 42, "foo").await;
+        match cache.get_cached(&42).await {
+            GetCachedResult::Found(value) => assert_eq!(*value, "foo"),
+            GetCachedResult::NotFound(_) => panic!("the value should have been written"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiting_reader_takes_over_abandoned_write() {
+        let cache = Arc::new(default_cache());
+        let write_token = get_cached_not_found(&cache, 42).await;
+
+        // A reader waits for the writer, which goes away without writing
+        let reader = tokio::spawn({
+            let cache = cache.clone();
+            async move { get_cached_not_found(&cache, 42).await }
+        });
+        tokio::task::yield_now().await;
+        drop(write_token);
+
+        // The reader becomes the writer, and other readers wait for it
+        let write_token = reader.await.unwrap();
+        let other_reader = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.get_cached(&42).await }
+        });
+        tokio::task::yield_now().await;
+        cache.set(write_token, Arc::new("foo"));
+        match other_reader.await.unwrap() {
+            GetCachedResult::Found(value) => assert_eq!(*value, "foo"),
+            GetCachedResult::NotFound(_) => panic!("the value should have been written"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clear_discards_writes_in_progress() {
+        let cache = default_cache();
+        add_item(&cache, 42, "foo").await;
+        let write_token = get_cached_not_found(&cache, 43).await;
+
+        assert_eq!(cache.clear(), 1);
+        assert!(cache.is_empty());
+
+        // The write started before clearing, so its value is outdated
+        assert!(!cache.set(write_token, Arc::new("bar")));
+        assert!(cache.is_empty());
+
+        // Writes started after clearing are cached as usual
+        add_item(&cache, 43, "baz").await;
+        match cache.get_cached(&43).await {
+            GetCachedResult::Found(value) => assert_eq!(*value, "baz"),
+            GetCachedResult::NotFound(_) => panic!("the value should have been written"),
+        }
+    }
+
     async fn get_cached_not_found(
         cache: &GenericCache<usize, &'static str>,
         key: usize,
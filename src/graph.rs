@@ -0,0 +1,191 @@
+//! Builds the dependency graph of a solved environment
+
+use rattler_conda_types::{MatchSpec, RepoDataRecord};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// Maps the name of each package in `packages` to the names of the packages in `packages` that
+/// satisfy its dependencies.
+///
+/// Since the graph is a flat adjacency map, dependency cycles are represented as-is and do not
+/// require any special treatment. Dependencies on packages outside the solution (e.g. virtual
+/// packages) do not result in edges.
+pub fn dependency_graph(packages: &[RepoDataRecord]) -> BTreeMap<String, Vec<String>> {
+    let mut graph: BTreeMap<_, Vec<_>> = packages
+        .iter()
+        .map(|p| {
+            (
+                p.package_record.name.as_normalized().to_string(),
+                Vec::new(),
+            )
+        })
+        .collect();
+    for edge in dependency_edges(packages) {
+        graph.entry(edge.from).or_default().push(edge.to);
+    }
+    graph
+}
+
+/// The dependency graph of a solved environment, with a node for every package and an edge for
+/// every dependency that is satisfied by another package
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DependencyGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GraphNode {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub subdir: String,
+}
+
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct GraphEdge {
+    /// The name of the package that has the dependency
+    pub from: String,
+    /// The name of the package that satisfies it
+    pub to: String,
+    /// The dependency, as found in the `depends` of `from`
+    pub spec: String,
+}
+
+impl DependencyGraph {
+    pub fn from_packages(packages: &[RepoDataRecord]) -> DependencyGraph {
+        DependencyGraph {
+            nodes: packages
+                .iter()
+                .map(|p| GraphNode {
+                    name: p.package_record.name.as_normalized().to_string(),
+                    version: p.package_record.version.to_string(),
+                    build: p.package_record.build.clone(),
+                    subdir: p.package_record.subdir.clone(),
+                })
+                .collect(),
+            edges: dependency_edges(packages),
+        }
+    }
+
+    /// Renders the graph in the DOT language of Graphviz
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph dependencies {\n");
+        for node in &self.nodes {
+            let label = format!("{} {} {}", node.name, node.version, node.build);
+            let _ = writeln!(
+                dot,
+                "  {} [label={}];",
+                dot_string(&node.name),
+                dot_string(&label)
+            );
+        }
+        for edge in &self.edges {
+            let _ = writeln!(
+                dot,
+                "  {} -> {} [label={}];",
+                dot_string(&edge.from),
+                dot_string(&edge.to),
+                dot_string(&edge.spec)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Quotes a string as a DOT identifier
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns the dependencies of each package that are satisfied by a package in `packages`, in
+/// the order of `packages`, with a single edge between any two packages
+fn dependency_edges(packages: &[RepoDataRecord]) -> Vec<GraphEdge> {
+    let mut edges = Vec::new();
+    for package in packages {
+        let record = &package.package_record;
+        let from = record.name.as_normalized();
+        let mut dependencies: Vec<&str> = Vec::new();
+        for depends in &record.depends {
+            let Ok(spec) = MatchSpec::from_str(depends) else {
+                continue;
+            };
+
+            let to = packages
+                .iter()
+                .map(|p| &p.package_record)
+                .find(|p| spec.matches(p))
+                .map(|p| p.name.as_normalized());
+            if let Some(to) = to {
+                if !dependencies.contains(&to) {
+                    dependencies.push(to);
+                    edges.push(GraphEdge {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        spec: depends.clone(),
+                    });
+                }
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::{PackageName, PackageRecord, Version};
+
+    fn record(name: &str, depends: &[&str]) -> RepoDataRecord {
+        let mut package_record = PackageRecord::new(
+            PackageName::new_unchecked(name),
+            Version::from_str("1.0").unwrap(),
+            "0".to_string(),
+        );
+        package_record.depends = depends.iter().map(|d| d.to_string()).collect();
+        RepoDataRecord {
+            package_record,
+            file_name: format!("{name}-1.0-0.tar.bz2"),
+            url: format!("https://example.com/{name}-1.0-0.tar.bz2")
+                .parse()
+                .unwrap(),
+            channel: "https://example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_cycles_are_represented() {
+        let packages = [record("a", &["b"]), record("b", &["a >=1", "__glibc"])];
+        let graph = dependency_graph(&packages);
+
+        assert_eq!(graph["a"], vec!["b"]);
+        assert_eq!(graph["b"], vec!["a"]);
+    }
+
+    #[test]
+    fn test_dot_quotes_identifiers() {
+        let packages = [record("a", &["b"]), record("b", &["a >=1", "__glibc"])];
+        let dot = DependencyGraph::from_packages(&packages).to_dot();
+
+        assert_eq!(
+            dot,
+            "digraph dependencies {\n  \"a\" [label=\"a 1.0 0\"];\n  \"b\" [label=\"b 1.0 0\"];\n  \"a\" -> \"b\" [label=\"b\"];\n  \"b\" -> \"a\" [label=\"a >=1\"];\n}\n"
+        );
+        assert_eq!(dot_string(r#"say "hi""#), r#""say \"hi\"""#);
+    }
+
+    #[test]
+    fn test_unmatched_dependencies_are_skipped() {
+        let packages = [record("a", &["b >=2", "c"])];
+        let graph = dependency_graph(&packages);
+
+        assert!(graph["a"].is_empty());
+    }
+}
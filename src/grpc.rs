@@ -0,0 +1,503 @@
+//! Serves a gRPC interface alongside the HTTP one, for platforms that only speak gRPC. The RPCs
+//! share the caches and the solver pool of the HTTP endpoints, and their messages (defined in
+//! `proto/rattler_server.proto`) mirror the HTTP types.
+
+use crate::admin::{self, FlushCache, InvalidateCache};
+use crate::api_keys;
+use crate::auth::{bearer_token, token_matches};
+use crate::available_packages_cache::{CacheEntryInfo, FlushResult};
+use crate::cli::Solver;
+use crate::credentials::ChannelCredentials;
+use crate::dto::{
+    ChannelPriority, Depth, FeaturePreference, MatchMode, PackageFormat, PackageReference,
+    SolveEnvironment, SolveEnvironmentOk,
+};
+use crate::error::{response_from_error, ApiError};
+use crate::output::{OutputFormat, OutputParams};
+use crate::rate_limit;
+use crate::{audit, graph, multi_platform, solve_unconditionally, AppState};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use chrono::{DateTime, Utc};
+use rattler_conda_types::{NoArchKind, RepoDataRecord};
+use rattler_networking::Authentication;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+use tracing::Instrument;
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("rattler_server.v1");
+}
+
+use proto::rattler_server_server::{RattlerServer, RattlerServerServer};
+
+/// Serves the gRPC interface until `shutdown` completes
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(RattlerServerServer::new(GrpcService { state }))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+        .await
+}
+
+struct GrpcService {
+    state: Arc<AppState>,
+}
+
+#[tonic::async_trait]
+impl RattlerServer for GrpcService {
+    async fn solve(
+        &self,
+        request: Request<proto::SolveRequest>,
+    ) -> Result<Response<proto::SolveResponse>, Status> {
+        let state = self.state.clone();
+        let headers = headers_from_metadata(request.metadata());
+        let client_ip = request.remote_addr().map(|addr| addr.ip());
+        let (environment, output) = environment_from_proto(request.into_inner())?;
+
+        let solve = async {
+            state.metrics.record_solve_request();
+            let mut solution =
+                solve_unconditionally(state.clone(), &headers, &environment, &output).await?;
+            if output.include_graph {
+                solution.graph = Some(graph::dependency_graph(&solution.packages));
+            }
+            if !output.debug {
+                solution.solver_stats = None;
+                solution.timings = None;
+            }
+            Ok(solution_to_proto(solution))
+        };
+        let solve = audit::with_client_ip(client_ip, solve);
+        authorize(&state, &headers, client_ip, solve)
+            .await
+            .map(Response::new)
+    }
+
+    async fn solve_multi(
+        &self,
+        request: Request<proto::SolveMultiRequest>,
+    ) -> Result<Response<proto::SolveMultiResponse>, Status> {
+        let state = self.state.clone();
+        let headers = headers_from_metadata(request.metadata());
+        let client_ip = request.remote_addr().map(|addr| addr.ip());
+        let request = request.into_inner();
+        let environment = request.environment.unwrap_or_default();
+        let (environment, output) = environment_from_proto(environment)?;
+
+        let solve = async {
+            state.metrics.record_solve_request();
+            let solutions = multi_platform::solve_platforms(
+                state.clone(),
+                headers.clone(),
+                &environment,
+                request.platforms,
+                &output,
+            )
+            .await?;
+            Ok(proto::SolveMultiResponse {
+                platforms: solutions
+                    .into_iter()
+                    .map(|(platform, _, solution)| (platform, solution_to_proto(solution)))
+                    .collect(),
+            })
+        };
+        let solve = audit::with_client_ip(client_ip, solve);
+        authorize(&state, &headers, client_ip, solve)
+            .await
+            .map(Response::new)
+    }
+
+    async fn get_cache_info(
+        &self,
+        request: Request<proto::GetCacheInfoRequest>,
+    ) -> Result<Response<proto::CacheInfo>, Status> {
+        require_admin_token(&self.state, request.metadata()).await?;
+        let info = self.state.available_packages.info();
+        Ok(Response::new(proto::CacheInfo {
+            entries: info.entries.into_iter().map(cache_entry_to_proto).collect(),
+            snapshots: info
+                .snapshots
+                .into_iter()
+                .map(cache_entry_to_proto)
+                .collect(),
+        }))
+    }
+
+    async fn flush_cache(
+        &self,
+        request: Request<proto::FlushCacheRequest>,
+    ) -> Result<Response<proto::FlushCacheResponse>, Status> {
+        require_admin_token(&self.state, request.metadata()).await?;
+        let request = request.into_inner();
+        let params = FlushCache {
+            disk: request.disk,
+            solve_cache: request.solve_cache,
+        };
+        match admin::flush(&self.state, params).await {
+            Ok(result) => Ok(Response::new(flush_result_to_proto(result))),
+            Err(e) => Err(status_from_error(e).await),
+        }
+    }
+
+    async fn invalidate_cache(
+        &self,
+        request: Request<proto::InvalidateCacheRequest>,
+    ) -> Result<Response<proto::FlushCacheResponse>, Status> {
+        require_admin_token(&self.state, request.metadata()).await?;
+        let request = request.into_inner();
+        let params = InvalidateCache {
+            channel: request.channel,
+            platform: request.platform,
+            disk: request.disk,
+            solve_cache: request.solve_cache,
+        };
+        match admin::invalidate(&self.state, params).await {
+            Ok(result) => Ok(Response::new(flush_result_to_proto(result))),
+            Err(e) => Err(status_from_error(e).await),
+        }
+    }
+}
+
+/// Runs the RPC if its caller has a valid API key (when the server requires one) and is within its
+/// rate limit, like the HTTP endpoints do
+async fn authorize<T>(
+    state: &AppState,
+    headers: &HeaderMap,
+    client_ip: Option<IpAddr>,
+    rpc: impl Future<Output = Result<T, ApiError>>,
+) -> Result<T, Status> {
+    let authorized = match api_keys::authorize(state, headers) {
+        Ok(authorized) => authorized,
+        Err(e) => return Err(status_from_error(e).await),
+    };
+    if let Err(e) = rate_limit::limit_client(state, headers, client_ip) {
+        return Err(status_from_error(e).await);
+    }
+    let result = match authorized {
+        Some(api_keys::Authorized { label, .. }) => {
+            rpc.instrument(tracing::info_span!("api_key", label = %label))
+                .await
+        }
+        None => rpc.await,
+    };
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => Err(status_from_error(e).await),
+    }
+}
+
+/// Rejects calls that don't carry the admin token as a bearer token
+async fn require_admin_token(state: &AppState, metadata: &MetadataMap) -> Result<(), Status> {
+    let headers = headers_from_metadata(metadata);
+    match (&state.admin_token, bearer_token(&headers)) {
+        (Some(expected), Some(provided)) if token_matches(expected, provided) => Ok(()),
+        (None, _) => Err(Status::unimplemented(
+            "the cache admin RPCs are disabled, since the server has no admin token",
+        )),
+        _ => Err(status_from_error(ApiError::Unauthorized).await),
+    }
+}
+
+/// Converts the metadata of a call to the headers of the equivalent HTTP request, so callers are
+/// authenticated (and may pass channel credentials) in the same way
+fn headers_from_metadata(metadata: &MetadataMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in metadata.clone().into_headers().iter() {
+        let name = HeaderName::from_bytes(name.as_str().as_bytes());
+        let value = HeaderValue::from_bytes(value.as_bytes());
+        if let (Ok(name), Ok(value)) = (name, value) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// Converts an error to the status of the equivalent HTTP error response, which carries the JSON
+/// body of that response in its details
+async fn status_from_error(e: ApiError) -> Status {
+    let response = response_from_error(e);
+    let code = match response.status() {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::FailedPrecondition,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+    let message = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| {
+            let message = body.get("message").and_then(|m| m.as_str());
+            let error_kind = body.get("error_kind").and_then(|k| k.as_str());
+            message.or(error_kind).map(str::to_string)
+        })
+        .unwrap_or_default();
+    Status::with_details(code, message, body)
+}
+
+fn environment_from_proto(
+    request: proto::SolveRequest,
+) -> Result<(SolveEnvironment, OutputParams), Status> {
+    let match_mode = request.match_mode.map(|_| match request.match_mode() {
+        proto::MatchMode::Strict => MatchMode::Strict,
+        proto::MatchMode::Flexible => MatchMode::Flexible,
+    });
+    let channel_priority = match request.channel_priority() {
+        proto::ChannelPriority::Strict => ChannelPriority::Strict,
+        proto::ChannelPriority::Disabled => ChannelPriority::Disabled,
+    };
+    let package_format = match request.package_format() {
+        proto::PackageFormat::Any => PackageFormat::Any,
+        proto::PackageFormat::Conda => PackageFormat::Conda,
+        proto::PackageFormat::Tarbz2 => PackageFormat::Tarbz2,
+    };
+    let depth = match request.depth() {
+        proto::Depth::Full => Depth::Full,
+        proto::Depth::Direct => Depth::Direct,
+    };
+    let solver = request.solver.map(|_| match request.solver() {
+        proto::Solver::Resolvo => Solver::Resolvo,
+        proto::Solver::LibsolvC => Solver::Libsolvc,
+    });
+
+    let mut track_features_preferences = std::collections::BTreeMap::new();
+    for (feature, preference) in request.track_features_preferences {
+        let preference = match proto::FeaturePreference::try_from(preference) {
+            Ok(proto::FeaturePreference::Prefer) => FeaturePreference::Prefer,
+            Ok(proto::FeaturePreference::Require) => FeaturePreference::Require,
+            Ok(proto::FeaturePreference::Forbid) => FeaturePreference::Forbid,
+            Ok(proto::FeaturePreference::Unspecified) | Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "no preference for tracked feature `{feature}`"
+                )))
+            }
+        };
+        track_features_preferences.insert(feature, preference);
+    }
+
+    let snapshot = request
+        .snapshot
+        .map(|snapshot| {
+            DateTime::parse_from_rfc3339(&snapshot)
+                .map(|snapshot| snapshot.with_timezone(&Utc))
+                .map_err(|e| {
+                    Status::invalid_argument(format!("invalid snapshot `{snapshot}`: {e}"))
+                })
+        })
+        .transpose()?;
+
+    let mut channel_credentials = ChannelCredentials::default();
+    for (host, credentials) in request.channel_credentials {
+        let authentication = match credentials.credentials {
+            Some(proto::credentials::Credentials::BearerToken(token)) => {
+                Authentication::BearerToken(token)
+            }
+            Some(proto::credentials::Credentials::Basic(basic)) => Authentication::BasicHTTP {
+                username: basic.username,
+                password: basic.password,
+            },
+            Some(proto::credentials::Credentials::CondaToken(token)) => {
+                Authentication::CondaToken(token)
+            }
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "no credentials for host `{host}`"
+                )))
+            }
+        };
+        channel_credentials.0.insert(host, authentication);
+    }
+
+    let environment = SolveEnvironment {
+        name: request.name,
+        platform: request.platform,
+        specs: request.specs,
+        virtual_packages: request.virtual_packages.map(|v| v.specs),
+        profile: request.profile,
+        channels: request.channels,
+        match_mode,
+        channel_priority,
+        package_format,
+        license_deny: request.license_deny,
+        track_features_preferences,
+        exclude: request.exclude,
+        repodata_hashes: request.repodata_hashes.into_iter().collect(),
+        depth,
+        extra_subdirs: request.extra_subdirs,
+        snapshot,
+        locked_packages: package_references_from_proto(request.locked_packages)?,
+        pinned_packages: package_references_from_proto(request.pinned_packages)?,
+        timeout_ms: request.timeout_ms,
+        channel_credentials,
+        offline: request.offline,
+        solver,
+        deterministic: request.deterministic,
+    };
+    let output = OutputParams {
+        format: OutputFormat::Json,
+        include_graph: request.include_graph,
+        debug: request.debug,
+    };
+    Ok((environment, output))
+}
+
+fn package_references_from_proto(
+    references: Vec<proto::PackageReference>,
+) -> Result<Vec<PackageReference>, Status> {
+    references
+        .into_iter()
+        .map(|reference| match reference.reference {
+            Some(proto::package_reference::Reference::Exact(exact)) => {
+                Ok(PackageReference::Exact(exact))
+            }
+            Some(proto::package_reference::Reference::Record(package)) => Ok(
+                PackageReference::Record(Box::new(record_from_proto(package)?)),
+            ),
+            None => Err(Status::invalid_argument("empty package reference")),
+        })
+        .collect()
+}
+
+/// Reads a record in the same way as the records of `repodata.json`, so it is validated in the
+/// same way too
+fn record_from_proto(package: proto::Package) -> Result<RepoDataRecord, Status> {
+    let mut record = serde_json::json!({
+        "name": package.name,
+        "version": package.version,
+        "build": package.build,
+        "build_number": package.build_number,
+        "subdir": package.subdir,
+        "depends": package.depends,
+        "constrains": package.constrains,
+        "license": package.license,
+        "license_family": package.license_family,
+        "md5": package.md5,
+        "sha256": package.sha256,
+        "size": package.size,
+        "timestamp": package.timestamp,
+        "noarch": package.noarch,
+        "fn": package.file_name,
+        "url": package.url,
+        "channel": package.channel,
+    });
+    if !package.track_features.is_empty() {
+        record["track_features"] = package.track_features.join(",").into();
+    }
+    serde_json::from_value(record)
+        .map_err(|e| Status::invalid_argument(format!("invalid record of `{}`: {e}", package.name)))
+}
+
+fn record_to_proto(record: RepoDataRecord) -> proto::Package {
+    let package = record.package_record;
+    proto::Package {
+        name: package.name.as_source().to_string(),
+        version: package.version.to_string(),
+        build: package.build,
+        build_number: package.build_number,
+        subdir: package.subdir,
+        depends: package.depends,
+        constrains: package.constrains,
+        track_features: package.track_features,
+        license: package.license,
+        license_family: package.license_family,
+        md5: package.md5.map(|md5| format!("{md5:x}")),
+        sha256: package.sha256.map(|sha256| format!("{sha256:x}")),
+        size: package.size,
+        timestamp: package.timestamp.map(|t| t.timestamp_millis()),
+        noarch: package.noarch.kind().map(|kind| match kind {
+            NoArchKind::Python => "python".to_string(),
+            NoArchKind::Generic => "generic".to_string(),
+        }),
+        file_name: record.file_name,
+        url: record.url.to_string(),
+        channel: record.channel,
+    }
+}
+
+fn solution_to_proto(solution: SolveEnvironmentOk) -> proto::SolveResponse {
+    proto::SolveResponse {
+        packages: solution.packages.into_iter().map(record_to_proto).collect(),
+        loosened_specs: solution.loosened_specs,
+        summary: Some(proto::SolveSummary {
+            package_count: solution.summary.package_count as u64,
+            total_download_bytes: solution.summary.total_download_bytes,
+            total_installed_bytes: solution.summary.total_installed_bytes,
+            sizes_unknown: solution.summary.sizes_unknown,
+            hashes_unknown: solution.summary.hashes_unknown,
+        }),
+        applied_constraints: solution
+            .applied_constraints
+            .into_iter()
+            .map(|c| proto::AppliedConstraint {
+                package: c.package,
+                constraint: c.constraint,
+            })
+            .collect(),
+        repodata_hashes: solution.repodata_hashes.into_iter().collect(),
+        repodata_etags: solution.repodata_etags.into_iter().collect(),
+        tie_breaks: solution
+            .tie_breaks
+            .into_iter()
+            .map(|t| proto::TieBreak {
+                name: t.name,
+                version: t.version,
+                build_number: t.build_number,
+                chosen: t.chosen,
+                candidates: t.candidates,
+            })
+            .collect(),
+        graph: solution
+            .graph
+            .into_iter()
+            .flatten()
+            .map(|(name, names)| (name, proto::Dependencies { names }))
+            .collect(),
+        solver_stats: solution.solver_stats.map(|stats| proto::SolverStats {
+            duration_ms: stats.duration_ms,
+            candidates_considered: stats.candidates_considered as u64,
+            conflicts: stats.conflicts.map(|c| c as u64),
+            backtracks: stats.backtracks.map(|b| b as u64),
+            solver: match stats.solver {
+                Solver::Resolvo => proto::Solver::Resolvo,
+                Solver::Libsolvc => proto::Solver::LibsolvC,
+            }
+            .into(),
+        }),
+    }
+}
+
+fn cache_entry_to_proto(entry: CacheEntryInfo) -> proto::CacheEntry {
+    proto::CacheEntry {
+        url: entry.url.to_string(),
+        hash: entry.hash,
+        age_seconds: entry.age_seconds,
+        expires_in_seconds: entry.expires_in_seconds,
+        record_count: entry.record_count as u64,
+        approximate_bytes: entry.approximate_bytes,
+    }
+}
+
+fn flush_result_to_proto(result: FlushResult) -> proto::FlushCacheResponse {
+    proto::FlushCacheResponse {
+        entries_removed: result.entries_removed as u64,
+        snapshots_removed: result.snapshots_removed as u64,
+        files_removed: result.files_removed as u64,
+        solve_results_removed: result.solve_results_removed as u64,
+    }
+}
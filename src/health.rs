@@ -0,0 +1,164 @@
+//! Contains the `/healthz` and `/readyz` endpoints, which tell load balancers and orchestrators
+//! (e.g. Kubernetes probes) whether the process is alive and whether it should get traffic
+
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// The name of the file that is written to check that the cache directory is writable
+const PROBE_FILE_NAME: &str = ".readyz-probe";
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Health {
+    pub status: String,
+}
+
+/// Whether the server is ready, along with the result of each check
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The repodata of the subdirs passed to `--warm-subdirs` was fetched once
+    pub warm_up: Check,
+    /// The solver queue has room for more solves
+    pub solver_pool: Check,
+    /// Repodata can be written to the cache directory
+    pub disk_cache: Check,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// The progress of warming the subdirs passed to `--warm-subdirs`
+pub struct WarmUp {
+    total: usize,
+    warm: AtomicUsize,
+    /// Whether every subdir was attempted once. Subdirs that could not be fetched are retried in
+    /// the background, without holding up the server.
+    complete: AtomicBool,
+}
+
+impl WarmUp {
+    pub fn new(total: usize) -> WarmUp {
+        WarmUp {
+            total,
+            warm: AtomicUsize::new(0),
+            complete: AtomicBool::new(total == 0),
+        }
+    }
+
+    /// Records the outcome of a round of warming
+    pub fn record_round(&self, warm: usize) {
+        self.warm.store(warm, Ordering::Relaxed);
+        self.complete.store(true, Ordering::Relaxed);
+    }
+
+    fn check(&self) -> Check {
+        let warm = self.warm.load(Ordering::Relaxed);
+        let complete = self.complete.load(Ordering::Relaxed);
+        let detail = match (self.total, complete) {
+            (0, _) => "there are no subdirs to warm".to_string(),
+            (total, true) => format!("{warm} of {total} subdirs are warm"),
+            (total, false) => format!("warming {total} subdirs"),
+        };
+        Check {
+            ok: complete,
+            detail,
+        }
+    }
+}
+
+/// Reports that the process is alive and serving requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses((status = 200, description = "The server is alive", body = Health))
+)]
+pub async fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok".to_string(),
+    })
+}
+
+/// Reports whether the server is ready to take traffic: the subdirs to warm were fetched, the
+/// solver can take more solves and the cache directory is writable
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The server is ready", body = Readiness),
+        (status = 503, description = "The server is not ready", body = Readiness),
+    )
+)]
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let warm_up = state.warm_up.check();
+    let solver_pool = match state.solver_pool.queued() {
+        (queued, Some(limit)) if queued >= limit => Check {
+            ok: false,
+            detail: format!("the solver queue is full ({queued} of {limit} solves are waiting)"),
+        },
+        (queued, _) => Check {
+            ok: true,
+            detail: format!("{queued} solves are waiting"),
+        },
+    };
+    let disk_cache = check_writable(state.available_packages.cache_dir()).await;
+
+    let ready = warm_up.ok && solver_pool.ok && disk_cache.ok;
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let readiness = Readiness {
+        ready,
+        warm_up,
+        solver_pool,
+        disk_cache,
+    };
+    (status, Json(readiness))
+}
+
+/// Checks that a file can be written to the directory (creating it if needed) and removed again
+async fn check_writable(dir: &std::path::Path) -> Check {
+    let probe = dir.join(PROBE_FILE_NAME);
+    let result = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await
+    }
+    .await;
+    match result {
+        Ok(()) => Check {
+            ok: true,
+            detail: format!("{} is writable", dir.display()),
+        },
+        Err(e) => Check {
+            ok: false,
+            detail: format!("cannot write to {}: {e}", dir.display()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_warm_up_completes_after_a_round() {
+        assert!(WarmUp::new(0).check().ok);
+
+        let warm_up = WarmUp::new(2);
+        assert!(!warm_up.check().ok);
+        warm_up.record_round(1);
+        let check = warm_up.check();
+        assert!(check.ok);
+        assert_eq!(check.detail, "1 of 2 subdirs are warm");
+    }
+}
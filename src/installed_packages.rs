@@ -0,0 +1,107 @@
+//! Resolves the packages that a request reports as already installed (e.g. from a lockfile), so
+//! the solver can keep them instead of recomputing the environment from scratch
+
+use crate::dto::PackageReference;
+use crate::error::{ApiError, ParseError, ParseErrors, ValidationError};
+use rattler_conda_types::{PackageName, RepoDataRecord, Version};
+use std::str::FromStr;
+
+/// A package reference whose `name=version=build` string (if any) has been parsed
+pub enum InstalledPackage {
+    Record(Box<RepoDataRecord>),
+    Exact {
+        reference: String,
+        name: PackageName,
+        version: Version,
+        build: String,
+    },
+}
+
+impl InstalledPackage {
+    pub fn name(&self) -> &PackageName {
+        match self {
+            InstalledPackage::Record(record) => &record.package_record.name,
+            InstalledPackage::Exact { name, .. } => name,
+        }
+    }
+}
+
+/// Parses the `name=version=build` strings among the references
+pub fn parse_installed_packages(
+    references: &[PackageReference],
+) -> Result<Vec<InstalledPackage>, ApiError> {
+    let mut packages = Vec::with_capacity(references.len());
+    let mut invalid = Vec::new();
+    for reference in references {
+        match reference {
+            PackageReference::Record(record) => {
+                packages.push(InstalledPackage::Record(record.clone()))
+            }
+            PackageReference::Exact(reference) => match parse_exact(reference) {
+                Ok(package) => packages.push(package),
+                Err(error) => invalid.push(ParseError {
+                    input: reference.clone(),
+                    error,
+                }),
+            },
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Err(ApiError::Validation(ValidationError::InstalledPackages(
+            ParseErrors(invalid),
+        )));
+    }
+
+    Ok(packages)
+}
+
+fn parse_exact(reference: &str) -> Result<InstalledPackage, String> {
+    let [name, version, build] = reference.split('=').collect::<Vec<_>>()[..] else {
+        return Err("expected `name=version=build`".to_string());
+    };
+
+    Ok(InstalledPackage::Exact {
+        reference: reference.to_string(),
+        name: PackageName::try_from(name).map_err(|e| e.to_string())?,
+        version: Version::from_str(version).map_err(|e| format!("invalid version - {e}"))?,
+        build: build.to_string(),
+    })
+}
+
+/// Looks up the records of the packages given by `name=version=build`, taking the first match in
+/// channel order. Fails if any of them is not available.
+pub fn resolve_installed_packages(
+    packages: Vec<InstalledPackage>,
+    available_packages: &[Vec<RepoDataRecord>],
+) -> Result<Vec<RepoDataRecord>, ApiError> {
+    let mut records = Vec::with_capacity(packages.len());
+    let mut unknown = Vec::new();
+    for package in packages {
+        match package {
+            InstalledPackage::Record(record) => records.push(*record),
+            InstalledPackage::Exact {
+                reference,
+                name,
+                version,
+                build,
+            } => {
+                let found = available_packages.iter().flatten().find(|r| {
+                    r.package_record.name == name
+                        && r.package_record.version == version
+                        && r.package_record.build == build
+                });
+                match found {
+                    Some(record) => records.push(record.clone()),
+                    None => unknown.push(reference),
+                }
+            }
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(ApiError::UnknownPackages(unknown));
+    }
+
+    Ok(records)
+}
@@ -0,0 +1,297 @@
+//! Keeps the records of a subdir in a compact form, in which the strings that repeat across them
+//! are kept in memory once (see [`crate::cli::RepodataCacheMode::Parsed`])
+
+use chrono::{DateTime, Utc};
+use rattler_conda_types::{
+    NoArchType, PackageName, PackageRecord, PackageUrl, RepoDataRecord, VersionWithSource,
+};
+use rattler_digest::{Md5Hash, Sha256Hash};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::sync::Arc;
+
+/// The reference counts that precede the contents of an [`Arc`]
+const ARC_HEADER_BYTES: usize = 2 * size_of::<usize>();
+
+/// The records of a (channel, subdir) pair, which share their channel and subdir, the entries of
+/// their dependencies and constraints, and their license, arch and platform. Records are only
+/// rebuilt when they are needed, like those of [`crate::sparse::SparseRecords`].
+pub struct InternedRecords {
+    records: Vec<InternedRecord>,
+    /// The indexes of the records of each package, in order
+    by_name: HashMap<PackageName, Box<[u32]>>,
+    approximate_bytes: u64,
+}
+
+/// A [`RepoDataRecord`], with its shared strings interned and the fields that most records leave
+/// empty moved out of line
+struct InternedRecord {
+    name: PackageName,
+    version: VersionWithSource,
+    build: Box<str>,
+    build_number: u64,
+    file_name: Box<str>,
+    url: Url,
+    origin: Arc<Origin>,
+    depends: Box<[Arc<str>]>,
+    constrains: Box<[Arc<str>]>,
+    license: Option<Arc<str>>,
+    license_family: Option<Arc<str>>,
+    arch: Option<Arc<str>>,
+    platform: Option<Arc<str>>,
+    md5: Option<Md5Hash>,
+    sha256: Option<Sha256Hash>,
+    size: Option<u64>,
+    timestamp: Option<DateTime<Utc>>,
+    noarch: NoArchType,
+    rare: Option<Box<RareFields>>,
+}
+
+/// The channel and subdir of a record, which are the same for (nearly) every record of a subdir
+#[derive(PartialEq, Eq, Hash)]
+struct Origin {
+    channel: String,
+    subdir: String,
+}
+
+#[derive(Default, PartialEq)]
+struct RareFields {
+    features: Option<String>,
+    legacy_bz2_md5: Option<String>,
+    legacy_bz2_size: Option<u64>,
+    purls: Vec<PackageUrl>,
+    track_features: Vec<String>,
+}
+
+/// The strings seen so far, together with the memory they use
+#[derive(Default)]
+struct Interner {
+    strings: HashSet<Arc<str>>,
+    origins: HashSet<Arc<Origin>>,
+    bytes: usize,
+}
+
+impl Interner {
+    fn intern(&mut self, string: String) -> Arc<str> {
+        if let Some(interned) = self.strings.get(string.as_str()) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = string.into();
+        self.bytes += ARC_HEADER_BYTES + interned.len();
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    fn intern_all(&mut self, strings: Vec<String>) -> Box<[Arc<str>]> {
+        strings.into_iter().map(|s| self.intern(s)).collect()
+    }
+
+    fn intern_origin(&mut self, origin: Origin) -> Arc<Origin> {
+        if let Some(interned) = self.origins.get(&origin) {
+            return interned.clone();
+        }
+        self.bytes +=
+            ARC_HEADER_BYTES + size_of::<Origin>() + origin.channel.len() + origin.subdir.len();
+        let interned = Arc::new(origin);
+        self.origins.insert(interned.clone());
+        interned
+    }
+}
+
+impl InternedRecords {
+    pub fn new(records: &[RepoDataRecord]) -> InternedRecords {
+        let mut interner = Interner::default();
+        let records: Vec<_> = records
+            .iter()
+            .map(|record| InternedRecord::new(record.clone(), &mut interner))
+            .collect();
+        let mut by_name: HashMap<_, Vec<u32>> = HashMap::new();
+        for (index, record) in records.iter().enumerate() {
+            let index = u32::try_from(index).expect("a subdir has fewer than 2^32 records");
+            by_name.entry(record.name.clone()).or_default().push(index);
+        }
+        let by_name: HashMap<_, Box<[u32]>> = by_name
+            .into_iter()
+            .map(|(name, indexes)| (name, indexes.into()))
+            .collect();
+        let index_bytes = by_name
+            .iter()
+            .map(|(name, indexes)| {
+                size_of::<(PackageName, Box<[u32]>)>()
+                    + name.as_source().len()
+                    + name.as_normalized().len()
+                    + indexes.len() * size_of::<u32>()
+            })
+            .sum::<usize>();
+        let approximate_bytes = records
+            .iter()
+            .map(InternedRecord::approximate_bytes)
+            .sum::<usize>()
+            + interner.bytes
+            + index_bytes;
+
+        InternedRecords {
+            records,
+            by_name,
+            approximate_bytes: approximate_bytes as u64,
+        }
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.records.len()
+    }
+
+    /// The memory used by the records and the strings they share, estimated in the same way as
+    /// that of parsed records (see `record_bytes`)
+    pub fn approximate_bytes(&self) -> u64 {
+        self.approximate_bytes
+    }
+
+    /// Rebuilds the records, which are equal to the ones the interned records were created from
+    pub fn to_records(&self) -> Vec<RepoDataRecord> {
+        self.records.iter().map(InternedRecord::to_record).collect()
+    }
+
+    /// Rebuilds the records of a single package, in the order of [`InternedRecords::to_records`]
+    pub fn load(&self, name: &PackageName) -> Vec<RepoDataRecord> {
+        self.by_name.get(name).map_or_else(Vec::new, |indexes| {
+            indexes
+                .iter()
+                .map(|&index| self.records[index as usize].to_record())
+                .collect()
+        })
+    }
+}
+
+impl InternedRecord {
+    fn new(record: RepoDataRecord, interner: &mut Interner) -> InternedRecord {
+        let RepoDataRecord {
+            package_record: package,
+            file_name,
+            url,
+            channel,
+        } = record;
+        let rare = RareFields {
+            features: package.features,
+            legacy_bz2_md5: package.legacy_bz2_md5,
+            legacy_bz2_size: package.legacy_bz2_size,
+            purls: package.purls,
+            track_features: package.track_features,
+        };
+        InternedRecord {
+            name: package.name,
+            version: package.version,
+            build: package.build.into(),
+            build_number: package.build_number,
+            file_name: file_name.into(),
+            url,
+            origin: interner.intern_origin(Origin {
+                channel,
+                subdir: package.subdir,
+            }),
+            depends: interner.intern_all(package.depends),
+            constrains: interner.intern_all(package.constrains),
+            license: package.license.map(|s| interner.intern(s)),
+            license_family: package.license_family.map(|s| interner.intern(s)),
+            arch: package.arch.map(|s| interner.intern(s)),
+            platform: package.platform.map(|s| interner.intern(s)),
+            md5: package.md5,
+            sha256: package.sha256,
+            size: package.size,
+            timestamp: package.timestamp,
+            noarch: package.noarch,
+            rare: (rare != RareFields::default()).then(|| Box::new(rare)),
+        }
+    }
+
+    fn to_record(&self) -> RepoDataRecord {
+        let rare = self.rare.as_deref();
+        let to_strings = |strings: &[Arc<str>]| strings.iter().map(|s| s.to_string()).collect();
+        let to_string = |string: &Option<Arc<str>>| string.as_deref().map(str::to_string);
+        RepoDataRecord {
+            package_record: PackageRecord {
+                arch: to_string(&self.arch),
+                build: self.build.to_string(),
+                build_number: self.build_number,
+                constrains: to_strings(&self.constrains),
+                depends: to_strings(&self.depends),
+                features: rare.and_then(|r| r.features.clone()),
+                legacy_bz2_md5: rare.and_then(|r| r.legacy_bz2_md5.clone()),
+                legacy_bz2_size: rare.and_then(|r| r.legacy_bz2_size),
+                license: to_string(&self.license),
+                license_family: to_string(&self.license_family),
+                md5: self.md5,
+                name: self.name.clone(),
+                noarch: self.noarch,
+                platform: to_string(&self.platform),
+                purls: rare.map(|r| r.purls.clone()).unwrap_or_default(),
+                sha256: self.sha256,
+                size: self.size,
+                subdir: self.origin.subdir.clone(),
+                timestamp: self.timestamp,
+                track_features: rare.map(|r| r.track_features.clone()).unwrap_or_default(),
+                version: self.version.clone(),
+            },
+            file_name: self.file_name.to_string(),
+            url: self.url.clone(),
+            channel: self.origin.channel.clone(),
+        }
+    }
+
+    /// The memory used by the record, besides the strings it shares
+    fn approximate_bytes(&self) -> usize {
+        let shared = self.depends.len() + self.constrains.len();
+        let rare = self.rare.as_deref().map_or(0, |rare| {
+            size_of::<RareFields>()
+                + optional_len(&rare.features)
+                + optional_len(&rare.legacy_bz2_md5)
+                + strings_len(&rare.track_features)
+                + rare.purls.len() * size_of::<PackageUrl>()
+        });
+        size_of::<InternedRecord>()
+            + names_len(&self.name, &self.version)
+            + self.build.len()
+            + self.file_name.len()
+            + self.url.as_str().len()
+            + shared * size_of::<Arc<str>>()
+            + rare
+    }
+}
+
+/// Estimates the memory used by a parsed record, as the size of the record itself and of what it
+/// owns
+#[cfg(test)]
+pub fn record_bytes(record: &RepoDataRecord) -> u64 {
+    let package = &record.package_record;
+    let bytes = size_of::<RepoDataRecord>()
+        + names_len(&package.name, &package.version)
+        + package.build.len()
+        + record.file_name.len()
+        + record.url.as_str().len()
+        + record.channel.len()
+        + package.subdir.len()
+        + strings_len(&package.depends)
+        + strings_len(&package.constrains)
+        + optional_len(&package.license)
+        + optional_len(&package.license_family)
+        + optional_len(&package.arch)
+        + optional_len(&package.platform)
+        + optional_len(&package.features)
+        + optional_len(&package.legacy_bz2_md5)
+        + strings_len(&package.track_features)
+        + package.purls.len() * size_of::<PackageUrl>();
+    bytes as u64
+}
+
+fn names_len(name: &PackageName, version: &VersionWithSource) -> usize {
+    name.as_source().len() + name.as_normalized().len() + version.as_str().len()
+}
+
+fn strings_len(strings: &[String]) -> usize {
+    strings.iter().map(|s| size_of::<String>() + s.len()).sum()
+}
+
+fn optional_len(string: &Option<String>) -> usize {
+    string.as_ref().map_or(0, String::len)
+}
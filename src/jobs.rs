@@ -0,0 +1,266 @@
+//! Contains the `/jobs` endpoints, which solve requests in the background, so clients that cannot
+//! keep a connection open for the duration of a solve (e.g. behind gateways with short timeouts)
+//! can poll for the result instead
+
+use crate::error::{response_from_error, ApiError};
+use crate::extract::SolveRequest;
+use crate::output::OutputParams;
+use crate::{audit, solve_response, AppState};
+use axum::extract::{OriginalUri, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[cfg(test)]
+use mock_instant::Instant;
+
+#[cfg(not(test))]
+use std::time::Instant;
+
+/// The solves running in the background, and the results of the finished ones
+pub struct Jobs {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    /// How long the result of a finished job remains available
+    retention: Duration,
+}
+
+struct Job {
+    /// Cancels the solve, if it is still running
+    task: AbortHandle,
+    state: JobState,
+}
+
+enum JobState {
+    Running,
+    Finished {
+        finished_at: Instant,
+        http_status: StatusCode,
+        result: serde_json::Value,
+    },
+}
+
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobStatus {
+    pub id: String,
+    pub status: JobStatusKind,
+    /// The status code that the solve would have been answered with, once finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    /// The body of the solve response (or error), once finished. Responses in a format other
+    /// than JSON are given as a string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<serde_json::Value>,
+}
+
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatusKind {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl Jobs {
+    pub fn new(retention: Duration) -> Jobs {
+        Jobs {
+            jobs: Mutex::default(),
+            retention,
+        }
+    }
+
+    /// How many jobs are still solving
+    pub fn running(&self) -> usize {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values()
+            .filter(|job| matches!(job.state, JobState::Running))
+            .count()
+    }
+
+    fn status(&self, id: Uuid) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.remove_expired(&mut jobs);
+        let job = jobs.get(&id)?;
+        let status = match &job.state {
+            JobState::Running => JobStatus {
+                id: id.to_string(),
+                status: JobStatusKind::Running,
+                http_status: None,
+                result: None,
+            },
+            JobState::Finished {
+                http_status,
+                result,
+                ..
+            } => JobStatus {
+                id: id.to_string(),
+                status: if http_status.is_success() {
+                    JobStatusKind::Succeeded
+                } else {
+                    JobStatusKind::Failed
+                },
+                http_status: Some(http_status.as_u16()),
+                result: Some(result.clone()),
+            },
+        };
+        Some(status)
+    }
+
+    fn finish(&self, id: Uuid, http_status: StatusCode, result: serde_json::Value) {
+        // The job is gone if it was cancelled in the meantime
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.state = JobState::Finished {
+                finished_at: Instant::now(),
+                http_status,
+                result,
+            };
+        }
+    }
+
+    /// Removes the job, cancelling its solve if it is still running. Returns false if there is no
+    /// such job.
+    fn cancel(&self, id: Uuid) -> bool {
+        let Some(job) = self.jobs.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        job.task.abort();
+        true
+    }
+
+    fn remove_expired(&self, jobs: &mut HashMap<Uuid, Job>) {
+        jobs.retain(|_, job| match job.state {
+            JobState::Running => true,
+            JobState::Finished { finished_at, .. } => finished_at.elapsed() < self.retention,
+        });
+    }
+}
+
+/// Starts solving the request in the background, responding right away with the id of the job
+#[utoipa::path(
+    post,
+    path = "/v1/jobs",
+    params(OutputParams),
+    request_body = SolveEnvironment,
+    responses(
+        (status = 202, description = "The job was started, and can be polled at the `Location`", body = JobStatus),
+    )
+)]
+pub async fn create_job(
+    State(state): State<Arc<AppState>>,
+    Query(output): Query<OutputParams>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    SolveRequest(payload): SolveRequest,
+) -> Response {
+    let id = Uuid::new_v4();
+
+    // The solve is bounded like a regular solve request
+    let timeout = state.request_timeouts.for_route(Some("/solve"));
+    let mut jobs = state.jobs.jobs.lock().unwrap();
+    state.jobs.remove_expired(&mut jobs);
+    // The job is audited as a solve of the client that created it
+    let client_ip = audit::current_client_ip();
+    let task = tokio::spawn({
+        let state = state.clone();
+        audit::with_client_ip(client_ip, async move {
+            // The result is always returned in full, since it is only retrieved once
+            let solve = solve_response(state.clone(), &headers, &payload, &output, false);
+            let response = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, solve)
+                    .await
+                    .unwrap_or_else(|_| response_from_error(ApiError::RequestTimeout(timeout))),
+                None => solve.await,
+            };
+            let (http_status, result) = response_result(response).await;
+            state.jobs.finish(id, http_status, result);
+        })
+    });
+    jobs.insert(
+        id,
+        Job {
+            task: task.abort_handle(),
+            state: JobState::Running,
+        },
+    );
+    drop(jobs);
+
+    let status = JobStatus {
+        id: id.to_string(),
+        status: JobStatusKind::Running,
+        http_status: None,
+        result: None,
+    };
+    (
+        StatusCode::ACCEPTED,
+        // The job is polled through the same version of the API that it was created through
+        [(header::LOCATION, format!("{}/{id}", uri.path()))],
+        Json(status),
+    )
+        .into_response()
+}
+
+/// Converts a solve response into the result of its job
+async fn response_result(response: Response) -> (StatusCode, serde_json::Value) {
+    let http_status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+
+    let result = match is_json {
+        true => serde_json::from_slice(&body).unwrap_or_default(),
+        false => String::from_utf8_lossy(&body).into_owned().into(),
+    };
+    (http_status, result)
+}
+
+/// Returns the status of a job, and its result once finished
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{id}",
+    params(("id" = String, Path, description = "The id of the job")),
+    responses(
+        (status = 200, body = JobStatus),
+        (status = 404, description = "There is no such job", body = ErrorResponse),
+    )
+)]
+pub async fn get_job(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match parse_id(&id).and_then(|id| state.jobs.status(id)) {
+        Some(status) => Json(status).into_response(),
+        None => response_from_error(ApiError::UnknownJob(id)),
+    }
+}
+
+/// Cancels a job, or forgets its result if it finished
+#[utoipa::path(
+    delete,
+    path = "/v1/jobs/{id}",
+    params(("id" = String, Path, description = "The id of the job")),
+    responses(
+        (status = 204, description = "The job was removed"),
+        (status = 404, description = "There is no such job", body = ErrorResponse),
+    )
+)]
+pub async fn delete_job(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match parse_id(&id).is_some_and(|id| state.jobs.cancel(id)) {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => response_from_error(ApiError::UnknownJob(id)),
+    }
+}
+
+fn parse_id(id: &str) -> Option<Uuid> {
+    Uuid::parse_str(id).ok()
+}
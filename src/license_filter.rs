@@ -0,0 +1,107 @@
+//! Removes the packages whose license is denied by the request, so the solver can't select them
+
+use crate::error::ApiError;
+use rattler_conda_types::{MatchSpec, RepoDataRecord};
+
+/// Removes the records whose license matches any of the `deny` patterns. A pattern matches a
+/// license if it matches the whole license string, or any of the license identifiers in it when it
+/// is an SPDX expression (e.g. `GPL*` matches `MIT OR GPL-3.0-only`). Patterns are matched
+/// case-insensitively, and support `*` and `?` wildcards.
+///
+/// Fails if a spec could have been satisfied before the denied packages were removed, but no
+/// longer can, since the solver would otherwise report it as a missing package.
+pub fn apply_license_deny(
+    deny: &[String],
+    specs: &[MatchSpec],
+    available_packages: Vec<Vec<RepoDataRecord>>,
+) -> Result<Vec<Vec<RepoDataRecord>>, ApiError> {
+    if deny.is_empty() {
+        return Ok(available_packages);
+    }
+
+    let deny: Vec<String> = deny.iter().map(|p| p.to_lowercase()).collect();
+    let mut denied = Vec::new();
+    let allowed = available_packages
+        .into_iter()
+        .map(|records| {
+            let (allowed, rejected): (Vec<_>, Vec<_>) = records
+                .into_iter()
+                .partition(|r| !is_denied(&deny, r.package_record.license.as_deref()));
+            denied.extend(rejected);
+            allowed
+        })
+        .collect::<Vec<_>>();
+
+    let mut conflicts = Vec::new();
+    for spec in specs {
+        let matches = |r: &&RepoDataRecord| spec.matches(&r.package_record);
+        if allowed.iter().flatten().any(|r| matches(&r)) {
+            continue;
+        }
+
+        let mut licenses: Vec<&str> = denied
+            .iter()
+            .filter(matches)
+            .filter_map(|r| r.package_record.license.as_deref())
+            .collect();
+        licenses.sort_unstable();
+        licenses.dedup();
+        if !licenses.is_empty() {
+            conflicts.push(format!("{spec} (license {})", licenses.join(", ")));
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(allowed)
+    } else {
+        Err(ApiError::LicenseDenied(conflicts))
+    }
+}
+
+fn is_denied(deny: &[String], license: Option<&str>) -> bool {
+    let Some(license) = license else {
+        return false;
+    };
+
+    let license = license.to_lowercase();
+    let identifiers = license
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|id| !id.is_empty() && !matches!(*id, "and" | "or" | "with"));
+
+    deny.iter().any(|pattern| {
+        glob_matches(pattern, &license) || identifiers.clone().any(|id| glob_matches(pattern, id))
+    })
+}
+
+/// Matches `text` against a pattern in which `*` matches any sequence of characters and `?` any
+/// single character
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Classic backtracking over the last `*`, which is linear for patterns with a single wildcard
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
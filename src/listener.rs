@@ -0,0 +1,427 @@
+//! Binds the socket at which the HTTP interface is served: a TCP port or a Unix domain socket,
+//! optionally speaking TLS (and verifying client certificates) for deployments without a proxy in
+//! front of the server
+
+use crate::cli::Args;
+use anyhow::Context;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::future::{Future, IntoFuture};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+/// How long to wait before accepting connections again after failing to (e.g. because the process
+/// ran out of file descriptors)
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The socket at which the HTTP interface listens
+pub struct Listener {
+    socket: Socket,
+    tls: Option<TlsAcceptor>,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// A connection accepted from either kind of socket
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+impl Listener {
+    /// Binds the Unix domain socket passed to `--unix-socket`, or the port otherwise, setting up
+    /// TLS if a certificate was provided
+    pub async fn bind(args: &Args) -> anyhow::Result<Listener> {
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsAcceptor::from(Arc::new(server_config(
+                cert,
+                key,
+                args.tls_client_ca.as_deref(),
+            )?))),
+            _ => None,
+        };
+        let socket = match &args.unix_socket {
+            Some(path) => bind_unix(path)?,
+            None => Socket::Tcp(
+                TcpListener::bind(("127.0.0.1", args.port))
+                    .await
+                    .context("binding the HTTP port")?,
+            ),
+        };
+        Ok(Listener { socket, tls })
+    }
+
+    /// Serves the app until `signal` completes, and then until the connections in flight are closed
+    pub async fn serve(
+        self,
+        app: Router,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        match self {
+            Listener {
+                socket: Socket::Tcp(listener),
+                tls: None,
+            } => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(signal)
+                .into_future()
+                .await
+            }
+            listener => listener.serve_connections(app, signal).await,
+        }
+    }
+
+    /// Like [`axum::serve`], for the sockets and the TLS streams it does not support
+    async fn serve_connections(
+        self,
+        app: Router,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        // The connections are told to shut down once the receiver is dropped, and the server waits
+        // for them to finish by waiting for their receivers to be dropped
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let shutdown_tx = Arc::new(shutdown_tx);
+        tokio::spawn(async move {
+            signal.await;
+            drop(shutdown_rx);
+        });
+        let (close_tx, close_rx) = watch::channel(());
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.socket.accept() => accepted,
+                () = shutdown_tx.closed() => break,
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("cannot accept a connection: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let (app, tls) = (app.clone(), self.tls.clone());
+            let (shutdown_tx, close_rx) = (shutdown_tx.clone(), close_rx.clone());
+            tokio::spawn(async move {
+                match tls {
+                    // The handshake happens in the task, so slow clients don't hold up the others
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            serve_connection(stream, remote_addr, app, &shutdown_tx).await
+                        }
+                        Err(err) => tracing::debug!("TLS handshake failed: {err}"),
+                    },
+                    None => serve_connection(stream, remote_addr, app, &shutdown_tx).await,
+                }
+                drop(close_rx);
+            });
+        }
+
+        drop(close_rx);
+        close_tx.closed().await;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Socket::Unix(_) => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+/// A plain HTTP listener at the port
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener {
+            socket: Socket::Tcp(listener),
+            tls: None,
+        }
+    }
+}
+
+impl Socket {
+    /// Accepts a connection, along with the address of the client if it connected over TCP
+    async fn accept(&self) -> std::io::Result<(Box<dyn Stream>, Option<SocketAddr>)> {
+        match self {
+            Socket::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+        }
+    }
+}
+
+/// Serves the requests of a connection, closing it gracefully once the server shuts down. The
+/// address of the client is available to the app as [`ConnectInfo`], like with [`axum::serve`].
+async fn serve_connection(
+    stream: impl Stream + 'static,
+    remote_addr: Option<SocketAddr>,
+    app: Router,
+    shutdown: &watch::Sender<()>,
+) {
+    let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(addr) = remote_addr {
+            request.extensions_mut().insert(ConnectInfo(addr));
+        }
+        app.clone().oneshot(request)
+    });
+    let builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    let mut connection = std::pin::pin!(connection);
+    let mut shutting_down = std::pin::pin!(shutdown.closed());
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(err) = result {
+                    tracing::debug!("connection closed with an error: {err}");
+                }
+                return;
+            }
+            () = &mut shutting_down, if !draining => {
+                draining = true;
+                connection.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// Binds a Unix domain socket, replacing the socket left behind by a previous run, if any
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> anyhow::Result<Socket> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("binding {}", path.display()))?;
+    Ok(Socket::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> anyhow::Result<Socket> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
+
+/// Reads the certificate chain and private key of the server from PEM files. If a CA is provided,
+/// clients must present a certificate signed by it.
+fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("adding a CA from {}", client_ca.display()))?;
+            }
+            let verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots);
+            builder.with_client_cert_verifier(verifier.boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(read_certs(cert)?, read_key(key)?)
+        .context("configuring TLS")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("reading {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("{} contains no certificates", path.display());
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Reads the first private key in the file, which may be in PKCS #8, PKCS #1 (RSA) or SEC1 (EC)
+/// format
+fn read_key(path: &Path) -> anyhow::Result<rustls::PrivateKey> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("reading {}", path.display()))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(rustls::PrivateKey(key)),
+            Some(_) => {}
+            None => anyhow::bail!("{} contains no private key", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::routing::get;
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// The certificates in `test-data/tls`, signed by the CA in `ca.pem` (whose key was discarded)
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/tls")
+            .join(name)
+    }
+
+    fn tls_args(client_ca: bool) -> Args {
+        let mut args = Args::parse_from(["rattler-server", "-p", "0"]);
+        args.tls_cert = Some(fixture("server.pem"));
+        args.tls_key = Some(fixture("server.key"));
+        args.tls_client_ca = client_ca.then(|| fixture("ca.pem"));
+        args
+    }
+
+    /// An app that reports whether the address of the client is known
+    fn app() -> Router {
+        Router::new().route(
+            "/",
+            get(|addr: Option<ConnectInfo<SocketAddr>>| async move {
+                match addr {
+                    Some(_) => "tcp",
+                    None => "unix",
+                }
+            }),
+        )
+    }
+
+    /// Sends a request over the stream, returning the body of the response
+    async fn http_get(mut stream: impl Stream) -> std::io::Result<String> {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let read = stream.read(&mut buf).await?;
+            response.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&response);
+            // The body is one of the short strings returned by the app
+            if read == 0 || text.ends_with("tcp") || text.ends_with("unix") {
+                break;
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        if !response.starts_with("HTTP/1.1 200") {
+            let message = format!("unexpected response: {response:?}");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                message,
+            ));
+        }
+        Ok(response.rsplit("\r\n").next().unwrap().to_string())
+    }
+
+    async fn tls_get(addr: SocketAddr, client_cert: bool) -> std::io::Result<String> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in read_certs(&fixture("ca.pem")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            true => builder
+                .with_client_auth_cert(
+                    read_certs(&fixture("client.pem")).unwrap(),
+                    read_key(&fixture("client.key")).unwrap(),
+                )
+                .unwrap(),
+            false => builder.with_no_client_auth(),
+        };
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let stream = connector
+            .connect("localhost".try_into().unwrap(), stream)
+            .await?;
+        http_get(stream).await
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        let listener = Listener::bind(&tls_args(false)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(app(), std::future::pending()));
+
+        assert_eq!(tls_get(addr, false).await.unwrap(), "tcp");
+
+        // Plain HTTP is not served on a TLS port
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(http_get(stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls_with_client_certificates() {
+        let listener = Listener::bind(&tls_args(true)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(listener.serve(app(), std::future::pending()));
+
+        assert_eq!(tls_get(addr, true).await.unwrap(), "tcp");
+        assert!(tls_get(addr, false).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.join("rattler-server.sock");
+        let mut args = Args::parse_from(["rattler-server"]);
+        args.unix_socket = Some(path.clone());
+
+        // A socket left behind by a previous run is replaced
+        std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = Listener::bind(&args).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(listener.serve(app(), async move {
+            let _ = shutdown_rx.await;
+        }));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert_eq!(http_get(stream).await.unwrap(), "unix");
+
+        // The server stops once it is asked to
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
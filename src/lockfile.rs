@@ -0,0 +1,430 @@
+//! Contains the `/lockfile/validate` endpoint, which checks that the packages pinned by an existing
+//! lockfile are still available in their channels, with the hashes they were locked with
+
+use crate::channels::check_access;
+use crate::credentials::DownloadClient;
+use crate::error::{
+    response_from_error, ApiError, LimitExceeded, ParseError, ParseErrors, ValidationError,
+};
+use crate::explicit::parse_package_url;
+use crate::subdir::Subdir;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{StreamExt, TryStreamExt};
+use rattler_conda_types::{Channel, Platform, RepoDataRecord};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ValidateLockfile {
+    /// The contents of a `conda-lock.yml` lockfile or of an `@EXPLICIT` spec file
+    pub lockfile: String,
+    /// The format of the lockfile, which is detected from its contents if absent
+    pub format: Option<LockfileFormat>,
+    /// The channels the packages must belong to. Defaults to the channels listed in a conda-lock
+    /// file, and to the channel of each package of an explicit file.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Only the packages locked for this platform are checked, if the lockfile locks several (as
+    /// conda-lock files may)
+    pub platform: Option<String>,
+}
+
+/// The formats of lockfiles that can be validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockfileFormat {
+    /// A `conda-lock.yml` lockfile, as written by conda-lock (or by the `conda-lock` output format)
+    CondaLock,
+    /// An `@EXPLICIT` spec file, as written by `conda list --explicit --md5`
+    Explicit,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct LockfileValidation {
+    /// Whether every package is still available, with the hashes of the lockfile
+    pub valid: bool,
+    /// The status of each package, in the order of the lockfile
+    pub packages: Vec<LockedPackageStatus>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct LockedPackageStatus {
+    pub url: String,
+    pub status: PackageStatus,
+    /// How the package differs from the lockfile, if it was modified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageStatus {
+    /// The package is available, and its hashes (if the lockfile has any) match
+    Ok,
+    /// The package is available, but its hashes differ from those of the lockfile
+    Modified,
+    /// The package is no longer listed in the repodata of its channel, which is how channels yank
+    /// packages
+    Yanked,
+    /// The package belongs to none of the requested channels
+    OutsideChannels,
+}
+
+/// A package of the lockfile, with the hashes it was locked with (if any)
+struct LockedPackage {
+    url: String,
+    md5: Option<String>,
+    sha256: Option<String>,
+}
+
+/// The subset of a `conda-lock.yml` file that is needed to validate it
+#[derive(Deserialize)]
+struct CondaLockFile {
+    #[serde(default)]
+    metadata: CondaLockMetadata,
+    #[serde(default)]
+    package: Vec<CondaLockPackage>,
+}
+
+#[derive(Default, Deserialize)]
+struct CondaLockMetadata {
+    #[serde(default)]
+    channels: Vec<CondaLockChannel>,
+}
+
+#[derive(Deserialize)]
+struct CondaLockChannel {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct CondaLockPackage {
+    manager: String,
+    platform: String,
+    url: String,
+    #[serde(default)]
+    hash: CondaLockHash,
+}
+
+#[derive(Default, Deserialize)]
+struct CondaLockHash {
+    md5: Option<String>,
+    sha256: Option<String>,
+}
+
+/// Checks that every package of the lockfile is still available in its channel, unmodified, so the
+/// environment it pins can still be installed
+#[utoipa::path(
+    post,
+    path = "/v1/lockfile/validate",
+    request_body = ValidateLockfile,
+    responses(
+        (status = 200, description = "The status of each package", body = LockfileValidation),
+        (status = 400, description = "The lockfile, channels or platform are invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn validate_lockfile(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<ValidateLockfile>,
+) -> Response {
+    match validate_lockfile_inner(&state, &payload, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn validate_lockfile_inner(
+    state: &AppState,
+    payload: &ValidateLockfile,
+    headers: &HeaderMap,
+) -> Result<LockfileValidation, ApiError> {
+    let settings = state.settings();
+    if payload.channels.len() > settings.max_channels_per_request {
+        return Err(ApiError::Validation(ValidationError::TooManyChannels(
+            LimitExceeded {
+                count: payload.channels.len(),
+                limit: settings.max_channels_per_request,
+            },
+        )));
+    }
+    if let Some(platform) = &payload.platform {
+        Platform::from_str(platform).map_err(|e| {
+            ValidationError::Platform(ParseError {
+                input: platform.clone(),
+                error: e.to_string(),
+            })
+        })?;
+    }
+
+    let format = payload
+        .format
+        .unwrap_or_else(|| detect_format(&payload.lockfile));
+    let (lockfile_channels, locked) = match format {
+        LockfileFormat::CondaLock => {
+            parse_conda_lock(&payload.lockfile, payload.platform.as_deref())?
+        }
+        LockfileFormat::Explicit => (Vec::new(), parse_explicit(&payload.lockfile)),
+    };
+    let channel_names = if payload.channels.is_empty() {
+        &lockfile_channels
+    } else {
+        &payload.channels
+    };
+
+    let mut channels = Vec::with_capacity(channel_names.len());
+    let mut invalid_channels = Vec::new();
+    for channel in channel_names {
+        match settings.parse_channel(channel) {
+            Ok(c) => channels.push(c),
+            Err(e) => invalid_channels.push(ParseError {
+                input: channel.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !invalid_channels.is_empty() {
+        return Err(ApiError::Validation(ValidationError::Channels(
+            ParseErrors(invalid_channels),
+        )));
+    }
+
+    let mut package_urls = Vec::with_capacity(locked.len());
+    let mut invalid_urls = Vec::new();
+    for package in &locked {
+        match parse_package_url(&package.url) {
+            Ok(package_url) => package_urls.push(package_url),
+            Err(error) => invalid_urls.push(ParseError {
+                input: package.url.clone(),
+                error,
+            }),
+        }
+    }
+    if !invalid_urls.is_empty() {
+        return Err(ApiError::Validation(ValidationError::PackageUrls(
+            ParseErrors(invalid_urls),
+        )));
+    }
+
+    // Each package is looked up in the channel it belongs to, if that is one of the requested ones
+    let package_channels: Vec<Option<Channel>> = package_urls
+        .iter()
+        .map(|package_url| {
+            if channels.is_empty() {
+                Some(package_url.channel.clone())
+            } else {
+                channels
+                    .iter()
+                    .find(|c| same_channel(c, &package_url.channel))
+                    .cloned()
+            }
+        })
+        .collect();
+
+    // Each repodata.json is fetched only once, even if it contains multiple of the packages
+    let mut repodata: HashMap<Url, (Channel, Subdir)> = HashMap::new();
+    let mut clients: HashMap<Url, Option<&DownloadClient>> = HashMap::new();
+    for (package_url, channel) in package_urls.iter().zip(&package_channels) {
+        let Some(channel) = channel else {
+            continue;
+        };
+        if let Entry::Vacant(entry) = repodata.entry(package_url.subdir.url(channel)) {
+            clients.insert(entry.key().clone(), check_access(state, channel, headers)?);
+            entry.insert((channel.clone(), package_url.subdir.clone()));
+        }
+    }
+    let clients = &clients;
+    let repodata: HashMap<_, _> = futures::stream::iter(repodata)
+        .map(|(platform_url, (channel, subdir))| async move {
+            let snapshot = state
+                .available_packages
+                .get(&channel, &subdir, clients[&platform_url], None)
+                .await?;
+            Ok::<_, ApiError>((platform_url, snapshot.records.into_loaded()?))
+        })
+        .buffer_unordered(settings.concurrent_repodata_downloads_per_request)
+        .try_collect()
+        .await?;
+    let records: HashMap<_, HashMap<_, _>> = repodata
+        .iter()
+        .map(|(url, records)| {
+            let by_file_name = records.iter().map(|r| (r.file_name.as_str(), r)).collect();
+            (url.clone(), by_file_name)
+        })
+        .collect();
+
+    let packages: Vec<_> = locked
+        .into_iter()
+        .zip(package_urls.iter().zip(&package_channels))
+        .map(|(package, (package_url, channel))| {
+            let (status, details) = match channel {
+                None => (PackageStatus::OutsideChannels, None),
+                Some(channel) => {
+                    let record = records[&package_url.subdir.url(channel)]
+                        .get(package_url.file_name.as_str());
+                    match record {
+                        None => (PackageStatus::Yanked, None),
+                        Some(record) => match hash_differences(&package, record) {
+                            None => (PackageStatus::Ok, None),
+                            Some(details) => (PackageStatus::Modified, Some(details)),
+                        },
+                    }
+                }
+            };
+            LockedPackageStatus {
+                url: package.url,
+                status,
+                details,
+            }
+        })
+        .collect();
+
+    Ok(LockfileValidation {
+        valid: packages.iter().all(|p| p.status == PackageStatus::Ok),
+        packages,
+    })
+}
+
+/// Explicit files are marked by an `@EXPLICIT` line, which conda-lock files never contain
+fn detect_format(lockfile: &str) -> LockfileFormat {
+    if lockfile.lines().any(|line| line.trim() == "@EXPLICIT") {
+        LockfileFormat::Explicit
+    } else {
+        LockfileFormat::CondaLock
+    }
+}
+
+/// Returns the channels of the lockfile and its conda packages for the platform (or for every
+/// platform). The pip packages a conda-lock file may contain are not checked.
+fn parse_conda_lock(
+    lockfile: &str,
+    platform: Option<&str>,
+) -> Result<(Vec<String>, Vec<LockedPackage>), ValidationError> {
+    let file: CondaLockFile = serde_yaml::from_str(lockfile).map_err(|e| {
+        ValidationError::Lockfile(ParseError {
+            input: lockfile.to_string(),
+            error: e.to_string(),
+        })
+    })?;
+    let channels = file.metadata.channels.into_iter().map(|c| c.url).collect();
+    let packages = file
+        .package
+        .into_iter()
+        .filter(|p| {
+            p.manager == "conda" && platform.map_or(true, |platform| p.platform == platform)
+        })
+        .map(|p| LockedPackage {
+            url: p.url,
+            md5: p.hash.md5,
+            sha256: p.hash.sha256,
+        })
+        .collect();
+    Ok((channels, packages))
+}
+
+/// Returns the packages of an explicit file, whose URLs may be anchored by their md5 hash (or by
+/// their sha256 hash, prefixed by `sha256:`)
+fn parse_explicit(lockfile: &str) -> Vec<LockedPackage> {
+    lockfile
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && *line != "@EXPLICIT")
+        .map(|line| {
+            let (url, anchor) = line.split_once('#').unwrap_or((line, ""));
+            let (md5, sha256) = match anchor.strip_prefix("sha256:") {
+                Some(sha256) => (None, Some(sha256.to_string())),
+                None if anchor.is_empty() => (None, None),
+                None => (Some(anchor.to_string()), None),
+            };
+            LockedPackage {
+                url: url.to_string(),
+                md5,
+                sha256,
+            }
+        })
+        .collect()
+}
+
+fn same_channel(a: &Channel, b: &Channel) -> bool {
+    a.base_url.as_str().trim_end_matches('/') == b.base_url.as_str().trim_end_matches('/')
+}
+
+/// Describes how the hashes of the record differ from those of the lockfile, if they do. Hashes
+/// that only one of them has are not compared.
+fn hash_differences(package: &LockedPackage, record: &RepoDataRecord) -> Option<String> {
+    let actual_md5 = record.package_record.md5.map(|md5| format!("{md5:x}"));
+    let actual_sha256 = record
+        .package_record
+        .sha256
+        .map(|sha256| format!("{sha256:x}"));
+    let differences: Vec<_> = [
+        ("md5", &package.md5, actual_md5),
+        ("sha256", &package.sha256, actual_sha256),
+    ]
+    .into_iter()
+    .filter_map(|(name, locked, actual)| match (locked, actual) {
+        (Some(locked), Some(actual)) if !locked.eq_ignore_ascii_case(&actual) => Some(format!(
+            "the {name} is {locked} in the lockfile, but {actual} in the channel"
+        )),
+        _ => None,
+    })
+    .collect();
+    (!differences.is_empty()).then(|| differences.join("; "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_explicit() {
+        let packages = parse_explicit(
+            "# platform: linux-64\n\
+             @EXPLICIT\n\
+             https://conda.anaconda.org/conda-forge/linux-64/foo-1.0-0.conda#d65ab674acf3b7294ebacaec05fc5b54\n\
+             https://conda.anaconda.org/conda-forge/noarch/bar-1.0-0.conda#sha256:97ec377d\n\
+             \n\
+             https://conda.anaconda.org/conda-forge/noarch/baz-1.0-0.tar.bz2\n",
+        );
+        let parsed: Vec<_> = packages
+            .iter()
+            .map(|p| (p.url.as_str(), p.md5.as_deref(), p.sha256.as_deref()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                (
+                    "https://conda.anaconda.org/conda-forge/linux-64/foo-1.0-0.conda",
+                    Some("d65ab674acf3b7294ebacaec05fc5b54"),
+                    None
+                ),
+                (
+                    "https://conda.anaconda.org/conda-forge/noarch/bar-1.0-0.conda",
+                    None,
+                    Some("97ec377d")
+                ),
+                (
+                    "https://conda.anaconda.org/conda-forge/noarch/baz-1.0-0.tar.bz2",
+                    None,
+                    None
+                ),
+            ]
+        );
+        assert_eq!(detect_format("@EXPLICIT\n"), LockfileFormat::Explicit);
+        assert_eq!(detect_format("version: 1\n"), LockfileFormat::CondaLock);
+    }
+}
@@ -0,0 +1,115 @@
+//! Sets up tracing with a log level that can be changed while the server is running
+
+use crate::cli::Args;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::format::{format, FmtSpan};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The log level used when the server starts
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::TRACE;
+
+/// Allows reading and changing the server's log level at runtime
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+    current: Mutex<LevelFilter>,
+}
+
+impl LogLevelHandle {
+    /// Returns the log level currently in use
+    pub fn current(&self) -> LevelFilter {
+        *self.current.lock().unwrap()
+    }
+
+    /// Replaces the log level, taking effect immediately
+    pub fn set(&self, level: LevelFilter) -> anyhow::Result<()> {
+        // Hold the lock during the reload, so concurrent updates can't leave `current` out of sync
+        let mut current = self.current.lock().unwrap();
+        self.handle.reload(env_filter(level))?;
+        *current = level;
+        Ok(())
+    }
+}
+
+/// Installs the global tracing subscriber, returning a handle to change its log level
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+pub fn init(args: &Args) -> anyhow::Result<LogLevelHandle> {
+    // TODO: this is all right for prototyping, but we will want to use a different subscriber for
+    // production
+    let (filter, handle) = reloadable_filter(DEFAULT_LOG_LEVEL);
+    let subscriber = tracing_subscriber::registry().with(filter).with(
+        tracing_subscriber::fmt::layer()
+            .event_format(format().pretty())
+            .with_span_events(FmtSpan::CLOSE),
+    );
+
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(crate::telemetry::layer(&args.otlp)?);
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(handle)
+}
+
+/// Creates a filter layer for the given level, together with the handle to change it
+pub fn reloadable_filter(
+    level: LevelFilter,
+) -> (reload::Layer<EnvFilter, Registry>, LogLevelHandle) {
+    let (filter, handle) = reload::Layer::new(env_filter(level));
+    let handle = LogLevelHandle {
+        handle,
+        current: Mutex::new(level),
+    };
+    (filter, handle)
+}
+
+fn env_filter(level: LevelFilter) -> EnvFilter {
+    EnvFilter::new(format!("rattler_server={level}"))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tracing::{event, Level, Subscriber};
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::Layer;
+
+    /// A layer that counts the events it sees
+    #[derive(Clone, Default)]
+    pub struct EventCounter(pub Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for EventCounter {
+        fn on_event(&self, _event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl EventCounter {
+        pub fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn test_set_log_level_takes_effect() {
+        let (filter, handle) = reloadable_filter(LevelFilter::INFO);
+        let counter = EventCounter::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(counter.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            event!(Level::DEBUG, "suppressed");
+            assert_eq!(counter.count(), 0);
+
+            handle.set(LevelFilter::DEBUG).unwrap();
+            assert_eq!(handle.current(), LevelFilter::DEBUG);
+
+            event!(Level::DEBUG, "emitted");
+            assert_eq!(counter.count(), 1);
+        });
+    }
+}
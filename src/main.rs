@@ -1,135 +1,725 @@
+mod admin;
+mod api_keys;
+mod audit;
+mod auth;
+mod availability;
 mod available_packages_cache;
+mod batch;
+mod cache_warming;
+mod caching;
+mod channel_policy;
+mod channel_priority;
+mod channel_settings;
+mod channel_watch;
+mod channels;
 mod cli;
+mod coalesce;
+mod conda_lock;
+mod config;
+mod constraints;
+mod credentials;
+mod custom_channels;
+mod deterministic;
+mod diff;
+mod disk_cache;
+mod download;
 mod dto;
+mod environment_yml;
 mod error;
+mod exclude;
+mod explicit;
+mod extract;
 mod generic_cache;
+mod graph;
+mod grpc;
+mod health;
+mod installed_packages;
+mod interned;
+mod jobs;
+mod license_filter;
+mod listener;
+mod lockfile;
+mod logging;
+mod match_mode;
+mod metrics;
+mod msgpack;
+mod multi_platform;
+mod oci;
+mod openapi;
+mod outbound;
+mod output;
+mod package_format;
+mod pixi_lock;
+mod problem_report;
+mod progress;
+mod rate_limit;
+mod redact;
+mod repodata_store;
+mod request_timeout;
+mod retry;
+mod run_exports;
+mod s3;
+mod selftest;
+mod shards;
+mod shutdown;
+mod snapshot_date;
+mod solver_pool;
+mod sparse;
+mod subdir;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod tenants;
+mod track_features;
+mod v1;
+mod version;
 
+use crate::channel_settings::ChannelSettings;
 use crate::cli::Args;
-use crate::dto::{SolveEnvironment, SolveEnvironmentOk};
-use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
-use anyhow::Context;
+use crate::config::{ConfigReloader, Settings};
+use crate::credentials::{ChannelCredentials, EnvCredentials};
+use crate::dto::{
+    Depth, RepodataTiming, SolveEnvironment, SolveEnvironmentOk, SolveSummary, SolveTimings,
+    SolverStats,
+};
+use crate::error::{
+    response_from_error, ApiError, LimitExceeded, ParseError, ParseErrors, SolvePhase,
+    ValidationError,
+};
+use crate::extract::SolveRequest;
+use crate::subdir::Subdir;
+use api_keys::ApiKeys;
 use available_packages_cache::AvailablePackagesCache;
-use axum::extract::State;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware;
 use axum::response::{IntoResponse, Response};
-use axum::{routing::post, Json, Router};
+use axum::routing::{get, post};
+use axum::Router;
+use channel_priority::apply_channel_priority;
 use clap::Parser;
-use cli::Solver;
-use futures::{StreamExt, TryStreamExt};
+use cli::{PipDependencies, Solver};
+use coalesce::Coalescer;
+use exclude::{exclude_packages, parse_exclude_specs};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use generic_cache::GenericCache;
+use installed_packages::{parse_installed_packages, resolve_installed_packages};
+use license_filter::apply_license_deny;
+use match_mode::apply_match_mode;
+use metrics::Metrics;
+use output::OutputParams;
+use package_format::filter_package_format;
+use progress::Progress;
+use rate_limit::ClientRateLimits;
 use rattler_conda_types::{
-    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, PackageName, PackageRecord, Platform,
-    RepoDataRecord,
+    GenericVirtualPackage, MatchSpec, PackageName, PackageRecord, Platform, RepoDataRecord,
 };
-use rattler_solve::{libsolv_c, resolvo, SolverImpl, SolverTask};
+use rattler_solve::{libsolv_c, resolvo, SolveError, SolverImpl, SolverTask};
+use redact::redact_url;
 
+use anyhow::Context;
+use logging::LogLevelHandle;
+use repodata_store::RepodataStore;
+use request_timeout::{within_deadline, RequestTimeouts, SolveDeadline};
+use retry::RetryPolicy;
+use snapshot_date::filter_snapshot_date;
+use solver_pool::SolverPool;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tenants::Tenants;
+use tower_http::compression::CompressionLayer;
 use tracing::{span, Instrument, Level};
-use tracing_subscriber::fmt::format::{format, FmtSpan};
+use track_features::apply_track_features_preferences;
 
-struct AppState {
-    available_packages: AvailablePackagesCache,
-    concurrent_repodata_downloads_per_request: usize,
-    channel_config: ChannelConfig,
-    solver: Solver,
-}
-
-/// Checks the `AvailablePackagesCache` every minute to remove outdated entries
-async fn cache_gc_task(state: Arc<AppState>) {
-    let mut interval_timer = tokio::time::interval(Duration::from_secs(60));
-    loop {
-        interval_timer.tick().await;
-        state.available_packages.gc();
-    }
+pub struct AppState {
+    available_packages: Arc<AvailablePackagesCache>,
+    /// Replaced whenever the config file is reloaded
+    settings: RwLock<Arc<Settings>>,
+    /// Absent if no config file was given
+    config: Option<ConfigReloader>,
+    /// The virtual packages of requests that don't specify any, or absent to derive them from the
+    /// request's platform
+    default_virtual_packages: Option<Vec<String>>,
+    solver_pool: SolverPool,
+    /// The solves in flight, keyed by [`caching::solve_key`], which identical requests share
+    solves: Coalescer<String, Result<(Vec<RepoDataRecord>, SolverStats), ApiError>>,
+    /// The results of successful solves, keyed like `solves`. Absent if disabled.
+    solve_results: Option<GenericCache<String, (Vec<RepoDataRecord>, SolverStats)>>,
+    pip_dependencies: PipDependencies,
+    max_solves_per_batch: usize,
+    admin_token: Option<String>,
+    /// Absent if anyone may use the server
+    api_keys: Option<ApiKeys>,
+    /// Absent if requests without an API key are not rate limited
+    client_rate_limits: Option<ClientRateLimits>,
+    tenants: Option<Tenants>,
+    /// Absent if solves are not audited
+    audit_log: Option<audit::AuditLog>,
+    selftest: cli::SelftestArgs,
+    request_timeouts: RequestTimeouts,
+    /// How long fetching and solving may take for requests that don't specify a timeout, if
+    /// limited
+    solve_timeout: Option<Duration>,
+    /// The solves started through `/jobs`, whose results are polled for
+    jobs: jobs::Jobs,
+    warm_up: health::WarmUp,
+    run_exports: run_exports::RunExportsCache,
+    metrics_route: String,
+    swagger_ui: bool,
+    metrics: Arc<Metrics>,
+    /// Absent when tracing was not initialized through [`logging::init`] (e.g. during tests)
+    log_level: Option<LogLevelHandle>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli_args = Args::parse();
+    let config = cli_args
+        .config_file
+        .clone()
+        .map(|path| ConfigReloader::new(path, cli_args.clone()));
+    let args = match &config {
+        Some(config) => config.load()?,
+        None => cli_args,
+    };
 
-    // TODO: this is all right for prototyping, but we will want to use a different subscriber for
-    // production
-    let subscriber = tracing_subscriber::fmt()
-        .event_format(format().pretty())
-        .with_span_events(FmtSpan::CLOSE)
-        .with_env_filter("rattler_server=trace")
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    let log_level = logging::init(&args)?;
+    outbound::configure(&args)?;
 
-    let state = Arc::new(state_from_args(&args));
+    let mut state = state_from_args(&args)?;
+    state.log_level = Some(log_level);
+    state.config = config;
+    let state = Arc::new(state);
 
-    tokio::spawn(cache_gc_task(state.clone()));
+    #[cfg(unix)]
+    if state.config.is_some() {
+        tokio::spawn(config::reload_on_hangup(Arc::downgrade(&state)));
+    }
 
-    let app = app(state);
+    let watched_channels = args
+        .watch_channels
+        .iter()
+        .map(|channel| state.settings().parse_channel(channel))
+        .collect::<Result<Vec<_>, _>>()
+        .context("parsing the watched channels")?;
+    if !watched_channels.is_empty() {
+        tokio::spawn(channel_watch::watch_channels(
+            Arc::downgrade(&state),
+            watched_channels,
+            Duration::from_secs(args.watch_interval_seconds),
+        ));
+    }
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", args.port))
-        .await
-        .unwrap();
+    let warm_subdirs = args
+        .warm_subdirs
+        .iter()
+        .map(|subdir| {
+            let settings = state.settings();
+            cache_warming::parse_warm_subdir(
+                subdir,
+                &settings.channel_config,
+                &settings.custom_channels,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(anyhow::Error::msg)
+        .context("parsing the subdirs to warm")?;
+    if !warm_subdirs.is_empty() {
+        tokio::spawn(cache_warming::warm_subdirs(
+            Arc::downgrade(&state),
+            warm_subdirs,
+            Duration::from_secs(args.warm_interval_seconds),
+        ));
+    }
+
+    if args.disk_cache_gc_interval_seconds > 0 {
+        tokio::spawn(disk_cache::collect_garbage(
+            Arc::downgrade(&state),
+            args.cache_dir.clone(),
+            match args.disk_cache_budget_megabytes {
+                0 => None,
+                megabytes => Some(megabytes * 1024 * 1024),
+            },
+            Duration::from_secs(args.disk_cache_gc_interval_seconds),
+        ));
+    }
+
+    let grace_period = Duration::from_secs(args.shutdown_grace_period_seconds);
+    let grpc = match args.grpc_port {
+        Some(port) => {
+            let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}"))
+                .await
+                .context("binding the gRPC port")?;
+            Some(tokio::spawn(grpc::serve(
+                listener,
+                state.clone(),
+                shutdown::signal(),
+            )))
+        }
+        None => None,
+    };
+
+    let app = app(state.clone());
+
+    let listener = listener::Listener::bind(&args).await?;
+
+    shutdown::serve(listener, app, state, grace_period, shutdown::signal()).await?;
 
-    axum::serve(listener, app.into_make_service()).await?;
+    // The gRPC calls in flight got the same grace period as the HTTP requests
+    if let Some(grpc) = grpc {
+        match tokio::time::timeout(grace_period, grpc).await {
+            Ok(result) => result?.context("serving gRPC")?,
+            Err(_) => tracing::warn!("the gRPC calls in flight did not finish in time"),
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
 
     Ok(())
 }
 
-fn state_from_args(args: &Args) -> AppState {
+fn state_from_args(args: &Args) -> anyhow::Result<AppState> {
     let cache_expiration = Duration::from_secs(args.repodata_cache_expiration_seconds);
+    let tenants = args
+        .tenants_file
+        .as_deref()
+        .map(Tenants::from_path)
+        .transpose()?;
 
-    AppState {
-        available_packages: AvailablePackagesCache::new(cache_expiration, args.cache_dir.clone()),
-        concurrent_repodata_downloads_per_request: args.concurrent_repodata_downloads_per_request,
-        channel_config: ChannelConfig::default(),
-        solver: args.solver,
+    let mut available_packages = AvailablePackagesCache::new(
+        cache_expiration,
+        match args.repodata_snapshot_retention_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        args.cache_dir.clone(),
+        args.repodata_cache_mode,
+        match args.repodata_cache_gc_interval_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        args.repodata_parse_concurrency,
+        match args.repodata_cache_budget_megabytes {
+            0 => None,
+            megabytes => Some(megabytes * 1024 * 1024),
+        },
+        Duration::from_secs(args.repodata_cache_max_staleness_seconds),
+    );
+    if let Some(path) = &args.credentials_file {
+        available_packages
+            .set_download_credentials(EnvCredentials::from_env().with_credentials_file(path)?);
+    }
+    if let Some(path) = &args.channel_settings_file {
+        available_packages.set_channel_settings(ChannelSettings::from_path(path)?);
+    }
+    if let Some(dir) = &args.persisted_repodata_dir {
+        available_packages.set_repodata_store(RepodataStore::new(dir.clone()));
+    }
+    available_packages.set_offline(args.offline);
+    available_packages.set_retry_policy(RetryPolicy {
+        max_attempts: args.download_max_attempts,
+        initial_backoff: Duration::from_millis(args.download_retry_backoff_ms),
+        max_backoff: Duration::from_millis(args.download_retry_max_backoff_ms),
+        statuses: args
+            .download_retry_statuses
+            .iter()
+            .map(|&status| reqwest::StatusCode::from_u16(status))
+            .collect::<Result<_, _>>()?,
+    });
+    let metrics = Arc::new(Metrics::default());
+    let solve_results = (args.solve_cache_size > 0).then(|| {
+        GenericCache::with_expiration(solve_results_expiration(args))
+            .with_capacity(args.solve_cache_size)
+    });
+    available_packages.set_metrics(metrics.clone());
+    let mut solver_pool = SolverPool::new(
+        args.solver_threads,
+        (args.solver_queue_size > 0).then_some(args.solver_queue_size),
+    )?;
+    solver_pool.set_metrics(metrics.clone());
+    for spec in args.default_virtual_packages.iter().flatten() {
+        parse_virtual_package(spec).map_err(|e| {
+            anyhow::anyhow!("invalid default virtual package `{spec}`: {}", e.error)
+        })?;
     }
+
+    Ok(AppState {
+        available_packages: Arc::new(available_packages),
+        settings: RwLock::new(Arc::new(Settings::from_args(args)?)),
+        config: None,
+        default_virtual_packages: args.default_virtual_packages.clone(),
+        solver_pool,
+        solves: Coalescer::default(),
+        solve_results,
+        pip_dependencies: args.pip_dependencies,
+        max_solves_per_batch: args.max_solves_per_batch,
+        admin_token: args.admin_token.clone(),
+        api_keys: args
+            .api_keys_file
+            .as_deref()
+            .map(ApiKeys::from_path)
+            .transpose()?,
+        client_rate_limits: args.rate_limit_per_minute.map(|requests_per_minute| {
+            ClientRateLimits::new(
+                requests_per_minute,
+                args.rate_limit_burst,
+                args.rate_limit_forwarded_for,
+            )
+        }),
+        tenants,
+        audit_log: args
+            .audit_log
+            .as_deref()
+            .map(|path| audit::AuditLog::open(path, args.rate_limit_forwarded_for))
+            .transpose()?,
+        selftest: args.selftest.clone(),
+        request_timeouts: RequestTimeouts::new(args.request_timeout_seconds, &args.route_timeouts),
+        solve_timeout: (args.solve_timeout_seconds > 0)
+            .then(|| Duration::from_secs(args.solve_timeout_seconds)),
+        jobs: jobs::Jobs::new(Duration::from_secs(args.job_retention_seconds)),
+        warm_up: health::WarmUp::new(args.warm_subdirs.len()),
+        run_exports: run_exports::RunExportsCache::new(cache_expiration),
+        metrics_route: args.metrics_route.clone(),
+        swagger_ui: args.swagger_ui,
+        metrics,
+        log_level: None,
+    })
+}
+
+/// How long solve results are cached: as long as the repodata they were computed from, at most
+fn solve_results_expiration(args: &Args) -> Duration {
+    let cache_expiration = Duration::from_secs(args.repodata_cache_expiration_seconds);
+    args.solve_cache_expiration_seconds
+        .map_or(cache_expiration, Duration::from_secs)
+        .min(cache_expiration)
 }
 
 fn app(state: Arc<AppState>) -> Router {
-    Router::new()
+    let api = Router::new()
         .route("/solve", post(solve_environment))
-        .with_state(state)
+        .route("/solve/multi", post(multi_platform::solve_multi_platform))
+        .route("/solve/batch", post(batch::solve_batch))
+        .route(
+            progress::EVENTS_ROUTE,
+            post(progress::solve_environment_events),
+        )
+        .route("/jobs", post(jobs::create_job))
+        .route("/jobs/:id", get(jobs::get_job).delete(jobs::delete_job))
+        .route("/explicit", post(explicit::explicit_environment))
+        .route("/diff", post(diff::environment_diff))
+        .route("/lockfile/validate", post(lockfile::validate_lockfile))
+        .route("/check", post(availability::check_specs))
+        .route("/run-exports", post(run_exports::run_exports))
+        .route("/version", get(version::get_version))
+        .route("/selftest", get(selftest::selftest))
+        .route("/channels/validate", get(channels::validate_channel))
+        .route(
+            "/channels/:channel/packages/:name",
+            get(channels::package_metadata),
+        )
+        .route(
+            "/channels/:channel/:platform/search",
+            get(channels::search_packages),
+        )
+        .route(
+            "/channels/:channel/:platform/whoneeds",
+            get(channels::package_dependents),
+        )
+        .route(
+            "/channels/:channel/:platform/packages/:name/:version/:build",
+            get(channels::package_build),
+        );
+    // The unversioned routes are kept for the clients that predate the versioned API
+    let mut router = Router::new().nest(v1::PREFIX, api.clone()).merge(api);
+    if state.api_keys.is_some() {
+        router = router.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::require_api_key,
+        ));
+    }
+    if state.client_rate_limits.is_some() {
+        router = router.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_clients,
+        ));
+    }
+
+    // Metrics are scraped, probes are answered and the API is documented without an API key, and
+    // the admin routes have a token of their own
+    router = router
+        .route(&state.metrics_route, get(metrics::get_metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route(openapi::OPENAPI_ROUTE, get(openapi::openapi_json));
+    if state.swagger_ui {
+        router = router.route(openapi::SWAGGER_UI_ROUTE, get(openapi::swagger_ui));
+    }
+    if state.admin_token.is_some() {
+        router = router.merge(admin::router(state.clone()));
+    }
+
+    let router = router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            request_timeout::enforce,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_in_flight,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit::identify_client,
+        ))
+        .layer(middleware::from_fn(msgpack::negotiate))
+        // Compresses responses with zstd or gzip, as requested through `Accept-Encoding`
+        .layer(CompressionLayer::new());
+    // Nests the spans of every request below the span of the caller, if any
+    #[cfg(feature = "otlp")]
+    let router = router.layer(middleware::from_fn(telemetry::propagate_context));
+    router.with_state(state)
 }
 
+/// Solves an environment, given as JSON or as an `environment.yml` file
+#[utoipa::path(
+    post,
+    path = "/v1/solve",
+    params(OutputParams),
+    request_body(
+        content = SolveEnvironment,
+        description = "The environment to solve, or an `environment.yml` file when sent with a YAML content type"
+    ),
+    responses(
+        (status = 200, description = "The solution, in the requested format", body = SolveEnvironmentOk),
+        (status = 304, description = "The solution did not change since the one in `If-None-Match`"),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 401, description = "The API key or tenant token is missing or invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 409, description = "The environment cannot be solved", body = SolveEnvironmentUnsolvable),
+        (status = 429, description = "The server is too busy, or the rate limit was exceeded", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+        (status = 503, description = "The repodata is not available", body = ErrorResponse),
+        (status = 504, description = "The request or solve timed out", body = ErrorResponse),
+    )
+)]
 #[tracing::instrument(level = "info", skip(state))]
 async fn solve_environment(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<SolveEnvironment>,
+    Query(output): Query<OutputParams>,
+    headers: HeaderMap,
+    SolveRequest(payload): SolveRequest,
+) -> Response {
+    solve_response(state, &headers, &payload, &output, true).await
+}
+
+/// Solves an environment into a response, which is a `304 Not Modified` if `conditional` is set and
+/// the client sent the ETag of the solution in `If-None-Match`
+pub async fn solve_response(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    payload: &SolveEnvironment,
+    output: &OutputParams,
+    conditional: bool,
 ) -> Response {
-    let result = solve_environment_inner(state, payload).await;
+    state.metrics.record_solve_request();
+    let settings = state.settings();
+    let result = solve_environment_inner(state, headers, payload, output, conditional).await;
     match result {
-        Ok(packages) => Json(SolveEnvironmentOk { packages }).into_response(),
+        Ok(SolveOutcome::Solved {
+            mut solution,
+            etag,
+            solve_key,
+            max_age,
+        }) => {
+            if output.include_graph {
+                solution.graph = Some(graph::dependency_graph(&solution.packages));
+            }
+            if !output.debug {
+                solution.solver_stats = None;
+                solution.timings = None;
+            }
+            let start = Instant::now();
+            let mut response =
+                output::render(output.format, &settings, payload, solution, &solve_key);
+            if output.debug {
+                // Serializing happens after the timings in the body are known
+                let serialize_ms = start.elapsed().as_secs_f64() * 1000.0;
+                if let Ok(value) =
+                    HeaderValue::from_str(&format!("serialize;dur={serialize_ms:.3}"))
+                {
+                    response.headers_mut().insert("server-timing", value);
+                }
+            }
+            if let Some(etag) = etag {
+                caching::insert_headers(response.headers_mut(), &etag, max_age);
+            }
+            response
+        }
+        Ok(SolveOutcome::NotModified { etag, max_age }) => {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            caching::insert_headers(response.headers_mut(), &etag, max_age);
+            response
+        }
         Err(e) => response_from_error(e),
     }
 }
 
+/// The outcome of a successful solve request
+pub enum SolveOutcome {
+    /// The environment was solved. The `etag` is absent if the client asked for the response not
+    /// to be cached. The `solve_key` identifies the solution (see [`caching::solve_key`]), with
+    /// the server's defaults and the profile of the request resolved.
+    Solved {
+        solution: SolveEnvironmentOk,
+        etag: Option<String>,
+        solve_key: String,
+        max_age: Duration,
+    },
+    /// The client already has the solution, identified by the `etag` it sent
+    NotModified { etag: String, max_age: Duration },
+}
+
+/// Solves an environment, recording the solve in the audit log (if any). The outcome is only
+/// [`SolveOutcome::NotModified`] if the solve is `conditional`, i.e. its response may be served
+/// based on the client's `If-None-Match`.
 async fn solve_environment_inner(
     state: Arc<AppState>,
-    payload: SolveEnvironment,
-) -> Result<Vec<RepoDataRecord>, ApiError> {
+    headers: &HeaderMap,
+    payload: &SolveEnvironment,
+    output: &OutputParams,
+    conditional: bool,
+) -> Result<SolveOutcome, ApiError> {
+    let start = Instant::now();
+    let result =
+        solve_environment_unaudited(state.clone(), headers, payload, output, conditional).await;
+    if let Some(audit_log) = &state.audit_log {
+        audit_log.record(&state, headers, payload, &result, start.elapsed());
+    }
+    result
+}
+
+/// Solves an environment for callers that never respond with `304 Not Modified`, regardless of
+/// the client's `If-None-Match`
+pub async fn solve_unconditionally(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    payload: &SolveEnvironment,
+    output: &OutputParams,
+) -> Result<SolveEnvironmentOk, ApiError> {
+    let (solution, _) = solve_keyed(state, headers, payload, output).await?;
+    Ok(solution)
+}
+
+/// Like [`solve_unconditionally`], also returning the key that identifies the solution (see
+/// [`SolveOutcome::Solved`])
+pub async fn solve_keyed(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    payload: &SolveEnvironment,
+    output: &OutputParams,
+) -> Result<(SolveEnvironmentOk, String), ApiError> {
+    match solve_environment_inner(state, headers, payload, output, false).await? {
+        SolveOutcome::Solved {
+            solution,
+            solve_key,
+            ..
+        } => Ok((solution, solve_key)),
+        SolveOutcome::NotModified { .. } => {
+            unreachable!("unconditional solves are never unmodified")
+        }
+    }
+}
+
+async fn solve_environment_unaudited(
+    state: Arc<AppState>,
+    headers: &HeaderMap,
+    payload: &SolveEnvironment,
+    output: &OutputParams,
+    conditional: bool,
+) -> Result<SolveOutcome, ApiError> {
     let root_span = span!(Level::TRACE, "solve_environment");
     let _enter = root_span.enter();
+    let request_start = Instant::now();
 
-    // Get match specs
-    let mut matchspecs = Vec::with_capacity(payload.specs.len());
-    let mut invalid_matchspecs = Vec::new();
-    for spec in &payload.specs {
-        match MatchSpec::from_str(spec) {
-            Ok(spec) => matchspecs.push(spec),
-            Err(e) => invalid_matchspecs.push(ParseError {
-                input: spec.to_string(),
-                error: e.to_string(),
-            }),
+    // The whole request uses the same settings, even if they are reloaded in the meantime
+    let settings = state.settings();
+
+    // Requests without virtual packages or a solver get the server's defaults, which are filled in
+    // so they are part of the request's cache keys. Profiles are replaced by their virtual
+    // packages too, so changing a profile changes the keys of the requests that use it.
+    let payload = match (&payload.virtual_packages, &payload.profile, payload.solver) {
+        (Some(_), None, Some(_)) => Cow::Borrowed(payload),
+        (virtual_packages, profile, solver) => {
+            let virtual_packages = match profile {
+                Some(profile) => {
+                    let profile_packages = settings
+                        .virtual_package_profiles
+                        .get(profile)
+                        .ok_or_else(|| {
+                            ValidationError::Profile(ParseError {
+                                input: profile.clone(),
+                                error: "no such profile is configured".to_string(),
+                            })
+                        })?;
+                    match virtual_packages {
+                        Some(overrides) => override_virtual_packages(profile_packages, overrides),
+                        None => override_virtual_packages(
+                            &state.default_virtual_packages(&payload.platform),
+                            profile_packages,
+                        ),
+                    }
+                }
+                None => virtual_packages
+                    .clone()
+                    .unwrap_or_else(|| state.default_virtual_packages(&payload.platform)),
+            };
+            Cow::Owned(SolveEnvironment {
+                virtual_packages: Some(virtual_packages),
+                profile: None,
+                solver: Some(solver.unwrap_or(settings.solver)),
+                ..payload.clone()
+            })
         }
-    }
+    };
+    let payload = payload.as_ref();
+    let deadline = payload
+        .timeout_ms
+        .map(Duration::from_millis)
+        .or(state.solve_timeout)
+        .map(SolveDeadline::start);
 
-    // Forbid invalid matchspecs
-    if !invalid_matchspecs.is_empty() {
-        return Err(ApiError::Validation(ValidationError::MatchSpecs(
-            ParseErrors(invalid_matchspecs),
+    // Reject oversized requests before doing any work for them
+    if payload.channels.len() > settings.max_channels_per_request {
+        return Err(ApiError::Validation(ValidationError::TooManyChannels(
+            LimitExceeded {
+                count: payload.channels.len(),
+                limit: settings.max_channels_per_request,
+            },
+        )));
+    }
+    if payload.specs.len() > settings.max_specs_per_request {
+        return Err(ApiError::Validation(ValidationError::TooManySpecs(
+            LimitExceeded {
+                count: payload.specs.len(),
+                limit: settings.max_specs_per_request,
+            },
         )));
     }
 
+    let tenant = auth::authenticate_tenant(&state, headers)?;
+
+    // Get match specs
+    let matchspecs = parse_match_specs(&payload.specs)?;
+    let exclude = parse_exclude_specs(&payload.exclude)?;
+    let locked_packages = parse_installed_packages(&payload.locked_packages)?;
+    let pinned_packages = parse_installed_packages(&payload.pinned_packages)?;
+
     // Get the virtual packages
-    let mut virtual_packages = Vec::with_capacity(payload.virtual_packages.len());
-    for spec in &payload.virtual_packages {
+    let mut virtual_packages = Vec::new();
+    for spec in payload.virtual_packages.iter().flatten() {
         virtual_packages
             .push(parse_virtual_package(spec.as_str()).map_err(ValidationError::VirtualPackage)?);
     }
@@ -138,7 +728,7 @@ async fn solve_environment_inner(
     let mut channels = Vec::new();
     let mut invalid_channels = Vec::new();
     for channel in &payload.channels {
-        match Channel::from_str(channel, &state.channel_config) {
+        match settings.parse_channel(channel) {
             Ok(c) => channels.push(c),
             Err(e) => invalid_channels.push(ParseError {
                 input: channel.to_string(),
@@ -171,49 +761,403 @@ async fn solve_environment_inner(
 
     let default_platforms = &[target_platform, Platform::NoArch];
 
-    // The (channel, platform) combinations that have their own repodata.json
+    // Non-standard subdirs are fetched for every channel, in addition to the platform ones
+    let mut extra_subdirs = Vec::with_capacity(payload.extra_subdirs.len());
+    for name in &payload.extra_subdirs {
+        let subdir = Subdir::parse(name).map_err(|error| {
+            ValidationError::Subdir(ParseError {
+                input: name.clone(),
+                error,
+            })
+        })?;
+        extra_subdirs.push(subdir);
+    }
+
+    // The (channel, subdir) combinations that have their own repodata.json
     let channels_and_platforms = channels.into_iter().flat_map(|channel| {
         let platforms = channel
             .platforms
             .as_ref()
             .map(|p| p.as_slice())
-            .unwrap_or(default_platforms)
-            .to_vec();
+            .unwrap_or(default_platforms);
+        let subdirs: Vec<_> = platforms
+            .iter()
+            .map(|&p| Subdir::Platform(p))
+            .chain(extra_subdirs.iter().cloned())
+            .collect();
 
-        platforms.into_iter().map(move |p| (channel.clone(), p))
+        subdirs.into_iter().map(move |s| (channel.clone(), s))
     });
 
+    // Disallowed and private channels must be rejected before any download takes place
+    let channels_and_platforms: Vec<_> = channels_and_platforms.collect();
+    for (channel, _) in &channels_and_platforms {
+        settings.channel_policy.check(&channel.base_url)?;
+    }
+    if let Some(tenants) = &state.tenants {
+        for (channel, _) in &channels_and_platforms {
+            tenants.check_access(tenant, &channel.base_url)?;
+        }
+    }
+    let client = tenant.map(|t| t.client());
+
+    // Hosts with credentials supplied by the caller are downloaded with those instead
+    let mut channel_credentials = ChannelCredentials::from_headers(headers)?;
+    channel_credentials.merge(payload.channel_credentials.clone());
+    let caller_clients = channel_credentials.into_clients();
+
+    // The repodata is only copied out of the cache (or parsed, if sparse, or downloaded, if
+    // sharded) as far as the solve can reach from the specs and the installed packages. Without
+    // roots (e.g. because a spec has no name), the full repodata is needed.
+    let roots: Option<HashSet<_>> = matchspecs
+        .iter()
+        .map(|s| s.name.clone())
+        .chain(locked_packages.iter().map(|p| Some(p.name().clone())))
+        .chain(pinned_packages.iter().map(|p| Some(p.name().clone())))
+        .collect();
+
     // Get the available packages for each (channel, platform) combination
-    let available_packages: Vec<_> = futures::stream::iter(channels_and_platforms)
-        .map(|(channel, platform)| {
-            let state = &state;
-            async move { state.available_packages.get(&channel, platform).await }
+    let snapshots = futures::stream::iter(channels_and_platforms)
+        .map(|(channel, subdir)| {
+            let (state, roots) = (&state, &roots);
+            let caller_clients = &caller_clients;
+            async move {
+                let subdir_url = subdir.url(&channel);
+                let client = subdir_url
+                    .host_str()
+                    .and_then(|host| caller_clients.get(host))
+                    .or(client);
+                let platform_url = subdir_url.to_string();
+                let pinned_hash = payload.repodata_hashes.get(&platform_url);
+                progress::report(|| Progress::Fetching {
+                    channel: redact_url(&channel.base_url),
+                    platform: subdir.to_string(),
+                });
+                let pinned_hash = pinned_hash.map(String::as_str);
+                let start = Instant::now();
+                let cache = &state.available_packages;
+                let snapshot = match (payload.offline, roots.is_some()) {
+                    (true, _) => {
+                        cache
+                            .get_offline(&channel, &subdir, client, pinned_hash)
+                            .await?
+                    }
+                    (false, true) => {
+                        cache
+                            .get_sharded(&channel, &subdir, client, pinned_hash)
+                            .await?
+                    }
+                    (false, false) => cache.get(&channel, &subdir, client, pinned_hash).await?,
+                };
+                progress::report(|| Progress::Fetched {
+                    channel: redact_url(&channel.base_url),
+                    platform: subdir.to_string(),
+                    records: snapshot.records.count(),
+                });
+                let timing = RepodataTiming {
+                    duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+                    cache_hit: snapshot.cache_hit,
+                };
+                let expires_in = cache.expires_in(&channel, &subdir, client, &snapshot);
+                Ok::<_, ApiError>((platform_url, snapshot, timing, expires_in))
+            }
         })
-        .buffer_unordered(state.concurrent_repodata_downloads_per_request)
-        .try_collect()
-        .await?;
+        // The solver derives the channel priority from the order of the repodata, so it must
+        // match the order of the request's channels
+        .buffered(settings.concurrent_repodata_downloads_per_request)
+        .try_collect::<Vec<_>>();
+    let snapshots = within_deadline(deadline, SolvePhase::Fetching, snapshots).await?;
 
-    // This call will block for hundreds of milliseconds, or longer
-    let result = tokio::task::spawn_blocking(move || {
-        let problem = SolverTask {
-            available_packages: &available_packages,
-            virtual_packages,
-            specs: matchspecs,
-            locked_packages: Vec::new(),
-            pinned_packages: Vec::new(),
-        };
+    let mut repodata_hashes = BTreeMap::new();
+    let mut repodata_etags = BTreeMap::new();
+    let mut repodata_timings = BTreeMap::new();
+    let mut available_packages = Vec::with_capacity(snapshots.len());
+    // Clients may cache the solution until the first of its repo data expires
+    let mut max_age = Duration::MAX;
+    for (platform_url, snapshot, timing, expires_in) in snapshots {
+        max_age = max_age.min(expires_in.unwrap_or(Duration::MAX));
+        if let (true, Some(etag)) = (payload.deterministic, snapshot.etag()) {
+            repodata_etags.insert(platform_url.clone(), etag.to_string());
+        }
+        repodata_timings.insert(platform_url.clone(), timing);
+        repodata_hashes.insert(platform_url, snapshot.hash);
+        available_packages.push(snapshot.records);
+    }
+
+    // The solution is fully determined at this point, so there is no need to solve if the client
+    // already has it (debug responses are never cached, since their statistics vary)
+    let etag = (caching::is_cacheable(headers) && !output.debug).then(|| {
+        let msgpack = msgpack::accepts_msgpack(headers);
+        caching::etag(payload, output, &repodata_hashes, msgpack)
+    });
+    if let Some(etag) = etag.as_ref() {
+        if conditional && caching::if_none_match(headers, etag) {
+            return Ok(SolveOutcome::NotModified {
+                etag: etag.clone(),
+                max_age,
+            });
+        }
+    }
 
-        match state.solver {
-            Solver::Resolvo => resolvo::Solver.solve(problem),
-            Solver::Libsolvc => libsolv_c::Solver.solve(problem),
+    let prepare_start = Instant::now();
+
+    // Shards that cannot be downloaded are reported like repodata that cannot be
+    let available_packages = sparse::load_reachable(&available_packages, roots)
+        .await
+        .map_err(|err| match err.downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(err) => ApiError::Internal(err.context("parsing sparse repodata")),
+        })?;
+    let available_packages = filter_snapshot_date(payload.snapshot, available_packages);
+    let available_packages = filter_package_format(payload.package_format, available_packages);
+    let available_packages = exclude_packages(&exclude, available_packages);
+    let available_packages =
+        apply_license_deny(&payload.license_deny, &matchspecs, available_packages)?;
+    let (available_packages, original_track_features) =
+        apply_track_features_preferences(&payload.track_features_preferences, available_packages);
+    let (mut available_packages, original_channels) =
+        apply_channel_priority(payload.channel_priority, &matchspecs, available_packages);
+    let (matchspecs, loosened_specs) = match payload.match_mode {
+        Some(match_mode) => apply_match_mode(match_mode, matchspecs, &available_packages)?,
+        None => (matchspecs, Vec::new()),
+    };
+    let locked_packages = resolve_installed_packages(locked_packages, &available_packages)?;
+    let pinned_packages = resolve_installed_packages(pinned_packages, &available_packages)?;
+    let ties = payload.deterministic.then(|| {
+        deterministic::sort_candidates(&mut available_packages);
+        deterministic::Ties::find(&available_packages)
+    });
+
+    let root_names: HashSet<_> = matchspecs.iter().filter_map(|s| s.name.clone()).collect();
+    let prepare_ms = prepare_start.elapsed().as_secs_f64() * 1000.0;
+
+    // Identical requests against the same repodata share their solution, awaiting a single solve
+    // if they arrive concurrently
+    let key = caching::solve_key(payload, &repodata_hashes);
+    let cached = state
+        .solve_results
+        .as_ref()
+        .and_then(|results| results.get_fresh(&key));
+    let solver = payload.solver.unwrap_or(settings.solver);
+    let solve_start = Instant::now();
+    let solve_cache_hit = cached.is_some();
+    let (mut packages, solver_stats) = match cached {
+        Some(cached) => {
+            state.metrics.record_solve_cache_hit();
+            cached.as_ref().clone()
+        }
+        None => {
+            let (solve_state, key) = (state.clone(), key.clone());
+            let solve = state
+                .solves
+                .run(key.clone(), move || async move {
+                    let result = solve(
+                        &solve_state,
+                        SolveInput {
+                            available_packages,
+                            virtual_packages,
+                            specs: matchspecs,
+                            locked_packages,
+                            pinned_packages,
+                            solver,
+                        },
+                    )
+                    .await;
+                    if let (Ok(solution), Some(results)) = (&result, &solve_state.solve_results) {
+                        results.insert(key, Arc::new(solution.clone()));
+                    }
+                    result
+                })
+                .map(|result| copy_solve_result(&result));
+            within_deadline(deadline, SolvePhase::Solving, solve).await?
         }
+    };
+    let solve_ms = solve_start.elapsed().as_secs_f64() * 1000.0;
+    original_track_features.restore(&mut packages);
+    original_channels.restore(&mut packages);
+    let applied_constraints = constraints::applied_constraints(&packages);
+
+    // The solve always covers the full environment, which is then narrowed down if requested
+    if payload.depth == Depth::Direct {
+        packages.retain(|p| root_names.contains(&p.package_record.name));
+    }
+    let tie_breaks = ties.map_or_else(Vec::new, |ties| ties.tie_breaks(&packages));
+
+    let solution = SolveEnvironmentOk {
+        summary: SolveSummary::from_records(&packages),
+        packages,
+        loosened_specs,
+        applied_constraints,
+        repodata_hashes,
+        repodata_etags,
+        tie_breaks,
+        graph: None,
+        solver_stats: Some(solver_stats),
+        timings: Some(SolveTimings {
+            repodata: repodata_timings,
+            prepare_ms,
+            solve_ms,
+            solve_cache_hit,
+            total_ms: request_start.elapsed().as_secs_f64() * 1000.0,
+        }),
+    };
+    Ok(SolveOutcome::Solved {
+        solution,
+        etag,
+        solve_key: key,
+        max_age,
     })
-    .instrument(span!(Level::DEBUG, "solve"))
-    .await
-    .context("solver thread panicked")
-    .map_err(ApiError::Internal)?;
+}
+
+/// Everything the solver needs to know about a solve
+struct SolveInput {
+    available_packages: Vec<Vec<RepoDataRecord>>,
+    virtual_packages: Vec<GenericVirtualPackage>,
+    specs: Vec<MatchSpec>,
+    /// The installed packages, which are kept if possible
+    locked_packages: Vec<RepoDataRecord>,
+    /// The packages that may not change
+    pinned_packages: Vec<RepoDataRecord>,
+    solver: Solver,
+}
+
+/// Runs the solver on the solver thread pool, returning the sorted solution together with
+/// statistics about the solve
+async fn solve(
+    state: &AppState,
+    input: SolveInput,
+) -> Result<(Vec<RepoDataRecord>, SolverStats), ApiError> {
+    // This call will block for hundreds of milliseconds, or longer
+    let solver = input.solver;
+    let candidates_considered = input.available_packages.iter().map(Vec::len).sum();
+    progress::report(|| Progress::Solving {
+        candidates: candidates_considered,
+    });
+    let (result, stats, duration) = state
+        .solver_pool
+        .run(move || {
+            let available_packages = input.available_packages;
+            let problem = SolverTask {
+                available_packages: &available_packages,
+                virtual_packages: input.virtual_packages,
+                specs: input.specs,
+                locked_packages: input.locked_packages,
+                pinned_packages: input.pinned_packages,
+            };
+
+            // Neither backend exposes its internal counters, so only the duration is measured
+            let start = std::time::Instant::now();
+            let result = match solver {
+                Solver::Resolvo => resolvo::Solver.solve(problem),
+                Solver::Libsolvc => libsolv_c::Solver.solve(problem),
+            };
+            let duration = start.elapsed();
+            let stats = SolverStats {
+                duration_ms: duration.as_secs_f64() * 1000.0,
+                solver,
+                candidates_considered,
+                conflicts: None,
+                backtracks: None,
+            };
+            (result, stats, duration)
+        })
+        .instrument(span!(Level::DEBUG, "solve"))
+        .await?;
+    state.metrics.record_solve_duration(duration);
+
+    Ok((sort_solution(result?), stats))
+}
+
+/// Copies the result of a solve that may be shared with other requests. Solve errors are copied
+/// as they are, while internal errors keep only their message.
+fn copy_solve_result(
+    result: &Result<(Vec<RepoDataRecord>, SolverStats), ApiError>,
+) -> Result<(Vec<RepoDataRecord>, SolverStats), ApiError> {
+    match result {
+        Ok((packages, stats)) => Ok((packages.clone(), stats.clone())),
+        Err(ApiError::Solver(SolveError::Unsolvable(problems))) => {
+            Err(SolveError::Unsolvable(problems.clone()).into())
+        }
+        Err(ApiError::Solver(SolveError::UnsupportedOperations(operations))) => {
+            Err(SolveError::UnsupportedOperations(operations.clone()).into())
+        }
+        Err(ApiError::Solver(SolveError::ParseMatchSpecError(e))) => {
+            Err(SolveError::ParseMatchSpecError(e.clone()).into())
+        }
+        Err(ApiError::SolverQueueFull(exceeded)) => {
+            Err(ApiError::SolverQueueFull(exceeded.clone()))
+        }
+        Err(e) => Err(ApiError::Internal(anyhow::anyhow!("{e:#}"))),
+    }
+}
+
+/// Sorts the solved packages topologically, independently of the order in which the solver
+/// returned them, so identical solves always produce identical responses
+fn sort_solution(packages: Vec<RepoDataRecord>) -> Vec<RepoDataRecord> {
+    // The order doesn't depend on the input: rattler visits the graph roots and the dependencies of
+    // each package sorted by name, so packages on the same level always come out in the same order
+    PackageRecord::sort_topologically(packages)
+}
+
+/// Parses the specs, failing with all invalid specs if there are any
+fn parse_match_specs(specs: &[String]) -> Result<Vec<MatchSpec>, ApiError> {
+    let mut matchspecs = Vec::with_capacity(specs.len());
+    let mut invalid_matchspecs = Vec::new();
+    for spec in specs {
+        match MatchSpec::from_str(spec) {
+            Ok(spec) => matchspecs.push(spec),
+            Err(e) => invalid_matchspecs.push(ParseError {
+                input: spec.to_string(),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // Forbid invalid matchspecs
+    if !invalid_matchspecs.is_empty() {
+        return Err(ApiError::Validation(ValidationError::MatchSpecs(
+            ParseErrors(invalid_matchspecs),
+        )));
+    }
+
+    Ok(matchspecs)
+}
+
+impl AppState {
+    /// Returns the settings currently in use
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
 
-    Ok(PackageRecord::sort_topologically(result?))
+    #[cfg(test)]
+    fn settings_mut(&mut self) -> &mut Settings {
+        Arc::make_mut(self.settings.get_mut().unwrap())
+    }
+
+    /// Returns the virtual packages to solve for when a request doesn't specify any
+    fn default_virtual_packages(&self, platform: &str) -> Vec<String> {
+        if let Some(defaults) = &self.default_virtual_packages {
+            return defaults.clone();
+        }
+
+        match Platform::from_str(platform) {
+            Ok(platform) if platform.is_unix() => vec!["__unix".to_string()],
+            Ok(platform) if platform.is_windows() => vec!["__win".to_string()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Returns the virtual packages of `base` that `overrides` has none of the same name of, followed
+/// by `overrides`
+fn override_virtual_packages(base: &[String], overrides: &[String]) -> Vec<String> {
+    let name = |spec: &str| spec.split('=').next().unwrap_or_default().to_string();
+    let overridden: HashSet<_> = overrides.iter().map(|spec| name(spec)).collect();
+    base.iter()
+        .filter(|spec| !overridden.contains(&name(spec)))
+        .chain(overrides)
+        .cloned()
+        .collect()
 }
 
 fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage, ParseError> {
@@ -251,32 +1195,112 @@ fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channel_policy::ChannelPolicy;
+    use crate::custom_channels::CustomChannels;
+    use crate::dto::{
+        AppliedConstraint, ChannelPriority, FeaturePreference, MatchMode, PackageFormat,
+        PackageReference,
+    };
     use axum::body::Body;
+    use axum::extract::ConnectInfo;
     use axum::http;
     use axum::http::{header, Request, StatusCode};
+    use grpc::proto::rattler_server_client::RattlerServerClient;
     use mktemp::Temp;
     use mockito::{Mock, ServerGuard};
+    use rattler_conda_types::{Channel, ChannelConfig, RepoData};
     use reqwest::Url;
     use tower::util::ServiceExt;
 
     async fn dummy_app() -> (ServerGuard, Router) {
+        let (mock_channel_server, state) = dummy_state().await;
+        (mock_channel_server, app(Arc::new(state)))
+    }
+
+    async fn dummy_state() -> (ServerGuard, AppState) {
+        let mock_channel_server = mockito::Server::new_async().await;
         let temp_dir = Temp::new_dir().unwrap();
         let cache_dir = temp_dir.to_path_buf();
-        let mut state = state_from_args(&Args {
+        let state = state_from_args(&Args {
+            config_file: None,
             concurrent_repodata_downloads_per_request: 1,
             repodata_cache_expiration_seconds: u64::MAX,
+            repodata_cache_mode: cli::RepodataCacheMode::Parsed,
+            repodata_cache_budget_megabytes: 0,
+            repodata_cache_max_staleness_seconds: 0,
+            offline: false,
+            download_max_attempts: 1,
+            download_retry_backoff_ms: 0,
+            download_retry_max_backoff_ms: 0,
+            download_retry_statuses: Vec::new(),
+            repodata_cache_gc_interval_seconds: 0,
+            repodata_snapshot_retention_seconds: 0,
+            max_channels_per_request: 32,
+            channel_alias: Url::parse(&mock_channel_server.url()).unwrap(),
+            custom_channels: Vec::new(),
+            allowed_channels: Vec::new(),
+            denied_channels: Vec::new(),
+            max_specs_per_request: 10_000,
+            max_solves_per_batch: 50,
+            default_virtual_packages: None,
+            virtual_package_profiles: Default::default(),
             // The port is ignored during testing
             port: 0,
+            grpc_port: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            unix_socket: None,
             cache_dir,
+            disk_cache_budget_megabytes: 0,
+            disk_cache_gc_interval_seconds: 0,
+            shutdown_grace_period_seconds: 30,
             solver: Solver::Resolvo,
-        });
-
-        let mock_channel_server = mockito::Server::new_async().await;
-        state.channel_config = ChannelConfig {
-            channel_alias: Url::parse(&mock_channel_server.url()).unwrap(),
-        };
+            solver_threads: 1,
+            solver_queue_size: 0,
+            repodata_parse_concurrency: 1,
+            watch_channels: Vec::new(),
+            watch_interval_seconds: 60,
+            warm_subdirs: Vec::new(),
+            warm_interval_seconds: 60,
+            api_keys_file: None,
+            rate_limit_per_minute: None,
+            rate_limit_burst: None,
+            rate_limit_forwarded_for: false,
+            credentials_file: None,
+            http_proxy: None,
+            no_proxy: Vec::new(),
+            ca_certificates: Vec::new(),
+            channel_settings_file: None,
+            persisted_repodata_dir: None,
+            request_timeout_seconds: 0,
+            route_timeouts: Vec::new(),
+            solve_timeout_seconds: 0,
+            job_retention_seconds: 600,
+            solve_cache_size: 0,
+            solve_cache_expiration_seconds: None,
+            metrics_route: "/metrics".to_string(),
+            swagger_ui: false,
+            pip_dependencies: PipDependencies::Ignore,
+            admin_token: None,
+            tenants_file: None,
+            audit_log: None,
+            selftest: cli::SelftestArgs {
+                selftest_channel: "conda-forge".to_string(),
+                selftest_platform: Platform::Linux64,
+                selftest_spec: MatchSpec::from_str("foo").unwrap(),
+            },
+            #[cfg(feature = "otlp")]
+            otlp: cli::OtlpArgs {
+                otlp_endpoint: None,
+                otlp_service_name: "rattler-server".to_string(),
+                otlp_headers: Vec::new(),
+                otlp_sampling_ratio: 1.0,
+            },
+        })
+        .unwrap();
 
-        (mock_channel_server, app(Arc::new(state)))
+        (mock_channel_server, state)
     }
 
     fn default_solve_body() -> SolveEnvironment {
@@ -285,14 +1309,38 @@ mod tests {
             platform: "linux-64".to_string(),
             specs: Vec::new(),
             channels: vec!["conda-forge".to_string()],
-            virtual_packages: Vec::new(),
+            virtual_packages: Some(Vec::new()),
+            profile: None,
+            match_mode: None,
+            channel_priority: ChannelPriority::default(),
+            package_format: PackageFormat::default(),
+            license_deny: Vec::new(),
+            track_features_preferences: Default::default(),
+            exclude: Vec::new(),
+            repodata_hashes: BTreeMap::new(),
+            depth: Depth::default(),
+            extra_subdirs: Vec::new(),
+            snapshot: None,
+            locked_packages: Vec::new(),
+            pinned_packages: Vec::new(),
+            timeout_ms: None,
+            channel_credentials: Default::default(),
+            offline: false,
+            solver: None,
+            deterministic: false,
         }
     }
 
     async fn setup_repodata_mocks(mock_server: &mut ServerGuard) -> Vec<Mock> {
+        mock_linux64(mock_server, small_repodata_json()).await
+    }
+
+    /// Serves the given repodata for `conda-forge/linux-64`, and empty repodata for
+    /// `conda-forge/noarch`
+    async fn mock_linux64(mock_server: &mut ServerGuard, body: impl AsRef<[u8]>) -> Vec<Mock> {
         let endpoint1 = mock_server
             .mock("GET", "/conda-forge/linux-64/repodata.json")
-            .with_body(small_repodata_json())
+            .with_body(body)
             .create_async()
             .await;
 
@@ -306,17 +1354,61 @@ mod tests {
     }
 
     async fn post_solve(app: Router, body: SolveEnvironment) -> Response {
+        post_solve_with_query(app, "", body).await
+    }
+
+    async fn post_solve_with_query(app: Router, query: &str, body: SolveEnvironment) -> Response {
         let json = Body::from(serde_json::to_vec(&body).unwrap());
 
         let request = Request::builder()
-            .uri("/solve")
+            .uri(format!("/solve?{query}"))
             .method(http::Method::POST)
             .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
             .body(json)
             .unwrap();
-        let response = app.oneshot(request).await.unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn post_solve_environment_yml(app: Router, query: &str, body: &str) -> Response {
+        post_solve_environment_yml_as(app, "application/x-yaml", query, body).await
+    }
+
+    async fn post_solve_environment_yml_as(
+        app: Router,
+        content_type: &str,
+        query: &str,
+        body: &str,
+    ) -> Response {
+        let request = Request::builder()
+            .uri(format!("/solve?{query}"))
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
 
-        response
+    async fn send_admin_request(
+        app: Router,
+        method: http::Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> Response {
+        let mut request = Request::builder().uri(uri).method(method);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+                Body::from(serde_json::to_vec(&body).unwrap())
+            }
+            None => Body::empty(),
+        };
+
+        app.oneshot(request.body(body).unwrap()).await.unwrap()
     }
 
     async fn response_body(response: Response) -> String {
@@ -363,7 +1455,7 @@ mod tests {
         let mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
 
         let body = SolveEnvironment {
-            virtual_packages: vec!["__unix".to_string()],
+            virtual_packages: Some(vec!["__unix".to_string()]),
             specs: vec!["foo".to_string(), "bar".to_string()],
             ..default_solve_body()
         };
@@ -383,44 +1475,4184 @@ mod tests {
             .map(|p| p.package_record.name.as_normalized())
             .collect();
         assert_eq!(resolved_package_names, vec!["foo", "bar"]);
+
+        assert_eq!(
+            body.summary,
+            SolveSummary {
+                package_count: 2,
+                total_download_bytes: 2 * 414494,
+                total_installed_bytes: 2 * 414494,
+                sizes_unknown: false,
+                hashes_unknown: false,
+            }
+        );
     }
 
     #[tokio::test]
-    async fn test_solve_unsolvable() {
-        let (mut mock_channel_server, app) = dummy_app().await;
+    async fn test_solve_pinned_repodata_hashes() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let cache_dir = Temp::new_dir().unwrap();
+        state.available_packages = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            // Retained for less time than the repodata is current
+            Some(Duration::from_secs(30)),
+            cache_dir.to_path_buf(),
+            cli::RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let app = app(Arc::new(state));
         let mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
 
-        // `bar` depends on `__unix`, but no virtual packages are provided
-        let body = SolveEnvironment {
-            specs: vec!["bar".to_string()],
-            ..default_solve_body()
+        let solve = |repodata_hashes: BTreeMap<String, String>| {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                repodata_hashes,
+                ..default_solve_body()
+            };
+            post_solve(app.clone(), body)
         };
-        let response = post_solve(app, body).await;
+        let parse = |body: String| serde_json::from_str::<SolveEnvironmentOk>(&body).unwrap();
 
-        for endpoint in mock_endpoints {
-            endpoint.assert_async().await;
-        }
+        let response = solve(BTreeMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let first_hashes = parse(response_body(response).await).repodata_hashes;
+        assert_eq!(first_hashes.len(), 2);
+
+        // The reported hashes can be used to pin the repodata
+        let response = solve(first_hashes.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            parse(response_body(response).await).repodata_hashes,
+            first_hashes
+        );
 
+        // Unknown hashes are rejected
+        let linux_url = format!("{}/conda-forge/linux-64/", mock_channel_server.url());
+        let mut unknown_hashes = first_hashes.clone();
+        unknown_hashes.insert(linux_url.clone(), "0000".to_string());
+        let response = solve(unknown_hashes).await;
         assert_eq!(response.status(), StatusCode::CONFLICT);
-        let body = response_body(response).await;
-        assert!(
-            body.contains("bar * cannot be installed because there are no viable options"),
-            "Unexpected body!\n{body}"
-        )
+        assert!(response_body(response)
+            .await
+            .contains(r#""error_kind":"snapshot""#));
+
+        // Once the channel is updated, the previous repodata remains available through its hash
+        for mock in mock_endpoints {
+            mock.remove_async().await;
+        }
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, licensed_repodata_json()).await;
+        mock_instant::MockClock::advance(Duration::from_secs(120));
+
+        let response = solve(BTreeMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = parse(response_body(response).await);
+        assert_ne!(body.repodata_hashes[&linux_url], first_hashes[&linux_url]);
+        assert_eq!(body.packages[0].package_record.version.as_str(), "2.0");
+
+        let response = solve(first_hashes.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = parse(response_body(response).await);
+        assert_eq!(body.repodata_hashes, first_hashes);
+        assert_eq!(body.packages[0].package_record.version.as_str(), "3.0.2");
     }
 
-    fn empty_repodata_json() -> String {
-        r#"{
-          "info": {
-            "subdir": "linux-64"
-          },
-          "packages": {},
-          "packages.conda": {},
+    async fn post_compressed_solve(app: Router, encoding: &str, body: Vec<u8>) -> Response {
+        let request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(Body::from(body))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+        let mut compressed = Vec::new();
+        async_compression::tokio::bufread::GzipEncoder::new(data)
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    #[tokio::test]
+    async fn test_solve_compressed_body() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let json = serde_json::to_vec(&body).unwrap();
+
+        let response = post_solve(app.clone(), body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = response_body(response).await;
+
+        let compressed = [
+            ("gzip", gzip(&json).await),
+            ("zstd", zstd::encode_all(json.as_slice(), 3).unwrap()),
+        ];
+        for (encoding, compressed) in compressed {
+            let response = post_compressed_solve(app.clone(), encoding, compressed).await;
+            assert_eq!(response.status(), StatusCode::OK, "{encoding}");
+            assert_eq!(response_body(response).await, expected, "{encoding}");
+        }
+
+        let response = post_compressed_solve(app.clone(), "br", json.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(response_body(response)
+            .await
+            .contains(r#""error_kind":"encoding""#));
+
+        // Bodies that don't match their encoding are rejected the same way
+        let response = post_compressed_solve(app, "gzip", json).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response_body(response)
+            .await
+            .contains(r#""error_kind":"encoding""#));
+    }
+
+    #[tokio::test]
+    async fn test_solve_compressed_body_size_limit() {
+        let (_mock_channel_server, app) = dummy_app().await;
+
+        // Compresses to a few kilobytes, but decompresses beyond the limit
+        let bomb = gzip(&vec![b' '; 3 * 1024 * 1024]).await;
+        let response = post_compressed_solve(app, "gzip", bomb).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response_body(response)
+            .await
+            .contains(r#""error_kind":"too_large""#));
+    }
+
+    #[tokio::test]
+    async fn test_solve_is_deterministic() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let first = response_body(post_solve(app.clone(), body).await).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["bar".to_string(), "foo".to_string()],
+            ..default_solve_body()
+        };
+        let second = response_body(post_solve(app, body).await).await;
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_sort_solution_ignores_input_order() {
+        let records: Vec<RepoDataRecord> =
+            serde_json::from_str::<rattler_conda_types::RepoData>(&small_repodata_json())
+                .unwrap()
+                .into_repo_data_records(
+                    &Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                );
+
+        let mut reversed = records.clone();
+        reversed.reverse();
+
+        assert_eq!(sort_solution(records), sort_solution(reversed));
+    }
+
+    #[tokio::test]
+    async fn test_solve_unsolvable() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // `bar` depends on `__unix`, but no virtual packages are provided
+        let body = SolveEnvironment {
+            specs: vec!["bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        for endpoint in mock_endpoints {
+            endpoint.assert_async().await;
+        }
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = response_body(response).await;
+        assert!(
+            body.contains("bar * cannot be installed because there are no viable options"),
+            "Unexpected body!\n{body}"
+        );
+
+        // The explanation is available in a structured form too
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let report = &body["problem_report"];
+        assert_eq!(report["conflicting_specs"], serde_json::json!(["bar *"]));
+        let bar = &report["problems"][0];
+        assert_eq!(bar["status"], "not_viable");
+        let candidate = &bar["children"][0];
+        assert_eq!(candidate["versions"], serde_json::json!(["1.2.3"]));
+        assert_eq!(candidate["status"], "requires");
+        assert_eq!(candidate["children"][0]["package"], "__unix");
+        assert_eq!(candidate["children"][0]["status"], "missing");
+    }
+
+    #[tokio::test]
+    async fn test_solve_default_virtual_packages() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        state.default_virtual_packages = Some(vec!["__glibc=2.17".to_string()]);
+        let app = app(Arc::new(state));
+
+        // `bar` depends on `__unix`, which the configured defaults don't include
+        let body = |virtual_packages: Option<Vec<String>>| SolveEnvironment {
+            specs: vec!["bar".to_string()],
+            virtual_packages,
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body(None)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // The request's own virtual packages replace the defaults
+        let response = post_solve(app, body(Some(vec!["__unix".to_string()]))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Without configured defaults, the platform's virtual packages are used
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let response = post_solve(app, body(None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_solve_virtual_package_profiles() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        state.default_virtual_packages = Some(vec!["__glibc=2.17".to_string()]);
+        state.settings_mut().virtual_package_profiles = BTreeMap::from([(
+            "linux".to_string(),
+            vec!["__unix=0".to_string(), "__glibc=2.31".to_string()],
+        )]);
+        let app = app(Arc::new(state));
+
+        // `bar` depends on `__unix`, which only the profile includes
+        let body = |profile: &str, virtual_packages: Option<Vec<String>>| SolveEnvironment {
+            specs: vec!["bar".to_string()],
+            virtual_packages,
+            profile: Some(profile.to_string()),
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body("linux", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post_solve(app.clone(), body("linux", Some(Vec::new()))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Lock files identify the solution by the virtual packages the profile resolves to
+        let content_hash = |body: SolveEnvironment| {
+            let app = app.clone();
+            async move {
+                let response = post_solve_with_query(app, "format=conda-lock", body).await;
+                let lockfile: serde_yaml::Value =
+                    serde_yaml::from_str(&response_body(response).await).unwrap();
+                lockfile["metadata"]["content_hash"]["linux-64"].clone()
+            }
+        };
+        let explicit = SolveEnvironment {
+            profile: None,
+            virtual_packages: Some(vec!["__unix=0".to_string(), "__glibc=2.31".to_string()]),
+            ..body("linux", None)
+        };
+        assert_eq!(
+            content_hash(body("linux", None)).await,
+            content_hash(explicit).await
+        );
+
+        let response = post_solve(app, body("cuda12", None)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "validation");
+        assert_eq!(body["additional_info"]["input"], "cuda12");
+    }
+
+    #[test]
+    fn test_override_virtual_packages() {
+        let strings = |specs: &[&str]| specs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            override_virtual_packages(
+                &strings(&["__unix", "__glibc=2.17", "__cuda=11.8"]),
+                &strings(&["__cuda=12.4", "__archspec=1=x86_64_v3"]),
+            ),
+            [
+                "__unix",
+                "__glibc=2.17",
+                "__cuda=12.4",
+                "__archspec=1=x86_64_v3"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_request_limits() {
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.settings_mut().max_channels_per_request = 2;
+        state.settings_mut().max_specs_per_request = 3;
+        let app = app(Arc::new(state));
+
+        let body = SolveEnvironment {
+            channels: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(body.contains(r#""additional_info":{"count":3,"limit":2}"#));
+        assert!(body.contains("too many channels"));
+
+        let body = SolveEnvironment {
+            specs: vec!["a", "b", "c", "d"]
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(body.contains(r#""additional_info":{"count":4,"limit":3}"#));
+        assert!(body.contains("too many specs"));
+    }
+
+    #[tokio::test]
+    async fn test_solve_strict_match_mode_rejects_missing_version() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // Only `foo 3.0.2` is available
+        let body = |match_mode| SolveEnvironment {
+            specs: vec!["foo==4.0".to_string()],
+            match_mode,
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body(Some(MatchMode::Strict))).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = response_body(response).await;
+        assert!(
+            response.contains("foo ==4.0"),
+            "Unexpected body!\n{response}"
+        );
+        assert!(response.contains(r#""error_kind":"match""#));
+
+        // Without a match mode, the spec is left to the solver
+        let response = post_solve(app, body(None)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(response_body(response)
+            .await
+            .contains(r#""error_kind":"solver""#));
+    }
+
+    #[tokio::test]
+    async fn test_solve_flexible_match_mode_loosens_missing_version() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // Only `foo 3.0.2` is available
+        let body = SolveEnvironment {
+            specs: vec!["foo==4.0".to_string()],
+            match_mode: Some(MatchMode::Flexible),
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body.packages.len(), 1);
+        assert_eq!(body.packages[0].package_record.version.as_str(), "3.0.2");
+        assert_eq!(body.loosened_specs, vec!["foo ==4.0"]);
+    }
+
+    #[tokio::test]
+    async fn test_solve_package_format() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dual_format_repodata_json()).await;
+
+        for (package_format, extension) in [
+            (PackageFormat::Conda, ".conda"),
+            (PackageFormat::Tarbz2, ".tar.bz2"),
+            (PackageFormat::Any, ".conda"),
+        ] {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                package_format,
+                ..default_solve_body()
+            };
+            let response = post_solve(app.clone(), body).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response_body(response).await;
+            let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+
+            assert_eq!(body.packages.len(), 1);
+            assert!(
+                body.packages[0].url.path().ends_with(extension),
+                "{package_format:?} selected {}",
+                body.packages[0].url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_license_deny() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, licensed_repodata_json()).await;
+
+        // The latest version is GPL-licensed, so the solver must fall back to the MIT one
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            license_deny: vec!["gpl*".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+        assert_eq!(body.packages.len(), 1);
+        assert_eq!(body.packages[0].package_record.version.as_str(), "1.0");
+
+        // No allowed version is left
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            license_deny: vec!["GPL*".to_string(), "MIT".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = response_body(response).await;
+        assert!(body.contains("foo (license GPL-3.0-only, MIT)"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_solve_exclude() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, licensed_repodata_json()).await;
+
+        // Without the exclusion, the solver would pick `foo 2.0`
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            exclude: vec!["foo 2.0".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+        assert_eq!(body.packages.len(), 1);
+        assert_eq!(body.packages[0].package_record.version.as_str(), "1.0");
+
+        // Names may be patterns
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            exclude: vec!["f*>=2".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body.packages[0].package_record.version.as_str(), "1.0");
+
+        // Without alternatives, the solve fails
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            exclude: vec!["foo".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_solve_channel_priority() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mut mock_endpoints = Vec::new();
+        for (channel, linux_64) in [
+            ("other", licensed_repodata_json()),
+            ("conda-forge", small_repodata_json()),
+        ] {
+            for (subdir, body) in [("linux-64", linux_64), ("noarch", empty_repodata_json())] {
+                let mock = mock_channel_server
+                    .mock("GET", format!("/{channel}/{subdir}/repodata.json").as_str())
+                    .with_body(body)
+                    .create_async()
+                    .await;
+                mock_endpoints.push(mock);
+            }
+        }
+
+        // `other` only provides `foo 2.0` and `foo 1.0`, while `conda-forge` provides `foo 3.0.2`
+        let solve_foo = |channel_priority: ChannelPriority| {
+            let app = app.clone();
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                channels: vec!["other".to_string(), "conda-forge".to_string()],
+                channel_priority,
+                ..default_solve_body()
+            };
+            async move {
+                let response = post_solve(app, body).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response_body(response).await;
+                let mut body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+                assert_eq!(body.packages.len(), 1);
+                body.packages.remove(0)
+            }
+        };
+
+        let foo = solve_foo(ChannelPriority::Strict).await;
+        assert_eq!(foo.package_record.version.as_str(), "2.0");
+        assert!(foo.channel.ends_with("/other/"), "{}", foo.channel);
+
+        // The solved record keeps the channel it was taken from
+        let foo = solve_foo(ChannelPriority::Disabled).await;
+        assert_eq!(foo.package_record.version.as_str(), "3.0.2");
+        assert!(foo.channel.ends_with("/conda-forge/"), "{}", foo.channel);
+    }
+
+    #[tokio::test]
+    async fn test_solve_installed_packages() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, licensed_repodata_json()).await;
+
+        let solve_foo = |locked: Vec<PackageReference>, pinned: Vec<PackageReference>| {
+            let app = app.clone();
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                locked_packages: locked,
+                pinned_packages: pinned,
+                ..default_solve_body()
+            };
+            async move { post_solve(app, body).await }
+        };
+        let version = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response_body(response).await;
+            let mut body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+            assert_eq!(body.packages.len(), 1);
+            body.packages.remove(0).package_record.version.to_string()
+        };
+        let exact = |reference: &str| PackageReference::Exact(reference.to_string());
+
+        // Without installed packages, the solver would pick `foo 2.0`
+        let response = solve_foo(vec![exact("foo=1.0=0")], Vec::new()).await;
+        assert_eq!(version(response).await, "1.0");
+        let response = solve_foo(Vec::new(), vec![exact("foo=1.0=0")]).await;
+        assert_eq!(version(response).await, "1.0");
+
+        // Full records are used as given
+        let response = solve_foo(vec![exact("foo=1.0=0")], Vec::new()).await;
+        let body = response_body(response).await;
+        let mut body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+        let record = PackageReference::Record(Box::new(body.packages.remove(0)));
+        let response = solve_foo(Vec::new(), vec![record]).await;
+        assert_eq!(version(response).await, "1.0");
+
+        let response = solve_foo(vec![exact("foo=3.0=0")], Vec::new()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = solve_foo(Vec::new(), vec![exact("foo 1.0")]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_solve_snapshot_date() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = mock_linux64(&mut mock_channel_server, dated_repodata_json()).await;
+
+        for (snapshot, expected) in [
+            (None, "2.0"),
+            (Some("2021-01-01T00:00:00Z"), "1.0"),
+            (Some("2019-01-01T00:00:00+01:00"), "0.1"),
+        ] {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                snapshot: snapshot.map(|s| s.parse().unwrap()),
+                ..default_solve_body()
+            };
+            let response = post_solve(app.clone(), body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response_body(response).await;
+            let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+            assert_eq!(body.packages.len(), 1);
+            assert_eq!(body.packages[0].package_record.version.as_str(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_track_features_preferences() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, featured_repodata_json()).await;
+
+        let solve_blas = |preferences: Vec<(&str, FeaturePreference)>| {
+            let app = app.clone();
+            let body = SolveEnvironment {
+                specs: vec!["blas".to_string()],
+                track_features_preferences: preferences
+                    .into_iter()
+                    .map(|(f, p)| (f.to_string(), p))
+                    .collect(),
+                ..default_solve_body()
+            };
+            async move {
+                let response = post_solve(app, body).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response_body(response).await;
+                let mut body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+                assert_eq!(body.packages.len(), 1);
+                body.packages.remove(0).package_record
+            }
+        };
+
+        // By default, the build with tracked features is avoided
+        let blas = solve_blas(Vec::new()).await;
+        assert_eq!(blas.build, "mkl");
+
+        // Unless the feature is preferred, in which case its tracked features are reported as-is
+        let blas = solve_blas(vec![("nomkl", FeaturePreference::Prefer)]).await;
+        assert_eq!(blas.build, "openblas");
+        assert_eq!(blas.track_features, vec!["nomkl"]);
+
+        let blas = solve_blas(vec![("nomkl", FeaturePreference::Require)]).await;
+        assert_eq!(blas.build, "openblas");
+    }
+
+    #[tokio::test]
+    async fn test_solve_direct_depth() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dependent_repodata_json()).await;
+
+        for (depth, expected) in [
+            (Depth::Full, vec!["base", "lib", "app"]),
+            (Depth::Direct, vec!["app"]),
+        ] {
+            let body = SolveEnvironment {
+                specs: vec!["app".to_string()],
+                depth,
+                ..default_solve_body()
+            };
+            let response = post_solve(app.clone(), body).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response_body(response).await;
+            let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+            let names: Vec<_> = body
+                .packages
+                .iter()
+                .map(|p| p.package_record.name.as_normalized())
+                .collect();
+            assert_eq!(names, expected, "{depth:?}");
+            assert_eq!(body.summary.package_count, expected.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_response_encoding() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let solve = |headers: &[(header::HeaderName, &str)]| {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                ..default_solve_body()
+            };
+            let mut request = Request::builder()
+                .uri("/solve")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            let request = request
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let body_bytes = |response: Response| async {
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+        };
+
+        let response = solve(&[(header::ACCEPT, "application/msgpack")])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            msgpack::MSGPACK_CONTENT_TYPE
+        );
+        let body: SolveEnvironmentOk = rmp_serde::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body.packages[0].package_record.name.as_normalized(), "foo");
+
+        let response = solve(&[(header::ACCEPT_ENCODING, "zstd")]).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+        let json = zstd::decode_all(&body_bytes(response).await[..]).unwrap();
+        let body: SolveEnvironmentOk = serde_json::from_slice(&json).unwrap();
+        assert_eq!(body.packages[0].package_record.name.as_normalized(), "foo");
+
+        // Both apply at once
+        let response = solve(&[
+            (header::ACCEPT, "application/msgpack"),
+            (header::ACCEPT_ENCODING, "gzip"),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            msgpack::MSGPACK_CONTENT_TYPE
+        );
+
+        // Plain JSON is returned otherwise
+        let response = solve(&[]).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body: SolveEnvironmentOk = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body.packages[0].package_record.name.as_normalized(), "foo");
+    }
+
+    #[tokio::test]
+    async fn test_solve_include_graph() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dependent_repodata_json()).await;
+
+        let body = || SolveEnvironment {
+            specs: vec!["app".to_string()],
+            ..default_solve_body()
+        };
+
+        // The graph is only included when requested
+        let response = post_solve(app.clone(), body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let solution: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(solution.graph, None);
+
+        let response = post_solve_with_query(app.clone(), "include_graph=1", body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let solution: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let graph = solution.graph.unwrap();
+        assert_eq!(graph.len(), 3);
+        assert_eq!(graph["app"], vec!["lib"]);
+        assert_eq!(graph["lib"], vec!["base"]);
+        assert!(graph["base"].is_empty());
+
+        let response = post_solve_with_query(app.clone(), "format=graph", body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let graph: graph::DependencyGraph =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let nodes: Vec<_> = graph.nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(nodes.len(), 3);
+        assert!(nodes.contains(&"base"));
+        assert!(graph.edges.contains(&graph::GraphEdge {
+            from: "app".to_string(),
+            to: "lib".to_string(),
+            spec: "lib >=1".to_string(),
+        }));
+
+        let response = post_solve_with_query(app, "format=dot", body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/vnd.graphviz"
+        );
+        let dot = response_body(response).await;
+        assert!(dot.starts_with("digraph dependencies {"));
+        assert!(dot.contains(r#""lib" -> "base" [label="base"];"#));
+    }
+
+    #[tokio::test]
+    async fn test_solve_max_age_is_the_remaining_repodata_lifetime() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let cache_dir = Temp::new_dir().unwrap();
+        state.available_packages = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            cache_dir.to_path_buf(),
+            cli::RepodataCacheMode::Parsed,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let app = app(Arc::new(state));
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let body = || SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            ..default_solve_body()
+        };
+
+        let response = post_solve(app.clone(), body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+
+        // Cached solutions expire together with their repodata
+        mock_instant::MockClock::advance(Duration::from_secs(20));
+        let response = post_solve(app, body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=40");
+    }
+
+    #[tokio::test]
+    async fn test_solve_etag() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dependent_repodata_json()).await;
+        let body = |spec: &str| SolveEnvironment {
+            specs: vec![spec.to_string()],
+            ..default_solve_body()
+        };
+        let etag = |response: &Response| response.headers().get(header::ETAG).cloned();
+
+        // Identical requests share their etag
+        let first = post_solve(app.clone(), body("app")).await;
+        let second = post_solve(app.clone(), body("app")).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[header::CACHE_CONTROL], "max-age=2147483648");
+        let first_etag = etag(&first).unwrap();
+        assert_eq!(etag(&second), Some(first_etag.clone()));
+
+        // Different requests don't
+        let other = post_solve(app.clone(), body("lib")).await;
+        assert_ne!(etag(&other), Some(first_etag.clone()));
+
+        // The client already has the solution
+        let response = post_solve_with_header(
+            app.clone(),
+            header::IF_NONE_MATCH,
+            first_etag.to_str().unwrap(),
+            body("app"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(etag(&response), Some(first_etag.clone()));
+        assert!(response_body(response).await.is_empty());
+
+        // MessagePack responses are a different representation, with an etag of their own
+        let post_msgpack = |if_none_match: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/solve")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .header(header::ACCEPT, msgpack::MSGPACK_CONTENT_TYPE);
+            if let Some(if_none_match) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, if_none_match);
+            }
+            let request = request
+                .body(Body::from(serde_json::to_vec(&body("app")).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let response = post_msgpack(Some(first_etag.to_str().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let msgpack_etag = etag(&response).unwrap();
+        assert_ne!(msgpack_etag, first_etag);
+        let response = post_msgpack(Some(msgpack_etag.to_str().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // The client does not want a cacheable response
+        let response =
+            post_solve_with_header(app, header::CACHE_CONTROL, "no-cache", body("app")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(etag(&response), None);
+        assert!(response.headers().get(header::CACHE_CONTROL).is_none());
+    }
+
+    async fn post_solve_with_header(
+        app: Router,
+        name: header::HeaderName,
+        value: &str,
+        body: SolveEnvironment,
+    ) -> Response {
+        let request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(name, value)
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn post_explicit(app: Router, urls: Vec<String>) -> Response {
+        let body = explicit::ExplicitEnvironment { urls };
+        let request = Request::builder()
+            .uri("/explicit")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_explicit() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mock = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .with_body(dependent_repodata_json())
+            .expect(1)
+            .create_async()
+            .await;
+        let url = |name: &str| {
+            format!(
+                "{}/conda-forge/linux-64/{name}-1.0-0.tar.bz2",
+                mock_channel_server.url()
+            )
+        };
+
+        let response = post_explicit(app.clone(), vec![url("app"), url("lib"), url("base")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: explicit::ExplicitEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let names: Vec<_> = body
+            .packages
+            .iter()
+            .map(|p| p.package_record.name.as_normalized())
+            .collect();
+        assert_eq!(names, ["app", "lib", "base"]);
+        assert_eq!(body.packages[0].package_record.depends, ["lib >=1"]);
+        assert_eq!(
+            body.packages[0].package_record.license.as_deref(),
+            Some("MIT")
+        );
+        assert_eq!(body.summary.package_count, 3);
+        // The repodata has no hashes, so the downloads cannot be verified
+        assert!(body.summary.hashes_unknown);
+
+        // `lib` is missing, so the set is inconsistent
+        let response = post_explicit(app.clone(), vec![url("app"), url("base")]).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "dependencies");
+        assert_eq!(
+            body["additional_info"],
+            serde_json::json!(["app=1.0=0 depends on lib >=1"])
+        );
+
+        // The package does not exist in the channel
+        let response = post_explicit(app.clone(), vec![url("nope")]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The url does not point to a package
+        let response = post_explicit(app, vec!["https://example.com/foo".to_string()]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The repodata was only downloaded once
+        mock.assert_async().await;
+    }
+
+    async fn post_validate_lockfile(app: Router, body: lockfile::ValidateLockfile) -> Response {
+        let request = Request::builder()
+            .uri("/lockfile/validate")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_validate_lockfile() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let url = |file_name: &str| {
+            format!(
+                "{}/conda-forge/linux-64/{file_name}",
+                mock_channel_server.url()
+            )
+        };
+        let validate = |lockfile: String, channels: Vec<String>| {
+            post_validate_lockfile(
+                app.clone(),
+                lockfile::ValidateLockfile {
+                    lockfile,
+                    format: None,
+                    channels,
+                    platform: Some("linux-64".to_string()),
+                },
+            )
+        };
+
+        // A lockfile generated by the server is valid, as long as the channel doesn't change
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve_with_query(app.clone(), "format=conda-lock", body).await;
+        let conda_lock = response_body(response).await;
+        let response = validate(conda_lock, Vec::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: lockfile::LockfileValidation =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(body.valid);
+        assert_eq!(body.packages.len(), 2);
+
+        let explicit = [
+            "@EXPLICIT".to_string(),
+            format!(
+                "{}#d65ab674acf3b7294ebacaec05fc5b54",
+                url("foo-3.0.2-py36h1af98f8_1.tar.bz2")
+            ),
+            format!(
+                "{}#00000000000000000000000000000000",
+                url("bar-1.0-unix_py36h1af98f8_2.tar.bz2")
+            ),
+            url("baz-1.0-0.tar.bz2"),
+            "https://conda.anaconda.org/bioconda/linux-64/qux-1.0-0.tar.bz2".to_string(),
+        ]
+        .join("\n");
+        let response = validate(explicit, vec!["conda-forge".to_string()]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: lockfile::LockfileValidation =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(!body.valid);
+        let statuses: Vec<_> = body.packages.iter().map(|p| p.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                lockfile::PackageStatus::Ok,
+                lockfile::PackageStatus::Modified,
+                lockfile::PackageStatus::Yanked,
+                lockfile::PackageStatus::OutsideChannels,
+            ]
+        );
+        assert_eq!(
+            body.packages[1].details.as_deref(),
+            Some(
+                "the md5 is 00000000000000000000000000000000 in the lockfile, but \
+                 bc13aa58e2092bcb0b97c561373d3905 in the channel"
+            )
+        );
+
+        let response = validate("package: [".to_string(), Vec::new()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_check_specs() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let check = |specs: &[&str]| {
+            let body = availability::CheckSpecs {
+                platform: "linux-64".to_string(),
+                channels: vec!["conda-forge".to_string()],
+                specs: specs.iter().map(|s| s.to_string()).collect(),
+            };
+            let request = Request::builder()
+                .uri("/check")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = check(&["foo >=3", "bar <1", "fooo"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: availability::SpecsAvailability =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(!body.available);
+        let foo = &body.specs[0];
+        assert_eq!(foo.spec, "foo >=3");
+        assert!(foo.available);
+        assert_eq!(foo.candidates, 1);
+        let best = foo.best.as_ref().unwrap();
+        assert_eq!(best.version, "3.0.2");
+        assert_eq!(best.build, "py36h1af98f8_1");
+        assert!(best.channel.ends_with("/conda-forge/"));
+        // Neither a version that doesn't exist nor a misspelled name have candidates
+        for spec in &body.specs[1..] {
+            assert!(!spec.available);
+            assert_eq!(spec.candidates, 0);
+            assert!(spec.best.is_none());
+        }
+
+        let response = check(&["foo", "bar"]).await.unwrap();
+        let body: availability::SpecsAvailability =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(body.available);
+
+        let response = check(&["foo >=>3"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_run_exports() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let repodata = serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "gcc_impl-12.3.0-0.tar.bz2": repodata_record("gcc_impl", "12.3.0", &[]),
+                "openssl-3.1.4-0.tar.bz2": repodata_record("openssl", "3.1.4", &[]),
+                "openssl-3.2.0-0.tar.bz2": repodata_record("openssl", "3.2.0", &[]),
+                "tzdata-2023c-0.tar.bz2": repodata_record("tzdata", "2023c", &[])
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        });
+        let run_exports = serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "gcc_impl-12.3.0-0.tar.bz2": {
+                    "run_exports": { "strong": ["libgcc-ng >=12.3.0"] }
+                }
+            }
+        });
+        let channel_data = serde_json::json!({
+            "channeldata_version": 1,
+            "packages": {
+                "openssl": {
+                    "run_exports": {
+                        "3.2.0": { "weak": ["openssl >=3.2.0,<4.0a0"] }
+                    }
+                }
+            }
+        });
+        let _repodata = mock_linux64(&mut mock_channel_server, repodata.to_string()).await;
+        let _mocks = [
+            // The files are downloaded once, and then cached
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/run_exports.json")
+                .with_body(run_exports.to_string())
+                .expect(1)
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/channeldata.json")
+                .with_body(channel_data.to_string())
+                .expect(1)
+                .create_async()
+                .await,
+        ];
+
+        let post_run_exports = |body: serde_json::Value| {
+            let request = Request::builder()
+                .uri("/run-exports")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        for _ in 0..2 {
+            let response = post_run_exports(serde_json::json!({
+                "platform": "linux-64",
+                "channels": ["conda-forge"],
+                "build": ["gcc_impl"],
+                "host": ["openssl", "tzdata"]
+            }))
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: run_exports::RunExportsResult =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            assert_eq!(body.host, ["libgcc-ng >=12.3.0"]);
+            assert_eq!(body.run, ["libgcc-ng >=12.3.0", "openssl >=3.2.0,<4.0a0"]);
+            assert!(body.run_constrained.is_empty());
+            let sources: Vec<_> = body.packages.iter().map(|p| p.source).collect();
+            assert_eq!(
+                sources,
+                [
+                    run_exports::RunExportsSource::RunExportsJson,
+                    run_exports::RunExportsSource::ChannelData,
+                    run_exports::RunExportsSource::None,
+                ]
+            );
+            assert_eq!(body.packages[1].version, "3.2.0");
+        }
+
+        let response = post_run_exports(serde_json::json!({
+            "platform": "linux-64",
+            "channels": ["conda-forge"],
+            "host": ["zlib"]
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = post_run_exports(serde_json::json!({
+            "platform": "linux-64",
+            "channels": ["conda-forge"],
+            "host": [">=1"]
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        for mock in _mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_environment_diff() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let repodata = serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "foo-1.0-0.tar.bz2": repodata_record("foo", "1.0", &[]),
+                "foo-2.0-0.tar.bz2": repodata_record("foo", "2.0", &[]),
+                "bar-1.0-0.tar.bz2": repodata_record("bar", "1.0", &[])
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        });
+        let mock = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .with_body(repodata.to_string())
+            .expect(1)
+            .create_async()
+            .await;
+        let _noarch = mock_channel_server
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_body(empty_repodata_json())
+            .create_async()
+            .await;
+
+        let post_diff = |body: serde_json::Value| {
+            let request = Request::builder()
+                .uri("/diff")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let response = post_diff(serde_json::json!({
+            "before": { "specs": ["foo <2"] },
+            "after": { "specs": ["foo", "bar"] },
+            "platform": "linux-64",
+            "virtual_packages": [],
+            "channels": ["conda-forge"]
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: diff::EnvironmentDiff =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body.added.len(), 1);
+        assert_eq!(body.added[0].name, "bar");
+        assert_eq!(body.upgraded.len(), 1);
+        assert_eq!(body.upgraded[0].before.as_ref().unwrap().version, "1.0");
+        assert_eq!(body.upgraded[0].after.as_ref().unwrap().version, "2.0");
+        assert!(body.removed.is_empty() && body.downgraded.is_empty() && body.rebuilt.is_empty());
+
+        // A side may be the packages of a previous solve
+        let mut solve = default_solve_body();
+        solve.specs = vec!["foo <2".to_string()];
+        let response = post_solve(app.clone(), solve).await;
+        let solution: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let response = post_diff(serde_json::json!({
+            "before": { "packages": solution.packages },
+            "after": { "specs": ["bar"] },
+            "platform": "linux-64",
+            "virtual_packages": [],
+            "channels": ["conda-forge"]
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: diff::EnvironmentDiff =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body.added[0].name, "bar");
+        assert_eq!(body.removed.len(), 1);
+        assert_eq!(body.removed[0].name, "foo");
+        assert!(body.removed[0].after.is_none());
+
+        // Both sides reuse the cached repodata
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_solve_with_sharded_repodata() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let shard = |name: &str, depends: &[&str]| {
+            let mut record = repodata_record(name, "1.0", depends);
+            record["sha256"] =
+                "6b86b273ff34fce19d6b804eff5a3f5747ada4eaa22f1d49c01e52ddb7875b4b".into();
+            let shard = shards::encode(&serde_json::json!({
+                "packages": { format!("{name}-1.0-0.tar.bz2"): record },
+            }));
+            let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(&shard);
+            (format!("{hash:x}"), shard)
+        };
+        let (foo, bar, baz) = (shard("foo", &["bar"]), shard("bar", &[]), shard("baz", &[]));
+        let index_file = shards::encode(&serde_json::json!({
+            "version": 1,
+            "info": {
+                "base_url": "./",
+                "shards_base_url": "../shards/",
+                "subdir": "linux-64",
+            },
+            "shards": { "foo": foo.0, "bar": bar.0, "baz": baz.0 },
+        }));
+        let index = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata_shards.msgpack.zst")
+            .with_body(index_file)
+            .expect(1)
+            .create_async()
+            .await;
+        let mut shard_mocks = Vec::new();
+        for ((hash, body), hits) in [(foo, 1), (bar, 1), (baz, 0)] {
+            let path = format!("/conda-forge/shards/{hash}.msgpack.zst");
+            let mock = mock_channel_server
+                .mock("GET", path.as_str())
+                .with_body(body)
+                .expect(hits)
+                .create_async()
+                .await;
+            shard_mocks.push(mock);
+        }
+        let full_repodata = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .expect(0)
+            .create_async()
+            .await;
+        // Subdirs without a shard index fall back to their repodata.json, and are not asked for one
+        // again while that is cached
+        let noarch_index = mock_channel_server
+            .mock("GET", "/conda-forge/noarch/repodata_shards.msgpack.zst")
+            .with_status(404)
+            .expect(1)
+            .create_async()
+            .await;
+        let noarch = mock_channel_server
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_body(empty_repodata_json())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let mut body = default_solve_body();
+        body.specs = vec!["foo".to_string()];
+        for _ in 0..2 {
+            let response = post_solve(app.clone(), body.clone()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let solution: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            let mut urls: Vec<_> = solution
+                .packages
+                .iter()
+                .map(|p| p.url.to_string())
+                .collect();
+            urls.sort();
+            assert_eq!(
+                urls,
+                [
+                    format!(
+                        "{}/conda-forge/linux-64/bar-1.0-0.tar.bz2",
+                        mock_channel_server.url()
+                    ),
+                    format!(
+                        "{}/conda-forge/linux-64/foo-1.0-0.tar.bz2",
+                        mock_channel_server.url()
+                    ),
+                ]
+            );
+        }
+
+        // The index and the shards are reused by the second solve
+        index.assert_async().await;
+        for mock in shard_mocks {
+            mock.assert_async().await;
+        }
+        full_repodata.assert_async().await;
+        noarch_index.assert_async().await;
+        noarch.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_solve_shares_channels_between_requests() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let base_repodata = serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "base-2.0-0.tar.bz2": {
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "base",
+                    "subdir": "linux-64",
+                    "version": "2.0"
+                }
+            }
+        })
+        .to_string();
+
+        // Every channel is downloaded once, even though conda-forge is used by both requests
+        let mut mocks = Vec::new();
+        for (channel, linux_repodata) in [
+            ("conda-forge", dependent_repodata_json()),
+            ("extra-a", base_repodata.clone()),
+            ("extra-b", base_repodata),
+        ] {
+            for (subdir, repodata) in [
+                ("linux-64", linux_repodata),
+                ("noarch", empty_repodata_json()),
+            ] {
+                let mock = mock_channel_server
+                    .mock("GET", format!("/{channel}/{subdir}/repodata.json").as_str())
+                    .with_body(repodata)
+                    .expect(1)
+                    .create_async()
+                    .await;
+                mocks.push(mock);
+            }
+        }
+
+        // `base` comes from the channel with the highest priority in each request
+        for (channels, base_version) in [
+            (["conda-forge", "extra-a"], "1.0"),
+            (["extra-b", "conda-forge"], "2.0"),
+        ] {
+            let body = SolveEnvironment {
+                specs: vec!["app".to_string()],
+                channels: channels.iter().map(|c| c.to_string()).collect(),
+                ..default_solve_body()
+            };
+            let response = post_solve(app.clone(), body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            let base = body
+                .packages
+                .iter()
+                .find(|p| p.package_record.name.as_normalized() == "base")
+                .unwrap();
+            assert_eq!(base.package_record.version.to_string(), base_version);
+        }
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_extra_subdirs() {
+        let (mut mock_channel_server, state) = dummy_state().await;
+        let state = Arc::new(state);
+        let app = app(state.clone());
+        let cuda_repodata = serde_json::json!({
+            "info": { "subdir": "linux-64-cuda" },
+            "packages": {
+                "foo-cuda-1.0-0.tar.bz2": {
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "foo-cuda",
+                    "subdir": "linux-64-cuda",
+                    "version": "1.0"
+                }
+            }
+        });
+        let _mock_endpoints = [
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/repodata.json")
+                .with_body(empty_repodata_json())
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/noarch/repodata.json")
+                .with_body(empty_repodata_json())
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64-cuda/repodata.json")
+                .with_body(cuda_repodata.to_string())
+                .create_async()
+                .await,
+        ];
+
+        let body = SolveEnvironment {
+            specs: vec!["foo-cuda".to_string()],
+            extra_subdirs: vec!["linux-64-cuda".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let subdir_url = format!("{}/conda-forge/linux-64-cuda/", mock_channel_server.url());
+        assert_eq!(
+            body.packages[0].url.as_str(),
+            format!("{subdir_url}foo-cuda-1.0-0.tar.bz2")
+        );
+        assert!(body.repodata_hashes.contains_key(&subdir_url));
+        let cache_keys: Vec<_> = state
+            .available_packages
+            .info()
+            .entries
+            .into_iter()
+            .map(|e| e.url.to_string())
+            .collect();
+        assert!(cache_keys.contains(&subdir_url), "{cache_keys:?}");
+
+        // Subdir names may not escape the channel
+        let body = SolveEnvironment {
+            specs: vec!["foo-cuda".to_string()],
+            extra_subdirs: vec!["../other".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_solve_with_each_solver() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.settings_mut().solver = Solver::Libsolvc;
+        let app = app(Arc::new(state));
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let solve = |solver: Option<Solver>| {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                solver,
+                ..default_solve_body()
+            };
+            post_solve_with_query(app.clone(), "debug=1", body)
+        };
+
+        // Requests without a solver get the server's default
+        let mut etags = HashSet::new();
+        for (solver, expected) in [
+            (None, Solver::Libsolvc),
+            (Some(Solver::Resolvo), Solver::Resolvo),
+            (Some(Solver::Libsolvc), Solver::Libsolvc),
+        ] {
+            let response = solve(solver).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let solution: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            assert_eq!(solution.solver_stats.unwrap().solver, expected);
+            assert_eq!(solution.packages.len(), 1);
+            assert_eq!(
+                solution.packages[0].package_record.version.as_str(),
+                "3.0.2"
+            );
+
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                solver,
+                ..default_solve_body()
+            };
+            let response = post_solve(app.clone(), body).await;
+            etags.insert(
+                response.headers()[header::ETAG]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        // Solutions of different backends are cached separately
+        assert_eq!(etags.len(), 2);
+
+        let request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                r#"{"platform": "linux-64", "specs": ["foo"], "channels": [], "solver": "pubgrub"}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_solve_sparse_repodata() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let cache_dir = Temp::new_dir().unwrap();
+        state.available_packages = Arc::new(AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            None,
+            cache_dir.to_path_buf(),
+            cli::RepodataCacheMode::Sparse,
+            None,
+            1,
+            None,
+            Duration::ZERO,
+        ));
+        let app = app(Arc::new(state));
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dependent_repodata_json()).await;
+
+        // Only the records reachable from the specs are given to the solver, whether or not the
+        // repodata was cached already
+        for _ in 0..2 {
+            let body = SolveEnvironment {
+                specs: vec!["lib".to_string()],
+                ..default_solve_body()
+            };
+            let response = post_solve_with_query(app.clone(), "debug=1", body).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let solution: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            let names: Vec<_> = solution
+                .packages
+                .iter()
+                .map(|p| p.package_record.name.as_normalized())
+                .collect();
+            assert_eq!(names, ["base", "lib"]);
+            assert_eq!(solution.solver_stats.unwrap().candidates_considered, 2);
+            let channel = format!("{}/conda-forge/", mock_channel_server.url());
+            assert!(solution.packages.iter().all(|p| p.channel == channel));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_debug_stats() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, dependent_repodata_json()).await;
+        let body = || SolveEnvironment {
+            specs: vec!["app".to_string()],
+            ..default_solve_body()
+        };
+
+        // The stats are only included when requested
+        let response = post_solve(app.clone(), body()).await;
+        let solution: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(solution.solver_stats.is_none());
+
+        assert!(solution.timings.is_none());
+
+        let response = post_solve_with_query(app, "debug=1", body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::ETAG).is_none());
+        let response_headers = response.headers().clone();
+        let solution: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let stats = solution.solver_stats.unwrap();
+        assert!(stats.duration_ms > 0.0);
+        assert_eq!(stats.candidates_considered, 3);
+        let timings = solution.timings.unwrap();
+        assert_eq!(timings.repodata.len(), 2);
+        let linux_64_url = format!("{}/conda-forge/linux-64/", mock_channel_server.url());
+        assert!(timings.repodata[&linux_64_url].cache_hit);
+        assert!(!timings.solve_cache_hit);
+        assert!(timings.total_ms >= timings.solve_ms);
+        let server_timing = response_headers.get("server-timing").unwrap();
+        assert!(server_timing
+            .to_str()
+            .unwrap()
+            .starts_with("serialize;dur="));
+    }
+
+    #[tokio::test]
+    async fn test_solve_respects_constrains() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, constrained_repodata_json()).await;
+
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+        let bar = body
+            .packages
+            .iter()
+            .find(|p| p.package_record.name.as_normalized() == "bar")
+            .unwrap();
+        assert_eq!(bar.package_record.version.as_str(), "1.0");
+        assert_eq!(
+            body.applied_constraints,
+            vec![AppliedConstraint {
+                package: "foo".to_string(),
+                constraint: "bar <2".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_environment_yml_matches_json() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let json_body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let json_response = post_solve(app.clone(), json_body).await;
+        assert_eq!(json_response.status(), StatusCode::OK);
+
+        let environment_yml = r#"
+name: dummy
+channels:
+  - conda-forge
+dependencies:
+  - foo
+  - bar
+  - pip:
+    - requests
+"#;
+        let yml_response = post_solve_environment_yml(
+            app,
+            "platform=linux-64&virtual_packages=__unix",
+            environment_yml,
+        )
+        .await;
+        assert_eq!(yml_response.status(), StatusCode::OK);
+
+        assert_eq!(
+            response_body(yml_response).await,
+            response_body(json_response).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_environment_yml_content_types() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        for content_type in ["application/yaml", "text/yaml; charset=utf-8"] {
+            let response = post_solve_environment_yml_as(
+                app.clone(),
+                content_type,
+                "platform=linux-64&virtual_packages=__unix",
+                "channels: [conda-forge]\ndependencies: [foo]",
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK, "{content_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_environment_yml_requires_platform() {
+        let (_mock_channel_server, app) = dummy_app().await;
+        let response =
+            post_solve_environment_yml(app, "", "channels: [conda-forge]\ndependencies: [foo]")
+                .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(body.contains("platform"), "Unexpected body!\n{body}");
+    }
+
+    #[tokio::test]
+    async fn test_solve_environment_yml_exclude_and_track_features() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints =
+            mock_linux64(&mut mock_channel_server, featured_repodata_json()).await;
+
+        let solve_blas = |query: &'static str| {
+            let app = app.clone();
+            async move {
+                post_solve_environment_yml(
+                    app,
+                    query,
+                    "channels: [conda-forge]\ndependencies: [blas]",
+                )
+                .await
+            }
+        };
+        let solved_build = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            body.packages[0].package_record.build.clone()
+        };
+
+        let response = solve_blas("platform=linux-64").await;
+        assert_eq!(solved_build(response).await, "mkl");
+
+        let response =
+            solve_blas("platform=linux-64&track_features_preferences=nomkl=prefer").await;
+        assert_eq!(solved_build(response).await, "openblas");
+
+        let response = solve_blas("platform=linux-64&exclude=blas%20*%20mkl").await;
+        assert_eq!(solved_build(response).await, "openblas");
+
+        let response = solve_blas("platform=linux-64&track_features_preferences=nomkl=maybe").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(body.contains("nomkl=maybe"), "Unexpected body!\n{body}");
+    }
+
+    #[tokio::test]
+    async fn test_solve_conda_lock_output() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve_with_query(app, "format=conda-lock", body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-yaml"
+        );
+        let body = response_body(response).await;
+        let lockfile: serde_yaml::Value = serde_yaml::from_str(&body).unwrap();
+
+        assert_eq!(lockfile["version"], 1);
+        let metadata = &lockfile["metadata"];
+        assert_eq!(
+            metadata["platforms"],
+            serde_yaml::from_str::<serde_yaml::Value>("[linux-64]").unwrap()
+        );
+        assert_eq!(metadata["channels"][0]["url"], "conda-forge");
+        assert!(metadata["content_hash"]["linux-64"].is_string());
+
+        let packages = lockfile["package"].as_sequence().unwrap();
+        assert_eq!(packages.len(), 1);
+        let bar = &packages[0];
+        assert_eq!(bar["name"], "bar");
+        assert_eq!(bar["version"], "1.2.3");
+        assert_eq!(bar["manager"], "conda");
+        assert_eq!(bar["platform"], "linux-64");
+        assert_eq!(bar["dependencies"]["__unix"], "*");
+        assert_eq!(bar["category"], "main");
+        assert_eq!(bar["optional"], false);
+        assert!(bar["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/conda-forge/linux-64/bar-1.0-unix_py36h1af98f8_2.tar.bz2"));
+        assert!(bar["hash"]["md5"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_solve_pixi_lock_output() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve_with_query(app, "format=pixi-lock", body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-yaml"
+        );
+        let body = response_body(response).await;
+        let lockfile: serde_yaml::Value = serde_yaml::from_str(&body).unwrap();
+
+        assert_eq!(lockfile["version"], 4);
+        let environment = &lockfile["environments"]["default"];
+        // The channel name is resolved against the channel alias
+        let channel = environment["channels"][0]["url"].as_str().unwrap();
+        assert!(channel.ends_with("/conda-forge/"), "{channel}");
+        let urls = environment["packages"]["linux-64"].as_sequence().unwrap();
+        assert_eq!(urls.len(), 1);
+        let url = urls[0]["conda"].as_str().unwrap();
+        assert!(url.ends_with("/conda-forge/linux-64/bar-1.0-unix_py36h1af98f8_2.tar.bz2"));
+
+        let packages = lockfile["packages"].as_sequence().unwrap();
+        assert_eq!(packages.len(), 1);
+        let bar = &packages[0];
+        assert_eq!(bar["kind"], "conda");
+        assert_eq!(bar["name"], "bar");
+        assert_eq!(bar["version"], "1.2.3");
+        assert_eq!(bar["build"], "unix_py36h1af98f8_2");
+        assert_eq!(bar["subdir"], "linux-64");
+        assert_eq!(bar["url"].as_str().unwrap(), url);
+        assert_eq!(
+            bar["depends"],
+            serde_yaml::from_str::<serde_yaml::Value>("[__unix]").unwrap()
+        );
+        assert!(bar["md5"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_solve_explicit_output() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve_with_query(app, "format=explicit", body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = response_body(response).await;
+        let lines: Vec<_> = body.lines().filter(|l| !l.starts_with('#')).collect();
+        let url = |file_name: &str| {
+            format!(
+                "{}/conda-forge/linux-64/{file_name}",
+                mock_channel_server.url()
+            )
+        };
+        assert_eq!(
+            lines,
+            [
+                "@EXPLICIT".to_string(),
+                format!(
+                    "{}#d65ab674acf3b7294ebacaec05fc5b54",
+                    url("foo-3.0.2-py36h1af98f8_1.tar.bz2")
+                ),
+                format!(
+                    "{}#bc13aa58e2092bcb0b97c561373d3905",
+                    url("bar-1.0-unix_py36h1af98f8_2.tar.bz2")
+                ),
+            ]
+        );
+        assert!(body.contains("# platform: linux-64\n"));
+    }
+
+    #[tokio::test]
+    async fn test_solve_environment_yml_output() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve_with_query(app, "format=environment-yml", body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-yaml"
+        );
+        let body = response_body(response).await;
+        let yml = environment_yml::EnvironmentYml::from_yaml_bytes(body.as_bytes()).unwrap();
+
+        assert_eq!(yml.name.as_deref(), Some("dummy"));
+        assert_eq!(yml.channels, vec!["conda-forge"]);
+        let pins: Vec<_> = yml
+            .dependencies
+            .into_iter()
+            .map(|d| match d {
+                environment_yml::EnvironmentYmlDependency::Spec(spec) => spec,
+                environment_yml::EnvironmentYmlDependency::Pip { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            pins,
+            vec!["foo=3.0.2=py36h1af98f8_1", "bar=1.2.3=unix_py36h1af98f8_2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let (_mock_channel_server, app) = dummy_app().await;
+        let request = Request::builder()
+            .uri("/version")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let build_info: version::BuildInfo = serde_json::from_str(&body).unwrap();
+        assert_eq!(build_info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!build_info.git_sha.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let (_mock_channel_server, app) = dummy_app().await;
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(get("/openapi.json")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let document: serde_json::Value =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(document["paths"]["/v1/solve"]["post"].is_object());
+        assert!(document["paths"]["/v1/jobs/{id}"]["get"].is_object());
+        assert!(document["paths"]["/healthz"]["get"].is_object());
+
+        // Every referenced schema is part of the document
+        fn refs<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+            match value {
+                serde_json::Value::Object(object) => {
+                    if let Some(reference) = object.get("$ref").and_then(|r| r.as_str()) {
+                        found.push(reference);
+                    }
+                    object.values().for_each(|v| refs(v, found));
+                }
+                serde_json::Value::Array(array) => array.iter().for_each(|v| refs(v, found)),
+                _ => {}
+            }
+        }
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(found.contains(&"#/components/schemas/SolveEnvironment"));
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"][name].is_object(),
+                "{reference} is missing"
+            );
+        }
+
+        // The Swagger UI is opt-in
+        let response = app.oneshot(get("/docs")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.swagger_ui = true;
+        let response = super::app(Arc::new(state))
+            .oneshot(get("/docs"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response_body(response).await.contains("/openapi.json"));
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let config: api_keys::ApiKeysConfig = serde_json::from_value(serde_json::json!({
+            "keys": {
+                "ci": { "token": "ci-secret" },
+                "dashboard": { "token": "dashboard-secret", "requests_per_minute": 1 }
+            }
+        }))
+        .unwrap();
+        state.api_keys = Some(ApiKeys::from_config(config).unwrap());
+        let app = app(Arc::new(state));
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let response = post_solve(app.clone(), default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = post_solve_as_tenant(app.clone(), "unknown", default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for _ in 0..2 {
+            let response =
+                post_solve_as_tenant(app.clone(), "ci-secret", default_solve_body()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // The second request of the dashboard exceeds its rate limit
+        let response =
+            post_solve_as_tenant(app.clone(), "dashboard-secret", default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["ratelimit-limit"], "1");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        let response =
+            post_solve_as_tenant(app.clone(), "dashboard-secret", default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(response.headers()["ratelimit-reset"], "60");
+
+        // Metrics don't require a key, and count the requests of each key
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = response_body(response).await;
+        for line in [
+            "rattler_server_api_key_requests_total{label=\"ci\"} 2",
+            "rattler_server_api_key_requests_total{label=\"dashboard\"} 1",
+            "rattler_server_api_key_rate_limited_total{label=\"dashboard\"} 1",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing `{line}`");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let app = app(Arc::new(state));
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::GET,
+            "/admin/log-level",
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send_admin_request(
+            app,
+            http::Method::GET,
+            "/admin/log-level",
+            Some("wrong"),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_disabled_without_token() {
+        let (_mock_channel_server, app) = dummy_app().await;
+        let response =
+            send_admin_request(app, http::Method::GET, "/admin/log-level", None, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reload_config() {
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let config_dir = Temp::new_dir().unwrap();
+        let config_file = config_dir.join("config.yml");
+        state.config = Some(ConfigReloader::new(
+            config_file.clone(),
+            Args::parse_from(["rattler-server"]),
+        ));
+        let state = Arc::new(state);
+        let app = app(state.clone());
+        assert_eq!(state.settings().max_specs_per_request, 10_000);
+
+        std::fs::write(
+            &config_file,
+            "channel_alias: https://conda.example.com/\nmax_specs_per_request: 1\n",
+        )
+        .unwrap();
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::POST,
+            "/admin/reload",
+            Some("secret"),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let settings = state.settings();
+        assert_eq!(settings.max_specs_per_request, 1);
+        assert_eq!(
+            settings.channel_config.channel_alias.as_str(),
+            "https://conda.example.com/"
+        );
+
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Invalid config files are rejected, keeping the current settings
+        std::fs::write(&config_file, "max_specs_per_request: many\n").unwrap();
+        let response = send_admin_request(
+            app,
+            http::Method::POST,
+            "/admin/reload",
+            Some("secret"),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response_body(response)
+            .await
+            .contains("invalid config file"));
+        assert_eq!(state.settings().max_specs_per_request, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_insert_repodata() {
+        // No repodata is served by the mock server, so the solve can only use the inserted records
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let app = app(Arc::new(state));
+
+        let repodata: RepoData = serde_json::from_str(&dependent_repodata_json()).unwrap();
+        let records = repodata.into_repo_data_records(
+            &Channel::from_str("https://example.com/local", &ChannelConfig::default()).unwrap(),
+        );
+        for (subdir, records) in [("linux-64", records), ("noarch", Vec::new())] {
+            let body = admin::InsertRepoData {
+                channel: "conda-forge".to_string(),
+                subdir: subdir.to_string(),
+                records,
+            };
+            let response = send_admin_request(
+                app.clone(),
+                http::Method::PUT,
+                "/admin/cache/repodata",
+                Some("secret"),
+                Some(serde_json::to_value(body).unwrap()),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: admin::InsertRepoDataOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            assert!(!body.hash.is_empty());
+        }
+
+        let body = SolveEnvironment {
+            specs: vec!["app".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body.packages.len(), 3);
+        assert!(body.packages[0]
+            .url
+            .as_str()
+            .starts_with("https://example.com/local/"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_flush_cache() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let app = app(Arc::new(state));
+        let mock_endpoints = [
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/repodata.json")
+                .with_body(dependent_repodata_json())
+                .expect(2)
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/noarch/repodata.json")
+                .with_body(empty_repodata_json())
+                .expect(2)
+                .create_async()
+                .await,
+        ];
+
+        let body = || SolveEnvironment {
+            specs: vec!["app".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::POST,
+            "/admin/cache/flush",
+            Some("secret"),
+            Some(serde_json::json!({ "disk": true })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: available_packages_cache::FlushResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(result.entries_removed, 2);
+        assert_eq!(result.snapshots_removed, 0);
+        // Both the repodata and the cache state of each subdir
+        assert_eq!(result.files_removed, 4);
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::GET,
+            "/admin/cache",
+            Some("secret"),
+            None,
+        )
+        .await;
+        let info: available_packages_cache::CacheInfo =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(info.entries.is_empty());
+
+        // The repodata is downloaded again
+        let response = post_solve(app, body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for mock in mock_endpoints {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_flush_solve_cache() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        state.solve_results =
+            Some(GenericCache::with_expiration(Duration::from_secs(60)).with_capacity(10));
+        let app = app(Arc::new(state));
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let flush = |method: http::Method, uri: &'static str, body: Option<serde_json::Value>| {
+            let app = app.clone();
+            async move {
+                let response = post_solve(app.clone(), default_solve_body()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let response = send_admin_request(app, method, uri, Some("secret"), body).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let result: available_packages_cache::FlushResult =
+                    serde_json::from_str(&response_body(response).await).unwrap();
+                result.solve_results_removed
+            }
+        };
+
+        // The solve results are only removed if requested
+        let removed = flush(
+            http::Method::POST,
+            "/admin/cache/flush",
+            Some(serde_json::json!({})),
+        )
+        .await;
+        assert_eq!(removed, 0);
+        let body = serde_json::json!({ "solve_cache": true });
+        let removed = flush(http::Method::POST, "/admin/cache/flush", Some(body)).await;
+        assert_eq!(removed, 1);
+        let uri = "/admin/cache?channel=conda-forge&solve_cache=true";
+        let removed = flush(http::Method::DELETE, uri, None).await;
+        assert_eq!(removed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_invalidate_cache() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let app = app(Arc::new(state));
+        let mock_endpoints = [
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/repodata.json")
+                .with_body(dependent_repodata_json())
+                .expect(1)
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/noarch/repodata.json")
+                .with_body(empty_repodata_json())
+                .expect(2)
+                .create_async()
+                .await,
+        ];
+
+        let body = || SolveEnvironment {
+            specs: vec!["app".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let invalidate = |uri: &'static str| {
+            let app = app.clone();
+            async move { send_admin_request(app, http::Method::DELETE, uri, Some("secret"), None).await }
+        };
+        let response = invalidate("/admin/cache?platform=noarch").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = invalidate("/admin/cache?channel=conda-forge&platform=noarch").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: available_packages_cache::FlushResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(result.entries_removed, 1);
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::GET,
+            "/admin/cache",
+            Some("secret"),
+            None,
+        )
+        .await;
+        let info: available_packages_cache::CacheInfo =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(info.entries.len(), 1);
+        assert!(info.entries[0].url.as_str().ends_with("/linux-64/"));
+
+        // Only the invalidated repodata is downloaded again
+        let response = post_solve(app.clone(), body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for mock in mock_endpoints {
+            mock.assert_async().await;
+        }
+
+        // Without a channel, everything is removed
+        let response = invalidate("/admin/cache").await;
+        let result: available_packages_cache::FlushResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(result.entries_removed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_log_level() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (filter, handle) =
+            logging::reloadable_filter(tracing::level_filters::LevelFilter::INFO);
+        // The filter must stay installed for the handle to work
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(filter));
+
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        state.log_level = Some(handle);
+        let app = app(Arc::new(state));
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::PUT,
+            "/admin/log-level",
+            Some("secret"),
+            Some(serde_json::json!({ "level": "debug" })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send_admin_request(
+            app.clone(),
+            http::Method::GET,
+            "/admin/log-level",
+            Some("secret"),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_body(response).await, r#"{"level":"debug"}"#);
+
+        let response = send_admin_request(
+            app,
+            http::Method::PUT,
+            "/admin/log-level",
+            Some("secret"),
+            Some(serde_json::json!({ "level": "loud" })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn grpc_client(state: AppState) -> RattlerServerClient<tonic::transport::Channel> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(grpc::serve(
+            listener,
+            Arc::new(state),
+            std::future::pending(),
+        ));
+        RattlerServerClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap()
+    }
+
+    fn grpc_solve_request() -> grpc::proto::SolveRequest {
+        grpc::proto::SolveRequest {
+            platform: "linux-64".to_string(),
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            channels: vec!["conda-forge".to_string()],
+            virtual_packages: Some(grpc::proto::VirtualPackages {
+                specs: vec!["__unix".to_string()],
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_solve() {
+        let (mut mock_channel_server, state) = dummy_state().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let mut client = grpc_client(state).await;
+
+        let request = grpc::proto::SolveRequest {
+            include_graph: true,
+            ..grpc_solve_request()
+        };
+        let response = client.solve(request).await.unwrap().into_inner();
+        let names: Vec<_> = response.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "bar"]);
+        assert_eq!(response.summary.unwrap().package_count, 2);
+        assert!(response.graph.contains_key("foo"));
+        assert_eq!(response.repodata_hashes.len(), 2);
+
+        // The records of a solve can be passed back, e.g. to keep them installed
+        let request = grpc::proto::SolveRequest {
+            locked_packages: vec![grpc::proto::PackageReference {
+                reference: Some(grpc::proto::package_reference::Reference::Record(
+                    response.packages[0].clone(),
+                )),
+            }],
+            ..grpc_solve_request()
+        };
+        let locked = client.solve(request).await.unwrap().into_inner();
+        assert_eq!(locked.packages, response.packages);
+
+        let response = client
+            .solve_multi(grpc::proto::SolveMultiRequest {
+                platforms: vec!["linux-64".to_string()],
+                environment: Some(grpc_solve_request()),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.platforms["linux-64"].packages.len(), 2);
+
+        // RPCs always return the solution, even if the client sends an ETag to revalidate
+        for if_none_match in ["*", "\"anything\""] {
+            let mut request = tonic::Request::new(grpc_solve_request());
+            request
+                .metadata_mut()
+                .insert("if-none-match", if_none_match.parse().unwrap());
+            let response = client.solve(request).await.unwrap().into_inner();
+            assert_eq!(response.packages.len(), 2);
+        }
+
+        // Errors carry the body of the equivalent HTTP error
+        let request = grpc::proto::SolveRequest {
+            platform: "asdfasdf".to_string(),
+            ..grpc_solve_request()
+        };
+        let status = client.solve(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let details: serde_json::Value = serde_json::from_slice(status.details()).unwrap();
+        assert_eq!(details["error_kind"], "validation");
+        assert!(details.to_string().contains("asdfasdf"));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_cache_admin() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let mut client = grpc_client(state).await;
+        client.solve(grpc_solve_request()).await.unwrap();
+
+        let status = client
+            .get_cache_info(grpc::proto::GetCacheInfoRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        fn authorized<T>(message: T) -> tonic::Request<T> {
+            let mut request = tonic::Request::new(message);
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer secret".parse().unwrap());
+            request
+        }
+        let info = client
+            .get_cache_info(authorized(grpc::proto::GetCacheInfoRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(info.entries.len(), 2);
+
+        let result = client
+            .flush_cache(authorized(grpc::proto::FlushCacheRequest {
+                disk: false,
+                solve_cache: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.entries_removed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_client_rate_limits() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.client_rate_limits = Some(ClientRateLimits::new(1, Some(1), false));
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let mut client = grpc_client(state).await;
+
+        client.solve(grpc_solve_request()).await.unwrap();
+        let status = client.solve(grpc_solve_request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_validate_channel() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mut mocks = Vec::new();
+        for path in [
+            "/conda-forge/linux-64/repodata.json",
+            "/conda-forge/linux-64/repodata.json.zst",
+        ] {
+            let mock = mock_channel_server.mock("HEAD", path).create_async().await;
+            mocks.push(mock);
+        }
+
+        let request = Request::builder()
+            .uri("/channels/validate?channel=conda-forge&platform=linux-64")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: channels::ValidateChannelResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+
+        assert!(result.reachable);
+        assert_eq!(
+            result.variants,
+            available_packages_cache::Availability {
+                zst: true,
+                bz2: false,
+                plain: true,
+            }
+        );
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+
+        // Nothing is served for this channel
+        let request = Request::builder()
+            .uri("/channels/validate?channel=missing&platform=linux-64")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: channels::ValidateChannelResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(!result.reachable);
+    }
+
+    #[tokio::test]
+    async fn test_package_metadata() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let repodata = |subdir: &str, builds: &[(&str, &str, u64)]| {
+            let packages: serde_json::Map<_, _> = builds
+                .iter()
+                .map(|(version, build, build_number)| {
+                    let mut record = repodata_record("foo", version, &["python"]);
+                    record["build"] = (*build).into();
+                    record["build_number"] = (*build_number).into();
+                    record["size"] = 1024.into();
+                    record["subdir"] = subdir.into();
+                    (format!("foo-{version}-{build}.tar.bz2"), record)
+                })
+                .collect();
+            serde_json::json!({ "info": { "subdir": subdir }, "packages": packages }).to_string()
+        };
+        let mut mocks = Vec::new();
+        for (subdir, builds) in [
+            ("linux-64", &[("1.0", "h1_0", 0), ("1.0", "h1_1", 1)][..]),
+            ("noarch", &[("2.0", "py_0", 0)][..]),
+        ] {
+            let mock = mock_channel_server
+                .mock(
+                    "GET",
+                    format!("/conda-forge/{subdir}/repodata.json").as_str(),
+                )
+                .with_body(repodata(subdir, builds))
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+
+        let request = Request::builder()
+            .uri("/channels/conda-forge/packages/foo?platform=linux-64,noarch")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let metadata: channels::PackageMetadata =
+            serde_json::from_str(&response_body(response).await).unwrap();
+
+        assert_eq!(metadata.name, "foo");
+        let versions: Vec<_> = metadata.versions.iter().map(|v| &v.version).collect();
+        assert_eq!(versions, ["2.0", "1.0"]);
+        let builds: Vec<_> = metadata.versions[1]
+            .builds
+            .iter()
+            .map(|b| (b.build.as_str(), b.subdir.as_str()))
+            .collect();
+        assert_eq!(builds, [("h1_1", "linux-64"), ("h1_0", "linux-64")]);
+        let build = &metadata.versions[0].builds[0];
+        assert_eq!(build.depends, ["python"]);
+        assert_eq!(build.size, Some(1024));
+        assert_eq!(
+            build.url,
+            format!(
+                "{}/conda-forge/noarch/foo-2.0-py_0.tar.bz2",
+                mock_channel_server.url()
+            )
+        );
+
+        // The package is not in the channel
+        let request = Request::builder()
+            .uri("/channels/conda-forge/packages/bar?platform=linux-64")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_packages() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let search = |query: &str| {
+            let request = Request::builder()
+                .uri(format!("/channels/conda-forge/linux-64/search?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = search("q=F*").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: channels::SearchResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let names: Vec<_> = result.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["foo"]);
+        let versions: Vec<_> = result.packages[0]
+            .versions
+            .iter()
+            .map(|v| v.version.as_str())
+            .collect();
+        assert_eq!(versions, ["3.0.2"]);
+        assert_eq!(result.packages[0].versions[0].builds[0].size, Some(414494));
+
+        let response = search("q=*&version=%3C3").await.unwrap();
+        let result: channels::SearchResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let names: Vec<_> = result.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["bar"]);
+
+        let response = search("q=?a?").await.unwrap();
+        let result: channels::SearchResult =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(result.packages.len(), 1);
+
+        let response = search("q=*&version=%3E%3E1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_package_build() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let get_build = |path: &str| {
+            let request = Request::builder()
+                .uri(format!("/channels/conda-forge/linux-64/packages/{path}"))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = get_build("foo/3.0.2/py36h1af98f8_1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let record: RepoDataRecord = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(record.package_record.name.as_normalized(), "foo");
+        assert_eq!(record.package_record.license.as_deref(), Some("MIT"));
+        assert_eq!(record.package_record.size, Some(414494));
+        assert_eq!(record.file_name, "foo-3.0.2-py36h1af98f8_1.tar.bz2");
+
+        let response = get_build("foo/3.0.2/py36h1af98f8_0").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get_build("foo/3.0.2!!/py36h1af98f8_1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_package_dependents() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let record = |name: &str, version: &str, depends: &[&str], constrains: &[&str]| {
+            let mut record = repodata_record(name, version, depends);
+            record["constrains"] = constrains.into();
+            (format!("{name}-{version}-0.tar.bz2"), record)
+        };
+        let packages: serde_json::Map<_, _> = [
+            record("openssl", "1.1.1", &[], &[]),
+            record("openssl", "3.0.0", &[], &[]),
+            record("curl", "8.0", &["openssl >=1.1.1,<1.1.2a"], &[]),
+            record("curl", "8.1", &["openssl >=3"], &[]),
+            record("python", "3.12", &["openssl"], &[]),
+            record("conda", "23.0", &["python"], &["openssl <3"]),
+        ]
+        .into_iter()
+        .collect();
+        let _mock = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .with_body(
+                serde_json::json!({ "info": { "subdir": "linux-64" }, "packages": packages })
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let whoneeds = |query: &str| {
+            let request = Request::builder()
+                .uri(format!("/channels/conda-forge/linux-64/whoneeds?{query}"))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let result: channels::DependentsResult =
+                    serde_json::from_str(&response_body(response).await).unwrap();
+                result
+                    .dependents
+                    .into_iter()
+                    .map(|d| format!("{}-{}", d.name, d.version))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            whoneeds("name=openssl").await,
+            ["conda-23.0", "curl-8.1", "curl-8.0", "python-3.12"]
+        );
+        assert_eq!(
+            whoneeds("name=openssl&version=1.1.*").await,
+            ["conda-23.0", "curl-8.0", "python-3.12"]
+        );
+        assert_eq!(whoneeds("name=python").await, ["conda-23.0"]);
+        assert!(whoneeds("name=curl").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = || SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string()],
+            ..default_solve_body()
+        };
+        for _ in 0..2 {
+            let response = post_solve(app.clone(), body()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let value = |name: &str| -> f64 {
+            let line = body
+                .lines()
+                .find(|line| line.split_once(' ').map(|(n, _)| n) == Some(name))
+                .unwrap_or_else(|| panic!("missing {name} in:\n{body}"));
+            line.split_once(' ').unwrap().1.parse().unwrap()
+        };
+
+        // Two subdirs are fetched by the first solve, and served from memory for the second one
+        assert_eq!(value("rattler_server_solve_requests_total"), 2.0);
+        assert_eq!(value("rattler_server_solve_duration_seconds_count"), 2.0);
+        assert_eq!(value("rattler_server_repodata_cache_misses_total"), 2.0);
+        assert_eq!(value("rattler_server_repodata_cache_hits_total"), 2.0);
+        assert!(value("rattler_server_downloaded_bytes_total") > 0.0);
+        assert!(value("rattler_server_repodata_cache_bytes") > 0.0);
+        // The metrics request itself is in flight
+        assert_eq!(value("rattler_server_in_flight_requests"), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_solve_results_are_cached() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.solve_results =
+            Some(GenericCache::with_expiration(Duration::from_secs(60)).with_capacity(10));
+        let state = Arc::new(state);
+        let app = app(state.clone());
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = |exclude: Vec<String>| SolveEnvironment {
+            virtual_packages: Some(vec!["__unix".to_string()]),
+            specs: vec!["foo".to_string()],
+            exclude,
+            ..default_solve_body()
+        };
+        let mut responses = Vec::new();
+        for exclude in [Vec::new(), Vec::new(), vec!["bar".to_string()]] {
+            let response = post_solve(app.clone(), body(exclude)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: SolveEnvironmentOk =
+                serde_json::from_str(&response_body(response).await).unwrap();
+            responses.push(body.packages);
+        }
+
+        // The repeated request is answered from the cache, but a different one is solved
+        assert_eq!(responses[0], responses[1]);
+        let metrics = state.metrics.render();
+        assert!(metrics
+            .lines()
+            .any(|l| l == "rattler_server_solve_cache_hits_total 1"));
+        assert!(metrics
+            .lines()
+            .any(|l| l == "rattler_server_solve_duration_seconds_count 2"));
+    }
+
+    #[tokio::test]
+    async fn test_solve_events() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let post_events = |specs: Vec<String>| {
+            let body = SolveEnvironment {
+                virtual_packages: Some(vec!["__unix".to_string()]),
+                specs,
+                ..default_solve_body()
+            };
+            let request = Request::builder()
+                .uri(progress::EVENTS_ROUTE)
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = post_events(vec!["foo".to_string()]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        let body = response_body(response).await;
+        let events: Vec<(&str, &str)> = body
+            .split("\n\n")
+            .filter_map(|event| {
+                let kind = event.lines().find_map(|l| l.strip_prefix("event: "))?;
+                let data = event.lines().find_map(|l| l.strip_prefix("data: "))?;
+                Some((kind, data))
+            })
+            .collect();
+
+        let phases: Vec<_> = events
+            .iter()
+            .filter(|(kind, _)| *kind == "progress")
+            .map(|(_, data)| {
+                let data: serde_json::Value = serde_json::from_str(data).unwrap();
+                data["phase"].as_str().unwrap().to_string()
+            })
+            .collect();
+        for phase in ["fetching", "downloading", "parsing", "fetched", "solving"] {
+            assert!(phases.iter().any(|p| p == phase), "{phase} in {phases:?}");
+        }
+
+        // The stream ends with the solution
+        let (kind, data) = events.last().unwrap();
+        assert_eq!(*kind, "result");
+        let solution: SolveEnvironmentOk = serde_json::from_str(data).unwrap();
+        assert_eq!(solution.packages.len(), 1);
+
+        // Failures end the stream too
+        let response = post_events(vec!["baz".to_string()]).await.unwrap();
+        let body = response_body(response).await;
+        assert!(
+            body.contains("event: error\ndata: {\"error_kind\":\"solver\""),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_versioned_routes() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let solve = |uri: &str| {
+            let body = SolveEnvironment {
+                virtual_packages: Some(vec!["__unix".to_string()]),
+                specs: vec!["foo".to_string()],
+                ..default_solve_body()
+            };
+            let request = Request::builder()
+                .uri(uri)
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let response = solve("/v1/solve").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let versioned: serde_json::Value =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let response = solve("/solve").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let unversioned: serde_json::Value =
+            serde_json::from_str(&response_body(response).await).unwrap();
+
+        // The unversioned routes respond with the same schema
+        assert_eq!(versioned["packages"], unversioned["packages"]);
+        let package = &versioned["packages"][0];
+        assert_eq!(package["name"], "foo");
+        assert_eq!(package["fn"], "foo-3.0.2-py36h1af98f8_1.tar.bz2");
+        assert_eq!(package["md5"], "d65ab674acf3b7294ebacaec05fc5b54");
+        assert_eq!(package["timestamp"], 1605110689658i64);
+
+        // Jobs are polled through the version of the API they were created through
+        let response = solve("/v1/jobs").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("/v1/jobs/"), "{location}");
+    }
+
+    #[tokio::test]
+    async fn test_solve_jobs() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let send = |method: http::Method, uri: String, body: Body| {
+            let request = Request::builder()
+                .uri(uri)
+                .method(method)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(body)
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let create = |specs: Vec<String>| {
+            let body = SolveEnvironment {
+                virtual_packages: Some(vec!["__unix".to_string()]),
+                specs,
+                ..default_solve_body()
+            };
+            let body = Body::from(serde_json::to_vec(&body).unwrap());
+            send(http::Method::POST, "/jobs".to_string(), body)
+        };
+        let poll = |location: String| async move {
+            loop {
+                let response = send(http::Method::GET, location.clone(), Body::empty())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let status: jobs::JobStatus =
+                    serde_json::from_str(&response_body(response).await).unwrap();
+                if status.status != jobs::JobStatusKind::Running {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        let response = create(vec!["foo".to_string()]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let created: jobs::JobStatus =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(location, format!("/jobs/{}", created.id));
+        assert_eq!(created.status, jobs::JobStatusKind::Running);
+
+        let status = poll(location.clone()).await;
+        assert_eq!(status.status, jobs::JobStatusKind::Succeeded);
+        assert_eq!(status.http_status, Some(200));
+        let solution: SolveEnvironmentOk = serde_json::from_value(status.result.unwrap()).unwrap();
+        assert_eq!(solution.packages.len(), 1);
+
+        // Failed solves are reported with the error they would have been answered with
+        let response = create(vec!["baz".to_string()]).await.unwrap();
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let status = poll(location.clone()).await;
+        assert_eq!(status.status, jobs::JobStatusKind::Failed);
+        assert_eq!(status.http_status, Some(409));
+        assert_eq!(status.result.unwrap()["error_kind"], "solver");
+
+        // Deleted jobs are gone
+        let response = send(http::Method::DELETE, location.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        for method in [http::Method::GET, http::Method::DELETE] {
+            let response = send(method, location.clone(), Body::empty()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let response = send(http::Method::GET, "/jobs/foo".to_string(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_solve_batch() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.max_solves_per_batch = 3;
+        let app = app(Arc::new(state));
+        let mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let solve_batch = |specs: &[&str]| {
+            let body = batch::BatchSolve {
+                requests: specs
+                    .iter()
+                    .map(|spec| SolveEnvironment {
+                        specs: vec![spec.to_string()],
+                        ..default_solve_body()
+                    })
+                    .collect(),
+            };
+            let request = Request::builder()
+                .uri("/solve/batch?debug=1")
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        // Each request gets its own result, and a failed request doesn't fail the others
+        let response = solve_batch(&["foo", "foo >=>=1", "foo <4"]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: batch::BatchSolveOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        let statuses: Vec<_> = body.results.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, [200, 400, 200]);
+        for i in [0, 2] {
+            let solution = body.results[i].solution.as_ref().unwrap();
+            assert_eq!(
+                solution.packages[0].package_record.version.as_str(),
+                "3.0.2"
+            );
+            assert!(solution.solver_stats.is_some());
+        }
+        assert!(body.results[1].solution.is_none());
+        let error = body.results[1].error.as_ref().unwrap();
+        assert_eq!(error["error_kind"], "validation");
+
+        // The repodata is only downloaded once
+        for endpoint in mock_endpoints {
+            endpoint.assert_async().await;
+        }
+
+        let response = solve_batch(&["foo"; 4]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(body.contains("too many solves"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_solve_multi_platform() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mut mocks = Vec::new();
+        for (subdir, repodata) in [
+            ("linux-64", small_repodata_json()),
+            ("osx-arm64", dual_format_repodata_json()),
+        ] {
+            let mock = mock_channel_server
+                .mock(
+                    "GET",
+                    format!("/conda-forge/{subdir}/repodata.json").as_str(),
+                )
+                .with_body(repodata)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        // The noarch repodata is shared by both platforms
+        let noarch = mock_channel_server
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_body(empty_repodata_json())
+            .expect(1)
+            .create_async()
+            .await;
+
+        let solve_multi = |query: &str, platforms: &[&str]| {
+            let mut environment = serde_json::to_value(SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                ..default_solve_body()
+            })
+            .unwrap();
+            environment.as_object_mut().unwrap().remove("platform");
+            let body = multi_platform::MultiPlatformSolve {
+                platforms: platforms.iter().map(|p| p.to_string()).collect(),
+                environment: environment.as_object().unwrap().clone(),
+            };
+            let request = Request::builder()
+                .uri(format!("/solve/multi?{query}"))
+                .method(http::Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = solve_multi("", &["linux-64", "osx-arm64", "linux-64"])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: multi_platform::MultiPlatformSolveOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(
+            body.platforms.keys().collect::<Vec<_>>(),
+            ["linux-64", "osx-arm64"]
+        );
+        for (platform, solution) in &body.platforms {
+            let names: Vec<_> = solution
+                .packages
+                .iter()
+                .map(|p| p.package_record.name.as_normalized())
+                .collect();
+            assert_eq!(names, ["foo"]);
+            assert!(solution
+                .repodata_hashes
+                .keys()
+                .any(|url| url.ends_with(&format!("/{platform}/"))));
+        }
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        noarch.assert_async().await;
+
+        // Both platforms end up in a single lockfile
+        let response = solve_multi("format=conda-lock", &["linux-64", "osx-arm64"])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let lockfile: serde_yaml::Value =
+            serde_yaml::from_str(&response_body(response).await).unwrap();
+        let content_hashes = lockfile["metadata"]["content_hash"].as_mapping().unwrap();
+        assert_eq!(content_hashes.len(), 2);
+        let platforms: Vec<_> = lockfile["package"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|p| p["platform"].as_str().unwrap())
+            .collect();
+        assert_eq!(platforms, ["linux-64", "osx-arm64"]);
+
+        let response = solve_multi("format=pixi-lock", &["linux-64", "osx-arm64"])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let lockfile: serde_yaml::Value =
+            serde_yaml::from_str(&response_body(response).await).unwrap();
+        let platforms = lockfile["environments"]["default"]["packages"]
+            .as_mapping()
+            .unwrap();
+        assert_eq!(platforms.len(), 2);
+        assert!(!lockfile["packages"].as_sequence().unwrap().is_empty());
+
+        let response = solve_multi("", &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeout() {
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        state.request_timeouts = RequestTimeouts::new(1, &[]);
+        let state = Arc::new(state);
+
+        // Sets the flag once the handler's future is dropped
+        struct SetOnDrop(Arc<std::sync::atomic::AtomicBool>);
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+        let cancelled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let guard_flag = cancelled.clone();
+        let slow_app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move {
+                    let _guard = SetOnDrop(guard_flag);
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                request_timeout::enforce,
+            ))
+            .with_state(state.clone());
+
+        let request = Request::builder().uri("/slow").body(Body::empty()).unwrap();
+        let response = slow_app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "timeout");
+        assert!(cancelled.load(std::sync::atomic::Ordering::SeqCst));
+
+        // The version endpoint is exempt
+        let request = Request::builder()
+            .uri("/version")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_solve_timeout() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.solve_timeout = Some(Duration::from_secs(3600));
+        let app = app(Arc::new(state));
+        let _slow_endpoints = [
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/repodata.json")
+                .with_body_from_request(|_| {
+                    std::thread::sleep(Duration::from_secs(2));
+                    small_repodata_json().into_bytes()
+                })
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/noarch/repodata.json")
+                .with_body(empty_repodata_json())
+                .create_async()
+                .await,
+        ];
+
+        // The request's timeout takes precedence over the server's
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            timeout_ms: Some(100),
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "timeout");
+        assert_eq!(
+            body["additional_info"],
+            serde_json::json!({ "phase": "fetching", "timeout_ms": 100 })
+        );
+    }
+
+    async fn get_selftest(app: Router) -> (StatusCode, selftest::SelftestResult) {
+        let request = Request::builder()
+            .uri("/selftest")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        (
+            status,
+            serde_json::from_str(&response_body(response).await).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_selftest_ok() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // The repodata is fetched again on every self-test
+        for _ in 0..2 {
+            let (status, result) = get_selftest(app.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(result.ok);
+            assert_eq!(result.stage, None);
+        }
+
+        for mock in mocks {
+            mock.expect(2).assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_selftest_reports_failed_stage() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+
+        // No repodata is available
+        let (status, result) = get_selftest(app.clone()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!result.ok);
+        assert_eq!(result.stage, Some(selftest::Stage::Fetch));
+
+        // The canary spec can't be satisfied by empty repodata
+        let _mocks = mock_linux64(&mut mock_channel_server, empty_repodata_json()).await;
+        let (status, result) = get_selftest(app).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(result.stage, Some(selftest::Stage::Solve));
+    }
+
+    #[tokio::test]
+    async fn test_admin_cache_info() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.admin_token = Some("secret".to_string());
+        let app = app(Arc::new(state));
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let response = post_solve(app.clone(), default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        mock_instant::MockClock::advance(Duration::from_secs(30));
+
+        let response =
+            send_admin_request(app, http::Method::GET, "/admin/cache", Some("secret"), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let info: available_packages_cache::CacheInfo =
+            serde_json::from_str(&response_body(response).await).unwrap();
+
+        let urls: Vec<_> = info.entries.iter().map(|e| e.url.path()).collect();
+        assert_eq!(urls, vec!["/conda-forge/linux-64/", "/conda-forge/noarch/"]);
+        assert!(info.entries.iter().all(|e| e.age_seconds == 30));
+        assert_eq!(info.entries[0].record_count, 2);
+        assert_eq!(info.entries[1].record_count, 0);
+        assert!(info.snapshots.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_health_and_readiness() {
+        let (_mock_channel_server, mut state) = dummy_state().await;
+        let config: api_keys::ApiKeysConfig = serde_json::from_value(serde_json::json!({
+            "keys": { "ci": { "token": "ci-secret" } }
+        }))
+        .unwrap();
+        state.api_keys = Some(ApiKeys::from_config(config).unwrap());
+        state.warm_up = health::WarmUp::new(1);
+        let state = Arc::new(state);
+        let app = app(state.clone());
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap()
+            }
+        };
+
+        // The probes don't require an API key
+        let response = get("/healthz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let health: health::Health = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(health.status, "ok");
+
+        // The server is not ready until the subdirs to warm were fetched once
+        let response = get("/readyz").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: health::Readiness =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(!readiness.ready);
+        assert!(!readiness.warm_up.ok);
+        assert!(readiness.solver_pool.ok);
+        assert!(readiness.disk_cache.ok);
+
+        state.warm_up.record_round(1);
+        let response = get("/readyz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let readiness: health::Readiness =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert!(readiness.ready);
+        assert_eq!(readiness.warm_up.detail, "1 of 1 subdirs are warm");
+    }
+
+    async fn post_solve_from(app: Router, client: &str, forwarded_for: Option<&str>) -> Response {
+        let mut request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("x-forwarded-for", forwarded_for);
+        }
+        let mut request = request
+            .body(Body::from(
+                serde_json::to_vec(&default_solve_body()).unwrap(),
+            ))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(client.parse::<std::net::SocketAddr>().unwrap()));
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.join("audit.jsonl");
+        state.audit_log = Some(audit::AuditLog::open(&path, false).unwrap());
+        state.solve_results =
+            Some(GenericCache::with_expiration(Duration::from_secs(60)).with_capacity(10));
+        let app = app(Arc::new(state));
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let response = post_solve_from(app.clone(), "10.0.0.1:1234", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = SolveEnvironment {
+            specs: vec!["foo >=>=1".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post_solve_from(app.clone(), "10.0.0.2:1234", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<audit::AuditRecord> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let [solved, failed, cached] = records.as_slice() else {
+            panic!("expected 3 records, got {log}");
+        };
+
+        assert_eq!(solved.client_ip, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(solved.specs, default_solve_body().specs);
+        assert_eq!(solved.platform, "linux-64");
+        assert_eq!(solved.repodata_fetched, 2);
+        assert_eq!(solved.repodata_cache_hits, 0);
+        assert_eq!(solved.solve_cache_hit, Some(false));
+        assert!(solved.result_hash.is_some());
+        assert!(solved.error_kind.is_none());
+
+        assert_eq!(failed.client_ip, None);
+        assert_eq!(failed.error_kind.as_deref(), Some("validation"));
+        assert!(failed.result_hash.is_none());
+
+        // The repeated solve is served from the caches, with the same result
+        assert_eq!(cached.client_ip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(cached.repodata_cache_hits, 2);
+        assert_eq!(cached.solve_cache_hit, Some(true));
+        assert_eq!(cached.result_hash, solved.result_hash);
+    }
+
+    #[tokio::test]
+    async fn test_client_rate_limits() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.client_rate_limits = Some(ClientRateLimits::new(1, Some(2), false));
+        let app = app(Arc::new(state));
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // Each client may make a burst of two requests
+        for remaining in ["1", "0"] {
+            let response = post_solve_from(app.clone(), "10.0.0.1:4000", None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["ratelimit-limit"], "2");
+            assert_eq!(response.headers()["ratelimit-remaining"], remaining);
+        }
+        let response = post_solve_from(app.clone(), "10.0.0.1:4001", None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        let response = post_solve_from(app.clone(), "10.0.0.2:4000", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The forwarded address is ignored unless enabled
+        let response = post_solve_from(app.clone(), "10.0.0.1:4000", Some("10.0.0.3")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let metrics = response_body(app.oneshot(request).await.unwrap()).await;
+        assert!(metrics
+            .lines()
+            .any(|l| l == "rattler_server_client_rate_limited_total 2"));
+    }
+
+    #[tokio::test]
+    async fn test_client_rate_limits_behind_proxy() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        state.client_rate_limits = Some(ClientRateLimits::new(1, None, true));
+        let app = app(Arc::new(state));
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // The clients are told apart by the address that the proxy appended
+        let proxy = "10.0.0.100:4000";
+        let response = post_solve_from(app.clone(), proxy, Some("1.2.3.4, 10.0.0.1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post_solve_from(app.clone(), proxy, Some("5.6.7.8, 10.0.0.1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = post_solve_from(app.clone(), proxy, Some("10.0.0.2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn post_solve_as_tenant(app: Router, token: &str, body: SolveEnvironment) -> Response {
+        let request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tenants_private_channels() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        let config: tenants::TenantsConfig = serde_json::from_value(serde_json::json!({
+            "tenants": {
+                "token-a": {
+                    "channels": { format!("{server_url}/team-a"): { "BearerToken": "secret-a" } }
+                },
+                "token-b": {
+                    "channels": { format!("{server_url}/team-b"): { "BearerToken": "secret-b" } }
+                }
+            }
+        }))
+        .unwrap();
+        state.tenants = Some(Tenants::from_config(config).unwrap());
+        let app = app(Arc::new(state));
+
+        let mut mocks = Vec::new();
+        for (team, secret) in [("team-a", "secret-a"), ("team-b", "secret-b")] {
+            for (subdir, body) in [
+                ("linux-64", small_repodata_json()),
+                ("noarch", empty_repodata_json()),
+            ] {
+                let mock = mock_channel_server
+                    .mock("GET", format!("/{team}/{subdir}/repodata.json").as_str())
+                    .match_header("authorization", format!("Bearer {secret}").as_str())
+                    .with_body(body)
+                    .create_async()
+                    .await;
+                mocks.push(mock);
+            }
+        }
+
+        let body_for = |team: &str| SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            channels: vec![format!("{server_url}/{team}")],
+            ..default_solve_body()
+        };
+
+        // Each tenant may use its own channel, downloaded with its own credentials
+        let response = post_solve_as_tenant(app.clone(), "token-a", body_for("team-a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post_solve_as_tenant(app.clone(), "token-b", body_for("team-b")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // But not another tenant's channel, even if it is already cached
+        let response = post_solve_as_tenant(app.clone(), "token-a", body_for("team-b")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = post_solve(app.clone(), body_for("team-a")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Unknown tokens are rejected
+        let response = post_solve_as_tenant(app, "token-c", body_for("team-a")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_and_tenants() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        let config: api_keys::ApiKeysConfig = serde_json::from_value(serde_json::json!({
+            "keys": { "ci": { "token": "ci-secret" } }
+        }))
+        .unwrap();
+        state.api_keys = Some(ApiKeys::from_config(config).unwrap());
+        let config: tenants::TenantsConfig = serde_json::from_value(serde_json::json!({
+            "tenants": {
+                "token-a": {
+                    "channels": { format!("{server_url}/team-a"): { "BearerToken": "secret-a" } }
+                }
+            }
+        }))
+        .unwrap();
+        state.tenants = Some(Tenants::from_config(config).unwrap());
+        let app = app(Arc::new(state));
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        // Callers with an API key are anonymous to the tenants, so they may use public channels
+        let response = post_solve_as_tenant(app.clone(), "ci-secret", default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // But not private ones
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            channels: vec![format!("{server_url}/team-a")],
+            ..default_solve_body()
+        };
+        let response = post_solve_as_tenant(app.clone(), "ci-secret", body).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Tokens that are neither are still rejected
+        let response = post_solve_as_tenant(app, "unknown", default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_channel_policy() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        let settings = state.settings_mut();
+        settings.channel_policy = ChannelPolicy::new(
+            &["conda-forge".to_string()],
+            &[],
+            &settings.channel_config,
+            &settings.custom_channels,
+        )
+        .unwrap();
+        let app = app(Arc::new(state));
+
+        let mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let other = mock_channel_server
+            .mock("GET", mockito::Matcher::Regex("^/other/".to_string()))
+            .expect(0)
+            .create_async()
+            .await;
+
+        let response = post_solve(app.clone(), default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Other channels are rejected without being downloaded
+        let body = SolveEnvironment {
+            channels: vec!["conda-forge".to_string(), format!("{server_url}/other")],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "channel_not_allowed");
+        assert_eq!(body["additional_info"], format!("{server_url}/other/"));
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        other.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_channels() {
+        let (mut mock_channel_server, mut state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        state.settings_mut().custom_channels =
+            CustomChannels::new(&[format!("internal={server_url}/mirror")]).unwrap();
+        let app = app(Arc::new(state));
+
+        let mut mocks = Vec::new();
+        for (subdir, repodata) in [
+            ("linux-64", small_repodata_json()),
+            ("noarch", empty_repodata_json()),
+        ] {
+            let mock = mock_channel_server
+                .mock(
+                    "GET",
+                    format!("/mirror/internal/label/dev/{subdir}/repodata.json").as_str(),
+                )
+                .with_body(repodata)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+
+        // A labeled channel within a custom channel is relative to the custom channel's alias
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            channels: vec!["internal/label/dev".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(
+            body.packages[0].channel,
+            format!("{server_url}/mirror/internal/label/dev/")
+        );
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_solve() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let record = |build: &str| {
+            let mut record = repodata_record("foo", "1.0", &[]);
+            record["build"] = build.into();
+            record
+        };
+        let repodata = serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "foo-1.0-b_0.tar.bz2": record("b_0"),
+                "foo-1.0-a_0.tar.bz2": record("a_0")
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        });
+        let _mocks = [
+            mock_channel_server
+                .mock("GET", "/conda-forge/linux-64/repodata.json")
+                .with_header("ETag", "\"v1\"")
+                .with_body(repodata.to_string())
+                .create_async()
+                .await,
+            mock_channel_server
+                .mock("GET", "/conda-forge/noarch/repodata.json")
+                .with_body(empty_repodata_json())
+                .create_async()
+                .await,
+        ];
+
+        let solve = |deterministic: bool| {
+            let body = SolveEnvironment {
+                specs: vec!["foo".to_string()],
+                deterministic,
+                ..default_solve_body()
+            };
+            post_solve(app.clone(), body)
+        };
+        let response = solve(true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let first = response_body(response).await;
+        // Identical requests against identical repodata get identical responses
+        assert_eq!(response_body(solve(true).await).await, first);
+
+        let body: SolveEnvironmentOk = serde_json::from_str(&first).unwrap();
+        let linux_url = format!("{}/conda-forge/linux-64/", mock_channel_server.url());
+        assert_eq!(body.repodata_etags.len(), 1);
+        assert_eq!(body.repodata_etags[&linux_url], "\"v1\"");
+        let [tie_break] = body.tie_breaks.as_slice() else {
+            panic!("expected a tie break, got {:?}", body.tie_breaks);
+        };
+        assert_eq!(tie_break.name, "foo");
+        assert_eq!(tie_break.version, "1.0");
+        assert_eq!(tie_break.chosen, body.packages[0].url.as_str());
+        assert_eq!(tie_break.candidates.len(), 1);
+        assert_ne!(tie_break.candidates[0], tie_break.chosen);
+
+        // Neither is reported otherwise
+        let body: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(solve(false).await).await).unwrap();
+        assert!(body.repodata_etags.is_empty());
+        assert!(body.tie_breaks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_solve() {
+        let (mut mock_channel_server, state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        let app = app(Arc::new(state));
+        let offline = || SolveEnvironment {
+            offline: true,
+            ..default_solve_body()
+        };
+
+        // Nothing is downloaded for offline solves, so they fail until the repodata is cached
+        let response = post_solve(app.clone(), offline()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error_kind"], "not_cached");
+        assert_eq!(
+            body["additional_info"],
+            format!("url: {server_url}/conda-forge/linux-64/")
+        );
+
+        let mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let response = post_solve(app.clone(), default_solve_body()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = post_solve(app, offline()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for mock in mocks {
+            mock.expect(1).assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_requests_in_flight() {
+        let (mut mock_channel_server, state) = dummy_state().await;
+        // The repodata arrives slowly, so the solve is still in flight when the server shuts down
+        let repodata = small_repodata_json();
+        let _linux_64 = mock_channel_server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .with_chunked_body(move |w| {
+                std::thread::sleep(Duration::from_millis(300));
+                w.write_all(repodata.as_bytes())
+            })
+            .create_async()
+            .await;
+        let _noarch = mock_channel_server
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .with_body(empty_repodata_json())
+            .create_async()
+            .await;
+
+        let state = Arc::new(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shut_down, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(shutdown::serve(
+            listener.into(),
+            app(state.clone()),
+            state,
+            Duration::from_secs(10),
+            async move {
+                shutdown_signal.await.ok();
+            },
+        ));
+
+        let solve = tokio::spawn(
+            reqwest::Client::new()
+                .post(format!("http://{addr}/solve"))
+                .header("content-type", "application/json")
+                .body(serde_json::to_vec(&default_solve_body()).unwrap())
+                .send(),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shut_down.send(()).unwrap();
+
+        let response = solve.await.unwrap().unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_caller_channel_credentials() {
+        let (mut mock_channel_server, state) = dummy_state().await;
+        let server_url = mock_channel_server.url();
+        let app = app(Arc::new(state));
+
+        let mut mocks = Vec::new();
+        for (subdir, body) in [
+            ("linux-64", small_repodata_json()),
+            ("noarch", empty_repodata_json()),
+        ] {
+            let path = format!("/private/{subdir}/repodata.json");
+            let authenticated = mock_channel_server
+                .mock("GET", path.as_str())
+                .match_header("authorization", "Bearer secret")
+                .with_body(body)
+                .expect(1)
+                .create_async()
+                .await;
+            mocks.push(authenticated);
+            mock_channel_server
+                .mock("GET", path.as_str())
+                .match_header("authorization", mockito::Matcher::Missing)
+                .with_status(401)
+                .create_async()
+                .await;
+        }
+
+        let credentials: credentials::ChannelCredentials =
+            serde_json::from_value(serde_json::json!({ "127.0.0.1": { "BearerToken": "secret" } }))
+                .unwrap();
+        let body = |channel_credentials| SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            channels: vec![format!("{server_url}/private")],
+            channel_credentials,
+            ..default_solve_body()
+        };
+
+        let response = post_solve(app.clone(), body(credentials.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The cached repodata is not shared with callers without the credentials
+        let response = post_solve(app.clone(), body(Default::default())).await;
+        assert_ne!(response.status(), StatusCode::OK);
+
+        // But it is with callers that forward the same credentials
+        let request = Request::builder()
+            .uri("/solve")
+            .method(http::Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(
+                credentials::CHANNEL_CREDENTIALS_HEADER,
+                serde_json::to_string(&credentials).unwrap(),
+            )
+            .body(Body::from(
+                serde_json::to_vec(&body(Default::default())).unwrap(),
+            ))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    /// A `linux-64` record of the package, with build `0`
+    fn repodata_record(name: &str, version: &str, depends: &[&str]) -> serde_json::Value {
+        serde_json::json!({
+            "build": "0",
+            "build_number": 0,
+            "depends": depends,
+            "name": name,
+            "subdir": "linux-64",
+            "version": version
+        })
+    }
+
+    fn empty_repodata_json() -> String {
+        r#"{
+          "info": {
+            "subdir": "linux-64"
+          },
+          "packages": {},
+          "packages.conda": {},
           "repodata_version": 1
         }"#
         .to_string()
     }
 
+    /// Repodata in which `foo 3.0.2` is available both as `.tar.bz2` and as `.conda`
+    fn dual_format_repodata_json() -> String {
+        let record = serde_json::json!({
+            "build": "py36h1af98f8_1",
+            "build_number": 1,
+            "depends": [],
+            "license": "MIT",
+            "md5": "d65ab674acf3b7294ebacaec05fc5b54",
+            "name": "foo",
+            "sha256": "1154fceeb5c4ee9bb97d245713ac21eb1910237c724d2b7103747215663273c2",
+            "size": 414494,
+            "subdir": "linux-64",
+            "timestamp": 1605110689658u64,
+            "version": "3.0.2"
+        });
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": { "foo-3.0.2-py36h1af98f8_1.tar.bz2": record },
+            "packages.conda": { "foo-3.0.2-py36h1af98f8_1.conda": record },
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
+    /// Repodata in which `foo 2.0` is GPL-licensed and `foo 1.0` is MIT-licensed
+    fn licensed_repodata_json() -> String {
+        let record = |version: &str, license: &str| {
+            let mut record = repodata_record("foo", version, &[]);
+            record["license"] = license.into();
+            record
+        };
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "foo-2.0-0.tar.bz2": record("2.0", "GPL-3.0-only"),
+                "foo-1.0-0.tar.bz2": record("1.0", "MIT")
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
+    /// Repodata in which `foo 0.1` has no timestamp, `foo 1.0` was published in 2020 and `foo 2.0`
+    /// in 2022
+    fn dated_repodata_json() -> String {
+        let record = |version: &str, timestamp: Option<u64>| {
+            let mut record = repodata_record("foo", version, &[]);
+            if let Some(timestamp) = timestamp {
+                record["timestamp"] = timestamp.into();
+            }
+            record
+        };
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "foo-0.1-0.tar.bz2": record("0.1", None),
+                "foo-1.0-0.tar.bz2": record("1.0", Some(1_577_836_800_000)),
+                "foo-2.0-0.tar.bz2": record("2.0", Some(1_640_995_200_000))
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
+    /// Repodata in which `blas` has an `mkl` build and an `openblas` build tracking `nomkl`
+    fn featured_repodata_json() -> String {
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "blas-1.0-mkl.tar.bz2": {
+                    "build": "mkl",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "blas",
+                    "subdir": "linux-64",
+                    "version": "1.0"
+                },
+                "blas-1.0-openblas.tar.bz2": {
+                    "build": "openblas",
+                    "build_number": 0,
+                    "depends": [],
+                    "name": "blas",
+                    "subdir": "linux-64",
+                    "track_features": "nomkl",
+                    "version": "1.0"
+                }
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
+    /// Repodata in which `app` depends on `lib`, which depends on `base`
+    fn dependent_repodata_json() -> String {
+        let record = |name: &str, depends: &[&str]| {
+            let mut record = repodata_record(name, "1.0", depends);
+            record["license"] = "MIT".into();
+            record
+        };
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "app-1.0-0.tar.bz2": record("app", &["lib >=1"]),
+                "lib-1.0-0.tar.bz2": record("lib", &["base"]),
+                "base-1.0-0.tar.bz2": record("base", &[])
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
+    /// Repodata in which `foo` constrains `bar` to versions below 2, and `baz` (which is absent
+    /// from the solution) to versions below 3
+    fn constrained_repodata_json() -> String {
+        let record = |name: &str, version: &str, constrains: &[&str]| {
+            let mut record = repodata_record(name, version, &[]);
+            record["constrains"] = constrains.into();
+            record["license"] = "MIT".into();
+            record
+        };
+        serde_json::json!({
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "foo-1.0-0.tar.bz2": record("foo", "1.0", &["bar <2", "baz <3"]),
+                "bar-1.0-0.tar.bz2": record("bar", "1.0", &[]),
+                "bar-2.0-0.tar.bz2": record("bar", "2.0", &[])
+            },
+            "packages.conda": {},
+            "repodata_version": 1
+        })
+        .to_string()
+    }
+
     fn small_repodata_json() -> String {
         r#"{
           "info": {
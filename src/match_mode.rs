@@ -0,0 +1,112 @@
+//! Applies the requested [`MatchMode`] to the specs of a solve request
+
+use crate::dto::MatchMode;
+use crate::error::ApiError;
+use rattler_conda_types::version_spec::{EqualityOperator, StrictRangeOperator};
+use rattler_conda_types::{MatchSpec, RepoDataRecord, StrictVersion, VersionSpec};
+
+/// Checks each spec that pins a version against the available packages. In strict mode, any such
+/// spec without a matching package causes an error. In flexible mode, the offending specs are
+/// loosened just enough to match an available package (see [`loosenings`]).
+///
+/// Returns the specs to solve, together with the textual representation of the specs that were
+/// loosened.
+pub fn apply_match_mode(
+    match_mode: MatchMode,
+    specs: Vec<MatchSpec>,
+    available_packages: &[Vec<RepoDataRecord>],
+) -> Result<(Vec<MatchSpec>, Vec<String>), ApiError> {
+    let mut result = Vec::with_capacity(specs.len());
+    let mut unmatched = Vec::new();
+    for spec in specs {
+        if !pins_version(&spec) || has_candidate(&spec, available_packages) {
+            result.push(spec);
+            continue;
+        }
+
+        // Specs for packages that don't exist at all are left to the solver, which reports them
+        // better than we can
+        let Some(loosened) = loosenings(&spec)
+            .into_iter()
+            .find(|loosened| has_candidate(loosened, available_packages))
+        else {
+            result.push(spec);
+            continue;
+        };
+
+        unmatched.push(spec.to_string());
+        result.push(loosened);
+    }
+
+    match match_mode {
+        MatchMode::Strict if !unmatched.is_empty() => Err(ApiError::NoMatchingVersion(unmatched)),
+        _ => Ok((result, unmatched)),
+    }
+}
+
+fn pins_version(spec: &MatchSpec) -> bool {
+    spec.version.is_some() || spec.build.is_some() || spec.build_number.is_some()
+}
+
+fn has_candidate(spec: &MatchSpec, available_packages: &[Vec<RepoDataRecord>]) -> bool {
+    available_packages
+        .iter()
+        .flatten()
+        .any(|record| spec.matches(&record.package_record))
+}
+
+/// Returns ever looser variants of the spec: without its build number, then without its build
+/// string too, then with its version relaxed one segment at a time if it pins one (e.g. `==1.2.3`
+/// to `1.2.3.*`, `1.2.*` and `1.*`), and finally without a version constraint.
+fn loosenings(spec: &MatchSpec) -> Vec<MatchSpec> {
+    let mut loosenings = Vec::new();
+    let mut loosened = spec.clone();
+    if loosened.build_number.take().is_some() {
+        loosenings.push(loosened.clone());
+    }
+    if loosened.build.take().is_some() {
+        loosenings.push(loosened.clone());
+    }
+
+    let mut prefix = match &loosened.version {
+        Some(VersionSpec::Exact(EqualityOperator::Equals, version)) => Some(version.clone()),
+        Some(VersionSpec::StrictRange(StrictRangeOperator::StartsWith, StrictVersion(version))) => {
+            version.pop_segments(1)
+        }
+        _ => None,
+    };
+    while let Some(version) = prefix {
+        prefix = version.pop_segments(1);
+        loosened.version = Some(VersionSpec::StrictRange(
+            StrictRangeOperator::StartsWith,
+            StrictVersion(version),
+        ));
+        loosenings.push(loosened.clone());
+    }
+
+    if loosened.version.take().is_some() {
+        loosenings.push(loosened);
+    }
+    loosenings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_loosenings_relax_one_constraint_at_a_time() {
+        let spec = MatchSpec::from_str("foo ==1.2.3 py38_0").unwrap();
+        let loosened: Vec<_> = loosenings(&spec).iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            loosened,
+            ["foo ==1.2.3", "foo 1.2.3.*", "foo 1.2.*", "foo 1.*", "foo"]
+        );
+
+        // Version ranges cannot be relaxed, so they are dropped
+        let spec = MatchSpec::from_str("foo >=2,<3").unwrap();
+        let loosened: Vec<_> = loosenings(&spec).iter().map(|s| s.to_string()).collect();
+        assert_eq!(loosened, ["foo"]);
+    }
+}
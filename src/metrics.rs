@@ -0,0 +1,339 @@
+//! Counts what the server is doing, exposed in the Prometheus text format
+
+use crate::AppState;
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The upper bounds of the solve duration buckets, in seconds
+const SOLVE_DURATION_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    solve_requests: AtomicU64,
+    solve_duration: Histogram,
+    solve_cache_hits: AtomicU64,
+    repodata_cache_hits: AtomicU64,
+    repodata_cache_misses: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    in_flight_requests: AtomicU64,
+    solver_queue_depth: AtomicU64,
+    solves_rejected: AtomicU64,
+    repodata_cache_bytes: AtomicU64,
+    disk_cache_bytes: AtomicU64,
+    warm_subdirs: AtomicU64,
+    warm_failures: AtomicU64,
+    clients_rate_limited: AtomicU64,
+    /// The requests of each API key, keyed by label
+    api_key_requests: Mutex<BTreeMap<String, ApiKeyRequests>>,
+}
+
+#[derive(Default)]
+struct ApiKeyRequests {
+    accepted: u64,
+    rate_limited: u64,
+}
+
+impl Metrics {
+    pub fn record_solve_request(&self) {
+        self.solve_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_solve_duration(&self, duration: Duration) {
+        self.solve_duration.observe(duration);
+    }
+
+    pub fn record_solve_cache_hit(&self) {
+        self.solve_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.repodata_cache_hits
+        } else {
+            &self.repodata_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_downloaded_bytes(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_solve_queued(&self) {
+        self.solver_queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_solve_dequeued(&self) {
+        self.solver_queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_solve_rejected(&self) {
+        self.solves_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_repodata_cache_bytes(&self, bytes: u64) {
+        self.repodata_cache_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_cache_bytes(&self, bytes: u64) {
+        self.disk_cache_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_warm_subdirs(&self, count: u64) {
+        self.warm_subdirs.store(count, Ordering::Relaxed);
+    }
+
+    pub fn record_warm_failure(&self) {
+        self.warm_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_client_rate_limited(&self) {
+        self.clients_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_api_key_request(&self, label: &str, accepted: bool) {
+        let mut requests = self.api_key_requests.lock().unwrap();
+        let requests = requests.entry(label.to_string()).or_default();
+        if accepted {
+            requests.accepted += 1;
+        } else {
+            requests.rate_limited += 1;
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        };
+        metric(
+            "rattler_server_solve_requests_total",
+            "counter",
+            "The number of solve requests received.",
+            &self.solve_requests,
+        );
+        metric(
+            "rattler_server_solve_cache_hits_total",
+            "counter",
+            "The number of solve requests answered from the solve result cache.",
+            &self.solve_cache_hits,
+        );
+        metric(
+            "rattler_server_repodata_cache_hits_total",
+            "counter",
+            "The number of repodata lookups served from the in-memory cache.",
+            &self.repodata_cache_hits,
+        );
+        metric(
+            "rattler_server_repodata_cache_misses_total",
+            "counter",
+            "The number of repodata lookups that had to fetch the repodata.",
+            &self.repodata_cache_misses,
+        );
+        metric(
+            "rattler_server_downloaded_bytes_total",
+            "counter",
+            "The number of repodata bytes downloaded from channels.",
+            &self.downloaded_bytes,
+        );
+        metric(
+            "rattler_server_repodata_cache_bytes",
+            "gauge",
+            "The approximate amount of memory used by the cached repodata.",
+            &self.repodata_cache_bytes,
+        );
+        metric(
+            "rattler_server_disk_cache_bytes",
+            "gauge",
+            "The size of the downloaded repodata in the cache directory, as of its last clean-up.",
+            &self.disk_cache_bytes,
+        );
+        metric(
+            "rattler_server_in_flight_requests",
+            "gauge",
+            "The number of requests that are being handled.",
+            &self.in_flight_requests,
+        );
+        metric(
+            "rattler_server_solver_queue_depth",
+            "gauge",
+            "The number of solves waiting for a solver thread.",
+            &self.solver_queue_depth,
+        );
+        metric(
+            "rattler_server_solves_rejected_total",
+            "counter",
+            "The number of solves rejected because the solver queue was full.",
+            &self.solves_rejected,
+        );
+        metric(
+            "rattler_server_warm_subdirs",
+            "gauge",
+            "The number of subdirs passed to --warm-subdirs whose repodata is cached.",
+            &self.warm_subdirs,
+        );
+        metric(
+            "rattler_server_warm_failures_total",
+            "counter",
+            "The number of times the repodata of a subdir to warm could not be downloaded.",
+            &self.warm_failures,
+        );
+        metric(
+            "rattler_server_client_rate_limited_total",
+            "counter",
+            "The number of requests without an API key rejected because their client exceeded its rate limit.",
+            &self.clients_rate_limited,
+        );
+        self.solve_duration.render(
+            &mut out,
+            "rattler_server_solve_duration_seconds",
+            "The time spent in the solver.",
+        );
+        self.render_api_key_requests(&mut out);
+        out
+    }
+
+    fn render_api_key_requests(&self, out: &mut String) {
+        let requests = self.api_key_requests.lock().unwrap();
+        if requests.is_empty() {
+            return;
+        }
+
+        let mut counter = |name: &str, help: &str, value: fn(&ApiKeyRequests) -> u64| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            for (label, requests) in requests.iter() {
+                let label = label
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                writeln!(out, "{name}{{label=\"{label}\"}} {}", value(requests)).unwrap();
+            }
+        };
+        counter(
+            "rattler_server_api_key_requests_total",
+            "The number of requests accepted for each API key.",
+            |r| r.accepted,
+        );
+        counter(
+            "rattler_server_api_key_rate_limited_total",
+            "The number of requests rejected because their API key exceeded its rate limit.",
+            |r| r.rate_limited,
+        );
+    }
+}
+
+/// A histogram of durations with the buckets of [`SOLVE_DURATION_BUCKETS`]
+#[derive(Default)]
+struct Histogram {
+    /// The amount of observations in each bucket, non-cumulative. The last bucket is `+Inf`.
+    buckets: [AtomicU64; SOLVE_DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = SOLVE_DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(SOLVE_DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = SOLVE_DURATION_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), f64::to_string);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "{name}_sum {sum}").unwrap();
+        writeln!(out, "{name}_count {count}").unwrap();
+    }
+}
+
+/// Decrements the in-flight requests when dropped, so cancelled requests are accounted for too
+struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware that counts the requests being handled
+pub async fn track_in_flight(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let in_flight = &state.metrics.in_flight_requests;
+    in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = InFlightGuard(in_flight);
+    next.run(request).await
+}
+
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> Response {
+    // The size of the cache changes without requests, so it is measured when scraped
+    state
+        .metrics
+        .record_repodata_cache_bytes(state.available_packages.approximate_bytes());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_histogram() {
+        let metrics = Metrics::default();
+        metrics.record_solve_duration(Duration::from_millis(20));
+        metrics.record_solve_duration(Duration::from_millis(300));
+        metrics.record_solve_duration(Duration::from_secs(60));
+        metrics.record_cache_lookup(true);
+        metrics.record_cache_lookup(false);
+        metrics.record_cache_lookup(false);
+
+        let rendered = metrics.render();
+        for line in [
+            "rattler_server_repodata_cache_hits_total 1",
+            "rattler_server_repodata_cache_misses_total 2",
+            "rattler_server_solve_duration_seconds_bucket{le=\"0.01\"} 0",
+            "rattler_server_solve_duration_seconds_bucket{le=\"0.05\"} 1",
+            "rattler_server_solve_duration_seconds_bucket{le=\"0.5\"} 2",
+            "rattler_server_solve_duration_seconds_bucket{le=\"30\"} 2",
+            "rattler_server_solve_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "rattler_server_solve_duration_seconds_sum 60.32",
+            "rattler_server_solve_duration_seconds_count 3",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "missing `{line}` in:\n{rendered}"
+            );
+        }
+    }
+}
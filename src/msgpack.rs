@@ -0,0 +1,88 @@
+//! Serves JSON responses as MessagePack to clients that ask for it with
+//! `Accept: application/msgpack`, which is more compact and faster to parse
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Converts JSON responses to MessagePack if the request accepts it
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let wants_msgpack = accepts_msgpack(request.headers());
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/json"));
+    if !wants_msgpack || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let json = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(json) => json,
+        Err(e) => {
+            tracing::warn!("cannot read the response body: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let value: serde_json::Value = match serde_json::from_slice(&json) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(json)),
+    };
+    let msgpack =
+        rmp_serde::to_vec_named(&value).expect("JSON values can be serialized as MessagePack");
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(MSGPACK_CONTENT_TYPE),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(msgpack))
+}
+
+/// Whether the `Accept` header lists MessagePack (under its official or legacy media type),
+/// without a quality of zero
+pub fn accepts_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media_range| {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            let rejected = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            matches!(media_type, MSGPACK_CONTENT_TYPE | "application/x-msgpack") && !rejected
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accepts_msgpack() {
+        let accepts = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+            accepts_msgpack(&headers)
+        };
+        assert!(accepts("application/msgpack"));
+        assert!(accepts("application/json;q=0.5, application/x-msgpack"));
+        assert!(!accepts("application/json"));
+        assert!(!accepts("application/msgpack; q=0"));
+        assert!(!accepts_msgpack(&HeaderMap::new()));
+    }
+}
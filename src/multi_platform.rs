@@ -0,0 +1,203 @@
+//! Contains the `/solve/multi` endpoint, which solves the same environment for several platforms
+//! at once (e.g. to generate a lockfile)
+
+use crate::conda_lock::{self, PlatformSolution};
+use crate::dto::{SolveEnvironment, SolveEnvironmentOk};
+use crate::error::{response_from_error, ApiError, ParseError, ValidationError};
+use crate::output::{self, deserialize_flag, OutputFormat, OutputParams};
+use crate::pixi_lock;
+use crate::{graph, solve_keyed, AppState};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// A solve request for several platforms, which has the same fields as a `/solve` request except
+/// for `platform`
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MultiPlatformSolve {
+    pub platforms: Vec<String>,
+    /// The other fields of a `/solve` request
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub environment: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Serialize, ToSchema)]
+pub struct MultiPlatformSolveOk {
+    /// The solution for each platform, keyed by platform
+    pub platforms: BTreeMap<String, SolveEnvironmentOk>,
+}
+
+/// The formats in which the solve results can be returned
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum MultiPlatformFormat {
+    /// The JSON representation of [`MultiPlatformSolveOk`]
+    #[default]
+    Json,
+    /// A `conda-lock.yml` lockfile covering every platform
+    CondaLock,
+    /// A `pixi.lock` lockfile with every platform in its default environment
+    PixiLock,
+}
+
+/// Query parameters that determine how the solve results are returned
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MultiPlatformOutputParams {
+    #[serde(default)]
+    pub format: MultiPlatformFormat,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub include_graph: bool,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub debug: bool,
+}
+
+/// Solves the same environment for several platforms at once
+#[utoipa::path(
+    post,
+    path = "/v1/solve/multi",
+    params(MultiPlatformOutputParams),
+    request_body = MultiPlatformSolve,
+    responses(
+        (status = 200, description = "The solution for each platform, in the requested format", body = MultiPlatformSolveOk),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 401, description = "The API key or tenant token is missing or invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 409, description = "The environment cannot be solved", body = SolveEnvironmentUnsolvable),
+        (status = 429, description = "The server is too busy, or the rate limit was exceeded", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+        (status = 503, description = "The repodata is not available", body = ErrorResponse),
+        (status = 504, description = "The request or solve timed out", body = ErrorResponse),
+    )
+)]
+pub async fn solve_multi_platform(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MultiPlatformOutputParams>,
+    headers: HeaderMap,
+    Json(payload): Json<MultiPlatformSolve>,
+) -> Response {
+    state.metrics.record_solve_request();
+
+    // The rest of the body must be a valid solve request once the platform is filled in
+    let mut environment = payload.environment;
+    environment.insert(
+        "platform".to_string(),
+        serde_json::Value::String(payload.platforms.first().cloned().unwrap_or_default()),
+    );
+    let environment: SolveEnvironment = match serde_json::from_value(environment.into()) {
+        Ok(environment) => environment,
+        Err(e) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Failed to deserialize the JSON body into the target type: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    let output = OutputParams {
+        format: OutputFormat::Json,
+        include_graph: params.include_graph,
+        debug: params.debug,
+    };
+    let settings = state.settings();
+    let solutions =
+        match solve_platforms(state, headers, &environment, payload.platforms, &output).await {
+            Ok(solutions) => solutions,
+            Err(e) => return response_from_error(e),
+        };
+    match params.format {
+        MultiPlatformFormat::Json => Json(MultiPlatformSolveOk {
+            platforms: solutions
+                .into_iter()
+                .map(|(platform, _, solution)| (platform, solution))
+                .collect(),
+        })
+        .into_response(),
+        MultiPlatformFormat::CondaLock => {
+            let solutions = solutions
+                .iter()
+                .map(|(platform, content_hash, solution)| PlatformSolution {
+                    platform,
+                    content_hash: content_hash.clone(),
+                    solution,
+                })
+                .collect();
+            output::render_conda_lock(&conda_lock::lockfile(&environment.channels, solutions))
+        }
+        MultiPlatformFormat::PixiLock => {
+            let solutions = solutions
+                .iter()
+                .map(|(platform, content_hash, solution)| PlatformSolution {
+                    platform,
+                    content_hash: content_hash.clone(),
+                    solution,
+                })
+                .collect();
+            output::render_pixi_lock(&pixi_lock::lockfile(
+                &settings,
+                &environment.channels,
+                solutions,
+            ))
+        }
+    }
+}
+
+/// Solves the environment for each of the platforms (ignoring its own platform), returning the
+/// platform, the content hash and the solution of each
+pub async fn solve_platforms(
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    environment: &SolveEnvironment,
+    platforms: Vec<String>,
+    output: &OutputParams,
+) -> Result<Vec<(String, String, SolveEnvironmentOk)>, ApiError> {
+    let mut unique_platforms = Vec::with_capacity(platforms.len());
+    for platform in platforms {
+        if !unique_platforms.contains(&platform) {
+            unique_platforms.push(platform);
+        }
+    }
+    if unique_platforms.is_empty() {
+        return Err(ApiError::Validation(ValidationError::Platform(
+            ParseError {
+                input: String::new(),
+                error: "at least one platform is required".to_string(),
+            },
+        )));
+    }
+
+    // The platforms are solved concurrently, sharing the download of their common (e.g. noarch)
+    // repodata through the cache
+    let solves = unique_platforms.iter().map(|platform| {
+        let environment = SolveEnvironment {
+            platform: platform.clone(),
+            ..environment.clone()
+        };
+        let state = state.clone();
+        let headers = &headers;
+        async move {
+            // Solve responses for a single platform are never returned, so they are not cached by
+            // ETag
+            let (mut solution, content_hash) =
+                solve_keyed(state, headers, &environment, output).await?;
+            if output.include_graph {
+                solution.graph = Some(graph::dependency_graph(&solution.packages));
+            }
+            if !output.debug {
+                solution.solver_stats = None;
+                solution.timings = None;
+            }
+            Ok((environment.platform, content_hash, solution))
+        }
+    });
+    futures::future::try_join_all(solves).await
+}
@@ -0,0 +1,523 @@
+//! Downloads the repodata of channels hosted in OCI registries (`oci://<registry>/<repository>`),
+//! as mirrored by conda-oci-mirror (e.g. `oci://ghcr.io/channel-mirrors/conda-forge`)
+//!
+//! The repodata of a subdir is an artifact in the `<repository>/<subdir>/repodata.json`
+//! repository, tagged `latest`, whose manifest points to the blob holding the file. Registries
+//! hand out a bearer token per repository through the challenge of their `WWW-Authenticate`
+//! header, which is requested anonymously or with the credentials of the docker configuration
+//! (`$DOCKER_CONFIG/config.json` or `~/.docker/config.json`, including its credential helpers).
+
+use crate::download::{self, DownloadedObject};
+use crate::outbound;
+use crate::retry::RetryPolicy;
+use anyhow::Context;
+use base64::Engine;
+use rattler_repodata_gateway::fetch::{DownloadProgress, FetchRepoDataError};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const REPODATA_MEDIA_TYPE: &str = "application/vnd.conda.repodata.v1+json";
+const REPODATA_TAG: &str = "latest";
+
+/// How long a token is used if the registry doesn't tell
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The directory within the cache directory that holds the downloaded repodata
+const CACHE_SUBDIR: &str = "oci";
+
+/// The parts of the docker configuration that tell how to authenticate to registries
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DockerConfig {
+    #[serde(default)]
+    pub auths: HashMap<String, DockerAuth>,
+    /// The credential helpers of specific registries, by name (`docker-credential-<name>`)
+    #[serde(default)]
+    pub cred_helpers: HashMap<String, String>,
+    /// The credential helper of the other registries
+    pub creds_store: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct DockerAuth {
+    /// The base64-encoded `username:password`
+    pub auth: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for DockerAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DockerAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DockerConfig {
+    /// Reads the configuration of the current user, if any. An unreadable configuration is
+    /// ignored, so registries are accessed anonymously.
+    pub fn from_env() -> DockerConfig {
+        let path = std::env::var_os("DOCKER_CONFIG")
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".docker")))
+            .map(|dir| dir.join("config.json"));
+        match path
+            .filter(|path| path.exists())
+            .map(|p| Self::from_path(&p))
+        {
+            Some(Ok(config)) => config,
+            Some(Err(err)) => {
+                tracing::warn!("ignoring the docker configuration: {err:#}");
+                DockerConfig::default()
+            }
+            None => DockerConfig::default(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> anyhow::Result<DockerConfig> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&contents).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Returns the username and password for the registry, from its credential helper if it has
+    /// one, and from the `auths` otherwise
+    async fn credentials(&self, registry: &str) -> Option<(String, String)> {
+        if let Some(helper) = self.cred_helpers.get(registry) {
+            return run_credential_helper(helper, registry).await;
+        }
+
+        let auth = self
+            .auths
+            .iter()
+            .find(|(key, _)| auth_key_host(key) == registry)
+            .map(|(_, auth)| auth);
+        match auth {
+            Some(DockerAuth {
+                username: Some(username),
+                password: Some(password),
+                ..
+            }) => Some((username.clone(), password.clone())),
+            Some(DockerAuth {
+                auth: Some(auth), ..
+            }) => {
+                let decoded = base64::engine::general_purpose::STANDARD
+                    .decode(auth)
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())?;
+                let (username, password) = decoded.split_once(':')?;
+                Some((username.to_string(), password.to_string()))
+            }
+            _ => match &self.creds_store {
+                Some(helper) => run_credential_helper(helper, registry).await,
+                None => None,
+            },
+        }
+    }
+}
+
+/// Returns the host of a key of the `auths`, which may be a URL (e.g. `https://ghcr.io/v1/`)
+fn auth_key_host(key: &str) -> &str {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    key.split('/').next().unwrap_or(key)
+}
+
+/// Asks a docker credential helper for the credentials of the registry, following the protocol
+/// of `docker-credential-<helper> get`
+async fn run_credential_helper(helper: &str, registry: &str) -> Option<(String, String)> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct HelperCredentials {
+        username: String,
+        secret: String,
+    }
+
+    let program = format!("docker-credential-{helper}");
+    let output = async {
+        let mut child = tokio::process::Command::new(&program)
+            .arg("get")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        tokio::io::AsyncWriteExt::write_all(&mut stdin, registry.as_bytes()).await?;
+        drop(stdin);
+        child.wait_with_output().await
+    }
+    .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            match serde_json::from_slice::<HelperCredentials>(&output.stdout) {
+                Ok(credentials) => Some((credentials.username, credentials.secret)),
+                Err(err) => {
+                    tracing::warn!("invalid output of {program}: {err}");
+                    None
+                }
+            }
+        }
+        // Helpers fail for registries they don't know about
+        Ok(_) => None,
+        Err(err) => {
+            tracing::warn!("cannot run {program}: {err}");
+            None
+        }
+    }
+}
+
+/// A repository of a registry, as addressed by the registry API
+#[derive(Debug, PartialEq, Eq)]
+struct Repository {
+    /// The host of the registry, including its port if it has one
+    registry: String,
+    name: String,
+}
+
+impl Repository {
+    /// Returns the repository of the artifact that an `oci://` URL points to
+    fn from_url(url: &Url) -> Result<Repository, FetchRepoDataError> {
+        let registry = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| {
+                FetchRepoDataError::IoError(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid OCI URL {url}"),
+                ))
+            })?;
+        Ok(Repository {
+            registry: match url.port() {
+                Some(port) => format!("{registry}:{port}"),
+                None => registry.to_string(),
+            },
+            name: url.path().trim_matches('/').to_string(),
+        })
+    }
+
+    /// Returns the URL of an endpoint of the registry API for the repository. Registries on the
+    /// local machine are accessed over plain HTTP, like docker does.
+    fn api_url(&self, endpoint: &str) -> Url {
+        let is_local = ["localhost", "127.0.0.1", "[::1]"].iter().any(|local| {
+            self.registry == *local || self.registry.starts_with(&format!("{local}:"))
+        });
+        let scheme = if is_local { "http" } else { "https" };
+        Url::parse(&format!(
+            "{scheme}://{}/v2/{}/{endpoint}",
+            self.registry, self.name
+        ))
+        .expect("the registry and repository come from a valid URL")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Layer {
+    media_type: String,
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+    expires_in: Option<u64>,
+}
+
+/// Sends requests to OCI registries
+pub struct OciTransport {
+    http: reqwest::Client,
+    docker_config: DockerConfig,
+    /// The `Authorization` headers obtained for each repository (`<registry>/<name>`), with the
+    /// time at which they expire
+    authorizations: Mutex<HashMap<String, (HeaderValue, Instant)>>,
+}
+
+impl OciTransport {
+    pub fn new(docker_config: DockerConfig) -> OciTransport {
+        OciTransport {
+            http: outbound::client(),
+            docker_config,
+            authorizations: Mutex::default(),
+        }
+    }
+
+    pub fn from_env() -> OciTransport {
+        OciTransport::new(DockerConfig::from_env())
+    }
+
+    /// Requests the manifest of the repodata artifact that the `oci://` URL points to, whose
+    /// `ETag` changes along with the repodata
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+    ) -> Result<reqwest::Response, FetchRepoDataError> {
+        let repository = Repository::from_url(url)?;
+        let manifest_url = repository.api_url(&format!("manifests/{REPODATA_TAG}"));
+        self.send_authorized(&repository, method, manifest_url, HeaderMap::new())
+            .await
+    }
+
+    /// Downloads the repodata to a file in `cache_dir`, reporting the progress as it goes.
+    /// Interrupted downloads of the blob are resumed with range requests, as often as the retry
+    /// policy allows.
+    pub async fn download(
+        &self,
+        url: &Url,
+        cache_dir: &Path,
+        retry: &RetryPolicy,
+        progress: impl FnMut(DownloadProgress),
+    ) -> Result<DownloadedObject, FetchRepoDataError> {
+        let repository = Repository::from_url(url)?;
+        let response = download::check_status(self.send(Method::GET, url).await?)?;
+        let etag = download::header(&response, reqwest::header::ETAG);
+        let last_modified = download::header(&response, reqwest::header::LAST_MODIFIED);
+        let manifest: Manifest = serde_json::from_slice(&response.bytes().await?).map_err(|e| {
+            FetchRepoDataError::IoError(std::io::Error::new(ErrorKind::InvalidData, e))
+        })?;
+
+        // Artifacts pushed by other tools may not set the media type of their only layer
+        let layer = match &manifest.layers[..] {
+            [layer] => Some(layer),
+            layers => layers
+                .iter()
+                .find(|layer| layer.media_type == REPODATA_MEDIA_TYPE),
+        }
+        .ok_or_else(|| {
+            FetchRepoDataError::IoError(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("the manifest of {url} has no repodata layer"),
+            ))
+        })?;
+
+        let blob_url = repository.api_url(&format!("blobs/{}", layer.digest));
+        let response = download::check_status(
+            self.send_authorized(&repository, Method::GET, blob_url.clone(), HeaderMap::new())
+                .await?,
+        )?;
+        // Blobs are addressed by their digest, so they cannot change in between
+        let (path, bytes) = download::save_response(
+            url,
+            response,
+            &cache_dir.join(CACHE_SUBDIR),
+            progress,
+            layer.digest.strip_prefix("sha256:"),
+            retry,
+            |start| {
+                let headers = download::range_headers(start, None);
+                self.send_authorized(&repository, Method::GET, blob_url.clone(), headers)
+            },
+        )
+        .await?;
+
+        Ok(DownloadedObject {
+            path,
+            url: url.clone(),
+            etag,
+            last_modified,
+            bytes,
+        })
+    }
+
+    /// Sends a request to the registry, authorizing it first if the registry asks for it
+    async fn send_authorized(
+        &self,
+        repository: &Repository,
+        method: Method,
+        url: Url,
+        extra_headers: HeaderMap,
+    ) -> Result<reqwest::Response, FetchRepoDataError> {
+        let key = format!("{}/{}", repository.registry, repository.name);
+        let request = |authorization: Option<HeaderValue>| {
+            let mut request = self
+                .http
+                .request(method.clone(), url.clone())
+                .headers(extra_headers.clone())
+                .header(ACCEPT, MANIFEST_MEDIA_TYPE);
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            request.send()
+        };
+
+        let cached = self
+            .authorizations
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(_, expires_at)| Instant::now() < *expires_at)
+            .map(|(authorization, _)| authorization.clone());
+        let response = request(cached).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = download::header(&response, WWW_AUTHENTICATE).unwrap_or_default();
+        let (authorization, lifetime) =
+            self.authorize(repository, &challenge).await.map_err(|e| {
+                FetchRepoDataError::IoError(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{e:#}"),
+                ))
+            })?;
+        self.authorizations
+            .lock()
+            .unwrap()
+            .insert(key, (authorization.clone(), Instant::now() + lifetime));
+        Ok(request(Some(authorization)).await?)
+    }
+
+    /// Answers the challenge of the registry, returning the `Authorization` header to send and
+    /// how long it may be used
+    async fn authorize(
+        &self,
+        repository: &Repository,
+        challenge: &str,
+    ) -> anyhow::Result<(HeaderValue, Duration)> {
+        let credentials = self.docker_config.credentials(&repository.registry).await;
+        let (scheme, params) = parse_challenge(challenge);
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let (username, password) = credentials.context("the registry requires credentials")?;
+            let encoded =
+                base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+            let authorization = HeaderValue::from_str(&format!("Basic {encoded}"))?;
+            return Ok((authorization, DEFAULT_TOKEN_LIFETIME));
+        }
+        anyhow::ensure!(
+            scheme.eq_ignore_ascii_case("bearer"),
+            "unsupported authentication scheme `{scheme}`"
+        );
+
+        let realm = params.get("realm").context("the challenge has no realm")?;
+        let mut token_url = Url::parse(realm).context("invalid realm")?;
+        {
+            let mut query = token_url.query_pairs_mut();
+            if let Some(service) = params.get("service") {
+                query.append_pair("service", service);
+            }
+            match params.get("scope") {
+                Some(scope) => query.append_pair("scope", scope),
+                None => query.append_pair("scope", &format!("repository:{}:pull", repository.name)),
+            };
+        }
+        let mut request = self.http.get(token_url);
+        if let Some((username, password)) = credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await?.error_for_status()?;
+        let token: TokenResponse =
+            serde_json::from_slice(&response.bytes().await?).context("parsing the token")?;
+
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", token.token))?;
+        let lifetime = token
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        Ok((authorization, lifetime))
+    }
+}
+
+/// Splits a `WWW-Authenticate` challenge (e.g. `Bearer realm="https://ghcr.io/token",service=...`)
+/// into its scheme and parameters
+fn parse_challenge(challenge: &str) -> (&str, HashMap<String, String>) {
+    let (scheme, rest) = challenge.trim().split_once(' ').unwrap_or((challenge, ""));
+    let mut params = HashMap::new();
+    let mut rest = rest.trim();
+    while let Some((name, value)) = rest.split_once('=') {
+        let name = name
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        let (value, remainder) = match value.strip_prefix('"') {
+            // Quoted values may contain commas (e.g. scopes with several actions)
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        params.insert(name, value.to_string());
+        rest = remainder.trim();
+    }
+    (scheme, params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:channel-mirrors/conda-forge/noarch/repodata.json:pull,push""#,
+        );
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://ghcr.io/token");
+        assert_eq!(params["service"], "ghcr.io");
+        assert_eq!(
+            params["scope"],
+            "repository:channel-mirrors/conda-forge/noarch/repodata.json:pull,push"
+        );
+
+        let (scheme, params) = parse_challenge("Basic realm=registry");
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "registry");
+    }
+
+    #[test]
+    fn test_repository_from_url() {
+        let url =
+            Url::parse("oci://ghcr.io/channel-mirrors/conda-forge/noarch/repodata.json").unwrap();
+        let repository = Repository::from_url(&url).unwrap();
+        assert_eq!(
+            repository.api_url("manifests/latest").as_str(),
+            "https://ghcr.io/v2/channel-mirrors/conda-forge/noarch/repodata.json/manifests/latest"
+        );
+
+        let url = Url::parse("oci://localhost:5000/conda-forge/noarch/repodata.json").unwrap();
+        assert_eq!(
+            Repository::from_url(&url)
+                .unwrap()
+                .api_url("blobs/sha256:abc")
+                .as_str(),
+            "http://localhost:5000/v2/conda-forge/noarch/repodata.json/blobs/sha256:abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_docker_config_credentials() {
+        let config: DockerConfig = serde_json::from_value(serde_json::json!({
+            "auths": {
+                "https://registry.example.com/v1/": { "auth": "dXNlcjpzZWNyZXQ=" },
+                "ghcr.io": { "username": "octocat", "password": "token" }
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            config.credentials("registry.example.com").await,
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            config.credentials("ghcr.io").await,
+            Some(("octocat".to_string(), "token".to_string()))
+        );
+        assert_eq!(config.credentials("quay.io").await, None);
+    }
+}
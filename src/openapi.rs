@@ -0,0 +1,175 @@
+//! Describes the API in an OpenAPI 3 document, which is generated from the request and response
+//! types and served at `/openapi.json`, and optionally serves a Swagger UI to browse it
+
+use crate::availability::{Candidate, CheckSpecs, SpecAvailability, SpecsAvailability};
+use crate::available_packages_cache::Availability;
+use crate::batch::{BatchResult, BatchSolve, BatchSolveOk};
+use crate::channels::{
+    Dependent, DependentsResult, PackageBuild, PackageMetadata, PackageVersion, SearchResult,
+    ValidateChannelResult,
+};
+use crate::cli::Solver;
+use crate::diff::{DiffPackage, DiffSide, EnvironmentDiff, EnvironmentDiffRequest, PackageDiff};
+use crate::dto::{
+    AppliedConstraint, ChannelPriority, Depth, ErrorResponse, FeaturePreference, MatchMode,
+    PackageFormat, PackageReference, RepodataTiming, SolveEnvironment, SolveEnvironmentOk,
+    SolveEnvironmentUnsolvable, SolveSummary, SolveTimings, SolverStats, TieBreak,
+};
+use crate::explicit::{ExplicitEnvironment, ExplicitEnvironmentOk};
+use crate::graph::{DependencyGraph, GraphEdge, GraphNode};
+use crate::health::{Check, Health, Readiness};
+use crate::jobs::{JobStatus, JobStatusKind};
+use crate::lockfile::{
+    LockedPackageStatus, LockfileFormat, LockfileValidation, PackageStatus, ValidateLockfile,
+};
+use crate::multi_platform::{MultiPlatformFormat, MultiPlatformSolve, MultiPlatformSolveOk};
+use crate::output::OutputFormat;
+use crate::problem_report::{CandidateStatus, ProblemNode, ProblemReport, RequirementStatus};
+use crate::run_exports::{
+    DependencyEnv, PackageRunExports, RunExports, RunExportsQuery, RunExportsResult,
+    RunExportsSource,
+};
+use crate::selftest::{SelftestResult, Stage};
+use crate::v1::PackageRecord;
+use crate::version::BuildInfo;
+use axum::response::Html;
+use axum::Json;
+use utoipa::OpenApi;
+
+/// The route of the OpenAPI document
+pub const OPENAPI_ROUTE: &str = "/openapi.json";
+
+/// The route of the Swagger UI, if enabled
+pub const SWAGGER_UI_ROUTE: &str = "/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "rattler-server",
+        description = "Solves conda environments against the repodata of conda channels"
+    ),
+    paths(
+        crate::solve_environment,
+        crate::multi_platform::solve_multi_platform,
+        crate::batch::solve_batch,
+        crate::progress::solve_environment_events,
+        crate::jobs::create_job,
+        crate::jobs::get_job,
+        crate::jobs::delete_job,
+        crate::explicit::explicit_environment,
+        crate::diff::environment_diff,
+        crate::lockfile::validate_lockfile,
+        crate::availability::check_specs,
+        crate::run_exports::run_exports,
+        crate::version::get_version,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::selftest::selftest,
+        crate::channels::validate_channel,
+        crate::channels::package_metadata,
+        crate::channels::search_packages,
+        crate::channels::package_build,
+        crate::channels::package_dependents,
+    ),
+    components(schemas(
+        SolveEnvironment,
+        PackageReference,
+        MatchMode,
+        ChannelPriority,
+        PackageFormat,
+        Depth,
+        FeaturePreference,
+        Solver,
+        OutputFormat,
+        SolveEnvironmentOk,
+        SolveSummary,
+        AppliedConstraint,
+        TieBreak,
+        SolverStats,
+        SolveTimings,
+        RepodataTiming,
+        DependencyGraph,
+        GraphNode,
+        GraphEdge,
+        PackageRecord,
+        ErrorResponse,
+        SolveEnvironmentUnsolvable,
+        ProblemReport,
+        ProblemNode,
+        RequirementStatus,
+        CandidateStatus,
+        MultiPlatformSolve,
+        BatchSolve,
+        BatchSolveOk,
+        BatchResult,
+        MultiPlatformSolveOk,
+        MultiPlatformFormat,
+        JobStatus,
+        JobStatusKind,
+        ExplicitEnvironment,
+        ExplicitEnvironmentOk,
+        EnvironmentDiffRequest,
+        DiffSide,
+        EnvironmentDiff,
+        PackageDiff,
+        DiffPackage,
+        ValidateLockfile,
+        LockfileFormat,
+        LockfileValidation,
+        LockedPackageStatus,
+        PackageStatus,
+        CheckSpecs,
+        SpecsAvailability,
+        SpecAvailability,
+        Candidate,
+        RunExportsQuery,
+        RunExportsResult,
+        PackageRunExports,
+        DependencyEnv,
+        RunExportsSource,
+        RunExports,
+        BuildInfo,
+        Health,
+        Readiness,
+        Check,
+        SelftestResult,
+        Stage,
+        ValidateChannelResult,
+        Availability,
+        PackageMetadata,
+        PackageVersion,
+        PackageBuild,
+        SearchResult,
+        DependentsResult,
+        Dependent,
+    ))
+)]
+struct ApiDoc;
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Serves Swagger UI (loaded from a CDN), showing the OpenAPI document
+pub async fn swagger_ui() -> Html<String> {
+    Html(format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>rattler-server API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.onload = () => {{
+      window.ui = SwaggerUIBundle({{ url: "{OPENAPI_ROUTE}", dom_id: "#swagger-ui" }});
+    }};
+  </script>
+</body>
+</html>
+"##
+    ))
+}
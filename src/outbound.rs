@@ -0,0 +1,123 @@
+//! Configures the HTTP client of the requests that the server makes (to download repodata, and to
+//! authenticate with S3 and OCI registries), so deployments behind a proxy with a CA of their own
+//! can reach their channels
+//!
+//! The client is configured once on startup, and shared by every download. Clients that carry
+//! credentials of their own wrap the same client, so they go through the same proxy.
+
+use crate::cli::Args;
+use anyhow::Context;
+use reqwest::{Certificate, NoProxy, Proxy};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Configures the client returned by [`client`], which must happen before it is first used
+pub fn configure(args: &Args) -> anyhow::Result<()> {
+    let client = build_client(args)?;
+    CLIENT
+        .set(client)
+        .map_err(|_| anyhow::anyhow!("the outbound HTTP client was already configured"))
+}
+
+/// The client of outbound requests. Defaults to a client that uses the proxy of the environment,
+/// if [`configure`] was not called.
+pub fn client() -> reqwest::Client {
+    CLIENT.get_or_init(reqwest::Client::default).clone()
+}
+
+fn build_client(args: &Args) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(url) = &args.http_proxy {
+        // The credentials in the URL, if any, are sent to the proxy
+        let proxy = Proxy::all(url.clone())
+            .context("configuring the HTTP proxy")?
+            .no_proxy(NoProxy::from_string(&args.no_proxy.join(",")));
+        builder = builder.proxy(proxy);
+    }
+    for path in &args.ca_certificates {
+        for certificate in read_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder.build().context("building the HTTP client")
+}
+
+/// Reads every certificate in the PEM file, which may be a bundle of several
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("reading {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("{} contains no certificates", path.display());
+    }
+    certs
+        .iter()
+        .map(|der| {
+            Certificate::from_der(der).with_context(|| format!("parsing {}", path.display()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/tls")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let mut proxy = mockito::Server::new_async().await;
+        // The proxy receives the request for the channel, with the credentials of its URL
+        let mock = proxy
+            .mock("GET", "/conda-forge/noarch/repodata.json")
+            .match_header("host", "channel.example")
+            .match_header("proxy-authorization", "Basic dXNlcjpzZWNyZXQ=")
+            .with_body("{}")
+            .create_async()
+            .await;
+
+        let mut args = Args::parse_from(["rattler-server"]);
+        let mut url = reqwest::Url::parse(&proxy.url()).unwrap();
+        url.set_username("user").unwrap();
+        url.set_password(Some("secret")).unwrap();
+        args.http_proxy = Some(url);
+        let client = build_client(&args).unwrap();
+        let response = client
+            .get("http://channel.example/conda-forge/noarch/repodata.json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        mock.assert_async().await;
+
+        // Hosts that bypass the proxy are reached directly, so this one cannot be resolved
+        args.no_proxy = vec!["example".to_string()];
+        let client = build_client(&args).unwrap();
+        let result = client
+            .get("http://channel.example/conda-forge/noarch/repodata.json")
+            .send()
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_ca_certificates() {
+        let mut args = Args::parse_from(["rattler-server"]);
+        args.ca_certificates = vec![fixture("ca.pem")];
+        build_client(&args).unwrap();
+
+        // A file without certificates is rejected, instead of trusting nothing more than usual
+        args.ca_certificates = vec![fixture("server.key")];
+        let error = build_client(&args).unwrap_err();
+        assert!(error.to_string().contains("contains no certificates"));
+    }
+}
@@ -0,0 +1,179 @@
+//! Renders solve results in the output format requested by the client
+
+use crate::conda_lock::{self, CondaLock, PlatformSolution};
+use crate::config::Settings;
+use crate::dto::{SolveEnvironment, SolveEnvironmentOk};
+use crate::environment_yml::{EnvironmentYml, EnvironmentYmlDependency};
+use crate::graph::DependencyGraph;
+use crate::pixi_lock::{self, PixiLock};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// The formats in which a solve result can be returned
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// The JSON representation of [`SolveEnvironmentOk`]
+    #[default]
+    Json,
+    /// A conda `environment.yml` file, with each dependency pinned to its solved build
+    EnvironmentYml,
+    /// A `conda-lock.yml` lockfile for the requested platform
+    CondaLock,
+    /// A `pixi.lock` lockfile with the requested platform in its default environment
+    PixiLock,
+    /// An `@EXPLICIT` spec file, listing the URL of each package (as accepted by
+    /// `conda create --file`)
+    Explicit,
+    /// The dependency graph of the solution as JSON, with the solved packages as nodes and the
+    /// dependencies they satisfy as edges
+    Graph,
+    /// The dependency graph of the solution in the DOT language of Graphviz
+    Dot,
+}
+
+/// Query parameters that determine how the solve result is returned
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OutputParams {
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Whether to include the dependency graph of the solution
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub include_graph: bool,
+    /// Whether to include statistics about the solve
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub debug: bool,
+}
+
+/// Deserializes a query flag, which may be given as `1`/`0` or `true`/`false`
+pub fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        other => Err(serde::de::Error::custom(format!(
+            "expected 1, 0, true or false, found {other}"
+        ))),
+    }
+}
+
+/// Renders the solution to `request` as a response in the given format. Lock files record the
+/// `solve_key` of the solution as their content hash.
+pub fn render(
+    format: OutputFormat,
+    settings: &Settings,
+    request: &SolveEnvironment,
+    solution: SolveEnvironmentOk,
+    solve_key: &str,
+) -> Response {
+    match format {
+        OutputFormat::Json => Json(solution).into_response(),
+        OutputFormat::EnvironmentYml => {
+            let yml = to_environment_yml(request, &solution);
+            (
+                [(header::CONTENT_TYPE, "application/x-yaml")],
+                serde_yaml::to_string(&yml).expect("environment.yml serialization is infallible"),
+            )
+                .into_response()
+        }
+        OutputFormat::CondaLock => {
+            let lockfile = conda_lock::lockfile(
+                &request.channels,
+                vec![PlatformSolution {
+                    platform: &request.platform,
+                    content_hash: solve_key.to_string(),
+                    solution: &solution,
+                }],
+            );
+            render_conda_lock(&lockfile)
+        }
+        OutputFormat::PixiLock => {
+            let lockfile = pixi_lock::lockfile(
+                settings,
+                &request.channels,
+                vec![PlatformSolution {
+                    platform: &request.platform,
+                    content_hash: solve_key.to_string(),
+                    solution: &solution,
+                }],
+            );
+            render_pixi_lock(&lockfile)
+        }
+        OutputFormat::Explicit => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            to_explicit_file(request, &solution),
+        )
+            .into_response(),
+        OutputFormat::Graph => {
+            Json(DependencyGraph::from_packages(&solution.packages)).into_response()
+        }
+        OutputFormat::Dot => (
+            [(header::CONTENT_TYPE, "text/vnd.graphviz")],
+            DependencyGraph::from_packages(&solution.packages).to_dot(),
+        )
+            .into_response(),
+    }
+}
+
+/// Renders the lockfile as a YAML response
+pub fn render_conda_lock(lockfile: &CondaLock) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-yaml")],
+        serde_yaml::to_string(lockfile).expect("conda-lock.yml serialization is infallible"),
+    )
+        .into_response()
+}
+
+/// Renders the lockfile as a YAML response
+pub fn render_pixi_lock(lockfile: &PixiLock) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-yaml")],
+        serde_yaml::to_string(lockfile).expect("pixi.lock serialization is infallible"),
+    )
+        .into_response()
+}
+
+fn to_environment_yml(request: &SolveEnvironment, solution: &SolveEnvironmentOk) -> EnvironmentYml {
+    let dependencies = solution
+        .packages
+        .iter()
+        .map(|p| {
+            let record = &p.package_record;
+            EnvironmentYmlDependency::Spec(format!(
+                "{}={}={}",
+                record.name.as_normalized(),
+                record.version,
+                record.build
+            ))
+        })
+        .collect();
+
+    EnvironmentYml {
+        name: request.name.clone(),
+        channels: request.channels.clone(),
+        dependencies,
+    }
+}
+
+/// Lists the URL of every package, in installation order, anchored by its md5 (or sha256) hash so
+/// conda verifies the downloads
+fn to_explicit_file(request: &SolveEnvironment, solution: &SolveEnvironmentOk) -> String {
+    let mut file = String::from(
+        "# This file may be used to create an environment using:\n\
+         # $ conda create --name <env> --file <this file>\n",
+    );
+    file.push_str(&format!("# platform: {}\n@EXPLICIT\n", request.platform));
+    for record in &solution.packages {
+        let package = &record.package_record;
+        let anchor = match (package.md5, package.sha256) {
+            (Some(md5), _) => format!("#{md5:x}"),
+            (None, Some(sha256)) => format!("#sha256:{sha256:x}"),
+            (None, None) => String::new(),
+        };
+        file.push_str(&format!("{}{anchor}\n", record.url));
+    }
+    file
+}
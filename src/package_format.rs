@@ -0,0 +1,44 @@
+//! Restricts the available packages to the [`PackageFormat`] requested by the client
+
+use crate::dto::PackageFormat;
+use rattler_conda_types::RepoDataRecord;
+use std::collections::HashSet;
+
+const CONDA_EXTENSION: &str = ".conda";
+const TAR_BZ2_EXTENSION: &str = ".tar.bz2";
+
+/// Removes the records that don't have the requested format. With [`PackageFormat::Any`], builds
+/// that are available in both formats keep only their `.conda` artifact, so the solver never has
+/// to choose between two otherwise identical records.
+pub fn filter_package_format(
+    format: PackageFormat,
+    available_packages: Vec<Vec<RepoDataRecord>>,
+) -> Vec<Vec<RepoDataRecord>> {
+    available_packages
+        .into_iter()
+        .map(|records| match format {
+            PackageFormat::Conda => retain_extension(records, CONDA_EXTENSION),
+            PackageFormat::Tarbz2 => retain_extension(records, TAR_BZ2_EXTENSION),
+            PackageFormat::Any => prefer_conda(records),
+        })
+        .collect()
+}
+
+fn retain_extension(mut records: Vec<RepoDataRecord>, extension: &str) -> Vec<RepoDataRecord> {
+    records.retain(|r| r.file_name.ends_with(extension));
+    records
+}
+
+fn prefer_conda(mut records: Vec<RepoDataRecord>) -> Vec<RepoDataRecord> {
+    let conda_builds: HashSet<String> = records
+        .iter()
+        .filter_map(|r| r.file_name.strip_suffix(CONDA_EXTENSION))
+        .map(str::to_string)
+        .collect();
+
+    records.retain(|r| match r.file_name.strip_suffix(TAR_BZ2_EXTENSION) {
+        Some(stem) => !conda_builds.contains(stem),
+        None => true,
+    });
+    records
+}
@@ -0,0 +1,145 @@
+//! Renders solve results as a `pixi.lock` lockfile, in the format written by pixi (version 4 of
+//! the lockfile format of rattler-lock)
+
+use crate::conda_lock::PlatformSolution;
+use crate::config::Settings;
+use crate::v1;
+use rattler_conda_types::RepoDataRecord;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The version of the lockfile format
+const LOCKFILE_VERSION: u32 = 4;
+
+/// The environment pixi installs when no other is selected
+const DEFAULT_ENVIRONMENT: &str = "default";
+
+#[derive(Debug, Serialize)]
+pub struct PixiLock {
+    pub version: u32,
+    pub environments: BTreeMap<String, Environment>,
+    /// Every package of the environments, each listed once
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Environment {
+    pub channels: Vec<LockedChannel>,
+    /// The URLs of the packages of each platform, keyed by platform
+    pub packages: BTreeMap<String, Vec<PackageUrl>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockedChannel {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageUrl {
+    pub conda: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockedPackage {
+    pub kind: String,
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub build_number: u64,
+    pub subdir: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noarch: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub depends: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub constrains: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license_family: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The time at which the package was built, in milliseconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+/// Creates the lockfile of the given solutions, which were solved against `channels`. pixi only
+/// accepts channel URLs, so channel names are resolved against the settings of the server.
+pub fn lockfile(
+    settings: &Settings,
+    channels: &[String],
+    solutions: Vec<PlatformSolution>,
+) -> PixiLock {
+    let channels = channels
+        .iter()
+        .map(|channel| LockedChannel {
+            url: settings
+                .custom_channels
+                .parse_channel(channel, &settings.channel_config)
+                .map_or_else(|_| channel.clone(), |c| c.base_url.to_string()),
+        })
+        .collect();
+
+    let mut environment = Environment {
+        channels,
+        packages: BTreeMap::new(),
+    };
+    // noarch packages are shared by platforms, but listed once
+    let mut seen = BTreeSet::new();
+    let mut packages = Vec::new();
+    for PlatformSolution {
+        platform, solution, ..
+    } in solutions
+    {
+        let urls = solution
+            .packages
+            .iter()
+            .map(|record| PackageUrl {
+                conda: record.url.to_string(),
+            })
+            .collect();
+        environment.packages.insert(platform.to_string(), urls);
+        packages.extend(
+            solution
+                .packages
+                .iter()
+                .filter(|record| seen.insert(record.url.to_string()))
+                .map(locked_package),
+        );
+    }
+    packages.sort_by(|a, b| (&a.name, &a.subdir, &a.url).cmp(&(&b.name, &b.subdir, &b.url)));
+
+    PixiLock {
+        version: LOCKFILE_VERSION,
+        environments: BTreeMap::from([(DEFAULT_ENVIRONMENT.to_string(), environment)]),
+        packages,
+    }
+}
+
+fn locked_package(record: &RepoDataRecord) -> LockedPackage {
+    let package = &record.package_record;
+    LockedPackage {
+        kind: "conda".to_string(),
+        name: package.name.as_normalized().to_string(),
+        version: package.version.to_string(),
+        build: package.build.clone(),
+        build_number: package.build_number,
+        subdir: package.subdir.clone(),
+        noarch: v1::noarch_name(&package.noarch).map(str::to_string),
+        url: record.url.to_string(),
+        sha256: package.sha256.map(|sha256| format!("{sha256:x}")),
+        md5: package.md5.map(|md5| format!("{md5:x}")),
+        depends: package.depends.clone(),
+        constrains: package.constrains.clone(),
+        license: package.license.clone(),
+        license_family: package.license_family.clone(),
+        size: package.size,
+        timestamp: package.timestamp.map(|t| t.timestamp_millis()),
+    }
+}
@@ -0,0 +1,299 @@
+//! Turns the explanation of an unsolvable request into a machine-readable report
+//!
+//! The solvers only explain their failures as text. Resolvo's explanation is a tree (indented with
+//! `|-- `) whose lines each describe a requirement or the candidates considered for it, which is
+//! parsed back into its structure. Explanations in any other form yield no report.
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// The structured explanation of why a request is unsolvable
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct ProblemReport {
+    /// The specs of the request that take part in the conflict (e.g. `numpy >=2`)
+    pub conflicting_specs: Vec<String>,
+    /// The requirements of the request that could not be satisfied together, with the
+    /// dependency chains that rule out their candidates
+    pub problems: Vec<ProblemNode>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProblemNode {
+    /// A requirement on a package, made by the request (at the top level) or by the candidate
+    /// above it
+    Requirement {
+        package: String,
+        spec: String,
+        status: RequirementStatus,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        children: Vec<ProblemNode>,
+    },
+    /// Versions of a package that were considered for the requirement above it
+    Candidate {
+        package: String,
+        versions: Vec<String>,
+        status: CandidateStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        children: Vec<ProblemNode>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RequirementStatus {
+    /// No package matches the requirement
+    Missing,
+    /// Some candidates could be installed, but not together with the rest of the environment
+    Installable,
+    /// None of the candidates can be installed
+    NotViable,
+    /// The requirement is a constraint that conflicts with the other requirements
+    Conflicting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateStatus {
+    /// The candidates can be installed
+    Installable,
+    /// The candidates have the requirements listed below them
+    Requires,
+    /// The candidates have the constraints listed below them
+    Constrains,
+    /// The candidates were excluded before solving (see the `reason`)
+    Excluded,
+    /// The candidates conflict with other versions of the package that are required
+    Conflicts,
+    /// The candidates are locked, but other versions of the package are required
+    Locked,
+}
+
+const TREE_MARKER: &str = "|-- ";
+const INCOMPATIBLE_HEADER: &str = "The following packages are incompatible";
+
+/// Parses the explanation of an unsolvable request, returning `None` if it is not in the expected
+/// form
+pub fn parse_problem_report(explanation: &str) -> Option<ProblemReport> {
+    let mut roots = Vec::new();
+    // The nodes whose children are still being parsed, with the column at which their line started
+    let mut open: Vec<(usize, ProblemNode)> = Vec::new();
+
+    for line in explanation.lines().filter(|l| !l.trim().is_empty()) {
+        let indented = line.trim_start_matches(' ');
+        let (column, content) = match indented.strip_prefix(TREE_MARKER) {
+            Some(content) => (line.len() - content.len(), content),
+            None if indented.len() == line.len() => (0, line),
+            None => return None,
+        };
+
+        if column == 0 && content == INCOMPATIBLE_HEADER {
+            close_until(&mut open, &mut roots, 0);
+            continue;
+        }
+        let node = parse_line(content)?;
+
+        // Only top-level requirements may lack a tree marker, so other lines without one are not
+        // part of a tree
+        if column == 0 && !matches!(node, ProblemNode::Requirement { .. }) {
+            return None;
+        }
+
+        close_until(&mut open, &mut roots, column);
+        open.push((column, node));
+    }
+    close_until(&mut open, &mut roots, 0);
+
+    if roots.is_empty() {
+        return None;
+    }
+
+    let conflicting_specs = roots
+        .iter()
+        .filter_map(|node| match node {
+            ProblemNode::Requirement { package, spec, .. } => Some(format!("{package} {spec}")),
+            ProblemNode::Candidate { .. } => None,
+        })
+        .collect();
+    Some(ProblemReport {
+        conflicting_specs,
+        problems: roots,
+    })
+}
+
+/// Completes the open nodes that start at or after `column`, attaching each to its parent
+fn close_until(open: &mut Vec<(usize, ProblemNode)>, roots: &mut Vec<ProblemNode>, column: usize) {
+    while open.last().is_some_and(|(c, _)| *c >= column) {
+        let (_, node) = open.pop().unwrap();
+        match open.last_mut() {
+            Some((_, ProblemNode::Requirement { children, .. }))
+            | Some((_, ProblemNode::Candidate { children, .. })) => children.push(node),
+            None => roots.push(node),
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<ProblemNode> {
+    use CandidateStatus as C;
+    use RequirementStatus as R;
+
+    let requirement = |rest: &str, status| {
+        let (package, spec) = split_package(rest);
+        ProblemNode::Requirement {
+            package,
+            spec,
+            status,
+            children: Vec::new(),
+        }
+    };
+    let candidate = |rest: &str, status, reason: Option<&str>| {
+        let (package, versions) = split_package(rest);
+        ProblemNode::Candidate {
+            package,
+            versions: versions.split(" | ").map(str::to_string).collect(),
+            status,
+            reason: reason.map(str::to_string),
+            children: Vec::new(),
+        }
+    };
+
+    let node = if let Some(rest) = line
+        .strip_prefix("No candidates were found for ")
+        .and_then(|l| l.strip_suffix('.'))
+    {
+        requirement(rest, R::Missing)
+    } else if let Some(rest) = line.strip_suffix(", for which no candidates were found.") {
+        requirement(rest, R::Missing)
+    } else if let Some(rest) = line
+        .strip_suffix(" can be installed with any of the following options:")
+        .or_else(|| {
+            line.strip_suffix(", which can be installed with any of the following options:")
+        })
+    {
+        requirement(rest, R::Installable)
+    } else if let Some(rest) = line
+        .strip_suffix(" cannot be installed because there are no viable options:")
+        .or_else(|| {
+            line.strip_suffix(", which cannot be installed because there are no viable options:")
+        })
+    {
+        requirement(rest, R::NotViable)
+    } else if let Some(rest) =
+        line.strip_suffix(" , which conflicts with any installable versions previously reported")
+    {
+        requirement(rest, R::Conflicting)
+    } else if let Some((rest, reason)) = line.split_once(" is excluded because ") {
+        candidate(rest, C::Excluded, Some(reason))
+    } else if let Some(rest) = line.strip_suffix(" would require") {
+        candidate(rest, C::Requires, None)
+    } else if let Some(rest) = line.strip_suffix(" would constrain") {
+        candidate(rest, C::Constrains, None)
+    } else if let Some(rest) =
+        line.strip_suffix(", which conflicts with the versions reported above.")
+    {
+        candidate(rest, C::Conflicts, None)
+    } else if let Some(rest) =
+        line.strip_suffix(" is locked, but another version is required as reported above")
+    {
+        candidate(rest, C::Locked, None)
+    } else if line.contains(' ') && !line.ends_with(':') {
+        candidate(line, C::Installable, None)
+    } else {
+        return None;
+    };
+    Some(node)
+}
+
+/// Splits a line into the package name and the rest (its spec or versions)
+fn split_package(line: &str) -> (String, String) {
+    match line.split_once(' ') {
+        Some((package, rest)) => (package.to_string(), rest.trim().to_string()),
+        None => (line.to_string(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_problem_report() {
+        let explanation = "\
+No candidates were found for baz >=2.
+The following packages are incompatible
+|-- bar * cannot be installed because there are no viable options:
+    |-- bar 1.2.3 | 1.2.4 would require
+        |-- __unix *, for which no candidates were found.
+    |-- bar 1.0 is excluded because due to strict channel priority not using this option
+|-- foo 3.0.2 can be installed with any of the following options:
+    |-- foo 3.0.2
+";
+        let report = parse_problem_report(explanation).unwrap();
+        assert_eq!(
+            report.conflicting_specs,
+            vec!["baz >=2", "bar *", "foo 3.0.2"]
+        );
+        assert_eq!(
+            report.problems[1],
+            ProblemNode::Requirement {
+                package: "bar".to_string(),
+                spec: "*".to_string(),
+                status: RequirementStatus::NotViable,
+                children: vec![
+                    ProblemNode::Candidate {
+                        package: "bar".to_string(),
+                        versions: vec!["1.2.3".to_string(), "1.2.4".to_string()],
+                        status: CandidateStatus::Requires,
+                        reason: None,
+                        children: vec![ProblemNode::Requirement {
+                            package: "__unix".to_string(),
+                            spec: "*".to_string(),
+                            status: RequirementStatus::Missing,
+                            children: Vec::new(),
+                        }],
+                    },
+                    ProblemNode::Candidate {
+                        package: "bar".to_string(),
+                        versions: vec!["1.0".to_string()],
+                        status: CandidateStatus::Excluded,
+                        reason: Some(
+                            "due to strict channel priority not using this option".to_string()
+                        ),
+                        children: Vec::new(),
+                    },
+                ],
+            }
+        );
+        assert!(matches!(
+            &report.problems[2],
+            ProblemNode::Requirement { children, .. } if children.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_parse_problem_report_without_incompatible_packages() {
+        let explanation = "\
+bar * cannot be installed because there are no viable options:
+|-- bar 1.2.3 would require
+    |-- __unix *, for which no candidates were found.
+";
+        let report = parse_problem_report(explanation).unwrap();
+        assert_eq!(report.conflicting_specs, vec!["bar *"]);
+        let ProblemNode::Requirement { children, .. } = &report.problems[0] else {
+            panic!("unexpected report: {report:?}");
+        };
+        assert!(matches!(
+            &children[..],
+            [ProblemNode::Candidate { status: CandidateStatus::Requires, children, .. }]
+                if children.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_parse_problem_report_rejects_other_explanations() {
+        assert_eq!(parse_problem_report("nothing provides requested bar"), None);
+        assert_eq!(parse_problem_report(""), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Contains the `/solve/events` endpoint, which streams the progress of a solve as server-sent
+//! events before its result
+//!
+//! The phases of a solve report their progress to the sink of the task they run in, if any, so the
+//! code that fetches and solves doesn't need to know whether anybody is listening.
+
+use crate::error::{response_from_error, ApiError};
+use crate::extract::SolveRequest;
+use crate::output::OutputParams;
+use crate::{solve_response, AppState};
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{FutureExt, StreamExt};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// The route of the endpoint, whose timeout also bounds the solve once the stream has started
+pub const EVENTS_ROUTE: &str = "/solve/events";
+
+tokio::task_local! {
+    static PROGRESS: ProgressSink;
+}
+
+/// A phase of a solve
+#[derive(Debug, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum Progress {
+    /// The repodata of a subdir is being fetched, from the cache if possible
+    Fetching { channel: String, platform: String },
+    /// Part of the repodata of a subdir has been downloaded
+    Downloading {
+        channel: String,
+        platform: String,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total: Option<u64>,
+    },
+    /// The downloaded repodata of a subdir is being parsed
+    Parsing { channel: String, platform: String },
+    /// The repodata of a subdir is available
+    Fetched {
+        channel: String,
+        platform: String,
+        records: usize,
+    },
+    /// The solver is looking for a solution among the candidates
+    Solving { candidates: usize },
+}
+
+/// Receives the progress of a solve
+#[derive(Clone)]
+pub struct ProgressSink(UnboundedSender<Event>);
+
+impl ProgressSink {
+    pub fn send(&self, progress: Progress) {
+        if let Ok(event) = Event::default().event("progress").json_data(progress) {
+            // The client may have gone away, in which case nobody is interested anymore
+            let _ = self.0.unbounded_send(event);
+        }
+    }
+}
+
+/// Reports progress to the sink of the current task, if any. The progress is only computed if
+/// there is a sink.
+pub fn report(progress: impl FnOnce() -> Progress) {
+    let _ = PROGRESS.try_with(|sink| sink.send(progress()));
+}
+
+/// Returns the sink of the current task, to report progress from outside of it (e.g. from a
+/// download callback)
+pub fn current_sink() -> Option<ProgressSink> {
+    PROGRESS.try_with(ProgressSink::clone).ok()
+}
+
+/// Solves an environment like `/solve`, streaming its progress as server-sent events
+#[utoipa::path(
+    post,
+    path = "/v1/solve/events",
+    params(OutputParams),
+    request_body = SolveEnvironment,
+    responses(
+        (status = 200, description = "A stream of `progress` events, followed by a `result` event with the status and body of the solve response", content_type = "text/event-stream", body = String),
+    )
+)]
+pub async fn solve_environment_events(
+    State(state): State<Arc<AppState>>,
+    Query(output): Query<OutputParams>,
+    headers: HeaderMap,
+    SolveRequest(payload): SolveRequest,
+) -> Response {
+    let (sender, receiver) = mpsc::unbounded();
+    let sink = ProgressSink(sender);
+    let timeout = state.request_timeouts.for_route(Some(EVENTS_ROUTE));
+
+    let result_sink = sink.clone();
+    let work = PROGRESS.scope(sink, async move {
+        // The result is always sent in full, since the client cannot revalidate a stream
+        let solve = solve_response(state, &headers, &payload, &output, false);
+        let response = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, solve)
+                .await
+                .unwrap_or_else(|_| response_from_error(ApiError::RequestTimeout(timeout))),
+            None => solve.await,
+        };
+
+        let kind = if response.status().is_success() {
+            "result"
+        } else {
+            "error"
+        };
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap_or_default();
+        let event = Event::default()
+            .event(kind)
+            .data(String::from_utf8_lossy(&body));
+        let _ = result_sink.0.unbounded_send(event);
+
+        // Progress reported after the result (e.g. by downloads shared with other requests) is of
+        // no use anymore, so the stream ends once the events sent so far are delivered
+        result_sink.0.close_channel();
+    });
+
+    // The stream drives the solve, which is therefore cancelled if the client disconnects
+    let events = futures::stream::select(receiver.map(Some), work.into_stream().map(|()| None))
+        .filter_map(futures::future::ready)
+        .map(Ok::<_, Infallible>);
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,342 @@
+//! Limits the rate of requests with token buckets, per API key (see [`crate::api_keys`]) or, for
+//! requests without one, per client IP address (or per /64 network, for IPv6 clients)
+
+use crate::error::{response_from_error, ApiError};
+use crate::AppState;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(test)]
+use mock_instant::Instant;
+
+#[cfg(not(test))]
+use std::time::Instant;
+
+/// The maximum amount of client buckets. Once it is reached, the full buckets are dropped (since
+/// they are indistinguishable from new ones), and then the least recently used ones until half of
+/// the buckets are left.
+const MAX_CLIENTS: usize = 10_000;
+
+/// A token bucket holding up to `burst` requests, refilled at a steady rate
+pub struct RateLimiter {
+    capacity: f64,
+    /// The amount of requests that become available per second
+    refill_rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    available: f64,
+    updated_at: Instant,
+}
+
+/// The state of a bucket after taking a request from it, as reported in the `RateLimit-*` headers
+#[derive(Debug, Clone)]
+pub struct RateLimitStatus {
+    /// The size of the bucket
+    pub limit: u32,
+    /// The amount of requests that can be made right away
+    pub remaining: u32,
+    /// How long until the bucket is full again
+    pub reset: Duration,
+    /// How long to wait until a request is available, if this one was rejected
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32, burst: u32) -> RateLimiter {
+        let capacity = f64::from(burst);
+        RateLimiter {
+            capacity,
+            refill_rate: f64::from(requests_per_minute) / 60.0,
+            state: Mutex::new(BucketState {
+                available: capacity,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a request from the bucket, if one is available
+    pub fn acquire(&self) -> RateLimitStatus {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+
+        let retry_after = if state.available >= 1.0 {
+            state.available -= 1.0;
+            None
+        } else if self.refill_rate == 0.0 {
+            Some(Duration::from_secs(60))
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - state.available) / self.refill_rate,
+            ))
+        };
+        let reset = if self.refill_rate == 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((self.capacity - state.available) / self.refill_rate)
+        };
+        RateLimitStatus {
+            limit: self.capacity as u32,
+            remaining: state.available as u32,
+            reset,
+            retry_after,
+        }
+    }
+
+    /// Whether the bucket is full, and thus equivalent to a new one
+    fn is_full(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.available >= self.capacity
+    }
+
+    /// When a request was last taken from the bucket (or when it was last checked)
+    fn last_used(&self) -> Instant {
+        self.state.lock().unwrap().updated_at
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated_at).as_secs_f64();
+        state.available = (state.available + elapsed * self.refill_rate).min(self.capacity);
+        state.updated_at = now;
+    }
+}
+
+impl RateLimitStatus {
+    /// Adds the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers, and
+    /// `Retry-After` for rejected requests
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let seconds = |duration: Duration| HeaderValue::from(duration.as_secs_f64().ceil() as u64);
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", seconds(self.reset));
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(header::RETRY_AFTER, seconds(retry_after));
+        }
+    }
+}
+
+/// The buckets of the clients that make requests without an API key, keyed by IP address. IPv6
+/// clients are keyed by their /64 network instead, since a single client usually has all of its
+/// addresses.
+pub struct ClientRateLimits {
+    requests_per_minute: u32,
+    burst: u32,
+    /// Whether the client address is taken from the `X-Forwarded-For` header, set by a proxy
+    forwarded_for: bool,
+    clients: Mutex<HashMap<IpAddr, RateLimiter>>,
+}
+
+impl ClientRateLimits {
+    /// The burst defaults to a minute worth of requests
+    pub fn new(requests_per_minute: u32, burst: Option<u32>, forwarded_for: bool) -> Self {
+        ClientRateLimits {
+            requests_per_minute,
+            burst: burst.unwrap_or(requests_per_minute),
+            forwarded_for,
+            clients: Mutex::default(),
+        }
+    }
+
+    fn acquire(&self, client: IpAddr) -> RateLimitStatus {
+        let client = bucket_key(client);
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            prune(&mut clients);
+        }
+        clients
+            .entry(client)
+            .or_insert_with(|| RateLimiter::new(self.requests_per_minute, self.burst))
+            .acquire()
+    }
+}
+
+/// The key of the client's bucket: its address, or its /64 network for IPv6 clients
+fn bucket_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+/// Drops the full buckets, and then the least recently used ones until at most half of
+/// [`MAX_CLIENTS`] are left, so pruning again only happens after as many new clients
+fn prune(clients: &mut HashMap<IpAddr, RateLimiter>) {
+    clients.retain(|_, limiter| !limiter.is_full());
+    let excess = clients.len().saturating_sub(MAX_CLIENTS / 2);
+    if excess == 0 {
+        return;
+    }
+    let mut by_last_use: Vec<_> = clients
+        .iter()
+        .map(|(client, limiter)| (limiter.last_used(), *client))
+        .collect();
+    by_last_use.select_nth_unstable(excess - 1);
+    for (_, client) in &by_last_use[..excess] {
+        clients.remove(client);
+    }
+}
+
+/// The address of the client that made the request. Behind a proxy (if `forwarded_for` is set),
+/// that is the last address in `X-Forwarded-For`, since earlier ones may be made up by the client.
+pub fn client_ip(request: &Request, forwarded_for: bool) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    caller_ip(request.headers(), peer, forwarded_for)
+}
+
+/// Like [`client_ip`], for a request with the given headers from the given peer address
+fn caller_ip(headers: &HeaderMap, peer: Option<IpAddr>, forwarded_for: bool) -> Option<IpAddr> {
+    if forwarded_for {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer
+}
+
+/// Rejects the requests of clients that exceed their rate limit. Requests with an API key are
+/// limited by the key instead.
+pub async fn limit_clients(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // The address is unknown if the app is not served over TCP (e.g. in tests)
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let status = match limit_client(&state, request.headers(), peer) {
+        Ok(status) => status,
+        Err(e) => return response_from_error(e),
+    };
+    let mut response = next.run(request).await;
+    if let Some(status) = status {
+        status.insert_headers(response.headers_mut());
+    }
+    response
+}
+
+/// Takes a request from the bucket of the client that sent a request with the given headers from
+/// the given peer address, failing if the client exceeded its rate limit. Returns the state of the
+/// bucket, if the client was limited (i.e. if client rate limits are enabled, its address is known
+/// and the request has no API key).
+pub fn limit_client(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+) -> Result<Option<RateLimitStatus>, ApiError> {
+    let Some(limits) = &state.client_rate_limits else {
+        return Ok(None);
+    };
+    let has_api_key = state
+        .api_keys
+        .as_ref()
+        .is_some_and(|keys| keys.is_known(headers));
+    let client = caller_ip(headers, peer, limits.forwarded_for);
+    let Some(client) = client.filter(|_| !has_api_key) else {
+        return Ok(None);
+    };
+
+    let status = limits.acquire(client);
+    if status.retry_after.is_some() {
+        state.metrics.record_client_rate_limited();
+        tracing::info!("rate limited client {client}");
+        return Err(ApiError::RateLimited(status));
+    }
+    Ok(Some(status))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mock_instant::MockClock;
+
+    #[test]
+    fn test_rate_limiter_refills_over_time() {
+        let limiter = RateLimiter::new(2, 2);
+        assert!(limiter.acquire().retry_after.is_none());
+        let status = limiter.acquire();
+        assert!(status.retry_after.is_none());
+        assert_eq!((status.limit, status.remaining), (2, 0));
+        assert_eq!(status.reset.as_secs_f64().round(), 60.0);
+        let retry_after = limiter.acquire().retry_after.unwrap();
+        assert_eq!(retry_after.as_secs_f64().round(), 30.0);
+
+        MockClock::advance(Duration::from_secs(30));
+        assert!(limiter.acquire().retry_after.is_none());
+        assert!(limiter.acquire().retry_after.is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_allows_bursts() {
+        let limiter = RateLimiter::new(60, 5);
+        for _ in 0..5 {
+            assert!(limiter.acquire().retry_after.is_none());
+        }
+        let retry_after = limiter.acquire().retry_after.unwrap();
+        assert_eq!(retry_after.as_secs_f64().round(), 1.0);
+        assert!(!limiter.is_full());
+
+        MockClock::advance(Duration::from_secs(5));
+        assert!(limiter.is_full());
+    }
+
+    #[test]
+    fn test_ipv6_clients_share_their_network_bucket() {
+        let limits = ClientRateLimits::new(60, Some(1), false);
+        let client = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(limits.acquire(client("2001:db8::1")).retry_after.is_none());
+        assert!(limits.acquire(client("2001:db8::2")).retry_after.is_some());
+        assert!(limits
+            .acquire(client("2001:db8:0:1::1"))
+            .retry_after
+            .is_none());
+
+        // IPv4 clients connecting over IPv6 are keyed by their IPv4 address
+        assert!(limits.acquire(client("192.0.2.1")).retry_after.is_none());
+        assert!(limits
+            .acquire(client("::ffff:192.0.2.1"))
+            .retry_after
+            .is_some());
+    }
+
+    #[test]
+    fn test_client_buckets_are_bounded() {
+        let limits = ClientRateLimits::new(1, Some(1), false);
+        let client = |i: usize| IpAddr::V4(std::net::Ipv4Addr::from(i as u32));
+        for i in 0..MAX_CLIENTS {
+            limits.acquire(client(i));
+            MockClock::advance(Duration::from_millis(1));
+        }
+        assert_eq!(limits.clients.lock().unwrap().len(), MAX_CLIENTS);
+
+        // None of the buckets is full again yet, so the least recently used half is dropped
+        limits.acquire(client(MAX_CLIENTS));
+        let clients = limits.clients.lock().unwrap();
+        assert_eq!(clients.len(), MAX_CLIENTS / 2 + 1);
+        assert!(!clients.contains_key(&client(0)));
+        assert!(clients.contains_key(&client(MAX_CLIENTS - 1)));
+    }
+}
@@ -0,0 +1,115 @@
+//! Persists downloaded repodata on disk, so it survives restarts of the server. Entries are keyed
+//! by the URL of their subdir and stored as zstd-compressed JSON.
+
+use anyhow::Context;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// The zstd compression level of the stored files
+const COMPRESSION_LEVEL: i32 = 3;
+
+pub struct RepodataStore {
+    dir: PathBuf,
+}
+
+impl RepodataStore {
+    pub fn new(dir: PathBuf) -> RepodataStore {
+        RepodataStore { dir }
+    }
+
+    /// Reads the entry of the subdir, if any. This blocks, so it should be called from a blocking
+    /// task.
+    pub fn load<T: DeserializeOwned>(&self, subdir_url: &Url) -> anyhow::Result<Option<T>> {
+        let path = self.path(subdir_url);
+        let compressed = match std::fs::read(&path) {
+            Ok(compressed) => compressed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let json = zstd::decode_all(compressed.as_slice())
+            .with_context(|| format!("decompressing {}", path.display()))?;
+        let value =
+            serde_json::from_slice(&json).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(value))
+    }
+
+    /// Replaces the entry of the subdir. The file is written atomically, so concurrent readers
+    /// never see partial entries. This blocks, so it should be called from a blocking task.
+    pub fn save<T: Serialize>(&self, subdir_url: &Url, value: &T) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let json = serde_json::to_vec(value).context("serializing repodata")?;
+        let compressed =
+            zstd::encode_all(json.as_slice(), COMPRESSION_LEVEL).context("compressing repodata")?;
+
+        // The temporary file is removed if anything fails before it is renamed
+        let temp_file = mktemp::Temp::new_file_in(&self.dir).context("creating temporary file")?;
+        std::fs::write(&temp_file, compressed).context("writing temporary file")?;
+        let path = self.path(subdir_url);
+        std::fs::rename(&temp_file, &path)
+            .with_context(|| format!("writing {}", path.display()))?;
+        temp_file.release();
+        Ok(())
+    }
+
+    /// Removes every entry, returning the amount of removed files
+    pub fn clear(&self) -> anyhow::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.dir.display())),
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "zst") {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn path(&self, subdir_url: &Url) -> PathBuf {
+        let hash = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(
+            subdir_url.as_str().as_bytes(),
+        );
+        self.dir.join(format!("{hash:x}.json.zst"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_load_and_clear() {
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let store = RepodataStore::new(temp_dir.join("store"));
+        let url = Url::parse("https://conda.anaconda.org/conda-forge/linux-64/").unwrap();
+        let other_url = Url::parse("https://conda.anaconda.org/conda-forge/noarch/").unwrap();
+
+        assert_eq!(store.load::<Vec<String>>(&url).unwrap(), None);
+        store.save(&url, &vec!["foo".to_string()]).unwrap();
+        store.save(&other_url, &vec!["bar".to_string()]).unwrap();
+        assert_eq!(
+            store.load::<Vec<String>>(&url).unwrap(),
+            Some(vec!["foo".to_string()])
+        );
+
+        // Saving again replaces the entry
+        store.save(&url, &vec!["baz".to_string()]).unwrap();
+        assert_eq!(
+            store.load::<Vec<String>>(&url).unwrap(),
+            Some(vec!["baz".to_string()])
+        );
+
+        assert_eq!(store.clear().unwrap(), 2);
+        assert_eq!(store.load::<Vec<String>>(&url).unwrap(), None);
+    }
+}
@@ -0,0 +1,165 @@
+//! Aborts requests that take longer than the timeout of their route, so no handler can run
+//! unbounded if something hangs, as well as solves that take longer than their own timeout
+
+use crate::cli::RouteTimeout;
+use crate::error::{response_from_error, ApiError, PhaseTimeout, SolvePhase};
+use crate::{v1, AppState};
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Routes that don't do any I/O, so they are exempt from the timeout unless overridden
+const EXEMPT_ROUTES: [&str; 1] = ["/version"];
+
+/// The timeout of every route, where `None` means that requests are never aborted
+pub struct RequestTimeouts {
+    default: Option<Duration>,
+    routes: HashMap<String, Option<Duration>>,
+}
+
+impl RequestTimeouts {
+    /// Creates the timeouts from their amount of seconds, where 0 disables the timeout
+    pub fn new(default_seconds: u64, overrides: &[RouteTimeout]) -> RequestTimeouts {
+        let to_timeout = |seconds| (seconds > 0).then(|| Duration::from_secs(seconds));
+        let mut routes: HashMap<_, _> = EXEMPT_ROUTES
+            .iter()
+            .map(|route| (route.to_string(), None))
+            .collect();
+        for route_timeout in overrides {
+            routes.insert(
+                route_timeout.route.clone(),
+                to_timeout(route_timeout.seconds),
+            );
+        }
+
+        RequestTimeouts {
+            default: to_timeout(default_seconds),
+            routes,
+        }
+    }
+
+    /// The timeout of the route, which is the same in every version of the API
+    pub fn for_route(&self, route: Option<&str>) -> Option<Duration> {
+        route
+            .map(|route| route.strip_prefix(v1::PREFIX).unwrap_or(route))
+            .and_then(|route| self.routes.get(route))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Middleware that responds with a 504 once the route's timeout is exceeded. The handler's future
+/// is dropped at that point, which cancels the work it was waiting on.
+pub async fn enforce(
+    State(state): State<Arc<AppState>>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(timeout) = state
+        .request_timeouts
+        .for_route(matched_path.as_ref().map(MatchedPath::as_str))
+    else {
+        return next.run(request).await;
+    };
+
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => response_from_error(ApiError::RequestTimeout(timeout)),
+    }
+}
+
+/// The time by which a solve must be done, counted from when the request started being handled
+#[derive(Debug, Clone, Copy)]
+pub struct SolveDeadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl SolveDeadline {
+    pub fn start(timeout: Duration) -> SolveDeadline {
+        SolveDeadline {
+            at: Instant::now() + timeout,
+            timeout,
+        }
+    }
+}
+
+/// Runs a phase of a solve, failing if the deadline (if any) passes before it completes. The
+/// phase's future is dropped at that point, which cancels the work it was waiting on.
+pub async fn within_deadline<T>(
+    deadline: Option<SolveDeadline>,
+    phase: SolvePhase,
+    work: impl Future<Output = Result<T, ApiError>>,
+) -> Result<T, ApiError> {
+    let Some(deadline) = deadline else {
+        return work.await;
+    };
+
+    match tokio::time::timeout_at(deadline.at, work).await {
+        Ok(result) => result,
+        Err(_) => Err(ApiError::SolveTimeout(PhaseTimeout {
+            phase,
+            timeout_ms: deadline.timeout.as_millis() as u64,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route_timeouts() {
+        let timeouts = RequestTimeouts::new(
+            30,
+            &[
+                RouteTimeout {
+                    route: "/solve".to_string(),
+                    seconds: 600,
+                },
+                RouteTimeout {
+                    route: "/selftest".to_string(),
+                    seconds: 0,
+                },
+            ],
+        );
+
+        assert_eq!(
+            timeouts.for_route(Some("/solve")),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(timeouts.for_route(Some("/selftest")), None);
+        assert_eq!(timeouts.for_route(Some("/version")), None);
+        assert_eq!(
+            timeouts.for_route(Some("/explicit")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(timeouts.for_route(None), Some(Duration::from_secs(30)));
+        // The versioned routes have the timeouts of the unversioned ones
+        assert_eq!(
+            timeouts.for_route(Some("/v1/solve")),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(timeouts.for_route(Some("/v1/version")), None);
+
+        // Exempt routes can be given a timeout too
+        let timeouts = RequestTimeouts::new(
+            0,
+            &[RouteTimeout {
+                route: "/version".to_string(),
+                seconds: 1,
+            }],
+        );
+        assert_eq!(
+            timeouts.for_route(Some("/version")),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(timeouts.for_route(Some("/solve")), None);
+    }
+}
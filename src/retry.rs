@@ -0,0 +1,121 @@
+//! Decides whether, and when, a failed repodata download is attempted again
+
+use rand::Rng;
+use rattler_repodata_gateway::fetch::FetchRepoDataError;
+use reqwest::StatusCode;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// How failed downloads are retried. The delay before a retry doubles with every attempt, up to a
+/// maximum, and only a random part of it is waited for (full jitter), so the retries of many
+/// downloads that failed at the same time are spread out.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How often a download is attempted in total, so 1 never retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// The response statuses that are retried, besides network errors
+    pub statuses: Vec<StatusCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            statuses: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the (1-based) attempt may be followed by another one
+    pub fn allows_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// How long to wait after the (1-based) attempt failed
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen())
+    }
+
+    /// Whether the download failed for a reason that may go away by itself
+    pub fn is_transient(&self, err: &FetchRepoDataError) -> bool {
+        match err {
+            FetchRepoDataError::HttpError(e) => self.is_transient_http(e),
+            FetchRepoDataError::FailedToDownload(_, e) => {
+                match e.get_ref().and_then(|inner| inner.downcast_ref()) {
+                    Some(e) => self.is_transient_http(e),
+                    None => matches!(
+                        e.kind(),
+                        ErrorKind::UnexpectedEof
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::ConnectionAborted
+                            | ErrorKind::TimedOut
+                    ),
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the request failed with one of the retried statuses, or could not be completed
+    pub fn is_transient_http(&self, err: &reqwest::Error) -> bool {
+        match err.status() {
+            Some(status) => self.statuses.contains(&status),
+            None => err.is_connect() || err.is_timeout() || err.is_request() || err.is_body(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            statuses: Vec::new(),
+        };
+        for attempt in 1..10 {
+            let cap = Duration::from_millis(100 * 2u64.pow(attempt - 1)).min(policy.max_backoff);
+            assert!(policy.delay(attempt) <= cap);
+        }
+        assert!(policy.allows_retry(9));
+        assert!(!policy.allows_retry(10));
+    }
+
+    #[tokio::test]
+    async fn test_is_transient() {
+        let mut server = mockito::Server::new_async().await;
+        let policy = RetryPolicy {
+            statuses: vec![StatusCode::SERVICE_UNAVAILABLE],
+            ..RetryPolicy::default()
+        };
+        for (status, transient) in [(503, true), (500, false)] {
+            let _mock = server
+                .mock("GET", "/")
+                .with_status(status)
+                .create_async()
+                .await;
+            let response = reqwest::get(server.url()).await.unwrap();
+            let err = FetchRepoDataError::HttpError(response.error_for_status().unwrap_err());
+            assert_eq!(policy.is_transient(&err), transient);
+        }
+
+        let reset = std::io::Error::from(ErrorKind::ConnectionReset);
+        let url = reqwest::Url::parse("https://example.com").unwrap();
+        assert!(policy.is_transient(&FetchRepoDataError::FailedToDownload(url, reset)));
+        assert!(!policy.is_transient(&FetchRepoDataError::NoCacheAvailable));
+    }
+}
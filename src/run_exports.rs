@@ -0,0 +1,512 @@
+//! Contains the `/run-exports` endpoint, which tells build tooling the dependencies that the build
+//! and host dependencies of a recipe inject into it, without downloading the packages
+
+use crate::credentials::DownloadClient;
+use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
+use crate::generic_cache::GenericCache;
+use crate::subdir::Subdir;
+use crate::AppState;
+use anyhow::Context;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rattler_conda_types::{Channel, MatchSpec, Platform, RepoDataRecord};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// The file next to the repodata of a subdir with the run exports of its packages
+const RUN_EXPORTS_FILE_NAME: &str = "run_exports.json";
+
+/// The file at the root of a channel with metadata about its packages, including the run exports
+/// of the latest build of each version
+const CHANNEL_DATA_FILE_NAME: &str = "channeldata.json";
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RunExportsQuery {
+    /// The platform of the host environment, e.g. `linux-64`
+    pub platform: String,
+    /// The platform of the build environment, if it differs from `platform` (e.g. when
+    /// cross-compiling)
+    #[serde(default)]
+    pub build_platform: Option<String>,
+    pub channels: Vec<String>,
+    /// The specs of the build dependencies
+    #[serde(default)]
+    pub build: Vec<String>,
+    /// The specs of the host dependencies
+    #[serde(default)]
+    pub host: Vec<String>,
+    /// Whether the package being built is `noarch`, in which case only the `noarch` run exports of
+    /// the host dependencies apply
+    #[serde(default)]
+    pub noarch: bool,
+}
+
+/// The dependencies injected into the package being built, in the order of the specs that
+/// caused them (without duplicates)
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RunExportsResult {
+    /// Injected into the host dependencies, from the `strong` run exports of build dependencies
+    pub host: Vec<String>,
+    /// Injected into the run dependencies
+    pub run: Vec<String>,
+    /// Injected into the run constraints
+    pub run_constrained: Vec<String>,
+    /// The package that each spec resolved to, build dependencies first
+    pub packages: Vec<PackageRunExports>,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PackageRunExports {
+    pub spec: String,
+    pub env: DependencyEnv,
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub url: String,
+    pub source: RunExportsSource,
+    pub run_exports: RunExports,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyEnv {
+    Build,
+    Host,
+}
+
+/// Where the run exports of a package were found
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RunExportsSource {
+    /// The `run_exports.json` of the package's subdir
+    RunExportsJson,
+    /// The `channeldata.json` of the package's channel, which only describes the latest build of
+    /// each version
+    ChannelData,
+    /// Neither file describes the package, so it is assumed to have no run exports
+    None,
+}
+
+/// The run exports of a package, as in its `info/run_exports.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct RunExports {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weak: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strong: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noarch: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weak_constrains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strong_constrains: Vec<String>,
+}
+
+/// The run exports of the packages in a subdir, by file name
+type SubdirRunExports = HashMap<String, RunExports>;
+
+/// The run exports of the packages in a channel, by name and version
+type ChannelRunExports = HashMap<String, HashMap<String, RunExports>>;
+
+#[derive(Deserialize)]
+struct RunExportsFile {
+    #[serde(default)]
+    packages: HashMap<String, RunExportsEntry>,
+    #[serde(default, rename = "packages.conda")]
+    conda_packages: HashMap<String, RunExportsEntry>,
+}
+
+#[derive(Deserialize)]
+struct RunExportsEntry {
+    #[serde(default)]
+    run_exports: RunExports,
+}
+
+#[derive(Deserialize)]
+struct ChannelDataFile {
+    #[serde(default)]
+    packages: HashMap<String, ChannelDataPackage>,
+}
+
+#[derive(Deserialize)]
+struct ChannelDataPackage {
+    #[serde(default)]
+    run_exports: HashMap<String, RunExports>,
+}
+
+/// The run exports files that were downloaded, which expire together with the repodata. Files
+/// that a channel does not have are cached as absent.
+pub struct RunExportsCache {
+    subdirs: GenericCache<Url, Option<SubdirRunExports>>,
+    channels: GenericCache<Url, Option<ChannelRunExports>>,
+}
+
+impl RunExportsCache {
+    pub fn new(expiration: Duration) -> RunExportsCache {
+        RunExportsCache {
+            subdirs: GenericCache::with_expiration(expiration),
+            channels: GenericCache::with_expiration(expiration),
+        }
+    }
+}
+
+/// Looks up the run exports of the build and host dependencies of a package, and aggregates the
+/// dependencies they inject into it
+#[utoipa::path(
+    post,
+    path = "/v1/run-exports",
+    request_body = RunExportsQuery,
+    responses(
+        (status = 200, description = "The injected dependencies", body = RunExportsResult),
+        (status = 400, description = "The request is invalid", body = ErrorResponse),
+        (status = 403, description = "A channel may not be used", body = ErrorResponse),
+        (status = 404, description = "No package matches some of the specs", body = ErrorResponse),
+        (status = 502, description = "The repodata could not be downloaded", body = ErrorResponse),
+    )
+)]
+pub async fn run_exports(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RunExportsQuery>,
+) -> Response {
+    match run_exports_inner(&state, &payload, &headers).await {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => response_from_error(e),
+    }
+}
+
+async fn run_exports_inner(
+    state: &AppState,
+    payload: &RunExportsQuery,
+    headers: &HeaderMap,
+) -> Result<RunExportsResult, ApiError> {
+    let parse_platform = |platform: &str| {
+        Platform::from_str(platform).map_err(|e| {
+            ValidationError::Platform(ParseError {
+                input: platform.to_string(),
+                error: e.to_string(),
+            })
+        })
+    };
+    let host_platform = parse_platform(&payload.platform)?;
+    let build_platform = match &payload.build_platform {
+        Some(platform) => parse_platform(platform)?,
+        None => host_platform,
+    };
+
+    let mut channels = Vec::with_capacity(payload.channels.len());
+    let mut invalid_channels = Vec::new();
+    for channel in &payload.channels {
+        match state.settings().parse_channel(channel) {
+            Ok(channel) => channels.push(channel),
+            Err(e) => invalid_channels.push(ParseError {
+                input: channel.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !invalid_channels.is_empty() {
+        return Err(ValidationError::Channels(ParseErrors(invalid_channels)).into());
+    }
+
+    let mut specs = Vec::new();
+    let mut invalid_specs = Vec::new();
+    let requested = (payload.build.iter().map(|s| (DependencyEnv::Build, s)))
+        .chain(payload.host.iter().map(|s| (DependencyEnv::Host, s)));
+    for (env, spec) in requested {
+        match MatchSpec::from_str(spec) {
+            Ok(matchspec) if matchspec.name.is_some() => specs.push((env, spec, matchspec)),
+            Ok(_) => invalid_specs.push(ParseError {
+                input: spec.clone(),
+                error: "the spec has no package name".to_string(),
+            }),
+            Err(e) => invalid_specs.push(ParseError {
+                input: spec.clone(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !invalid_specs.is_empty() {
+        return Err(ValidationError::MatchSpecs(ParseErrors(invalid_specs)).into());
+    }
+
+    let mut client = None;
+    for channel in &channels {
+        client = crate::channels::check_access(state, channel, headers)?;
+    }
+
+    // Each environment is looked up in its own platform, and in noarch
+    let mut records: HashMap<DependencyEnv, Vec<(Channel, Vec<RepoDataRecord>)>> = HashMap::new();
+    for (env, platform) in [
+        (DependencyEnv::Build, build_platform),
+        (DependencyEnv::Host, host_platform),
+    ] {
+        if !specs.iter().any(|(e, _, _)| *e == env) {
+            continue;
+        }
+        let mut env_records = Vec::with_capacity(channels.len());
+        for channel in &channels {
+            let mut channel_records = Vec::new();
+            for subdir in [
+                Subdir::Platform(platform),
+                Subdir::Platform(Platform::NoArch),
+            ] {
+                let snapshot = state
+                    .available_packages
+                    .get(channel, &subdir, client, None)
+                    .await?;
+                channel_records.extend(snapshot.records.into_loaded()?.iter().cloned());
+            }
+            env_records.push((channel.clone(), channel_records));
+        }
+        records.insert(env, env_records);
+    }
+
+    let mut packages = Vec::with_capacity(specs.len());
+    let mut unknown = Vec::new();
+    for (env, spec, matchspec) in &specs {
+        let best = records[env].iter().find_map(|(channel, records)| {
+            records
+                .iter()
+                .filter(|r| matchspec.matches(&r.package_record))
+                .max_by(|a, b| {
+                    let (a, b) = (&a.package_record, &b.package_record);
+                    (a.version.cmp(&b.version))
+                        .then(a.build_number.cmp(&b.build_number))
+                        .then(a.timestamp.cmp(&b.timestamp))
+                })
+                .map(|record| (channel, record))
+        });
+        let Some((channel, record)) = best else {
+            unknown.push(spec.to_string());
+            continue;
+        };
+
+        let (source, run_exports) = package_run_exports(state, channel, record, client).await?;
+        packages.push(PackageRunExports {
+            spec: spec.to_string(),
+            env: *env,
+            name: record.package_record.name.as_normalized().to_string(),
+            version: record.package_record.version.to_string(),
+            build: record.package_record.build.clone(),
+            url: record.url.to_string(),
+            source,
+            run_exports,
+        });
+    }
+    if !unknown.is_empty() {
+        return Err(ApiError::UnknownPackages(unknown));
+    }
+
+    Ok(aggregate(packages, payload.noarch))
+}
+
+/// Combines the run exports of the packages the way conda-build applies them
+fn aggregate(packages: Vec<PackageRunExports>, noarch: bool) -> RunExportsResult {
+    let mut result = RunExportsResult {
+        host: Vec::new(),
+        run: Vec::new(),
+        run_constrained: Vec::new(),
+        packages: Vec::new(),
+    };
+    let extend = |target: &mut Vec<String>, specs: &[String]| {
+        for spec in specs {
+            if !target.contains(spec) {
+                target.push(spec.clone());
+            }
+        }
+    };
+    for package in &packages {
+        let exports = &package.run_exports;
+        match (package.env, noarch) {
+            (DependencyEnv::Build, false) => {
+                extend(&mut result.host, &exports.strong);
+                extend(&mut result.run, &exports.strong);
+                extend(&mut result.run_constrained, &exports.strong_constrains);
+            }
+            (DependencyEnv::Host, false) => {
+                extend(&mut result.run, &exports.weak);
+                extend(&mut result.run, &exports.strong);
+                extend(&mut result.run_constrained, &exports.weak_constrains);
+                extend(&mut result.run_constrained, &exports.strong_constrains);
+            }
+            (DependencyEnv::Host, true) => extend(&mut result.run, &exports.noarch),
+            (DependencyEnv::Build, true) => {}
+        }
+    }
+    result.packages = packages;
+    result
+}
+
+/// Looks up the run exports of a package in the `run_exports.json` of its subdir, falling back to
+/// the `channeldata.json` of its channel
+async fn package_run_exports(
+    state: &AppState,
+    channel: &Channel,
+    record: &RepoDataRecord,
+    client: Option<&DownloadClient>,
+) -> Result<(RunExportsSource, RunExports), ApiError> {
+    let subdir_url = channel
+        .base_url
+        .join(&format!("{}/", record.package_record.subdir))
+        .context("building the subdir URL")?;
+    let subdir_file = subdir_url
+        .join(RUN_EXPORTS_FILE_NAME)
+        .expect("file name is a valid relative URL");
+    let subdir_exports = cached_file(
+        state,
+        &state.run_exports.subdirs,
+        subdir_file,
+        client,
+        |f| {
+            let file: RunExportsFile = serde_json::from_slice(f)?;
+            Ok(file
+                .packages
+                .into_iter()
+                .chain(file.conda_packages)
+                .map(|(file_name, entry)| (file_name, entry.run_exports))
+                .collect())
+        },
+    )
+    .await?;
+    if let Some(exports) = subdir_exports
+        .as_ref()
+        .as_ref()
+        .and_then(|exports| exports.get(&record.file_name))
+    {
+        return Ok((RunExportsSource::RunExportsJson, exports.clone()));
+    }
+
+    let channel_file = channel
+        .base_url
+        .join(CHANNEL_DATA_FILE_NAME)
+        .expect("file name is a valid relative URL");
+    let channel_exports = cached_file(
+        state,
+        &state.run_exports.channels,
+        channel_file,
+        client,
+        |f| {
+            let file: ChannelDataFile = serde_json::from_slice(f)?;
+            Ok(file
+                .packages
+                .into_iter()
+                .map(|(name, package)| (name, package.run_exports))
+                .collect())
+        },
+    )
+    .await?;
+    let exports = channel_exports.as_ref().as_ref().and_then(|exports| {
+        exports
+            .get(record.package_record.name.as_normalized())?
+            .get(&record.package_record.version.to_string())
+    });
+    Ok(match exports {
+        Some(exports) => (RunExportsSource::ChannelData, exports.clone()),
+        None => (RunExportsSource::None, RunExports::default()),
+    })
+}
+
+/// Returns the parsed contents of a file from the cache, downloading it if needed
+async fn cached_file<T: Send + 'static>(
+    state: &AppState,
+    cache: &GenericCache<Url, Option<T>>,
+    url: Url,
+    client: Option<&DownloadClient>,
+    parse: fn(&[u8]) -> serde_json::Result<T>,
+) -> Result<Arc<Option<T>>, ApiError> {
+    // Files downloaded with the caller's credentials are only shared with the same caller
+    let mut key = url.clone();
+    if let Some(scope) = client.and_then(DownloadClient::cache_scope) {
+        key.query_pairs_mut().append_pair("scope", scope);
+    }
+    if let Some(cached) = cache.get_fresh(&key) {
+        return Ok(cached);
+    }
+
+    let contents = match state.available_packages.download_file(&url, client).await? {
+        Some(bytes) => {
+            // These files can be large (e.g. for conda-forge), so they are parsed off the runtime
+            let parsed = tokio::task::spawn_blocking(move || parse(&bytes))
+                .await
+                .context("parsing panicked")?
+                .with_context(|| format!("parsing {url}"))?;
+            Some(parsed)
+        }
+        None => None,
+    };
+    let contents = Arc::new(contents);
+    cache.insert(key, contents.clone());
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn package(env: DependencyEnv, run_exports: serde_json::Value) -> PackageRunExports {
+        PackageRunExports {
+            spec: String::new(),
+            env,
+            name: String::new(),
+            version: String::new(),
+            build: String::new(),
+            url: String::new(),
+            source: RunExportsSource::RunExportsJson,
+            run_exports: serde_json::from_value(run_exports).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_applies_exports_by_env() {
+        let packages = || {
+            vec![
+                package(
+                    DependencyEnv::Build,
+                    serde_json::json!({
+                        "weak": ["libgcc-ng >=12"],
+                        "strong": ["libgcc-ng >=12"],
+                        "strong_constrains": ["sysroot_linux-64 >=2.17"],
+                    }),
+                ),
+                package(
+                    DependencyEnv::Host,
+                    serde_json::json!({
+                        "weak": ["openssl >=3.2,<4.0a0"],
+                        "noarch": ["python"],
+                        "weak_constrains": ["libopenssl-static <0a0"],
+                    }),
+                ),
+                package(
+                    DependencyEnv::Host,
+                    serde_json::json!({ "strong": ["libgcc-ng >=12"] }),
+                ),
+            ]
+        };
+
+        let result = aggregate(packages(), false);
+        assert_eq!(result.host, ["libgcc-ng >=12"]);
+        assert_eq!(result.run, ["libgcc-ng >=12", "openssl >=3.2,<4.0a0"]);
+        assert_eq!(
+            result.run_constrained,
+            ["sysroot_linux-64 >=2.17", "libopenssl-static <0a0"]
+        );
+
+        let result = aggregate(packages(), true);
+        assert!(result.host.is_empty());
+        assert_eq!(result.run, ["python"]);
+        assert!(result.run_constrained.is_empty());
+    }
+}
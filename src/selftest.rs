@@ -0,0 +1,113 @@
+//! Contains the `/selftest` endpoint, which performs a canary solve to check that the server is
+//! able to fetch repodata and solve environments
+
+use crate::error::ApiError;
+use crate::{solve, AppState, SolveInput};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rattler_conda_types::Platform;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SelftestResult {
+    pub ok: bool,
+    pub duration_ms: u64,
+    /// The stage at which the self-test failed (absent if it succeeded)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<Stage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Fetch,
+    Solve,
+}
+
+/// Runs the canary solve, bypassing the repodata caches so the result always reflects the current
+/// health of the server and its upstream channel
+#[utoipa::path(
+    get,
+    path = "/v1/selftest",
+    responses(
+        (status = 200, description = "The canary solve succeeded", body = SelftestResult),
+        (status = 503, description = "The canary solve failed", body = SelftestResult),
+    )
+)]
+pub async fn selftest(State(state): State<Arc<AppState>>) -> Response {
+    let start = Instant::now();
+    let result = run(&state).await;
+    let duration_ms = start.elapsed().as_millis() as u64;
+
+    let (status, body) = match result {
+        Ok(()) => (
+            StatusCode::OK,
+            SelftestResult {
+                ok: true,
+                duration_ms,
+                stage: None,
+                error: None,
+            },
+        ),
+        Err((stage, err)) => {
+            tracing::warn!("selftest failed at the {stage:?} stage: {err}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                SelftestResult {
+                    ok: false,
+                    duration_ms,
+                    stage: Some(stage),
+                    error: Some(err.to_string()),
+                },
+            )
+        }
+    };
+
+    (status, Json(body)).into_response()
+}
+
+async fn run(state: &AppState) -> Result<(), (Stage, ApiError)> {
+    let args = &state.selftest;
+
+    let channel = state
+        .settings()
+        .parse_channel(&args.selftest_channel)
+        .map_err(|e| (Stage::Fetch, ApiError::Internal(e.into())))?;
+    let mut platforms = vec![args.selftest_platform];
+    if args.selftest_platform != Platform::NoArch {
+        platforms.push(Platform::NoArch);
+    }
+
+    let mut available_packages = Vec::new();
+    for platform in platforms {
+        let records = state
+            .available_packages
+            .get_uncached(&channel, platform)
+            .await
+            .map_err(|e| (Stage::Fetch, e))?;
+        available_packages.push(records);
+    }
+
+    solve(
+        state,
+        SolveInput {
+            available_packages,
+            virtual_packages: Vec::new(),
+            specs: vec![args.selftest_spec.clone()],
+            locked_packages: Vec::new(),
+            pinned_packages: Vec::new(),
+            solver: state.settings().solver,
+        },
+    )
+    .await
+    .map_err(|e| (Stage::Solve, e))?;
+
+    Ok(())
+}
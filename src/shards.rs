@@ -0,0 +1,605 @@
+//! Fetches sharded repo data (CEP-16), of which a solve only downloads the shards of the packages it
+//! can reach from its specs instead of the full repodata.json of a subdir
+
+use crate::available_packages_cache::copy_error;
+use crate::coalesce::Coalescer;
+use crate::download::{self, Fetched};
+use crate::error::{ApiError, TransferFailure};
+use crate::metrics::Metrics;
+use crate::progress::{self, Progress};
+use crate::redact::redact_url;
+use crate::retry::RetryPolicy;
+use anyhow::Context;
+use futures::{StreamExt, TryStreamExt};
+use rattler_conda_types::{Channel, ChannelInfo, PackageName, RepoData, RepoDataRecord};
+use rattler_digest::Sha256;
+use rattler_networking::AuthenticatedClient;
+use reqwest::Url;
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// The file of a subdir that lists its shards
+pub const INDEX_FILE_NAME: &str = "repodata_shards.msgpack.zst";
+
+/// The directory of the cache directory in which downloaded shards are kept
+pub const SHARDS_DIR: &str = "shards";
+
+/// The amount of shards of a subdir that are downloaded at the same time
+const CONCURRENT_SHARD_DOWNLOADS: usize = 16;
+
+/// The approximate memory used by the index entry of a package
+const INDEX_ENTRY_BYTES: u64 = 160;
+
+/// The layout of a shard index, once transcoded to JSON
+#[derive(serde::Deserialize)]
+struct ShardIndexFile {
+    info: ShardsInfo,
+    /// The hex-encoded SHA-256 hash of the shard of each package, which names the shard
+    shards: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ShardsInfo {
+    /// The base URL of the packages, relative to the subdir
+    base_url: String,
+    /// The base URL of the shards, relative to the subdir
+    shards_base_url: String,
+    subdir: String,
+}
+
+/// How the shards of a subdir are downloaded
+#[derive(Clone)]
+pub struct ShardFetcher {
+    pub client: AuthenticatedClient,
+    pub retry: RetryPolicy,
+    /// Where downloaded shards are kept, named by their hash. Shards never change, so they are
+    /// shared by every index (and scope) that lists them.
+    pub dir: PathBuf,
+    pub metrics: Arc<Metrics>,
+}
+
+#[derive(Clone)]
+struct Shard {
+    url: Url,
+    /// The hex-encoded SHA-256 hash of the shard
+    sha256: String,
+}
+
+/// The records of a shard, once it is loaded
+#[derive(Clone)]
+struct LoadedShard {
+    records: Arc<[RepoDataRecord]>,
+    /// The approximate memory used by the records
+    bytes: u64,
+}
+
+/// The records of a (channel, subdir) pair with sharded repo data, of which only the shards of the
+/// packages that solves need are downloaded (and kept for the next solves)
+pub struct ShardedRecords {
+    fetcher: ShardFetcher,
+    /// The shard of each package, keyed by normalized name
+    shards: HashMap<String, Shard>,
+    channel: Channel,
+    info: ChannelInfo,
+    /// The shards loaded so far, keyed by normalized package name
+    loaded: Mutex<HashMap<String, LoadedShard>>,
+    /// The memory used by the loaded shards
+    loaded_bytes: AtomicU64,
+    /// The shards being loaded, keyed by hash
+    fills: Coalescer<String, Result<LoadedShard, ApiError>>,
+    /// Called whenever shards were loaded, e.g. to keep a cache within its memory budget
+    on_loaded: OnceLock<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl ShardedRecords {
+    /// Parses the shard index of the subdir at `subdir_url`. The shards that `previous` (an older
+    /// index of the subdir) loaded are kept, so far as the index still lists them.
+    pub fn from_index(
+        index: &[u8],
+        subdir_url: &Url,
+        channel: &Channel,
+        fetcher: ShardFetcher,
+        previous: Option<&ShardedRecords>,
+    ) -> anyhow::Result<ShardedRecords> {
+        let index = zstd::decode_all(index).context("decompressing the shard index")?;
+        let index: ShardIndexFile =
+            serde_json::from_value(msgpack_to_json(&index)?).context("parsing the shard index")?;
+        let base_url = directory_url(subdir_url, &index.info.base_url)?;
+        let shards_base_url = directory_url(subdir_url, &index.info.shards_base_url)?;
+        let shards: HashMap<_, _> = index
+            .shards
+            .into_iter()
+            .map(|(name, sha256)| {
+                let url = shards_base_url.join(&format!("{sha256}.msgpack.zst"))?;
+                Ok((
+                    PackageName::try_from(name)?.as_normalized().to_string(),
+                    Shard { url, sha256 },
+                ))
+            })
+            .collect::<anyhow::Result<_>>()
+            .context("parsing the shard index")?;
+
+        let loaded: HashMap<_, _> = previous
+            .map(|previous| {
+                let loaded = previous.loaded.lock().unwrap();
+                loaded
+                    .iter()
+                    .filter(|(name, _)| {
+                        let (old, new) = (previous.shards.get(*name), shards.get(*name));
+                        old.zip(new).is_some_and(|(old, new)| old.url == new.url)
+                    })
+                    .map(|(name, shard)| (name.clone(), shard.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let loaded_bytes = loaded.values().map(|shard| shard.bytes).sum();
+
+        Ok(ShardedRecords {
+            fetcher,
+            shards,
+            channel: channel.clone(),
+            info: ChannelInfo {
+                subdir: index.info.subdir,
+                base_url: Some(base_url.to_string()),
+            },
+            loaded: Mutex::new(loaded),
+            loaded_bytes: AtomicU64::new(loaded_bytes),
+            fills: Coalescer::default(),
+            on_loaded: OnceLock::new(),
+        })
+    }
+
+    /// The amount of packages in the subdir (rather than of records, which is unknown until every
+    /// shard is downloaded)
+    pub fn package_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The approximate memory used by the index and the loaded shards
+    pub fn approximate_bytes(&self) -> u64 {
+        self.shards.len() as u64 * INDEX_ENTRY_BYTES + self.loaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sets what is called whenever shards were loaded. Only the first call has an effect.
+    pub fn on_loaded(&self, callback: impl Fn() + Send + Sync + 'static) {
+        let _ = self.on_loaded.set(Box::new(callback));
+    }
+
+    /// The records of the packages, whose shards are loaded from disk or downloaded if they were
+    /// not loaded yet. Packages without a shard have no records in the subdir, so they are left out.
+    pub async fn records(
+        self: &Arc<Self>,
+        names: &[PackageName],
+    ) -> Result<HashMap<String, Arc<[RepoDataRecord]>>, ApiError> {
+        let mut records = HashMap::with_capacity(names.len());
+        let mut missing = Vec::new();
+        {
+            let loaded = self.loaded.lock().unwrap();
+            for name in names.iter().map(PackageName::as_normalized) {
+                if let Some(shard) = loaded.get(name) {
+                    records.insert(name.to_string(), shard.records.clone());
+                } else if let Some(shard) = self.shards.get(name) {
+                    missing.push((name.to_string(), shard.clone()));
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(records);
+        }
+
+        let shards: Vec<_> = futures::stream::iter(missing)
+            .map(|(name, shard)| {
+                let this = self.clone();
+                let fill = self.fills.run(shard.sha256.clone(), move || async move {
+                    this.load_shard(&shard).await
+                });
+                async move {
+                    let shard = fill.await;
+                    match shard.as_ref() {
+                        Ok(shard) => Ok((name, shard.clone())),
+                        Err(err) => Err(copy_error(err)),
+                    }
+                }
+            })
+            .buffer_unordered(CONCURRENT_SHARD_DOWNLOADS)
+            .try_collect()
+            .await?;
+
+        {
+            let mut loaded = self.loaded.lock().unwrap();
+            for (name, shard) in shards {
+                records.insert(name.clone(), shard.records.clone());
+                if let Entry::Vacant(entry) = loaded.entry(name) {
+                    self.loaded_bytes.fetch_add(shard.bytes, Ordering::Relaxed);
+                    entry.insert(shard);
+                }
+            }
+        }
+        if let Some(on_loaded) = self.on_loaded.get() {
+            on_loaded();
+        }
+        Ok(records)
+    }
+
+    /// Loads a shard from disk, or downloads it (and keeps it on disk) if it is not there yet
+    async fn load_shard(&self, shard: &Shard) -> Result<LoadedShard, ApiError> {
+        let path = self
+            .fetcher
+            .dir
+            .join(format!("{}.msgpack.zst", shard.sha256));
+        if let Ok(bytes) = tokio::fs::read(&path).await {
+            match self.parse_shard(&bytes) {
+                Ok(loaded) => return Ok(loaded),
+                Err(err) => {
+                    tracing::debug!("downloading {} again: {err:#}", path.display());
+                }
+            }
+        }
+
+        let request = || self.fetcher.client.get(shard.url.clone());
+        let bytes = match download::fetch(request, None, &self.fetcher.retry).await {
+            Ok(Fetched::Modified { bytes, .. }) => bytes,
+            Ok(Fetched::NotModified) => unreachable!("unconditional requests are never unmodified"),
+            Err(err) => return Err(ApiError::FetchRepoDataJson(shard.url.clone(), err)),
+        };
+        self.fetcher
+            .metrics
+            .record_downloaded_bytes(bytes.len() as u64);
+        progress::report(|| Progress::Downloading {
+            channel: redact_url(&self.channel.base_url),
+            platform: self.info.subdir.clone(),
+            bytes: bytes.len() as u64,
+            total: Some(bytes.len() as u64),
+        });
+
+        let corrupt = || {
+            ApiError::RepodataCorrupt(TransferFailure {
+                url: redact_url(&shard.url),
+                received_bytes: bytes.len() as u64,
+                expected_bytes: None,
+            })
+        };
+        let digest = rattler_digest::compute_bytes_digest::<Sha256>(&bytes);
+        if format!("{digest:x}") != shard.sha256 {
+            tracing::debug!("{} does not match its hash", redact_url(&shard.url));
+            return Err(corrupt());
+        }
+        let loaded = self.parse_shard(&bytes).map_err(|err| {
+            tracing::debug!("cannot parse {}: {err:#}", redact_url(&shard.url));
+            corrupt()
+        })?;
+
+        if let Err(err) = write_atomically(&path, &bytes).await {
+            tracing::warn!("cannot write {}: {err}", path.display());
+        }
+        Ok(loaded)
+    }
+
+    /// Parses a shard, which is laid out like a repodata.json file without its `info`
+    fn parse_shard(&self, shard: &[u8]) -> anyhow::Result<LoadedShard> {
+        let shard = zstd::decode_all(shard)?;
+        let bytes = shard.len() as u64;
+        let mut shard = msgpack_to_json(&shard)?;
+        let fields = shard.as_object_mut().context("the shard is not a map")?;
+        fields
+            .entry("packages")
+            .or_insert_with(|| Value::Object(Default::default()));
+        fields.insert("info".to_string(), serde_json::to_value(&self.info)?);
+        let repo_data: RepoData = serde_json::from_value(shard)?;
+
+        let mut records = repo_data.into_repo_data_records(&self.channel);
+        for record in &mut records {
+            if record.package_record.subdir.is_empty() {
+                record.package_record.subdir = self.info.subdir.clone();
+            }
+        }
+        Ok(LoadedShard {
+            records: records.into(),
+            bytes,
+        })
+    }
+}
+
+/// Writes a file through a temporary file, so concurrent readers never see part of it
+async fn write_atomically(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let temp = path.with_extension(format!("{}.part", uuid::Uuid::new_v4()));
+    tokio::fs::write(&temp, bytes).await?;
+    let renamed = tokio::fs::rename(&temp, path).await;
+    if renamed.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    renamed
+}
+
+/// Resolves a base URL of the shard index against the subdir, as a directory
+fn directory_url(subdir_url: &Url, base_url: &str) -> anyhow::Result<Url> {
+    let mut url = subdir_url
+        .join(base_url)
+        .with_context(|| format!("invalid base URL {base_url}"))?;
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+/// Transcodes MessagePack to JSON. Binary values (the hashes of the shards and packages) become
+/// hex strings, which is how repodata.json represents them.
+fn msgpack_to_json(msgpack: &[u8]) -> anyhow::Result<Value> {
+    let Json(value) = rmp_serde::from_slice(msgpack).context("decoding MessagePack")?;
+    Ok(value)
+}
+
+struct Json(Value);
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(JsonVisitor).map(Json)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a MessagePack value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+        let mut hex = String::with_capacity(2 * v.len());
+        for byte in v {
+            write!(hex, "{byte:02x}").expect("writing to a string cannot fail");
+        }
+        Ok(Value::String(hex))
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Json::deserialize(deserializer).map(|Json(value)| value)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(Json(value)) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut fields = serde_json::Map::new();
+        while let Some((Json(key), Json(value))) = map.next_entry()? {
+            let key = match key {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            fields.insert(key, value);
+        }
+        Ok(Value::Object(fields))
+    }
+}
+
+/// Encodes JSON as the MessagePack of sharded repo data, in which hashes are binary values
+#[cfg(test)]
+pub fn encode(value: &Value) -> Vec<u8> {
+    let value = Encoded {
+        value,
+        binary: false,
+        binary_fields: false,
+    };
+    let msgpack = rmp_serde::to_vec_named(&value).unwrap();
+    zstd::encode_all(msgpack.as_slice(), 3).unwrap()
+}
+
+/// A JSON value, which is encoded as binary if it is a (hex-encoded) hash
+#[cfg(test)]
+struct Encoded<'a> {
+    value: &'a Value,
+    binary: bool,
+    /// Whether the fields of the value are all hashes, like the `shards` of an index
+    binary_fields: bool,
+}
+
+#[cfg(test)]
+impl serde::Serialize for Encoded<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        match self.value {
+            Value::String(hex) if self.binary => {
+                let bytes: Vec<_> = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                    .collect();
+                serializer.serialize_bytes(&bytes)
+            }
+            Value::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (key, value) in fields {
+                    let value = Encoded {
+                        value,
+                        binary: self.binary_fields || matches!(key.as_str(), "md5" | "sha256"),
+                        binary_fields: key == "shards",
+                    };
+                    map.serialize_entry(key, &value)?;
+                }
+                map.end()
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::ChannelConfig;
+    use serde_json::json;
+
+    const SHA256: &str = "6b86b273ff34fce19d6b804eff5a3f5747ada4eaa22f1d49c01e52ddb7875b4b";
+
+    #[test]
+    fn test_msgpack_to_json() {
+        let shard = json!({
+            "packages.conda": {
+                "foo-1.0-0.conda": {"name": "foo", "size": 1, "sha256": SHA256, "depends": []},
+            },
+            "removed": [],
+        });
+        let msgpack = zstd::decode_all(encode(&shard).as_slice()).unwrap();
+        assert_eq!(msgpack_to_json(&msgpack).unwrap(), shard);
+    }
+
+    /// A fetcher of the shards of the test channels, which it keeps in `dir`
+    fn fetcher(dir: &std::path::Path) -> ShardFetcher {
+        ShardFetcher {
+            client: AuthenticatedClient::default(),
+            retry: RetryPolicy::default(),
+            dir: dir.to_path_buf(),
+            metrics: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_requested_shards_are_fetched() {
+        let mut server = mockito::Server::new_async().await;
+        let foo_file = encode(&json!({
+            "packages.conda": {
+                "foo-1.0-0.conda": {
+                    "name": "foo",
+                    "version": "1.0",
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": ["bar"],
+                    "subdir": "linux-64",
+                    "sha256": SHA256,
+                },
+            },
+        }));
+        let foo_sha256 = format!(
+            "{:x}",
+            rattler_digest::compute_bytes_digest::<Sha256>(&foo_file)
+        );
+        let index = json!({
+            "version": 1,
+            "info": {
+                "base_url": "../packages/",
+                "shards_base_url": "shards",
+                "subdir": "linux-64",
+            },
+            "shards": {"foo": foo_sha256, "bar": SHA256},
+        });
+        let foo_shard = server
+            .mock(
+                "GET",
+                format!("/linux-64/shards/{foo_sha256}.msgpack.zst").as_str(),
+            )
+            .with_body(&foo_file)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let channel_url = Url::parse(&format!("{}/", server.url())).unwrap();
+        let channel = Channel::from_url(channel_url, None::<Vec<_>>, &ChannelConfig::default());
+        let subdir_url = channel.base_url.join("linux-64/").unwrap();
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let from_index = |previous| {
+            ShardedRecords::from_index(
+                &encode(&index),
+                &subdir_url,
+                &channel,
+                fetcher(&dir),
+                previous,
+            )
+            .map(Arc::new)
+            .unwrap()
+        };
+        let sharded = from_index(None);
+        assert_eq!(sharded.package_count(), 2);
+        let index_bytes = sharded.approximate_bytes();
+
+        let foo = PackageName::new_unchecked("foo");
+        let missing = PackageName::new_unchecked("missing");
+        let records = sharded
+            .records(&[foo.clone(), missing.clone()])
+            .await
+            .unwrap();
+        sharded.records(&[foo.clone()]).await.unwrap();
+        foo_shard.assert_async().await;
+        assert!(sharded.approximate_bytes() > index_bytes);
+
+        let records = &records["foo"];
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].package_record.depends, ["bar"]);
+        assert_eq!(
+            records[0].url.as_str(),
+            format!("{}/packages/foo-1.0-0.conda", server.url())
+        );
+        assert_eq!(records[0].channel, channel.canonical_name());
+
+        // The shards loaded by a previous index are kept, and the downloaded ones are on disk
+        let carried = from_index(Some(&sharded));
+        assert_eq!(carried.approximate_bytes(), sharded.approximate_bytes());
+        let reloaded = from_index(None);
+        assert_eq!(reloaded.records(&[foo.clone()]).await.unwrap().len(), 1);
+        foo_shard.assert_async().await;
+
+        // A shard that cannot be downloaded fails the fetch
+        let err = sharded
+            .records(&[PackageName::new_unchecked("bar")])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::FetchRepoDataJson(..)), "{err}");
+
+        // A shard that does not match its hash is rejected
+        let _corrupt = server
+            .mock(
+                "GET",
+                format!("/linux-64/shards/{SHA256}.msgpack.zst").as_str(),
+            )
+            .with_body(&foo_file)
+            .create_async()
+            .await;
+        let err = sharded
+            .records(&[PackageName::new_unchecked("bar")])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::RepodataCorrupt(..)), "{err}");
+    }
+}
@@ -0,0 +1,88 @@
+//! Shuts the server down gracefully: once asked to terminate, it stops accepting connections and
+//! gives the requests in flight (as well as the solves of jobs and the repodata downloads running
+//! in the background) a grace period to finish, so clients don't see their connections reset
+
+use crate::listener::Listener;
+use crate::AppState;
+use axum::Router;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How often the background work is checked for completion while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Completes once the process is asked to terminate, through SIGTERM (e.g. by Kubernetes) or
+/// Ctrl+C
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::warn!("cannot listen for Ctrl+C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::warn!("cannot listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+}
+
+/// Serves the app until `shutdown` completes, and then waits for the work in flight to finish for
+/// at most the grace period
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    state: Arc<AppState>,
+    grace_period: Duration,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let shutting_down = Arc::new(Notify::new());
+    let server = listener.serve(app, {
+        let shutting_down = shutting_down.clone();
+        async move {
+            shutdown.await;
+            shutting_down.notify_one();
+        }
+    });
+    let mut server = std::pin::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result,
+        () = shutting_down.notified() => {},
+    }
+    tracing::info!("shutting down, waiting up to {grace_period:?} for the requests in flight");
+
+    let drained = async {
+        let result = server.await;
+        // Background work that finishes in the meantime also finishes persisting its repodata
+        while state.jobs.running() > 0 || state.available_packages.downloads_in_flight() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        result
+    };
+    match tokio::time::timeout(grace_period, drained).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("the work in flight did not finish within the grace period");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! Restricts the available packages to the ones published by the request's snapshot date, so an
+//! environment can be solved as it would have been at that time
+
+use chrono::{DateTime, Utc};
+use rattler_conda_types::RepoDataRecord;
+
+/// Removes the records published after `snapshot`. Records without a timestamp are kept, since
+/// they predate the introduction of timestamps in repodata.
+pub fn filter_snapshot_date(
+    snapshot: Option<DateTime<Utc>>,
+    mut available_packages: Vec<Vec<RepoDataRecord>>,
+) -> Vec<Vec<RepoDataRecord>> {
+    let Some(snapshot) = snapshot else {
+        return available_packages;
+    };
+
+    for records in &mut available_packages {
+        records.retain(|r| r.package_record.timestamp.map_or(true, |t| t <= snapshot));
+    }
+    available_packages
+}
@@ -0,0 +1,180 @@
+//! Runs solves on a dedicated thread pool, so they don't compete with other blocking work (such as
+//! parsing repodata) for the threads of tokio's blocking pool
+//!
+//! Solves that arrive while every thread is busy wait in a queue. The queue is bounded, so a burst
+//! of requests is turned away instead of piling up work (and memory) that can't be handled.
+
+use crate::error::{ApiError, LimitExceeded};
+use crate::metrics::Metrics;
+use anyhow::Context;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The prefix of the names of the solver threads
+const THREAD_NAME_PREFIX: &str = "rattler-solver";
+
+pub struct SolverPool {
+    pool: rayon::ThreadPool,
+    /// The amount of solves waiting for a thread
+    queued: Arc<AtomicUsize>,
+    /// The maximum amount of queued solves, beyond which solves are rejected. Absent if unbounded.
+    max_queued: Option<usize>,
+    metrics: Arc<Metrics>,
+}
+
+impl SolverPool {
+    /// Creates a pool with the given amount of threads, or one thread per CPU if `num_threads` is 0.
+    /// At most `max_queued` solves wait for a thread, if provided.
+    pub fn new(num_threads: usize, max_queued: Option<usize>) -> anyhow::Result<SolverPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("{THREAD_NAME_PREFIX}-{i}"))
+            .build()
+            .context("creating the solver thread pool")?;
+        Ok(SolverPool {
+            pool,
+            queued: Arc::default(),
+            max_queued,
+            metrics: Arc::default(),
+        })
+    }
+
+    /// Records the depth of the queue in `metrics`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// The amount of solves waiting for a thread, and the maximum amount if bounded
+    pub fn queued(&self) -> (usize, Option<usize>) {
+        (self.queued.load(Ordering::SeqCst), self.max_queued)
+    }
+
+    /// Runs `f` on the pool, without blocking the calling task while waiting for the result. Fails
+    /// right away if the queue is full.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, ApiError> {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        if let Some(limit) = self.max_queued.filter(|&limit| queued >= limit) {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.metrics.record_solve_rejected();
+            return Err(ApiError::SolverQueueFull(LimitExceeded {
+                count: queued,
+                limit,
+            }));
+        }
+        self.metrics.record_solve_queued();
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (job_queued, metrics) = (self.queued.clone(), self.metrics.clone());
+        self.pool.spawn(move || {
+            job_queued.fetch_sub(1, Ordering::SeqCst);
+            metrics.record_solve_dequeued();
+
+            // Solves can't be interrupted once started, but queued ones are skipped if the request
+            // was cancelled (e.g. because it timed out) in the meantime
+            if tx.is_closed() {
+                return;
+            }
+
+            // Rayon aborts the process on panics in spawned jobs, so they are caught and reported
+            // to the caller instead
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+
+            // The receiver is gone if the request was cancelled, in which case nobody cares
+            let _ = tx.send(result);
+        });
+
+        match rx.await.context("the solver thread pool is shut down")? {
+            Ok(value) => Ok(value),
+            Err(_) => Err(ApiError::Internal(anyhow::anyhow!(
+                "solver thread panicked"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_uses_dedicated_threads() {
+        let pool = SolverPool::new(2, None).unwrap();
+        let name = pool
+            .run(|| std::thread::current().name().map(str::to_string))
+            .await
+            .unwrap();
+        assert!(name.unwrap().starts_with(THREAD_NAME_PREFIX));
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_panics() {
+        let pool = SolverPool::new(1, None).unwrap();
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+
+        // The pool is still usable afterwards
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_jobs_are_skipped() {
+        let pool = SolverPool::new(1, None).unwrap();
+
+        // Keep the only thread busy while the next job is queued and cancelled
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let mut blocker = Box::pin(pool.run(move || release_rx.recv().unwrap()));
+        assert!(futures::poll!(&mut blocker).is_pending());
+        let ran = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let job_ran = ran.clone();
+        let cancelled = pool.run(move || job_ran.store(true, std::sync::atomic::Ordering::SeqCst));
+        assert!(futures::poll!(Box::pin(cancelled)).is_pending());
+
+        release_tx.send(()).unwrap();
+        blocker.await.unwrap();
+        pool.run(|| ()).await.unwrap();
+        assert!(!ran.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_solves() {
+        let metrics = Arc::new(Metrics::default());
+        let mut pool = SolverPool::new(1, Some(1)).unwrap();
+        pool.set_metrics(metrics.clone());
+
+        // Keep the only thread busy, so the next solve has to wait in the queue
+        let (started_tx, started_rx) = std::sync::mpsc::channel::<()>();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let mut blocker = Box::pin(pool.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap()
+        }));
+        assert!(futures::poll!(&mut blocker).is_pending());
+        started_rx.recv().unwrap();
+        let mut queued = Box::pin(pool.run(|| 1));
+        assert!(futures::poll!(&mut queued).is_pending());
+        assert!(metrics
+            .render()
+            .contains("rattler_server_solver_queue_depth 1\n"));
+
+        let Err(ApiError::SolverQueueFull(exceeded)) = pool.run(|| 2).await else {
+            panic!("the queue should be full");
+        };
+        assert_eq!((exceeded.count, exceeded.limit), (1, 1));
+
+        // Once the queue drains, solves are accepted again
+        release_tx.send(()).unwrap();
+        blocker.await.unwrap();
+        assert_eq!(queued.await.unwrap(), 1);
+        assert_eq!(pool.run(|| 3).await.unwrap(), 3);
+        let rendered = metrics.render();
+        assert!(rendered.contains("rattler_server_solver_queue_depth 0\n"));
+        assert!(rendered.contains("rattler_server_solves_rejected_total 1\n"));
+    }
+}
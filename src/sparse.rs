@@ -0,0 +1,349 @@
+//! Keeps repo data memory-mapped from a file in the cache directory, so a solve only parses the
+//! records it can reach from its specs (see [`crate::cli::RepodataCacheMode::Sparse`])
+
+use crate::available_packages_cache::SnapshotRecords;
+use crate::interned::InternedRecords;
+use crate::shards::ShardedRecords;
+use anyhow::Context;
+use rattler_conda_types::{
+    Channel, ChannelConfig, ChannelInfo, PackageName, PackageRecord, RepoDataRecord,
+};
+use rattler_repodata_gateway::sparse::SparseRepoData;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The approximate amount of memory used by the index of a record, which is all that is kept of
+/// it outside the memory map
+const INDEX_ENTRY_BYTES: u64 = 64;
+
+/// The records of a (channel, subdir) pair, of which only an index is kept in memory
+pub struct SparseRecords {
+    repo_data: SparseRepoData,
+    /// The channel of the records, which is not necessarily the one their URLs point to (e.g. when
+    /// they were downloaded from a mirror)
+    channel: String,
+    record_count: usize,
+}
+
+/// The layout of a repodata.json file, borrowing the records it is written from
+#[derive(Serialize)]
+struct RepoDataFile<'a> {
+    info: ChannelInfo,
+    packages: BTreeMap<&'a str, &'a PackageRecord>,
+}
+
+impl SparseRecords {
+    /// Writes the records to a repodata.json file in `dir` and maps it into memory. The file is
+    /// removed right away, since the mapping keeps its contents available.
+    ///
+    /// Returns `None` if the records cannot be represented by a repodata.json file, which requires
+    /// them to belong to the same channel, to have URLs in the same directory and unique file names
+    /// that start with their package name. That is always the case for downloaded repo data, but
+    /// not necessarily for inserted records.
+    pub fn new(records: &[RepoDataRecord], dir: &Path) -> anyhow::Result<Option<SparseRecords>> {
+        let Some(first) = records.first() else {
+            return Ok(None);
+        };
+        let Some(base_url) = first.url.as_str().strip_suffix(&first.file_name) else {
+            return Ok(None);
+        };
+        let Ok(base_url) = Url::parse(base_url) else {
+            return Ok(None);
+        };
+
+        let mut packages = BTreeMap::new();
+        for record in records {
+            let representable = record.channel == first.channel
+                && base_url.join(&record.file_name).ok().as_ref() == Some(&record.url)
+                && package_name(&record.file_name)
+                    == Some(record.package_record.name.as_normalized());
+            if !representable
+                || packages
+                    .insert(record.file_name.as_str(), &record.package_record)
+                    .is_some()
+            {
+                return Ok(None);
+            }
+        }
+
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating directory {}", dir.display()))?;
+        let path = dir.join(format!("{}.json", uuid::Uuid::new_v4()));
+        let repo_data = RepoDataFile {
+            info: ChannelInfo {
+                subdir: first.package_record.subdir.clone(),
+                base_url: Some(base_url.to_string()),
+            },
+            packages,
+        };
+        let write = || {
+            let mut file = BufWriter::new(std::fs::File::create(&path)?);
+            serde_json::to_writer(&mut file, &repo_data)?;
+            file.flush()?;
+            anyhow::Ok(())
+        };
+        let mapped = write().and_then(|()| {
+            let channel =
+                Channel::from_url(base_url.clone(), None::<Vec<_>>, &ChannelConfig::default());
+            Ok(SparseRepoData::new(
+                channel,
+                first.package_record.subdir.clone(),
+                &path,
+                None,
+            )?)
+        });
+        let _ = std::fs::remove_file(&path);
+        let repo_data = mapped.with_context(|| format!("writing {}", path.display()))?;
+
+        Ok(Some(SparseRecords {
+            repo_data,
+            channel: first.channel.clone(),
+            record_count: records.len(),
+        }))
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.record_count
+    }
+
+    pub fn approximate_bytes(&self) -> u64 {
+        self.record_count as u64 * INDEX_ENTRY_BYTES
+    }
+
+    /// Parses the records of the package
+    pub fn load(&self, name: &PackageName) -> anyhow::Result<Vec<RepoDataRecord>> {
+        let mut records = self
+            .repo_data
+            .load_records(name)
+            .with_context(|| format!("parsing the records of {}", name.as_normalized()))?;
+        for record in &mut records {
+            record.channel = self.channel.clone();
+        }
+        Ok(records)
+    }
+
+    /// Parses all the records
+    pub fn load_all(&self) -> anyhow::Result<Vec<RepoDataRecord>> {
+        let mut records = Vec::with_capacity(self.record_count);
+        for name in self.repo_data.package_names() {
+            records.extend(self.load(&PackageName::new_unchecked(name))?);
+        }
+        Ok(records)
+    }
+}
+
+/// Copies the records of the packages named by `roots`, and of every package they may depend on
+/// (transitively), out of the shared snapshots. The solver never needs any other records, so this is
+/// all that is copied of loaded repo data, all that is rebuilt of interned repo data, all that is
+/// parsed of sparse repo data and all that is downloaded of sharded repo data. If there are no `roots` (e.g. because a spec has no name), all
+/// records are returned, which is not supported for sharded repo data since it would download every
+/// shard (see [`crate::available_packages_cache::AvailablePackagesCache::get_sharded`]).
+pub async fn load_reachable(
+    sources: &[SnapshotRecords],
+    roots: Option<HashSet<PackageName>>,
+) -> anyhow::Result<Vec<Vec<RepoDataRecord>>> {
+    let Some(roots) = roots else {
+        return sources
+            .iter()
+            .map(|source| Ok(source.clone().into_records()?.to_vec()))
+            .collect();
+    };
+
+    // Loaded records are grouped by name, so they are looked up like the sparse ones
+    enum Source<'a> {
+        Loaded(HashMap<&'a PackageName, Vec<&'a RepoDataRecord>>),
+        Interned(&'a InternedRecords),
+        Sparse(&'a SparseRecords),
+        Sharded(&'a Arc<ShardedRecords>),
+    }
+    let mut sources: Vec<_> = sources
+        .iter()
+        .map(|source| match source {
+            SnapshotRecords::Loaded(records) => {
+                let mut by_name: HashMap<_, Vec<_>> = HashMap::new();
+                for record in records.iter() {
+                    by_name
+                        .entry(&record.package_record.name)
+                        .or_default()
+                        .push(record);
+                }
+                Source::Loaded(by_name)
+            }
+            SnapshotRecords::Interned(interned) => Source::Interned(interned),
+            SnapshotRecords::Sparse(sparse) => Source::Sparse(sparse),
+            SnapshotRecords::Sharded(sharded) => Source::Sharded(sharded),
+        })
+        .collect();
+
+    let mut reachable: Vec<Vec<RepoDataRecord>> = sources.iter().map(|_| Vec::new()).collect();
+    // The roots are visited in a fixed order, since the order of the records depends on it (and
+    // the iteration order of a `HashSet` differs between processes)
+    let mut pending: Vec<_> = roots.iter().cloned().collect();
+    pending.sort_by(|a, b| a.as_normalized().cmp(b.as_normalized()));
+    let mut seen = roots;
+    // The packages are visited a level of dependencies at a time, so the shards of a level are
+    // downloaded together
+    while !pending.is_empty() {
+        let shards: Vec<_> = sources
+            .iter()
+            .map(|source| {
+                let pending = &pending;
+                async move {
+                    match source {
+                        Source::Sharded(sharded) => sharded.records(pending).await.map(Some),
+                        _ => Ok(None),
+                    }
+                }
+            })
+            .collect();
+        let mut shards = futures::future::try_join_all(shards).await?;
+
+        for name in std::mem::take(&mut pending) {
+            let levels = sources.iter_mut().zip(&mut shards).zip(&mut reachable);
+            for ((source, shards), reachable) in levels {
+                let records = match source {
+                    Source::Loaded(by_name) => by_name
+                        .remove(&name)
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect(),
+                    Source::Interned(interned) => interned.load(&name),
+                    Source::Sparse(sparse) => sparse.load(&name)?,
+                    Source::Sharded(_) => shards
+                        .as_mut()
+                        .and_then(|shards| shards.remove(name.as_normalized()))
+                        .map_or_else(Vec::new, |records| records.to_vec()),
+                };
+                let dependencies = records.iter().flat_map(|r| &r.package_record.depends);
+                for dependency in dependencies {
+                    let Some(dependency) = dependency_name(dependency) else {
+                        continue;
+                    };
+                    if seen.insert(dependency.clone()) {
+                        pending.push(dependency);
+                    }
+                }
+                reachable.extend(records);
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+/// The name of the package in a (`name-version-build.ext`) file name
+fn package_name(file_name: &str) -> Option<&str> {
+    file_name.rsplitn(3, '-').nth(2)
+}
+
+/// The name of the package that a dependency (e.g. `python >=3.11,<3.12.0a0`) refers to
+fn dependency_name(dependency: &str) -> Option<PackageName> {
+    let dependency = dependency.trim();
+    let name_len = dependency
+        .find(|c: char| c.is_whitespace() || "<>=!~[".contains(c))
+        .unwrap_or(dependency.len());
+    PackageName::try_from(&dependency[..name_len]).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::Version;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn record(name: &str, depends: &[&str]) -> RepoDataRecord {
+        let mut package_record = PackageRecord::new(
+            PackageName::new_unchecked(name),
+            Version::from_str("1.0").unwrap(),
+            "h0_0".to_string(),
+        );
+        package_record.subdir = "linux-64".to_string();
+        package_record.depends = depends.iter().map(|d| d.to_string()).collect();
+        let file_name = format!("{name}-1.0-h0_0.conda");
+        RepoDataRecord {
+            url: Url::parse("https://mirror.example.com/conda-forge/linux-64/")
+                .unwrap()
+                .join(&file_name)
+                .unwrap(),
+            channel: "https://conda.anaconda.org/conda-forge/".to_string(),
+            package_record,
+            file_name,
+        }
+    }
+
+    #[test]
+    fn test_sparse_records_are_loaded_as_they_were_written() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let records = vec![
+            record("python", &[]),
+            record("clang-format", &[]),
+            record("clang-format-13", &["clang-format"]),
+        ];
+        let sparse = SparseRecords::new(&records, &dir).unwrap().unwrap();
+        assert_eq!(sparse.record_count(), 3);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let loaded = sparse
+            .load(&PackageName::new_unchecked("clang-format"))
+            .unwrap();
+        assert_eq!(loaded, vec![records[1].clone()]);
+        let mut all = sparse.load_all().unwrap();
+        all.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        assert_eq!(
+            all,
+            vec![records[1].clone(), records[2].clone(), records[0].clone()]
+        );
+
+        // Records that a repodata.json file cannot represent are not made sparse
+        let mut renamed = record("python", &[]);
+        renamed.file_name = "cpython-1.0-h0_0.conda".to_string();
+        assert!(SparseRecords::new(&[renamed], &dir).unwrap().is_none());
+        let duplicated = vec![record("python", &[]), record("python", &[])];
+        assert!(SparseRecords::new(&duplicated, &dir).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_reachable_follows_dependencies() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let sparse = vec![
+            record("numpy", &["python >=3.11", "libblas>=3.9"]),
+            record("python", &["openssl"]),
+            record("pandas", &["numpy"]),
+        ];
+        let loaded = vec![
+            record("libblas", &[]),
+            record("openssl", &[]),
+            record("zlib", &[]),
+        ];
+        let sources = || {
+            vec![
+                SnapshotRecords::Sparse(Arc::new(
+                    SparseRecords::new(&sparse, &dir).unwrap().unwrap(),
+                )),
+                SnapshotRecords::Loaded(loaded.clone().into()),
+            ]
+        };
+        let names = |records: &[RepoDataRecord]| {
+            let mut names: Vec<_> = records
+                .iter()
+                .map(|r| r.package_record.name.as_normalized().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let roots = HashSet::from([PackageName::new_unchecked("numpy")]);
+        let reachable = load_reachable(&sources(), Some(roots)).await.unwrap();
+        assert_eq!(names(&reachable[0]), ["numpy", "python"]);
+        assert_eq!(names(&reachable[1]), ["libblas", "openssl"]);
+
+        let all = load_reachable(&sources(), None).await.unwrap();
+        assert_eq!(names(&all[0]), ["numpy", "pandas", "python"]);
+        assert_eq!(names(&all[1]), ["libblas", "openssl", "zlib"]);
+    }
+}
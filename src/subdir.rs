@@ -0,0 +1,96 @@
+//! Contains [`Subdir`], which identifies the subdirectories of a channel
+
+use rattler_conda_types::{Channel, Platform};
+use reqwest::Url;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// A subdirectory of a channel, containing its own repodata.json
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subdir {
+    /// The subdirectory of one of the standard platforms
+    Platform(Platform),
+    /// A non-standard subdirectory, e.g. `linux-64-cuda`
+    Custom(String),
+}
+
+impl Subdir {
+    /// Parses a subdirectory name, which is either a standard platform or a custom name consisting
+    /// of alphanumeric characters, `-`, `_` and `.`
+    pub fn parse(name: &str) -> Result<Subdir, String> {
+        if let Ok(platform) = Platform::from_str(name) {
+            return Ok(Subdir::Platform(platform));
+        }
+
+        let valid_chars = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if name.is_empty() || !valid_chars || name.chars().all(|c| c == '.') {
+            return Err(format!("'{name}' is not a valid subdir name"));
+        }
+
+        Ok(Subdir::Custom(name.to_string()))
+    }
+
+    /// The URL of the subdirectory within the channel, which is also used as cache key
+    pub fn url(&self, channel: &Channel) -> Url {
+        match self {
+            Subdir::Platform(platform) => channel.platform_url(*platform),
+            Subdir::Custom(name) => channel
+                .base_url()
+                .join(&format!("{name}/"))
+                .expect("subdir names are valid path segments"),
+        }
+    }
+}
+
+impl From<Platform> for Subdir {
+    fn from(platform: Platform) -> Self {
+        Subdir::Platform(platform)
+    }
+}
+
+impl Display for Subdir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subdir::Platform(platform) => write!(f, "{platform}"),
+            Subdir::Custom(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::ChannelConfig;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Subdir::parse("linux-64"),
+            Ok(Subdir::Platform(Platform::Linux64))
+        );
+        assert_eq!(
+            Subdir::parse("linux-64-cuda"),
+            Ok(Subdir::Custom("linux-64-cuda".to_string()))
+        );
+        assert!(Subdir::parse("").is_err());
+        assert!(Subdir::parse("..").is_err());
+        assert!(Subdir::parse("linux-64/../secret").is_err());
+    }
+
+    #[test]
+    fn test_url() {
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        assert_eq!(
+            Subdir::Custom("linux-64-cuda".to_string())
+                .url(&channel)
+                .as_str(),
+            "https://conda.anaconda.org/conda-forge/linux-64-cuda/"
+        );
+        assert_eq!(
+            Subdir::Platform(Platform::Linux64).url(&channel),
+            channel.platform_url(Platform::Linux64)
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Exports tracing spans to an OpenTelemetry collector over OTLP (only available with the `otlp`
+//! feature), as part of the distributed trace of the caller if it sends a `traceparent` header
+
+use crate::cli::OtlpArgs;
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{BatchSpanProcessor, Sampler, Tracer, TracerProvider};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use tracing::{Instrument, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Creates the layer that exports spans to the configured OTLP endpoint, or `None` if trace export
+/// is disabled
+pub fn layer<S>(args: &OtlpArgs) -> anyhow::Result<Option<OpenTelemetryLayer<S, Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = &args.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint)
+        .with_headers(args.otlp_headers.iter().cloned().collect::<HashMap<_, _>>())
+        .build_span_exporter()?;
+
+    Ok(Some(layer_with_exporter(args, exporter)))
+}
+
+/// Creates the layer that exports spans in batches through the given exporter, and registers its
+/// provider globally so [`shutdown`] flushes it
+fn layer_with_exporter<S, E>(args: &OtlpArgs, exporter: E) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    E: SpanExporter + 'static,
+{
+    let provider = TracerProvider::builder()
+        .with_span_processor(
+            BatchSpanProcessor::builder(exporter, opentelemetry_sdk::runtime::Tokio).build(),
+        )
+        .with_config(
+            opentelemetry_sdk::trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    args.otlp_service_name.clone(),
+                )]))
+                // Traces started by callers are sampled as they decided
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    args.otlp_sampling_ratio,
+                )))),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Runs the request in a span whose parent is the span given in its W3C `traceparent` (and
+/// `tracestate`) headers, if any, so it is exported as part of the trace of the caller
+pub async fn propagate_context(request: Request, next: Next) -> Response {
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+    span.set_parent(parent);
+    next.run(request).instrument(span).await
+}
+
+/// Reads the propagated context from the headers of a request
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Flushes any pending spans, to be called before the server exits
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{layer_with_exporter, propagate_context};
+    use crate::cli::OtlpArgs;
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use futures::future::BoxFuture;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use std::sync::{Arc, Mutex};
+    use tower::util::ServiceExt;
+    use tracing::{span, Level};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Debug, Default)]
+    struct InMemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for InMemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    // Multi-threaded, because flushing the batches blocks until the exporting task is done
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_spans_are_exported() {
+        let exporter = InMemoryExporter::default();
+        let args = OtlpArgs {
+            otlp_endpoint: None,
+            otlp_service_name: "solver-test".to_string(),
+            otlp_headers: Vec::new(),
+            otlp_sampling_ratio: 1.0,
+        };
+        let subscriber =
+            tracing_subscriber::registry().with(layer_with_exporter(&args, exporter.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = span!(Level::INFO, "solve_environment");
+            let _enter = root.enter();
+            span!(Level::DEBUG, "fetch_repo_data").in_scope(|| {});
+        });
+        super::shutdown();
+
+        let spans = exporter.0.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.name.as_ref()).collect();
+        assert_eq!(names, vec!["fetch_repo_data", "solve_environment"]);
+        assert_eq!(spans[0].parent_span_id, spans[1].span_context.span_id());
+        for span in spans.iter() {
+            assert_eq!(
+                span.resource.get("service.name".into()),
+                Some("solver-test".into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_continue_the_trace_of_the_caller() {
+        let exporter = InMemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/solve",
+                get(|| async { span!(Level::INFO, "solve_environment").in_scope(|| "solved") }),
+            )
+            .layer(axum::middleware::from_fn(propagate_context));
+        let request = Request::builder()
+            .uri("/solve")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+        provider.force_flush();
+
+        let spans = exporter.0.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.name.as_ref()).collect();
+        assert_eq!(names, vec!["solve_environment", "request"]);
+        for span in spans.iter() {
+            assert_eq!(
+                span.span_context.trace_id().to_string(),
+                "0af7651916cd43dd8448eb211c80319c"
+            );
+        }
+        assert_eq!(spans[1].parent_span_id.to_string(), "b7ad6b7169203331");
+    }
+}
@@ -0,0 +1,146 @@
+//! Restricts private channels to the tenants that are entitled to them, authenticating downloads
+//! with each tenant's own credentials
+
+use crate::auth::{token_digest, TokenDigest};
+use crate::credentials::{DownloadClient, MemoryStorage};
+use crate::error::ApiError;
+use crate::outbound;
+use anyhow::Context;
+use rattler_networking::{AuthenticatedClient, Authentication, AuthenticationStorage};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The contents of the tenants file
+#[derive(Debug, Deserialize)]
+pub struct TenantsConfig {
+    /// The tenants, keyed by the bearer token they use to authenticate to the server
+    pub tenants: HashMap<String, TenantConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TenantConfig {
+    /// The private channels the tenant may use, keyed by base URL, together with the credentials
+    /// used to download them
+    pub channels: HashMap<Url, Authentication>,
+}
+
+/// The tenants known to the server
+pub struct Tenants {
+    by_token: HashMap<TokenDigest, Tenant>,
+    /// The channels that are only available to the tenants entitled to them
+    private_channels: Vec<Url>,
+}
+
+/// A caller of the server that has access to private channels
+pub struct Tenant {
+    channels: Vec<Url>,
+    client: DownloadClient,
+}
+
+impl Tenants {
+    /// Loads the tenants from a JSON file
+    pub fn from_path(path: &Path) -> anyhow::Result<Tenants> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("opening tenants file {}", path.display()))?;
+        let config: TenantsConfig = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("parsing tenants file {}", path.display()))?;
+        Tenants::from_config(config)
+    }
+
+    pub fn from_config(config: TenantsConfig) -> anyhow::Result<Tenants> {
+        let mut by_token = HashMap::new();
+        let mut private_channels = Vec::new();
+        for (token, tenant) in config.tenants {
+            let mut channels = Vec::new();
+            let mut credentials = HashMap::new();
+            for (channel, authentication) in tenant.channels {
+                let host = channel
+                    .host_str()
+                    .with_context(|| format!("channel {channel} has no host"))?
+                    .to_string();
+
+                // Credentials are applied per host, so they must be unambiguous
+                if let Some(existing) = credentials.insert(host.clone(), authentication.clone()) {
+                    anyhow::ensure!(
+                        existing == authentication,
+                        "conflicting credentials for host {host} in a single tenant"
+                    );
+                }
+
+                let channel = with_trailing_slash(channel);
+                private_channels.push(channel.clone());
+                channels.push(channel);
+            }
+
+            let mut storage = AuthenticationStorage::new();
+            storage.add_backend(Arc::new(MemoryStorage(credentials)));
+            let client = DownloadClient::shared(AuthenticatedClient::from_client(
+                outbound::client(),
+                storage,
+            ));
+            by_token.insert(token_digest(&token), Tenant { channels, client });
+        }
+
+        Ok(Tenants {
+            by_token,
+            private_channels,
+        })
+    }
+
+    /// Identifies the tenant using the provided bearer token. Anonymous callers are allowed (they
+    /// simply don't have access to private channels), but unknown tokens are rejected.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Option<&Tenant>, ApiError> {
+        match token {
+            None => Ok(None),
+            Some(token) => self
+                .by_token
+                .get(&token_digest(token))
+                .map(Some)
+                .ok_or(ApiError::Unauthorized),
+        }
+    }
+
+    /// Fails if the channel is private and the tenant is not entitled to it
+    pub fn check_access(&self, tenant: Option<&Tenant>, channel_url: &Url) -> Result<(), ApiError> {
+        let is_private = self
+            .private_channels
+            .iter()
+            .any(|private| is_within(channel_url, private));
+        let is_entitled = tenant.map_or(false, |tenant| {
+            tenant
+                .channels
+                .iter()
+                .any(|channel| is_within(channel_url, channel))
+        });
+
+        if is_private && !is_entitled {
+            return Err(ApiError::ChannelForbidden(channel_url.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Tenant {
+    /// The client that authenticates downloads with the tenant's credentials
+    pub fn client(&self) -> &DownloadClient {
+        &self.client
+    }
+}
+
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Whether the URL is the base URL (with a trailing slash) or one of its descendants
+pub fn is_within(url: &Url, base: &Url) -> bool {
+    with_trailing_slash(url.clone())
+        .as_str()
+        .starts_with(base.as_str())
+}
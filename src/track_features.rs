@@ -0,0 +1,105 @@
+//! Steers the selection of builds according to their `track_features`
+//!
+//! Both solvers deprioritize builds that have any tracked feature, which is how conda steers
+//! selection away from variants such as `nomkl`. To honor the preferences of a request, the
+//! tracked features of the available records are adjusted before solving, and restored in the
+//! solution afterwards.
+
+use crate::dto::FeaturePreference;
+use rattler_conda_types::RepoDataRecord;
+use reqwest::Url;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The original tracked features of the records that were adjusted before solving
+#[derive(Default)]
+pub struct OriginalTrackFeatures(HashMap<Url, Vec<String>>);
+
+impl OriginalTrackFeatures {
+    /// Restores the tracked features of the solved records
+    pub fn restore(mut self, records: &mut [RepoDataRecord]) {
+        for record in records {
+            if let Some(original) = self.0.remove(&record.url) {
+                record.package_record.track_features = original;
+            }
+        }
+    }
+}
+
+/// Applies the preferences to the available packages. For every package that has builds carrying
+/// a feature:
+///
+/// * [`FeaturePreference::Prefer`]: the builds lacking the feature are deprioritized instead of
+///   the ones carrying it.
+/// * [`FeaturePreference::Require`]: the builds lacking the feature are removed.
+/// * [`FeaturePreference::Forbid`]: the builds carrying the feature are removed.
+pub fn apply_track_features_preferences(
+    preferences: &BTreeMap<String, FeaturePreference>,
+    mut available_packages: Vec<Vec<RepoDataRecord>>,
+) -> (Vec<Vec<RepoDataRecord>>, OriginalTrackFeatures) {
+    let mut original = OriginalTrackFeatures::default();
+    if preferences.is_empty() {
+        return (available_packages, original);
+    }
+
+    for (feature, preference) in preferences {
+        let with_feature = packages_with_feature(&available_packages, feature);
+        let eligible =
+            |r: &RepoDataRecord| with_feature.contains(r.package_record.name.as_normalized());
+
+        for records in &mut available_packages {
+            match preference {
+                FeaturePreference::Require => {
+                    records.retain(|r| !eligible(r) || has_feature(r, feature));
+                }
+                FeaturePreference::Forbid => records.retain(|r| !has_feature(r, feature)),
+                FeaturePreference::Prefer => {
+                    for record in records.iter_mut().filter(|r| eligible(r)) {
+                        original
+                            .0
+                            .entry(record.url.clone())
+                            .or_insert_with(|| record.package_record.track_features.clone());
+
+                        let mut track_features: Vec<String> =
+                            features(record).map(str::to_string).collect();
+                        if has_feature(record, feature) {
+                            track_features.retain(|f| f != feature);
+                        } else {
+                            // Any tracked feature deprioritizes a build, so a marker is enough
+                            track_features.push(format!("{feature}-not-preferred"));
+                        }
+                        record.package_record.track_features = track_features;
+                    }
+                }
+            }
+        }
+    }
+
+    (available_packages, original)
+}
+
+fn has_feature(record: &RepoDataRecord, feature: &str) -> bool {
+    features(record).any(|f| f == feature)
+}
+
+/// The tracked features of the record, which may be given as comma- or space-separated lists
+fn features(record: &RepoDataRecord) -> impl Iterator<Item = &str> {
+    record
+        .package_record
+        .track_features
+        .iter()
+        .flat_map(|f| f.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|f| !f.is_empty())
+}
+
+/// Returns the names of the packages that have at least one build carrying the feature
+fn packages_with_feature(
+    available_packages: &[Vec<RepoDataRecord>],
+    feature: &str,
+) -> HashSet<String> {
+    available_packages
+        .iter()
+        .flatten()
+        .filter(|r| has_feature(r, feature))
+        .map(|r| r.package_record.name.as_normalized().to_string())
+        .collect()
+}
@@ -0,0 +1,272 @@
+//! Defines version 1 of the wire schema of the API, served below `/v1` (and, for compatibility, at
+//! the unversioned routes)
+//!
+//! Requests and responses contain package records, which are converted from and to the records of
+//! rattler at this boundary instead of being serialized as rattler defines them, so upgrading
+//! rattler cannot change the shape of the responses.
+
+use chrono::{TimeZone, Utc};
+use rattler_conda_types::{
+    NoArchKind, NoArchType, PackageName, RepoDataRecord, Version, VersionWithSource,
+};
+use rattler_digest::{parse_digest_from_hex, Md5, Sha256};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// The prefix of the routes of this version of the API
+pub const PREFIX: &str = "/v1";
+
+/// Timestamps above this amount of seconds (the end of year 9999) are taken to be milliseconds,
+/// like rattler does, since older responses contained timestamps in seconds
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+
+/// The record of a package, as found in `repodata.json`, along with where the package can be
+/// downloaded from
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PackageRecord {
+    pub arch: Option<String>,
+    pub build: String,
+    pub build_number: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub constrains: Vec<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    pub features: Option<String>,
+    pub legacy_bz2_md5: Option<String>,
+    pub legacy_bz2_size: Option<u64>,
+    pub license: Option<String>,
+    pub license_family: Option<String>,
+    pub md5: Option<String>,
+    pub name: String,
+    /// `generic` or `python`, if the package is independent of the architecture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noarch: Option<String>,
+    pub platform: Option<String>,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub subdir: String,
+    /// The time at which the package was built, in milliseconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    /// The tracked features of the package, separated by commas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_features: Option<String>,
+    pub version: String,
+    /// The file name of the package
+    #[serde(rename = "fn")]
+    pub file_name: String,
+    /// The URL from which the package can be downloaded
+    pub url: String,
+    /// The canonical URL of the channel of the package
+    pub channel: String,
+}
+
+impl From<&RepoDataRecord> for PackageRecord {
+    fn from(record: &RepoDataRecord) -> PackageRecord {
+        let package = &record.package_record;
+        PackageRecord {
+            arch: package.arch.clone(),
+            build: package.build.clone(),
+            build_number: package.build_number,
+            constrains: package.constrains.clone(),
+            depends: package.depends.clone(),
+            features: package.features.clone(),
+            legacy_bz2_md5: package.legacy_bz2_md5.clone(),
+            legacy_bz2_size: package.legacy_bz2_size,
+            license: package.license.clone(),
+            license_family: package.license_family.clone(),
+            md5: package.md5.map(|md5| format!("{md5:x}")),
+            name: package.name.as_source().to_string(),
+            noarch: noarch_name(&package.noarch).map(str::to_string),
+            platform: package.platform.clone(),
+            sha256: package.sha256.map(|sha256| format!("{sha256:x}")),
+            size: package.size,
+            subdir: package.subdir.clone(),
+            timestamp: package.timestamp.map(|t| t.timestamp_millis()),
+            track_features: (!package.track_features.is_empty())
+                .then(|| package.track_features.join(",")),
+            version: package.version.as_str().into_owned(),
+            file_name: record.file_name.clone(),
+            url: record.url.to_string(),
+            channel: record.channel.clone(),
+        }
+    }
+}
+
+impl TryFrom<PackageRecord> for RepoDataRecord {
+    type Error = String;
+
+    fn try_from(record: PackageRecord) -> Result<RepoDataRecord, String> {
+        let version = Version::from_str(&record.version)
+            .map_err(|e| format!("invalid version {}: {e}", record.version))?;
+        let mut package = rattler_conda_types::PackageRecord::new(
+            PackageName::new_unchecked(record.name),
+            VersionWithSource::new(version, record.version),
+            record.build,
+        );
+        package.arch = record.arch;
+        package.build_number = record.build_number;
+        package.constrains = record.constrains;
+        package.depends = record.depends;
+        package.features = record.features;
+        package.legacy_bz2_md5 = record.legacy_bz2_md5;
+        package.legacy_bz2_size = record.legacy_bz2_size;
+        package.license = record.license;
+        package.license_family = record.license_family;
+        package.md5 = record
+            .md5
+            .map(|md5| parse_digest_from_hex::<Md5>(&md5).ok_or(format!("invalid md5 {md5}")))
+            .transpose()?;
+        package.noarch = match record.noarch.as_deref() {
+            None => NoArchType::none(),
+            Some("generic") => NoArchType::generic(),
+            Some("python") => NoArchType::python(),
+            Some(other) => return Err(format!("invalid noarch {other}")),
+        };
+        package.platform = record.platform;
+        package.sha256 = record
+            .sha256
+            .map(|sha256| {
+                parse_digest_from_hex::<Sha256>(&sha256).ok_or(format!("invalid sha256 {sha256}"))
+            })
+            .transpose()?;
+        package.size = record.size;
+        package.subdir = record.subdir;
+        package.timestamp = record
+            .timestamp
+            .map(|timestamp| {
+                let millis = if timestamp > MAX_TIMESTAMP_SECONDS {
+                    timestamp
+                } else {
+                    timestamp * 1000
+                };
+                Utc.timestamp_millis_opt(millis)
+                    .single()
+                    .ok_or(format!("invalid timestamp {timestamp}"))
+            })
+            .transpose()?;
+        package.track_features = record
+            .track_features
+            .iter()
+            .flat_map(|features| features.split([',', ' ']))
+            .filter(|feature| !feature.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Ok(RepoDataRecord {
+            package_record: package,
+            url: Url::parse(&record.url).map_err(|e| format!("invalid url {}: {e}", record.url))?,
+            file_name: record.file_name,
+            channel: record.channel,
+        })
+    }
+}
+
+/// The name of the kind of noarch package, if the package is independent of the architecture
+pub fn noarch_name(noarch: &NoArchType) -> Option<&'static str> {
+    noarch.kind().map(|kind| match kind {
+        NoArchKind::Generic => "generic",
+        NoArchKind::Python => "python",
+    })
+}
+
+/// (De)serializes a list of records as [`PackageRecord`]s, with
+/// `#[serde(with = "crate::v1::records")]`
+pub mod records {
+    use super::PackageRecord;
+    use rattler_conda_types::RepoDataRecord;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        records: &[RepoDataRecord],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(records.iter().map(PackageRecord::from))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<RepoDataRecord>, D::Error> {
+        Vec::<PackageRecord>::deserialize(deserializer)?
+            .into_iter()
+            .map(|record| record.try_into().map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+/// (De)serializes a single record as a [`PackageRecord`], with
+/// `#[serde(with = "crate::v1::record")]`
+pub mod record {
+    use super::PackageRecord;
+    use rattler_conda_types::RepoDataRecord;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        record: &RepoDataRecord,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        PackageRecord::from(record).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, R: From<RepoDataRecord>>(
+        deserializer: D,
+    ) -> Result<R, D::Error> {
+        let record: RepoDataRecord = PackageRecord::deserialize(deserializer)?
+            .try_into()
+            .map_err(de::Error::custom)?;
+        Ok(record.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_package_records_round_trip() {
+        let json = serde_json::json!({
+            "arch": "x86_64",
+            "build": "py311_0",
+            "build_number": 0,
+            "depends": ["python >=3.11,<3.12.0a0"],
+            "features": null,
+            "legacy_bz2_md5": null,
+            "legacy_bz2_size": null,
+            "license": "MIT",
+            "license_family": null,
+            "md5": "d65ab674acf3b7294ebacaec05fc5b54",
+            "name": "foo",
+            "noarch": "python",
+            "platform": "linux",
+            "sha256": "1154fceeb5c4ee9bb97d245713ac21eb1910237c724d2b7103747215663273c2",
+            "size": 414494,
+            "subdir": "noarch",
+            "timestamp": 1605110689658i64,
+            "track_features": "a,b",
+            "version": "1.0.0",
+            "fn": "foo-1.0.0-py311_0.conda",
+            "url": "https://conda.anaconda.org/conda-forge/noarch/foo-1.0.0-py311_0.conda",
+            "channel": "https://conda.anaconda.org/conda-forge/",
+        });
+        let record: PackageRecord = serde_json::from_value(json.clone()).unwrap();
+        let record = RepoDataRecord::try_from(record).unwrap();
+        assert_eq!(record.package_record.track_features, ["a", "b"]);
+        assert!(record.package_record.noarch.is_python());
+        assert_eq!(
+            serde_json::to_value(PackageRecord::from(&record)).unwrap(),
+            json
+        );
+
+        // Timestamps in seconds, as rattler wrote them for whole seconds, are still accepted
+        let mut seconds = json;
+        seconds["timestamp"] = 1605110689.into();
+        let record: PackageRecord = serde_json::from_value(seconds).unwrap();
+        let record = RepoDataRecord::try_from(record).unwrap();
+        assert_eq!(
+            PackageRecord::from(&record).timestamp,
+            Some(1_605_110_689_000)
+        );
+    }
+}
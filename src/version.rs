@@ -0,0 +1,29 @@
+//! Exposes information about the running build
+
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_sha: String,
+    pub rustc_version: String,
+}
+
+impl BuildInfo {
+    /// The information about the current build, as captured by the build script
+    pub fn current() -> BuildInfo {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_sha: env!("RATTLER_SERVER_GIT_SHA").to_string(),
+            rustc_version: env!("RATTLER_SERVER_RUSTC_VERSION").to_string(),
+        }
+    }
+}
+
+/// Returns the version of the server and how it was built
+#[utoipa::path(get, path = "/v1/version", responses((status = 200, body = BuildInfo)))]
+pub async fn get_version() -> Json<BuildInfo> {
+    Json(BuildInfo::current())
+}